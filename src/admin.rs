@@ -0,0 +1,835 @@
+use crate::audit::Outcome;
+use crate::bind::{self, Protocol};
+use crate::budget::Budget;
+use crate::config;
+use crate::console;
+use crate::json::Value;
+use crate::latency::Percentiles;
+use crate::registry::{Registry, SessionInfo, tag_key};
+use crate::session::CloseReason;
+use crate::wire;
+use std::cmp::Reverse;
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+
+/// `drain` 子命令查询剩余会话数的间隔
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// 一行命令的长度上限，超过时断开连接，免得一直不发换行的连接占满内存
+const MAX_LINE: u64 = 4096;
+
+const HELP: &str = "\
+commands:
+  status [json]         显示运行状态，加 json 时输出一行 JSON
+  sessions [tag...] [sort=<key>]
+                        列出当前会话；给了标签时只列出带有这些标签的，
+                        sort 按 age、sent、received、traffic 或者某个标签的值排序
+  tunnels               列出反向隧道和公网地址
+  peers                 列出控制通道另一端报告的会话数和流量
+  paths                 列出客户端的各条线路和它们的 RTT、丢包、流量
+  probe                 显示对服务端 ping 探测的 RTT 和丢包
+  attempts [seconds] [accepted|rejected|failed]
+                        列出最近的连接尝试和结果，可以只看最近多少秒内的、某一种结果的
+  kill <session id>     关闭指定会话
+  limit <id> <KB/s|off> 设置或取消指定会话每个方向的带宽上限
+  tag <id> <tag>        给指定会话打上标签，写作 key=value 或者 key
+  untag <id> <key>      去掉指定会话上的标签
+  drain [seconds]       停止接受新会话，等现有会话结束后退出；可选等待上限
+  backend [addr [secs]] 服务端：显示（带熔断状态）或切换后端，新会话立即使用新后端；
+                        指定 secs 时旧后端上的会话在这么多秒后关闭，否则继续运行到结束
+  memory                显示缓冲内存的使用情况
+  latency               显示所有会话和每个会话的转发延迟 p50/p95/p99
+  overhead              显示全局和每个会话线路上的 UDP 流量、转发的数据量和协议开销的比例
+  trace <id|all> [off]  以十六进制打印会话经过的数据，off 关闭
+  log [filter]          显示或设置日志级别：notice、info、debug，可以按模块设置，比如 info,control=debug
+  config                按配置文件的格式显示实际生效的配置，不显示密钥
+  help                  显示本帮助
+";
+
+/// 管理接口：基于 TCP 的按行文本协议，建议只监听在本地回环地址上
+pub async fn bind(addr: &str, retry: Option<Duration>) -> anyhow::Result<TcpListener> {
+    let listener = bind::with_retry("admin interface", Protocol::Tcp, addr, retry, || {
+        TcpListener::bind(addr)
+    })
+    .await?;
+    notice!(
+        "Admin interface listening on {:?}",
+        "管理接口正在监听 {:?}",
+        listener.local_addr()?
+    );
+    Ok(listener)
+}
+
+pub async fn serve(listener: TcpListener, registry: Arc<Registry>, budget: Arc<Budget>) {
+    loop {
+        let (stream, peer) = bind::accept("admin interface", &listener).await;
+        let registry = registry.clone();
+        let budget = budget.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(stream, &registry, &budget).await {
+                warn!(
+                    "Admin connection from {peer} ended with error: {e}",
+                    "来自 {peer} 的管理连接出错断开：{e}"
+                );
+            }
+        });
+    }
+}
+
+/// `status` 子命令：连接正在运行的实例的管理接口，打印它的运行状态
+pub async fn status(addr: &str, json: bool) -> anyhow::Result<()> {
+    let mut stream = TcpStream::connect(addr).await?;
+    let request = if json {
+        "status json\n"
+    } else {
+        "status\nsessions\n"
+    };
+    stream.write_all(request.as_bytes()).await?;
+    stream.shutdown().await?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await?;
+    print!("{response}");
+    Ok(())
+}
+
+/// `drain` 子命令：让正在运行的实例开始排空，等到会话全部结束、实例自己退出后再返回，
+/// 可以用作 Kubernetes 的 preStop 钩子；`deadline` 秒后实例关闭剩余的会话
+pub async fn drain(addr: &str, deadline: Option<u64>) -> anyhow::Result<()> {
+    let stream = TcpStream::connect(addr).await?;
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    let request = match deadline {
+        Some(secs) => format!("drain {secs}\n"),
+        None => "drain\n".to_string(),
+    };
+    writer.write_all(request.as_bytes()).await?;
+    let reply = lines.next_line().await?.unwrap_or_default();
+    // 已经在排空（比如钩子被重复调用）时照样等下去
+    if let Some(error) = reply.strip_prefix("error ")
+        && !error.starts_with("already draining")
+    {
+        anyhow::bail!("{error}");
+    }
+    let mut left = None;
+    loop {
+        // 实例排空完成后自己退出，管理连接随之断开
+        if writer.write_all(b"status\n").await.is_err() {
+            break;
+        }
+        let Ok(Some(line)) = lines.next_line().await else {
+            break;
+        };
+        let sessions = line
+            .split_whitespace()
+            .find_map(|field| field.strip_prefix("sessions="))
+            .and_then(|sessions| sessions.parse::<usize>().ok());
+        if sessions == Some(0) {
+            break;
+        }
+        if sessions != left {
+            left = sessions;
+            notice!(
+                "Draining, {} sessions left",
+                "正在排空，还剩 {} 个会话",
+                sessions.unwrap_or_default()
+            );
+        }
+        tokio::time::sleep(DRAIN_POLL_INTERVAL).await;
+    }
+    notice!("Drained", "排空完成");
+    Ok(())
+}
+
+async fn handle_connection(
+    stream: TcpStream,
+    registry: &Arc<Registry>,
+    budget: &Budget,
+) -> io::Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    let mut line = String::new();
+    loop {
+        line.clear();
+        let read = (&mut reader).take(MAX_LINE).read_line(&mut line).await?;
+        if read == 0 {
+            break;
+        }
+        if read as u64 == MAX_LINE && !line.ends_with('\n') {
+            writer.write_all(b"error command too long\n").await?;
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("command longer than {MAX_LINE} bytes"),
+            ));
+        }
+        let mut words = line.split_whitespace();
+        let Some(command) = words.next() else {
+            continue;
+        };
+        let args: Vec<&str> = words.collect();
+        let response = execute(command, &args, registry, budget);
+        writer.write_all(response.as_bytes()).await?;
+    }
+    Ok(())
+}
+
+/// 执行一条管理命令，返回以 `ok` 或 `error` 开头的应答
+pub fn execute(command: &str, args: &[&str], registry: &Arc<Registry>, budget: &Budget) -> String {
+    match (command, args) {
+        ("drain", _) if registry.is_draining() => {
+            format!("error already draining, {} sessions left\n", registry.len())
+        }
+        ("status", []) => {
+            let limit = budget
+                .limit()
+                .map_or("unlimited".to_string(), |limit| limit.to_string());
+            format!(
+                "ok sessions={} draining={} memory_used={} memory_peak={} memory_limit={limit} rejected={} panics={}\n",
+                registry.len(),
+                registry.is_draining(),
+                budget.used(),
+                budget.peak(),
+                budget.rejected(),
+                registry.panics()
+            )
+        }
+        ("status", ["json"]) => format!("{}\n", status_json(registry, budget)),
+        ("sessions", args) => {
+            let sessions = match select(registry.list(), args) {
+                Ok(sessions) => sessions,
+                Err(e) => return format!("error {e}\n"),
+            };
+            let mut out = String::new();
+            let matched = sessions.len();
+            for session in sessions {
+                let conv = session
+                    .conv
+                    .map_or("-".to_string(), |conv| format!("{conv:#010x}"));
+                let traced = if session.traced { " traced" } else { "" };
+                let bulk = if session.bulk { " bulk" } else { "" };
+                let limit = if session.limit > 0 {
+                    format!(" limit={}KB/s", session.limit / 1024)
+                } else {
+                    String::new()
+                };
+                let tags = if session.tags.is_empty() {
+                    String::new()
+                } else {
+                    format!(" tags={}", session.tags.join(","))
+                };
+                let backend = session
+                    .backend
+                    .map_or(String::new(), |backend| format!(" backend={backend}"));
+                let overhead = wire::overhead(
+                    session.wire_sent + session.wire_received,
+                    session.sent + session.received,
+                )
+                .map_or(String::new(), |overhead| {
+                    format!(" overhead={:.1}%", overhead * 100.0)
+                });
+                out += &format!(
+                    "{} {} conv={} {}s{backend}{bulk}{limit}{overhead}{traced}{tags}\n",
+                    session.id,
+                    session.peer,
+                    conv,
+                    session.age.as_secs()
+                );
+            }
+            if args.iter().all(|arg| arg.starts_with("sort=")) {
+                out += &format!("total {matched}\n");
+            } else {
+                out += &format!("matched {matched} of {}\n", registry.len());
+            }
+            out
+        }
+        ("tunnels", []) => {
+            let tunnels = registry.tunnels();
+            let mut out = String::new();
+            for tunnel in &tunnels {
+                out += &format!(
+                    "{} {} host={} peer={}\n",
+                    tunnel.name,
+                    tunnel.public_addr,
+                    tunnel.host.as_deref().unwrap_or("-"),
+                    tunnel.peer
+                );
+            }
+            out += &format!("total {}\n", tunnels.len());
+            out
+        }
+        ("peers", []) => {
+            let peers = registry.peers();
+            let mut out = String::new();
+            for peer in &peers {
+                out += &format!(
+                    "{} sessions={} sent={} received={}",
+                    peer.addr, peer.sessions, peer.sent, peer.received
+                );
+                if let Some(identity) = &peer.identity {
+                    out += &format!(" client={identity}");
+                }
+                if let Some(reason) = &peer.closing {
+                    out += &format!(" closing={reason:?}");
+                }
+                out += "\n";
+            }
+            out += &format!("total {}\n", peers.len());
+            out
+        }
+        ("paths", []) => {
+            let paths = registry.paths();
+            let mut out = String::new();
+            for path in &paths {
+                let rtt = path
+                    .rtt
+                    .map_or("-".to_string(), |rtt| format!("{}ms", rtt.as_millis()));
+                out += &format!(
+                    "{} {} rtt={rtt} loss={:.1}% sessions={} sent={} received={}\n",
+                    path.local,
+                    if path.up { "up" } else { "down" },
+                    path.loss * 100.0,
+                    path.sessions,
+                    path.sent,
+                    path.received
+                );
+            }
+            out += &format!("total {}\n", paths.len());
+            out
+        }
+        ("probe", []) => match registry.probe() {
+            Some(probe) => {
+                let ms = |rtt: Option<Duration>| {
+                    rtt.map_or("-".to_string(), |rtt| format!("{}ms", rtt.as_millis()))
+                };
+                format!(
+                    "ok target={} rtt={} min_rtt={} loss={:.1}% sent={} received={}\n",
+                    probe.target,
+                    ms(probe.rtt),
+                    ms(probe.min_rtt),
+                    probe.loss * 100.0,
+                    probe.sent,
+                    probe.received
+                )
+            }
+            None => "error probing is off, start the client with --probe-interval\n".to_string(),
+        },
+        ("kill", [id]) => {
+            if registry.stop(id, CloseReason::AdminKill) {
+                format!("ok killed {id}\n")
+            } else {
+                format!("error no such session {id}\n")
+            }
+        }
+        ("limit", [id, rate]) => {
+            let kbps = match *rate {
+                "off" | "0" => 0,
+                rate => match rate.parse::<u64>() {
+                    Ok(kbps) => kbps,
+                    Err(_) => return "error invalid rate, expected KB/s or off\n".to_string(),
+                },
+            };
+            let Some(rate) = kbps.checked_mul(1024) else {
+                return "error rate too large\n".to_string();
+            };
+            if !registry.limit(id, rate) {
+                return format!("error no such session {id}\n");
+            }
+            if kbps == 0 {
+                notice!(
+                    "Session {id}: bandwidth limit removed",
+                    "会话 {id}：取消了带宽上限"
+                );
+                format!("ok limit off for {id}\n")
+            } else {
+                notice!(
+                    "Session {id}: bandwidth limited to {kbps} KB/s each way",
+                    "会话 {id}：带宽上限设为每个方向 {kbps} KB/s"
+                );
+                format!("ok limit {kbps}KB/s for {id}\n")
+            }
+        }
+        ("tag", [id, tag]) => {
+            if tag.contains(',') || tag.starts_with('=') {
+                return "error tags can't contain commas or start with =\n".to_string();
+            }
+            if registry.tag(id, tag.to_string()) {
+                format!("ok tagged {id} with {tag}\n")
+            } else {
+                format!("error no such session {id}\n")
+            }
+        }
+        ("untag", [id, key]) => {
+            if registry.untag(id, key) {
+                format!("ok removed tag {key} from {id}\n")
+            } else {
+                format!("error no tag {key} on session {id}\n")
+            }
+        }
+        ("drain", []) => {
+            registry.start_drain();
+            format!("ok draining, {} sessions left\n", registry.len())
+        }
+        ("drain", [secs]) => {
+            let Ok(secs) = secs.parse() else {
+                return "error invalid deadline\n".to_string();
+            };
+            registry.start_drain();
+            let remaining = registry.len();
+            let registry = registry.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(Duration::from_secs(secs)).await;
+                if registry.len() > 0 {
+                    warn!(
+                        "Drain deadline reached, closing {} remaining sessions",
+                        "排空等待超时，关闭剩余的 {} 个会话",
+                        registry.len()
+                    );
+                    registry.stop_all(CloseReason::Shutdown);
+                }
+            });
+            format!("ok draining with {secs}s deadline, {remaining} sessions left\n")
+        }
+        ("backend", []) => match registry.backend() {
+            Some(backend) => {
+                let circuit = registry
+                    .circuits()
+                    .into_iter()
+                    .find(|circuit| circuit.backend == backend)
+                    .map_or(String::new(), |circuit| match circuit.open_for {
+                        Some(open_for) => format!(" circuit=open {}s", open_for.as_secs()),
+                        None => format!(" circuit=closed failures={}", circuit.failures),
+                    });
+                format!(
+                    "ok backend={backend} sessions={}{circuit}\n",
+                    registry.count_backend(&backend)
+                )
+            }
+            None => "error backend switching only works in server mode\n".to_string(),
+        },
+        ("backend", [addr] | [addr, _]) => {
+            let deadline = match args.get(1).map(|secs| secs.parse::<u64>()) {
+                Some(Ok(secs)) => Some(Duration::from_secs(secs)),
+                Some(Err(_)) => return "error invalid deadline\n".to_string(),
+                None => None,
+            };
+            switch_backend(registry, addr, deadline)
+        }
+        ("trace", [target] | [target, "on" | "off"]) => {
+            let on = args.get(1) != Some(&"off");
+            let state = if on { "on" } else { "off" };
+            if *target == "all" {
+                registry.trace_all(on);
+                format!("ok trace {state} for all sessions\n")
+            } else if registry.trace(target, on) {
+                format!("ok trace {state} for {target}\n")
+            } else {
+                format!("error no such session {target}\n")
+            }
+        }
+        ("log", []) => format!("ok log {}\n", console::filter()),
+        ("log", [filter]) => match console::set_filter(filter) {
+            Ok(()) => {
+                notice!(
+                    "Log level set to {} from the admin interface",
+                    "通过管理接口把日志级别设为 {}",
+                    console::filter()
+                );
+                format!("ok log {}\n", console::filter())
+            }
+            Err(e) => format!("error {e}\n"),
+        },
+        ("attempts", args) => attempts(registry, args),
+        ("config", []) => match config::effective() {
+            Some(effective) => effective.to_string(),
+            None => "error effective configuration was not recorded\n".to_string(),
+        },
+        ("memory", []) => {
+            let limit = budget
+                .limit()
+                .map_or("unlimited".to_string(), |limit| format!("{limit} bytes"));
+            format!(
+                "ok used {} bytes, peak {} bytes, limit {limit}, rejected {} sessions\n",
+                budget.used(),
+                budget.peak(),
+                budget.rejected()
+            )
+        }
+        ("latency", []) => {
+            let mut out = format!("all {}\n", latency_text(registry.latency()));
+            for session in registry.list() {
+                out += &format!("{} {}\n", session.id, latency_text(session.latency));
+            }
+            out += &format!("total {}\n", registry.len());
+            out
+        }
+        ("overhead", []) => {
+            let total = wire::total();
+            let traffic = registry.traffic();
+            let mut out = format!(
+                "all {}\n",
+                overhead_text(
+                    total.sent(),
+                    total.received(),
+                    traffic.sent() + traffic.received()
+                )
+            );
+            for session in registry.list() {
+                out += &format!(
+                    "{} {}\n",
+                    session.id,
+                    overhead_text(
+                        session.wire_sent,
+                        session.wire_received,
+                        session.sent + session.received
+                    )
+                );
+            }
+            out += &format!("total {}\n", registry.len());
+            out
+        }
+        ("help", _) => HELP.to_string(),
+        _ => format!("error unknown command {command:?}, try help\n"),
+    }
+}
+
+/// 切换后端，`deadline` 之后关闭还连在旧后端上的会话
+fn switch_backend(registry: &Arc<Registry>, addr: &str, deadline: Option<Duration>) -> String {
+    let Some(old) = registry.backend() else {
+        return "error backend switching only works in server mode\n".to_string();
+    };
+    let valid = addr
+        .rsplit_once(':')
+        .is_some_and(|(host, port)| !host.is_empty() && port.parse::<u16>().is_ok());
+    if !valid {
+        return format!("error invalid address {addr:?}, expected host:port\n");
+    }
+    if addr == old {
+        return format!("error already using backend {addr}\n");
+    }
+    registry.set_backend(addr);
+    let remaining = registry.count_backend(&old);
+    notice!(
+        "Backend switched from {old} to {addr}, {remaining} sessions stay on the old backend",
+        "后端已从 {old} 切换到 {addr}，{remaining} 个会话留在旧后端上"
+    );
+    let Some(deadline) = deadline else {
+        return format!("ok backend {old} -> {addr}, {remaining} sessions stay on {old}\n");
+    };
+    let response = format!(
+        "ok backend {old} -> {addr}, {remaining} sessions on {old} close in {}s\n",
+        deadline.as_secs()
+    );
+    let registry = registry.clone();
+    tokio::spawn(async move {
+        tokio::time::sleep(deadline).await;
+        let stopped = registry.stop_backend(&old, CloseReason::Shutdown);
+        if stopped > 0 {
+            notice!(
+                "Closed {stopped} sessions still on old backend {old}",
+                "关闭了 {stopped} 个还在旧后端 {old} 上的会话"
+            );
+        }
+    });
+    response
+}
+
+fn latency_text(latency: Option<Percentiles>) -> String {
+    let ms = |latency: Duration| format!("{:.2}ms", latency.as_secs_f64() * 1000.0);
+    match latency {
+        Some(latency) => format!(
+            "p50={} p95={} p99={} samples={}",
+            ms(latency.p50),
+            ms(latency.p95),
+            ms(latency.p99),
+            latency.samples
+        ),
+        None => "samples=0".to_string(),
+    }
+}
+
+fn overhead_text(wire_sent: u64, wire_received: u64, payload: u64) -> String {
+    let overhead = wire::overhead(wire_sent + wire_received, payload)
+        .map_or("-".to_string(), |overhead| {
+            format!("{:.1}%", overhead * 100.0)
+        });
+    format!(
+        "wire_sent={wire_sent} wire_received={wire_received} payload={payload} overhead={overhead}"
+    )
+}
+
+/// 全局的转发流量和线路上的 UDP 流量
+fn traffic_json(registry: &Registry) -> Value {
+    let (sent, received) = (registry.traffic().sent(), registry.traffic().received());
+    let total = wire::total();
+    Value::object([
+        ("sent", sent.into()),
+        ("received", received.into()),
+        ("wire_sent", total.sent().into()),
+        ("wire_received", total.received().into()),
+        (
+            "overhead",
+            wire::overhead(total.sent() + total.received(), sent + received).into(),
+        ),
+    ])
+}
+
+fn latency_json(latency: Option<Percentiles>) -> Value {
+    match latency {
+        Some(latency) => Value::object([
+            ("p50_us", (latency.p50.as_micros() as u64).into()),
+            ("p95_us", (latency.p95.as_micros() as u64).into()),
+            ("p99_us", (latency.p99.as_micros() as u64).into()),
+            ("samples", latency.samples.into()),
+        ]),
+        None => Value::Null,
+    }
+}
+
+/// 按 `sessions` 命令的参数筛选、排序会话：`key=value` 要求有这个标签，只写 `key` 时要求有这个名字的标签；
+/// `sort=<key>` 按时长（默认，最久的在前）、流量或者某个标签的值排序，没有这个标签的排在最后
+fn select(mut sessions: Vec<SessionInfo>, args: &[&str]) -> Result<Vec<SessionInfo>, String> {
+    let mut sort = None;
+    for arg in args {
+        match arg.strip_prefix("sort=") {
+            Some("") => return Err("sort needs a key".to_string()),
+            Some(key) => sort = Some(key),
+            None if arg.contains('=') => {
+                sessions.retain(|session| session.tags.iter().any(|tag| tag == arg))
+            }
+            None => sessions.retain(|session| session.tags.iter().any(|tag| tag_key(tag) == *arg)),
+        }
+    }
+    match sort {
+        None | Some("age") => {}
+        Some("sent") => sessions.sort_by_key(|session| Reverse(session.sent)),
+        Some("received") => sessions.sort_by_key(|session| Reverse(session.received)),
+        Some("traffic") => sessions.sort_by_key(|session| Reverse(session.sent + session.received)),
+        Some(key) => sessions.sort_by(|a, b| {
+            // 没有这个标签的排在最后
+            match (tag_value(a, key), tag_value(b, key)) {
+                (Some(a), Some(b)) => a.cmp(b),
+                (a, b) => a.is_none().cmp(&b.is_none()),
+            }
+        }),
+    }
+    Ok(sessions)
+}
+
+/// `attempts` 命令：按时间先后列出最近的连接尝试，参数是秒数和结果，都可以省略、顺序不限
+fn attempts(registry: &Registry, args: &[&str]) -> String {
+    let mut within = None;
+    let mut outcome = None;
+    for arg in args {
+        if let Ok(secs) = arg.parse::<u64>() {
+            within = Some(Duration::from_secs(secs));
+        } else if let Some(parsed) = Outcome::parse(arg) {
+            outcome = Some(parsed);
+        } else {
+            return format!(
+                "error unknown argument {arg}, expected seconds or accepted|rejected|failed\n"
+            );
+        }
+    }
+    let attempts: Vec<_> = registry
+        .audit()
+        .list(within)
+        .into_iter()
+        .filter(|attempt| outcome.is_none_or(|outcome| attempt.outcome == outcome))
+        .collect();
+    let mut out = String::new();
+    for attempt in &attempts {
+        let session = if attempt.session.is_empty() {
+            "-"
+        } else {
+            &attempt.session
+        };
+        out += &format!(
+            "{}s ago {} {} session={session}",
+            attempt.at.elapsed().as_secs(),
+            attempt.peer,
+            attempt.outcome.as_str()
+        );
+        if !attempt.detail.is_empty() {
+            out += &format!(" {}", attempt.detail);
+        }
+        out += "\n";
+    }
+    out += &format!("total {}\n", attempts.len());
+    out
+}
+
+/// 会话上名为 `key` 的标签的值，只有名字的标签值为空
+fn tag_value<'a>(session: &'a SessionInfo, key: &str) -> Option<&'a str> {
+    session
+        .tags
+        .iter()
+        .find(|tag| tag_key(tag) == key)
+        .map(|tag| tag[key.len()..].trim_start_matches('='))
+}
+
+pub fn status_json(registry: &Registry, budget: &Budget) -> Value {
+    let sessions: Vec<Value> = registry
+        .list()
+        .into_iter()
+        .map(|session| {
+            Value::object([
+                ("id", session.id.into()),
+                ("peer", session.peer.to_string().into()),
+                ("conv", session.conv.into()),
+                ("age_secs", session.age.as_secs().into()),
+                ("traced", session.traced.into()),
+                ("bulk", session.bulk.into()),
+                (
+                    "limit_kbps",
+                    (session.limit > 0).then_some(session.limit / 1024).into(),
+                ),
+                ("backend", session.backend.into()),
+                ("tags", session.tags.into()),
+                ("sent", session.sent.into()),
+                ("received", session.received.into()),
+                ("wire_sent", session.wire_sent.into()),
+                ("wire_received", session.wire_received.into()),
+                ("segments", session.segments.into()),
+                ("retransmits", session.retransmits.into()),
+                // 重传占发出的数据段的比例，近似这个方向的丢包率
+                (
+                    "loss",
+                    (session.segments > 0)
+                        .then(|| session.retransmits as f64 / session.segments as f64)
+                        .into(),
+                ),
+                (
+                    "rtt_ms",
+                    session.rtt.map(|rtt| rtt.as_millis() as u64).into(),
+                ),
+                (
+                    "overhead",
+                    wire::overhead(
+                        session.wire_sent + session.wire_received,
+                        session.sent + session.received,
+                    )
+                    .into(),
+                ),
+                ("latency", latency_json(session.latency)),
+            ])
+        })
+        .collect();
+    let tunnels: Vec<Value> = registry
+        .tunnels()
+        .into_iter()
+        .map(|tunnel| {
+            Value::object([
+                ("name", tunnel.name.into()),
+                ("port", tunnel.port.into()),
+                ("public_addr", tunnel.public_addr.into()),
+                ("host", tunnel.host.into()),
+                ("peer", tunnel.peer.into()),
+            ])
+        })
+        .collect();
+    let peers: Vec<Value> = registry
+        .peers()
+        .into_iter()
+        .map(|peer| {
+            Value::object([
+                ("addr", peer.addr.into()),
+                ("sessions", peer.sessions.into()),
+                ("sent", peer.sent.into()),
+                ("received", peer.received.into()),
+                ("client", peer.identity.into()),
+                ("closing", peer.closing.into()),
+            ])
+        })
+        .collect();
+    let paths: Vec<Value> = registry
+        .paths()
+        .into_iter()
+        .map(|path| {
+            Value::object([
+                ("local", path.local.to_string().into()),
+                ("up", path.up.into()),
+                ("rtt_ms", path.rtt.map(|rtt| rtt.as_millis() as u64).into()),
+                ("loss", path.loss.into()),
+                ("sessions", path.sessions.into()),
+                ("sent", path.sent.into()),
+                ("received", path.received.into()),
+            ])
+        })
+        .collect();
+    let circuits: Vec<Value> = registry
+        .circuits()
+        .into_iter()
+        .map(|circuit| {
+            Value::object([
+                ("backend", circuit.backend.into()),
+                ("failures", circuit.failures.into()),
+                ("open", circuit.open_for.is_some().into()),
+                (
+                    "open_secs",
+                    circuit.open_for.map(|open_for| open_for.as_secs()).into(),
+                ),
+            ])
+        })
+        .collect();
+    let probe = match registry.probe() {
+        Some(probe) => Value::object([
+            ("target", probe.target.to_string().into()),
+            ("rtt_ms", probe.rtt.map(|rtt| rtt.as_millis() as u64).into()),
+            (
+                "min_rtt_ms",
+                probe.min_rtt.map(|rtt| rtt.as_millis() as u64).into(),
+            ),
+            ("loss", probe.loss.into()),
+            ("sent", probe.sent.into()),
+            ("received", probe.received.into()),
+        ]),
+        None => Value::Null,
+    };
+    Value::object([
+        ("draining", registry.is_draining().into()),
+        ("session_count", registry.len().into()),
+        ("panics", registry.panics().into()),
+        ("sessions", Value::Array(sessions)),
+        ("tunnels", Value::Array(tunnels)),
+        ("peers", Value::Array(peers)),
+        ("paths", Value::Array(paths)),
+        ("probe", probe),
+        ("circuits", Value::Array(circuits)),
+        ("traffic", traffic_json(registry)),
+        ("latency", latency_json(registry.latency())),
+        (
+            "memory",
+            Value::object([
+                ("used", budget.used().into()),
+                ("peak", budget.peak().into()),
+                ("limit", budget.limit().into()),
+                ("rejected", budget.rejected().into()),
+            ]),
+        ),
+    ])
+}
+
+/// 退出时的运行汇总
+pub fn summary_json(registry: &Registry, budget: &Budget) -> Value {
+    let closes = registry
+        .closes()
+        .into_iter()
+        .map(|(reason, n)| (reason, n.into()))
+        .collect();
+    Value::object([
+        ("uptime_secs", registry.uptime().as_secs().into()),
+        ("total_sessions", registry.total().into()),
+        ("peak_sessions", registry.peak().into()),
+        ("traffic", traffic_json(registry)),
+        ("closes", Value::Object(closes)),
+        ("panics", registry.panics().into()),
+        (
+            "memory",
+            Value::object([
+                ("peak", budget.peak().into()),
+                ("rejected", budget.rejected().into()),
+            ]),
+        ),
+    ])
+}
@@ -0,0 +1,118 @@
+use anyhow::{Context, bail};
+
+/// CPU 编号的上限，和 Linux 的 `CPU_SETSIZE` 一样；也防止写错的范围展开成巨大的列表
+const MAX_CPUS: usize = 1024;
+
+/// 解析形如 `0,2,4-7` 的 CPU 列表
+pub fn parse_cpu_list(list: &str) -> anyhow::Result<Vec<usize>> {
+    let mut cpus = Vec::new();
+    for part in list
+        .split(',')
+        .map(str::trim)
+        .filter(|part| !part.is_empty())
+    {
+        match part.split_once('-') {
+            Some((start, end)) => {
+                let start: usize = start
+                    .trim()
+                    .parse()
+                    .with_context(|| format!("invalid CPU {part:?}"))?;
+                let end: usize = end
+                    .trim()
+                    .parse()
+                    .with_context(|| format!("invalid CPU {part:?}"))?;
+                if start > end || end >= MAX_CPUS {
+                    bail!("invalid CPU range {part:?}");
+                }
+                cpus.extend(start..=end);
+            }
+            None => cpus.push(
+                part.parse()
+                    .with_context(|| format!("invalid CPU {part:?}"))?,
+            ),
+        }
+    }
+    if cpus.is_empty() {
+        bail!("empty CPU list");
+    }
+    if let Some(cpu) = cpus.iter().find(|&&cpu| cpu >= MAX_CPUS) {
+        bail!("CPU {cpu} is out of range, expected below {MAX_CPUS}");
+    }
+    cpus.sort_unstable();
+    cpus.dedup();
+    Ok(cpus)
+}
+
+/// 把当前线程绑定到指定的 CPU 核心上
+#[cfg(target_os = "linux")]
+pub fn pin_current_thread(cpu: usize) -> std::io::Result<()> {
+    unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        libc::CPU_SET(cpu, &mut set);
+        if libc::sched_setaffinity(0, size_of::<libc::cpu_set_t>(), &set) != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(windows)]
+pub fn pin_current_thread(cpu: usize) -> std::io::Result<()> {
+    #[link(name = "kernel32")]
+    unsafe extern "system" {
+        fn GetCurrentThread() -> *mut std::ffi::c_void;
+        fn SetThreadAffinityMask(thread: *mut std::ffi::c_void, mask: usize) -> usize;
+    }
+
+    if cpu >= usize::BITS as usize {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "CPU index out of range",
+        ));
+    }
+    if unsafe { SetThreadAffinityMask(GetCurrentThread(), 1 << cpu) } == 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "linux", windows)))]
+pub fn pin_current_thread(_cpu: usize) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "CPU affinity is not supported on this platform",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_cpu_lists() {
+        assert_eq!(parse_cpu_list("3").unwrap(), [3]);
+        assert_eq!(parse_cpu_list("0,2,4-7").unwrap(), [0, 2, 4, 5, 6, 7]);
+        // 排序去重，忽略空白和多余的逗号
+        assert_eq!(parse_cpu_list(" 5 , 1 - 2,2,,").unwrap(), [1, 2, 5]);
+        assert_eq!(parse_cpu_list("4-4").unwrap(), [4]);
+        assert_eq!(parse_cpu_list("1023").unwrap(), [1023]);
+    }
+
+    #[test]
+    fn rejects_invalid_cpu_lists() {
+        for list in [
+            "",
+            " , ",
+            "a",
+            "1-",
+            "-1",
+            "3-1",
+            "1-2-3",
+            "1.5",
+            "1024",
+            "0-18446744073709551615",
+        ] {
+            assert!(parse_cpu_list(list).is_err(), "{list}");
+        }
+    }
+}
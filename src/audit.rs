@@ -0,0 +1,86 @@
+//! 最近的连接尝试：接受的、拒绝的（带原因）、握手失败的，记在内存里的环形缓冲区中，
+//! 通过管理接口的 `attempts` 命令查询，回答“最近一小时有谁连过来”不用翻日志。
+//!
+//! 服务端记录客户端发起的 KCP 连接，客户端记录本地程序发起的 TCP 连接。
+//! 只保留最近 `CAPACITY` 条，更早的被挤掉，进程重启后清空。
+
+use std::collections::VecDeque;
+use std::fmt::Display;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// 最多保留的条数
+const CAPACITY: usize = 1000;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Outcome {
+    /// 建立了会话
+    Accepted,
+    /// 本端按设置拒绝，比如内存预算用完、正在排空
+    Rejected,
+    /// 握手失败、超时，或者连不上另一端
+    Failed,
+}
+
+impl Outcome {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Outcome::Accepted => "accepted",
+            Outcome::Rejected => "rejected",
+            Outcome::Failed => "failed",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "accepted" => Some(Outcome::Accepted),
+            "rejected" => Some(Outcome::Rejected),
+            "failed" => Some(Outcome::Failed),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Attempt {
+    pub at: Instant,
+    pub peer: SocketAddr,
+    /// 会话 id，还没有分配时为空
+    pub session: String,
+    pub outcome: Outcome,
+    /// 原因或者连接的用途，可以为空
+    pub detail: String,
+}
+
+#[derive(Default)]
+pub struct Audit {
+    attempts: Mutex<VecDeque<Attempt>>,
+}
+
+impl Audit {
+    /// 记录一次连接尝试，满了时挤掉最早的一条
+    pub fn record(&self, peer: SocketAddr, session: &str, outcome: Outcome, detail: impl Display) {
+        let mut attempts = self.attempts.lock().unwrap();
+        if attempts.len() >= CAPACITY {
+            attempts.pop_front();
+        }
+        attempts.push_back(Attempt {
+            at: Instant::now(),
+            peer,
+            session: session.to_string(),
+            outcome,
+            detail: detail.to_string(),
+        });
+    }
+
+    /// 按时间先后列出最近 `within` 以内的尝试，`None` 时列出全部
+    pub fn list(&self, within: Option<Duration>) -> Vec<Attempt> {
+        let attempts = self.attempts.lock().unwrap();
+        attempts
+            .iter()
+            .filter(|attempt| within.is_none_or(|within| attempt.at.elapsed() <= within))
+            .cloned()
+            .collect()
+    }
+}
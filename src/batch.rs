@@ -0,0 +1,454 @@
+//! 服务端监听套接字的批量收发：Linux 上用 `recvmmsg`/`sendmmsg` 一次系统调用收发多个包，
+//! 包多的时候系统调用的开销比逐个收发小得多；其它平台逐个收发，用法相同。
+//!
+//! Linux 上还会尽量打开 UDP 分段卸载：发往同一个客户端的一串同样大小的包（KCP 一次刷出的满 MTU 的段）
+//! 用 `UDP_SEGMENT` 合成一个大包交给内核，由内核或网卡切开；收包时打开 `UDP_GRO`，
+//! 内核合并好的大包按它给出的分段大小切回一个个原来的包，KCP 看到的和逐个收到时一样。
+//! 内核或网卡不支持时（发送时报 `EIO`/`EINVAL`）打印一次警告，改为不合并发送。
+//!
+//! 只用在服务端的收发循环（见 `demux`）里，所有会话的包都经过同一个套接字；
+//! 客户端每个会话一个套接字，包的速率低，批量收发省不了多少。
+
+use bytes::Bytes;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use tokio::net::UdpSocket;
+
+/// 一次最多收发这么多个包（打开分段卸载时是合并后的大包）
+const SLOTS: usize = 32;
+/// 每个包的缓冲区，能放下最大的 UDP 包，也就能放下内核合并的大包
+const SLOT_SIZE: usize = 65536;
+/// 一个合并发送的大包最多分成这么多段，和内核的 `UDP_MAX_SEGMENTS` 一样
+#[cfg(target_os = "linux")]
+const MAX_SEGMENTS: usize = 64;
+/// 一个合并发送的大包最多这么多字节，给 IP 和 UDP 头留出余量
+#[cfg(target_os = "linux")]
+const MAX_GSO_BYTES: usize = 64000;
+
+/// 服务端的监听套接字
+pub struct Socket {
+    udp: UdpSocket,
+    /// 发送时是否合并成大包
+    gso: AtomicBool,
+}
+
+impl Socket {
+    /// 包装监听套接字，Linux 上尽量打开分段卸载
+    pub fn new(udp: UdpSocket) -> Self {
+        let (gso, gro) = offload(&udp);
+        debug!(
+            "UDP segmentation offload {}, receive offload {}",
+            "UDP 分段卸载{}，接收合并{}",
+            if gso { "on" } else { "off" },
+            if gro { "on" } else { "off" }
+        );
+        Self {
+            udp,
+            gso: AtomicBool::new(gso),
+        }
+    }
+
+    /// 等到有包可以收
+    pub async fn readable(&self) -> io::Result<()> {
+        self.udp.readable().await
+    }
+
+    /// 发出所有的包，套接字的发送缓冲区满时等待；发不出去的包（比如对端地址不可达）和逐个发送时一样丢掉
+    pub async fn send_all(&self, packets: &[(Bytes, SocketAddr)]) {
+        let mut rest = packets;
+        while !rest.is_empty() {
+            if self.udp.writable().await.is_err() {
+                return;
+            }
+            match self.send(rest) {
+                Ok(sent) => rest = &rest[sent.max(1)..],
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(_) => rest = &rest[1..],
+            }
+        }
+    }
+}
+
+/// 收包用的缓冲区和上一次收到的包
+pub struct RecvBatch {
+    buf: Vec<u8>,
+    /// 每个包在缓冲区里的位置、长度和来源，来源不是 IP 地址时为 `None`；合并的大包已经切开
+    received: Vec<(usize, usize, Option<SocketAddr>)>,
+}
+
+impl RecvBatch {
+    pub fn new() -> Self {
+        Self {
+            buf: vec![0; SLOTS * SLOT_SIZE],
+            received: Vec::with_capacity(SLOTS),
+        }
+    }
+
+    /// 非阻塞地收一批已经到达的包，返回收到的个数；一个包都没有时返回 `WouldBlock`
+    pub fn recv(&mut self, socket: &Socket) -> io::Result<usize> {
+        self.received.clear();
+        recv(&socket.udp, &mut self.buf, &mut self.received)?;
+        Ok(self.received.len())
+    }
+
+    /// 上一次收到的包和它们的来源
+    pub fn packets(&self) -> impl Iterator<Item = (&[u8], SocketAddr)> {
+        self.received
+            .iter()
+            .filter_map(|&(at, len, peer)| Some((&self.buf[at..at + len], peer?)))
+    }
+}
+
+/// 把一个槽里收到的 `len` 字节按分段大小 `segment` 切开，记到 `received` 上；`segment` 为 0 表示没有合并
+fn split(
+    at: usize,
+    len: usize,
+    segment: usize,
+    peer: Option<SocketAddr>,
+    received: &mut Vec<(usize, usize, Option<SocketAddr>)>,
+) {
+    let len = len.min(SLOT_SIZE);
+    if segment == 0 || segment >= len {
+        received.push((at, len, peer));
+        return;
+    }
+    for offset in (0..len).step_by(segment) {
+        received.push((at + offset, segment.min(len - offset), peer));
+    }
+}
+
+/// 打开分段卸载，返回发送和接收两边是否可用
+#[cfg(target_os = "linux")]
+fn offload(udp: &UdpSocket) -> (bool, bool) {
+    use std::os::fd::AsRawFd;
+
+    let fd = udp.as_raw_fd();
+    let on: libc::c_int = 1;
+    let gro = unsafe {
+        libc::setsockopt(
+            fd,
+            libc::SOL_UDP,
+            libc::UDP_GRO,
+            (&on as *const libc::c_int).cast(),
+            size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    } == 0;
+    // 内核支持 UDP_SEGMENT 时能读出这个选项
+    let mut value: libc::c_int = 0;
+    let mut len = size_of::<libc::c_int>() as libc::socklen_t;
+    let gso = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_UDP,
+            libc::UDP_SEGMENT,
+            (&mut value as *mut libc::c_int).cast(),
+            &mut len,
+        )
+    } == 0;
+    (gso, gro)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn offload(_udp: &UdpSocket) -> (bool, bool) {
+    (false, false)
+}
+
+/// 控制消息的缓冲区，放得下一个 `UDP_SEGMENT` 或 `UDP_GRO`，按 `cmsghdr` 的要求对齐
+#[cfg(target_os = "linux")]
+type Control = [u64; 4];
+
+#[cfg(target_os = "linux")]
+fn recv(
+    udp: &UdpSocket,
+    buf: &mut [u8],
+    received: &mut Vec<(usize, usize, Option<SocketAddr>)>,
+) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+    use tokio::io::Interest;
+
+    let mut names = [unsafe { std::mem::zeroed::<libc::sockaddr_storage>() }; SLOTS];
+    let mut controls = [Control::default(); SLOTS];
+    let mut iovecs: Vec<_> = buf
+        .chunks_mut(SLOT_SIZE)
+        .map(|slot| libc::iovec {
+            iov_base: slot.as_mut_ptr().cast(),
+            iov_len: slot.len(),
+        })
+        .collect();
+    let mut msgs: Vec<_> = iovecs
+        .iter_mut()
+        .zip(names.iter_mut())
+        .zip(controls.iter_mut())
+        .map(|((iovec, name), control)| {
+            let mut msg: libc::mmsghdr = unsafe { std::mem::zeroed() };
+            msg.msg_hdr.msg_name = (name as *mut libc::sockaddr_storage).cast();
+            msg.msg_hdr.msg_namelen = size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+            msg.msg_hdr.msg_iov = iovec;
+            msg.msg_hdr.msg_iovlen = 1;
+            msg.msg_hdr.msg_control = control.as_mut_ptr().cast();
+            msg.msg_hdr.msg_controllen = size_of::<Control>() as _;
+            msg
+        })
+        .collect();
+    let count = udp.try_io(Interest::READABLE, || {
+        let count = unsafe {
+            libc::recvmmsg(
+                udp.as_raw_fd(),
+                msgs.as_mut_ptr(),
+                msgs.len() as _,
+                libc::MSG_DONTWAIT as _,
+                std::ptr::null_mut(),
+            )
+        };
+        match count {
+            -1 => Err(io::Error::last_os_error()),
+            count => Ok(count as usize),
+        }
+    })?;
+    for (i, (msg, name)) in msgs[..count].iter().zip(&names).enumerate() {
+        split(
+            i * SLOT_SIZE,
+            msg.msg_len as usize,
+            gro_segment(&msg.msg_hdr),
+            socket_addr(name, msg.msg_hdr.msg_namelen),
+            received,
+        );
+    }
+    Ok(())
+}
+
+/// 内核合并收到的包时在控制消息里给出的分段大小，没有合并时为 0
+#[cfg(target_os = "linux")]
+fn gro_segment(hdr: &libc::msghdr) -> usize {
+    let mut cmsg = unsafe { libc::CMSG_FIRSTHDR(hdr) };
+    while let Some(header) = unsafe { cmsg.as_ref() } {
+        if header.cmsg_level == libc::SOL_UDP && header.cmsg_type == libc::UDP_GRO {
+            let segment = unsafe { libc::CMSG_DATA(cmsg).cast::<libc::c_int>().read_unaligned() };
+            return segment.max(0) as usize;
+        }
+        cmsg = unsafe { libc::CMSG_NXTHDR(hdr, cmsg) };
+    }
+    0
+}
+
+#[cfg(target_os = "linux")]
+impl Socket {
+    /// 非阻塞地发出 `packets` 开头的一批，返回发出的包数；一个都发不出时返回错误
+    fn send(&self, packets: &[(Bytes, SocketAddr)]) -> io::Result<usize> {
+        use socket2::SockAddr;
+        use std::os::fd::AsRawFd;
+        use tokio::io::Interest;
+
+        let gso = self.gso.load(Ordering::Relaxed);
+        // 每个要发出的 UDP 包：从第几个包开始、包含几个包、分段大小（不合并时为 0）
+        let mut groups = Vec::with_capacity(SLOTS);
+        let mut start = 0;
+        while start < packets.len() && groups.len() < SLOTS {
+            let (first, peer) = &packets[start];
+            let mut count = 1;
+            let mut bytes = first.len();
+            if gso {
+                // 同一个客户端、同样大小的包合在一起，最后一个可以短一些，之后就不能再合并
+                for (packet, next_peer) in &packets[start + 1..] {
+                    if next_peer != peer
+                        || packet.len() > first.len()
+                        || packet.is_empty()
+                        || count == MAX_SEGMENTS
+                        || bytes + packet.len() > MAX_GSO_BYTES
+                    {
+                        break;
+                    }
+                    count += 1;
+                    bytes += packet.len();
+                    if packet.len() < first.len() {
+                        break;
+                    }
+                }
+            }
+            let segment = if count > 1 { first.len() } else { 0 };
+            groups.push((start, count, segment));
+            start += count;
+        }
+
+        let names: Vec<_> = groups
+            .iter()
+            .map(|&(start, ..)| SockAddr::from(packets[start].1))
+            .collect();
+        let mut iovecs: Vec<_> = packets[..start]
+            .iter()
+            .map(|(packet, _)| libc::iovec {
+                iov_base: packet.as_ptr() as *mut _,
+                iov_len: packet.len(),
+            })
+            .collect();
+        let mut controls = vec![Control::default(); groups.len()];
+        let mut msgs: Vec<_> = groups
+            .iter()
+            .zip(&names)
+            .zip(controls.iter_mut())
+            .map(|((&(start, count, segment), name), control)| {
+                let mut msg: libc::mmsghdr = unsafe { std::mem::zeroed() };
+                msg.msg_hdr.msg_name = name.as_ptr() as *mut _;
+                msg.msg_hdr.msg_namelen = name.len();
+                msg.msg_hdr.msg_iov = iovecs[start..].as_mut_ptr();
+                msg.msg_hdr.msg_iovlen = count as _;
+                if segment > 0 {
+                    set_segment(&mut msg.msg_hdr, control, segment as u16);
+                }
+                msg
+            })
+            .collect();
+        let sent = self.udp.try_io(Interest::WRITABLE, || {
+            let sent = unsafe {
+                libc::sendmmsg(
+                    self.udp.as_raw_fd(),
+                    msgs.as_mut_ptr(),
+                    msgs.len() as _,
+                    libc::MSG_DONTWAIT as _,
+                )
+            };
+            match sent {
+                -1 => Err(io::Error::last_os_error()),
+                sent => Ok(sent as usize),
+            }
+        });
+        match sent {
+            Ok(sent) => Ok(groups[..sent].iter().map(|&(_, count, _)| count).sum()),
+            // 网卡不支持校验和卸载、或者分段超过了路径 MTU 时内核拒绝合并的包，之后不再合并
+            Err(e)
+                if groups[0].2 > 0
+                    && matches!(e.raw_os_error(), Some(libc::EIO | libc::EINVAL)) =>
+            {
+                self.gso.store(false, Ordering::Relaxed);
+                warn!(
+                    "UDP segmentation offload failed ({e}), sending packets one by one",
+                    "UDP 分段卸载失败（{e}），改为逐个发送"
+                );
+                self.send(packets)
+            }
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// 在要发出的消息上附加 `UDP_SEGMENT` 控制消息
+#[cfg(target_os = "linux")]
+fn set_segment(hdr: &mut libc::msghdr, control: &mut Control, segment: u16) {
+    hdr.msg_control = control.as_mut_ptr().cast();
+    hdr.msg_controllen = unsafe { libc::CMSG_SPACE(size_of::<u16>() as u32) } as _;
+    unsafe {
+        let cmsg = libc::CMSG_FIRSTHDR(hdr);
+        (*cmsg).cmsg_level = libc::SOL_UDP;
+        (*cmsg).cmsg_type = libc::UDP_SEGMENT;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(size_of::<u16>() as u32) as _;
+        libc::CMSG_DATA(cmsg).cast::<u16>().write_unaligned(segment);
+    }
+}
+
+/// 把内核填好的地址转换成 `SocketAddr`
+#[cfg(target_os = "linux")]
+fn socket_addr(name: &libc::sockaddr_storage, len: libc::socklen_t) -> Option<SocketAddr> {
+    use socket2::{SockAddr, SockAddrStorage};
+
+    let mut storage = SockAddrStorage::zeroed();
+    unsafe {
+        *storage.view_as::<libc::sockaddr_storage>() = *name;
+        SockAddr::new(storage, len)
+    }
+    .as_socket()
+}
+
+#[cfg(not(target_os = "linux"))]
+fn recv(
+    udp: &UdpSocket,
+    buf: &mut [u8],
+    received: &mut Vec<(usize, usize, Option<SocketAddr>)>,
+) -> io::Result<()> {
+    for (i, slot) in buf.chunks_mut(SLOT_SIZE).enumerate() {
+        match udp.try_recv_from(slot) {
+            Ok((len, peer)) => split(i * SLOT_SIZE, len, 0, Some(peer), received),
+            Err(e) if received.is_empty() => return Err(e),
+            Err(_) => break,
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+impl Socket {
+    fn send(&self, packets: &[(Bytes, SocketAddr)]) -> io::Result<usize> {
+        for (sent, (packet, peer)) in packets.iter().take(SLOTS).enumerate() {
+            if let Err(e) = self.udp.try_send_to(packet, *peer) {
+                return if sent == 0 { Err(e) } else { Ok(sent) };
+            }
+        }
+        Ok(packets.len().min(SLOTS))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_coalesced_packets() {
+        let peer = Some(SocketAddr::from(([127, 0, 0, 1], 1)));
+        let mut received = Vec::new();
+        split(0, 100, 0, peer, &mut received);
+        split(SLOT_SIZE, 3000, 1400, peer, &mut received);
+        split(2 * SLOT_SIZE, 1400, 1400, peer, &mut received);
+        assert_eq!(
+            received,
+            [
+                (0, 100, peer),
+                (SLOT_SIZE, 1400, peer),
+                (SLOT_SIZE + 1400, 1400, peer),
+                (SLOT_SIZE + 2800, 200, peer),
+                (2 * SLOT_SIZE, 1400, peer),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn sends_and_receives_batches() {
+        let server = Socket::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let client = Socket::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let (server_addr, client_addr) = (
+            server.udp.local_addr().unwrap(),
+            client.udp.local_addr().unwrap(),
+        );
+
+        // 超过一批的包分几次发出；同样大小的一串包在支持的系统上会合并发送，收到时再切开
+        let mut packets: Vec<_> = (0..SLOTS as u32 + 8)
+            .map(|i| {
+                (
+                    Bytes::from(i.to_le_bytes().repeat(i as usize + 1)),
+                    server_addr,
+                )
+            })
+            .collect();
+        packets.extend((0..20u8).map(|i| (Bytes::from(vec![i; 1200]), server_addr)));
+        packets.push((Bytes::from_static(b"tail"), server_addr));
+        client.send_all(&packets).await;
+
+        let mut batch = RecvBatch::new();
+        let mut received = Vec::new();
+        while received.len() < packets.len() {
+            server.readable().await.unwrap();
+            match batch.recv(&server) {
+                Ok(_) => received.extend(batch.packets().map(|(packet, peer)| {
+                    assert_eq!(peer, client_addr);
+                    packet.to_vec()
+                })),
+                Err(e) => assert_eq!(e.kind(), io::ErrorKind::WouldBlock),
+            }
+        }
+        let sent: Vec<_> = packets.iter().map(|(packet, _)| packet.to_vec()).collect();
+        assert_eq!(received, sent);
+        assert_eq!(
+            batch.recv(&server).unwrap_err().kind(),
+            io::ErrorKind::WouldBlock
+        );
+    }
+}
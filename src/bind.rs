@@ -0,0 +1,192 @@
+use anyhow::anyhow;
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::{self, Instant};
+
+const INITIAL_BACKOFF: Duration = Duration::from_millis(200);
+const MAX_BACKOFF: Duration = Duration::from_secs(5);
+/// 接受连接出错后重试的最长间隔
+const MAX_ACCEPT_BACKOFF: Duration = Duration::from_secs(1);
+/// 端口被占用时，在其后这么多个端口里找一个空闲的作为建议
+const FREE_PORT_SEARCH: u16 = 20;
+
+#[derive(Clone, Copy)]
+pub enum Protocol {
+    Tcp,
+    Udp,
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Protocol::Tcp => "tcp",
+            Protocol::Udp => "udp",
+        })
+    }
+}
+
+/// 接受一个 TCP 连接。出错时（比如文件描述符用完）记日志并退避重试，不会空转占满 CPU
+pub async fn accept(what: &str, listener: &TcpListener) -> (TcpStream, SocketAddr) {
+    let mut backoff = INITIAL_BACKOFF;
+    loop {
+        match listener.accept().await {
+            Ok(accepted) => return accepted,
+            Err(e) => {
+                warn_repeated!(
+                    tr!("{what} failed to accept connections", "{what}接受连接失败"),
+                    "{what} failed to accept a connection: {e}, retrying in {backoff:?}",
+                    "{what}接受连接失败：{e}，{backoff:?} 后重试"
+                );
+                time::sleep(backoff).await;
+                backoff = (backoff * 2).min(MAX_ACCEPT_BACKOFF);
+            }
+        }
+    }
+}
+
+/// 启动时地址可能还没分配好或者端口暂时被占用，这类错误值得重试
+fn is_transient(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::AddrInUse | io::ErrorKind::AddrNotAvailable
+    )
+}
+
+/// 执行绑定操作，遇到暂时性错误时按指数退避重试，直到超过 `period`；
+/// 最终失败时在错误里说明原因，比如端口被哪个进程占用
+pub async fn with_retry<T, F, Fut>(
+    what: &str,
+    protocol: Protocol,
+    addr: &str,
+    period: Option<Duration>,
+    mut bind: F,
+) -> anyhow::Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = io::Result<T>>,
+{
+    let deadline = period.map(|period| Instant::now() + period);
+    let mut backoff = INITIAL_BACKOFF;
+    loop {
+        match bind().await {
+            Ok(bound) => return Ok(bound),
+            Err(e)
+                if is_transient(&e) && deadline.is_some_and(|d| Instant::now() + backoff < d) =>
+            {
+                warn!(
+                    "Failed to bind {what}: {e}, retrying in {backoff:?}",
+                    "绑定{what}失败：{e}，{backoff:?} 后重试"
+                );
+                time::sleep(backoff).await;
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+            }
+            Err(e) => return Err(diagnose(e, what, protocol, addr)),
+        }
+    }
+}
+
+/// 给常见的绑定错误加上原因和建议
+fn diagnose(e: io::Error, what: &str, protocol: Protocol, addr: &str) -> anyhow::Error {
+    let (host, port) = match addr.rsplit_once(':') {
+        Some((host, port)) => (host, port.parse::<u16>().ok()),
+        None => (addr, None),
+    };
+    let hint = match (e.kind(), port) {
+        (io::ErrorKind::AddrInUse, Some(port)) => {
+            let holder = owner(protocol, port)
+                .map(|holder| format!(" by {holder}"))
+                .unwrap_or_default();
+            let free = free_port(protocol, host, port)
+                .map(|free| format!(", port {free} is free"))
+                .unwrap_or_default();
+            format!("port {port}/{protocol} is already taken{holder}{free}")
+        }
+        (io::ErrorKind::AddrNotAvailable, _) => {
+            "the address is not assigned to any local interface, \
+             use 0.0.0.0 or [::] to listen on all of them"
+                .to_string()
+        }
+        (io::ErrorKind::PermissionDenied, Some(port)) if port < 1024 => {
+            "ports below 1024 need root or CAP_NET_BIND_SERVICE, \
+             start as root and drop privileges with --user"
+                .to_string()
+        }
+        _ => return anyhow::Error::new(e).context(format!("failed to bind {what} on {addr}")),
+    };
+    anyhow!("failed to bind {what} on {addr}: {e}; {hint}")
+}
+
+/// 在 `port` 之后找一个同一地址上可以绑定的端口
+fn free_port(protocol: Protocol, host: &str, port: u16) -> Option<u16> {
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    (port.saturating_add(1)..=port.saturating_add(FREE_PORT_SEARCH)).find(|&port| match protocol {
+        Protocol::Tcp => std::net::TcpListener::bind((host, port)).is_ok(),
+        Protocol::Udp => std::net::UdpSocket::bind((host, port)).is_ok(),
+    })
+}
+
+/// 找出占用端口的进程，返回类似 `nginx (pid 1234)` 的描述
+#[cfg(target_os = "linux")]
+fn owner(protocol: Protocol, port: u16) -> Option<String> {
+    use std::fs;
+
+    // /proc/net/* 里监听中的套接字：TCP 的状态是 LISTEN（0A），UDP 绑定后是 07
+    let (tables, state) = match protocol {
+        Protocol::Tcp => (["tcp", "tcp6"], "0A"),
+        Protocol::Udp => (["udp", "udp6"], "07"),
+    };
+    let mut inodes = Vec::new();
+    for table in tables {
+        let Ok(content) = fs::read_to_string(format!("/proc/net/{table}")) else {
+            continue;
+        };
+        for line in content.lines().skip(1) {
+            let columns: Vec<&str> = line.split_whitespace().collect();
+            let local_port = columns
+                .get(1)
+                .and_then(|local| local.rsplit_once(':'))
+                .and_then(|(_, port)| u16::from_str_radix(port, 16).ok());
+            if local_port == Some(port) && columns.get(3) == Some(&state) {
+                inodes.extend(columns.get(9).map(|inode| format!("socket:[{inode}]")));
+            }
+        }
+    }
+    if inodes.is_empty() {
+        return None;
+    }
+    for process in fs::read_dir("/proc").ok()?.flatten() {
+        let Some(pid) = process
+            .file_name()
+            .to_str()
+            .and_then(|s| s.parse::<u32>().ok())
+        else {
+            continue;
+        };
+        let Ok(fds) = fs::read_dir(process.path().join("fd")) else {
+            continue;
+        };
+        for fd in fds.flatten() {
+            let Ok(target) = fs::read_link(fd.path()) else {
+                continue;
+            };
+            if inodes
+                .iter()
+                .any(|inode| target.as_os_str() == inode.as_str())
+            {
+                let name = fs::read_to_string(process.path().join("comm")).unwrap_or_default();
+                return Some(format!("{} (pid {pid})", name.trim()));
+            }
+        }
+    }
+    // 看不到其它用户的进程
+    Some("a process of another user".to_string())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn owner(_protocol: Protocol, _port: u16) -> Option<String> {
+    None
+}
@@ -0,0 +1,76 @@
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+/// 缓冲数据的内存预算
+///
+/// 每个会话建立时按预估占用（转发缓冲区加上 KCP 收发窗口）预留一份额度，
+/// 转发过程中临时多申请的缓冲区也从这里扣除。额度用完后新会话会被拒绝，
+/// 已有会话不再额外申请缓冲区，而是等写端消化后再继续读取，靠背压限制内存。
+#[derive(Default)]
+pub struct Budget {
+    limit: Option<usize>,
+    used: AtomicUsize,
+    peak: AtomicUsize,
+    rejected: AtomicU64,
+}
+
+/// 从预算中扣除的一份额度，drop 时归还
+pub struct Charge<'a> {
+    budget: &'a Budget,
+    bytes: usize,
+}
+
+impl Drop for Charge<'_> {
+    fn drop(&mut self) {
+        self.budget.used.fetch_sub(self.bytes, Ordering::AcqRel);
+    }
+}
+
+impl Budget {
+    /// `limit` 为 `None` 时只统计不限制
+    pub fn new(limit: Option<usize>) -> Self {
+        Self {
+            limit,
+            ..Default::default()
+        }
+    }
+
+    /// 尝试扣除 `bytes` 字节，超出预算时返回 `None`
+    pub fn try_charge(&self, bytes: usize) -> Option<Charge<'_>> {
+        let used = self
+            .used
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |used| {
+                let next = used.checked_add(bytes)?;
+                match self.limit {
+                    Some(limit) if next > limit => None,
+                    _ => Some(next),
+                }
+            })
+            .ok()?;
+        self.peak.fetch_max(used + bytes, Ordering::AcqRel);
+        Some(Charge {
+            budget: self,
+            bytes,
+        })
+    }
+
+    /// 记录一次因为预算不足而拒绝的会话
+    pub fn reject(&self) {
+        self.rejected.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn limit(&self) -> Option<usize> {
+        self.limit
+    }
+
+    pub fn used(&self) -> usize {
+        self.used.load(Ordering::Acquire)
+    }
+
+    pub fn peak(&self) -> usize {
+        self.peak.load(Ordering::Acquire)
+    }
+
+    pub fn rejected(&self) -> u64 {
+        self.rejected.load(Ordering::Relaxed)
+    }
+}
@@ -0,0 +1,213 @@
+//! 服务端：后端熔断。后端挂掉时每个新会话都要等一次 TCP 连接超时（可能长达两分钟）才失败，
+//! 客户端那边的程序一直卡着，等待建立的会话越积越多。
+//!
+//! 连续 `--circuit-breaker` 次连不上同一个后端后打开熔断：之后的新会话不再去连，立即按连不上处理
+//! （有 `--fallback-response` 时回给客户端这段内容再关闭），同时在后台每隔 `PROBE_INTERVAL` 试着连一次，
+//! 连上后关闭熔断，新会话恢复正常。熔断按后端地址分别记录，管理接口切换后端后旧后端的探测随之停止。
+
+use crate::dns;
+use kcp::KcpStream;
+use std::collections::HashMap;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::time::timeout;
+
+/// 熔断打开期间探测后端的间隔
+const PROBE_INTERVAL: Duration = Duration::from_secs(5);
+/// 每次探测最多等这么久
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+/// 发送 `--fallback-response` 最多等这么久
+const FALLBACK_TIMEOUT: Duration = Duration::from_secs(5);
+/// 发完后最多再等这么久让 KCP 把数据送到，对端先关闭就不用等
+const FALLBACK_LINGER: Duration = Duration::from_secs(2);
+
+pub struct Breaker {
+    /// 连续失败这么多次打开熔断
+    threshold: u32,
+    backends: Mutex<HashMap<String, State>>,
+}
+
+#[derive(Default)]
+struct State {
+    failures: u32,
+    /// 熔断打开的时间
+    opened: Option<Instant>,
+}
+
+/// 一个后端的熔断状态
+pub struct CircuitInfo {
+    pub backend: String,
+    /// 连续失败的次数
+    pub failures: u32,
+    /// 熔断已经打开了多久，关闭时为 `None`
+    pub open_for: Option<Duration>,
+}
+
+impl Breaker {
+    pub fn new(threshold: u32) -> Arc<Self> {
+        Arc::new(Self {
+            threshold,
+            backends: Mutex::default(),
+        })
+    }
+
+    /// 熔断打开时返回 false，这时不要去连后端
+    pub fn allow(&self, backend: &str) -> bool {
+        self.backends
+            .lock()
+            .unwrap()
+            .get(backend)
+            .is_none_or(|state| state.opened.is_none())
+    }
+
+    /// 连上了后端
+    pub fn succeeded(&self, backend: &str) {
+        if let Some(state) = self.backends.lock().unwrap().get_mut(backend) {
+            state.failures = 0;
+        }
+    }
+
+    /// 连不上后端；连续失败达到阈值时打开熔断，开始在后台探测
+    pub fn failed(self: &Arc<Self>, backend: &str) {
+        let mut backends = self.backends.lock().unwrap();
+        let state = backends.entry(backend.to_string()).or_default();
+        state.failures += 1;
+        if state.opened.is_some() || state.failures < self.threshold {
+            return;
+        }
+        state.opened = Some(Instant::now());
+        warn!(
+            "Backend {backend} failed {} times in a row, failing new sessions fast until it is reachable again",
+            "后端 {backend} 连续 {} 次连接失败，在它恢复之前新会话直接按失败处理", state.failures
+        );
+        tokio::spawn(self.clone().probe(backend.to_string()));
+    }
+
+    /// 不再使用这个后端，比如管理接口切换了后端
+    pub fn forget(&self, backend: &str) {
+        self.backends.lock().unwrap().remove(backend);
+    }
+
+    pub fn info(&self) -> Vec<CircuitInfo> {
+        let backends = self.backends.lock().unwrap();
+        let mut list: Vec<CircuitInfo> = backends
+            .iter()
+            .map(|(backend, state)| CircuitInfo {
+                backend: backend.clone(),
+                failures: state.failures,
+                open_for: state.opened.map(|opened| opened.elapsed()),
+            })
+            .collect();
+        list.sort_by(|a, b| a.backend.cmp(&b.backend));
+        list
+    }
+
+    /// 熔断打开期间定时探测，连上后关闭熔断
+    async fn probe(self: Arc<Self>, backend: String) {
+        loop {
+            tokio::time::sleep(PROBE_INTERVAL).await;
+            let reachable = matches!(
+                timeout(PROBE_TIMEOUT, dns::connect_tcp(&backend)).await,
+                Ok(Ok(_))
+            );
+            let mut backends = self.backends.lock().unwrap();
+            let Some(state) = backends.get_mut(&backend) else {
+                return;
+            };
+            if !reachable {
+                continue;
+            }
+            let down = state.opened.take().unwrap_or_else(Instant::now).elapsed();
+            state.failures = 0;
+            notice!(
+                "Backend {backend} is reachable again after {}s, accepting sessions for it",
+                "后端 {backend} 在 {} 秒后恢复，重新为它建立会话",
+                down.as_secs()
+            );
+            return;
+        }
+    }
+}
+
+/// 连不上后端时回给客户端 `--fallback-response` 的内容，然后关闭连接。
+///
+/// kcp-rs 关闭流时直接发 RESET，还没送到的数据会丢掉，所以写完后停留 `FALLBACK_LINGER` 再关闭
+pub async fn fall_back(stream: &mut KcpStream, response: &[u8]) {
+    if let Ok(Ok(())) = timeout(FALLBACK_TIMEOUT, stream.write_all(response)).await {
+        let _ = timeout(FALLBACK_LINGER, async {
+            let mut buf = [0; 1024];
+            while stream.read(&mut buf).await? > 0 {}
+            io::Result::Ok(())
+        })
+        .await;
+    }
+    let _ = stream.shutdown().await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BACKEND: &str = "127.0.0.1:1";
+
+    #[tokio::test]
+    async fn opens_after_consecutive_failures() {
+        let breaker = Breaker::new(3);
+        breaker.failed(BACKEND);
+        breaker.failed(BACKEND);
+        assert!(breaker.allow(BACKEND));
+        // 中间连上一次，连续失败的计数从头开始
+        breaker.succeeded(BACKEND);
+        breaker.failed(BACKEND);
+        breaker.failed(BACKEND);
+        assert!(breaker.allow(BACKEND));
+        breaker.failed(BACKEND);
+        assert!(!breaker.allow(BACKEND));
+        // 别的后端不受影响
+        assert!(breaker.allow("127.0.0.1:2"));
+
+        // 打开期间的失败继续计数，但不会再开一次探测
+        breaker.failed(BACKEND);
+        let info = breaker.info();
+        assert_eq!(info.len(), 1);
+        assert_eq!(info[0].backend, BACKEND);
+        assert_eq!(info[0].failures, 4);
+        assert!(info[0].open_for.is_some());
+    }
+
+    #[tokio::test]
+    async fn forgets_backends() {
+        let breaker = Breaker::new(1);
+        breaker.failed(BACKEND);
+        breaker.failed("127.0.0.1:2");
+        assert!(!breaker.allow(BACKEND));
+        breaker.forget(BACKEND);
+        assert!(breaker.allow(BACKEND));
+        let backends: Vec<_> = breaker
+            .info()
+            .into_iter()
+            .map(|info| info.backend)
+            .collect();
+        assert_eq!(backends, ["127.0.0.1:2"]);
+    }
+
+    #[test]
+    fn lists_backends_in_order() {
+        let breaker = Breaker::new(5);
+        // 没有失败过的后端不记录
+        breaker.succeeded("b:1");
+        assert!(breaker.info().is_empty());
+        for backend in ["c:1", "a:1", "b:1"] {
+            breaker.failed(backend);
+        }
+        let info = breaker.info();
+        let backends: Vec<_> = info.iter().map(|info| info.backend.as_str()).collect();
+        assert_eq!(backends, ["a:1", "b:1", "c:1"]);
+        assert!(
+            info.iter()
+                .all(|info| info.failures == 1 && info.open_for.is_none())
+        );
+    }
+}
@@ -0,0 +1,88 @@
+//! 区分交互会话和批量会话：游戏、SSH 这类会话每次只有少量数据，要的是低延迟；
+//! 下载地图、传文件这类会话一次塞进大量数据，和交互会话共用线路时会把排队延迟加到交互会话身上。
+//!
+//! `auto` 模式下会话一开始按交互会话处理，某个方向转发的数据超过阈值后改按批量会话处理；
+//! 也可以用 `--session-class` 把这个实例的所有会话固定为一类。两类会话的区别：
+//!
+//! - 交互会话读到多少写出多少，不攒批；批量会话把已经就绪的数据攒成一批再写出，减少写入次数；
+//! - 设置了 `--bulk-rate` 时，只要最近有交互会话在收发数据，所有批量会话写入 KCP 的速率合计不超过它，
+//!   让线路上排队的主要是交互会话的包；
+//! - 客户端固定为交互会话时 KCP 窗口缩小到 `INTERACTIVE_WINDOW`，在途的数据少，占用的内存也少。
+//!   KCP 的窗口在连接建立后不能修改，`auto` 模式下途中变成批量会话的不受影响。
+
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// 固定为交互会话时使用的 KCP 窗口
+pub const INTERACTIVE_WINDOW: u32 = 128;
+/// 交互会话最近这么久内有过数据，就认为它还在活跃，批量会话需要让路
+const CONTENTION_WINDOW: Duration = Duration::from_secs(1);
+/// 批量会话限速时允许一次性写入的数据量
+const BULK_BURST: u64 = 64 * 1024;
+
+/// 会话的分类方式
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Mode {
+    /// 按转发的数据量自动判断
+    #[default]
+    Auto,
+    Interactive,
+    Bulk,
+}
+
+/// 所有会话共用：记录交互会话的活跃时间，给批量会话限速
+pub struct Shaper {
+    start: Instant,
+    /// 最近一次交互会话有数据的时间，相对 `start` 的毫秒数
+    interactive_at: AtomicU64,
+    /// 有交互会话活跃时批量会话合计的速率上限，字节每秒，0 表示不限制
+    bulk_rate: u64,
+    /// 按速率计算的下一次可以写入的时间
+    next: Mutex<Instant>,
+}
+
+impl Shaper {
+    /// `bulk_rate` 以 KB/s 为单位
+    pub fn new(bulk_rate: u64) -> Self {
+        let start = Instant::now();
+        Self {
+            start,
+            interactive_at: AtomicU64::new(0),
+            bulk_rate: bulk_rate * 1024,
+            next: Mutex::new(start),
+        }
+    }
+
+    /// 交互会话刚转发了数据
+    pub fn interactive(&self) {
+        let now = self.start.elapsed().as_millis() as u64;
+        self.interactive_at.fetch_max(now, Ordering::Relaxed);
+    }
+
+    fn contended(&self) -> bool {
+        let at = Duration::from_millis(self.interactive_at.load(Ordering::Relaxed));
+        at > Duration::ZERO && self.start.elapsed().saturating_sub(at) < CONTENTION_WINDOW
+    }
+
+    /// 批量会话写入 `bytes` 字节之前调用，需要让路时等到按速率可以写入
+    pub async fn pace(&self, bytes: usize) {
+        if self.bulk_rate == 0 || !self.contended() {
+            return;
+        }
+        let delay = {
+            let mut next = self.next.lock().unwrap();
+            let now = Instant::now();
+            let cost = Duration::from_secs_f64(bytes as f64 / self.bulk_rate as f64);
+            let burst = Duration::from_secs_f64(BULK_BURST as f64 / self.bulk_rate as f64);
+            // 和 `Throttle` 一样按 GCRA 计算，空闲后可以先写入 `BULK_BURST`
+            let start = (*next).max(now);
+            *next = start + cost;
+            start.saturating_duration_since(now + burst)
+        };
+        if !delay.is_zero() {
+            tokio::time::sleep(delay).await;
+        }
+    }
+}
@@ -0,0 +1,347 @@
+//! 客户端模式：在 `--listen-addr` 上接受本地程序的 TCP 连接，每个连接新建一条 KCP 连接到
+//! `--proxy-addr` 的服务端，握手后转发。
+//!
+//! 连接服务端的方式按参数选择：`--simulate`、`--redundant-addr`、`--local-addrs` 多线路，
+//! 或者直接连接（有服务端推送的漫游地址时可以中途切换）；主地址连不上时依次尝试推送的备用服务端。
+//! 指定 `--reverse` 时改为运行反向隧道，见 `reverse`。
+
+use crate::audit::Outcome;
+use crate::bind::{self, Protocol};
+use crate::budget::Budget;
+use crate::control;
+use crate::dns;
+use crate::keepalive;
+use crate::multipath;
+use crate::pcap::Capture;
+use crate::pending::{self, Slot};
+use crate::protocol::{self, Request};
+use crate::push::Pushed;
+use crate::rebind;
+use crate::redundant;
+use crate::registry::Registry;
+use crate::reverse;
+use crate::roaming;
+use crate::session::{CloseReason, Role, handle_session, session_footprint};
+use crate::simulate;
+use crate::throttle::Throttle;
+use crate::wake;
+use crate::{Args, drain, report_session, seconds, spawn_session};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::time::timeout;
+use tokio_util::task::TaskTracker;
+use uuid::Uuid;
+
+/// 客户端：在 `--listen-addr` 上接受 TCP 连接，通过 KCP 转发到 `--proxy-addr` 的服务端，排空后返回
+pub async fn run(
+    args: &Args,
+    registry: &Arc<Registry>,
+    budget: &Arc<Budget>,
+    capture: &Option<Arc<Capture>>,
+    tracker: &TaskTracker,
+) -> anyhow::Result<()> {
+    tracker.spawn(wake::watch(
+        registry.clone(),
+        args.kcp_config().session_expire,
+    ));
+    // 多线路时各条线路绑定自己的地址，由 `multipath` 暂停和恢复
+    if args.local_addrs.is_empty() {
+        tracker.spawn(rebind::watch(
+            registry.clone(),
+            args.proxy_addr().to_string(),
+        ));
+    }
+    if let Some(local_addr) = &args.reverse {
+        warn!(
+            "Reverse tunnel secrets travel in cleartext, run the tunnel over an encrypted underlay (WireGuard, IPsec, SSH...)",
+            "反向隧道的口令是明文传输的，请让隧道跑在加密的底层线路上（WireGuard、IPsec、SSH 等）"
+        );
+        args.harden()?;
+        let kcp_config = args.kcp_config();
+        let sessions = reverse::Sessions {
+            registry: registry.clone(),
+            budget: budget.clone(),
+            capture: capture.clone(),
+            tracker: tracker.clone(),
+            options: args.session_options(),
+            footprint: session_footprint(&kcp_config),
+        };
+        let tunnel = reverse::Tunnel {
+            server_addr: args.proxy_addr().to_string(),
+            local_addr: local_addr.clone(),
+            name: args.reverse_name.clone(),
+            port: args.remote_port,
+            host: args.reverse_host.clone(),
+            secret: args.reverse_secret.clone(),
+            webhook: args.reverse_webhook.clone(),
+            kcp_config,
+            simulate: args.simulate,
+            redundant_addr: args.redundant_addr.clone(),
+        };
+        reverse::run_client(tunnel, sessions).await?;
+        drain(registry).await;
+        return Ok(());
+    }
+    let tcp_listener = bind::with_retry(
+        "TCP listener",
+        Protocol::Tcp,
+        &args.listen_addr,
+        seconds(args.bind_retry),
+        || TcpListener::bind(&args.listen_addr),
+    )
+    .await?;
+    notice!(
+        "Client TCP listening on {:?}",
+        "客户端 TCP 正在监听 {:?}",
+        tcp_listener.local_addr()?
+    );
+    args.harden()?;
+    let mut kcp_config = args.kcp_config();
+    let mut footprint = session_footprint(&kcp_config);
+    let options = args.session_options();
+    let mut throttle = args.throttle();
+    let pending = args.pending();
+    // 服务端推送的设置，变化后从下一个会话开始生效
+    let pushed = Arc::new(Pushed::default());
+    let mut applied = pushed.version();
+    let mut alternates = Arc::new(Vec::new());
+    let mut roam = Arc::new(Vec::new());
+    let paths = (!args.local_addrs.is_empty()).then(|| {
+        let limits = multipath::Limits {
+            max_rtt: (args.path_max_rtt > 0).then(|| Duration::from_millis(args.path_max_rtt)),
+            max_loss: args.path_max_loss / 100.0,
+        };
+        let paths = Arc::new(multipath::Paths::new(args.local_addrs.clone(), limits));
+        registry.set_paths(paths.clone());
+        paths
+    });
+    if !args.legacy_protocol {
+        let client = control::Client {
+            server_addr: args.proxy_addr().to_string(),
+            kcp_config: kcp_config.clone(),
+            simulate: args.simulate,
+            redundant_addr: args.redundant_addr.clone(),
+            options,
+            pushed: pushed.clone(),
+            suspend_after: seconds(args.suspend_after),
+        };
+        tracker.spawn(control::run_client(client, registry.clone()));
+    }
+    loop {
+        info!("Waiting for new connection...", "等待新连接...");
+        if let Some(throttle) = &throttle {
+            tokio::select! {
+                _ = throttle.acquire() => {}
+                _ = registry.draining() => break,
+            }
+        }
+        let session_id = Uuid::new_v4().to_string();
+        let (tcp_stream, peer_addr) = tokio::select! {
+            accepted = bind::accept("client listener", &tcp_listener) => accepted,
+            _ = registry.draining() => break,
+        };
+        info!(
+            "New connection from {peer_addr:?}, with session id {session_id}",
+            "{peer_addr:?} 发起新连接，会话 id {session_id}"
+        );
+        if pushed.version() != applied {
+            applied = pushed.version();
+            let settings = pushed.get();
+            kcp_config = args.tuned_kcp_config(settings.kcp.as_ref());
+            footprint = session_footprint(&kcp_config);
+            if args.session_rate == 0 {
+                throttle = settings
+                    .session_rate
+                    .filter(|rate| *rate > 0)
+                    .map(|rate| Throttle::new(rate, rate));
+            }
+            alternates = Arc::new(settings.servers);
+            roam = Arc::new(settings.roam);
+        }
+        let slot = match pending.as_ref().map(|pending| pending.admit(&session_id)) {
+            Some(None) => {
+                registry.audit().record(
+                    peer_addr,
+                    &session_id,
+                    Outcome::Rejected,
+                    "too many pending sessions",
+                );
+                continue;
+            }
+            Some(slot) => slot,
+            None => None,
+        };
+        let dropped = slot.as_ref().map(Slot::dropped);
+        let remote_addr = args.proxy_addr().to_string();
+        let legacy = args.legacy_protocol;
+        let registry = registry.clone();
+        let budget = budget.clone();
+        let kcp_config = kcp_config.clone();
+        let simulate = args.simulate;
+        let redundant_addr = args.redundant_addr.clone();
+        let paths = paths.clone();
+        let alternates = alternates.clone();
+        let roam = roam.clone();
+        let capture = capture.clone();
+        let (task_registry, task_id) = (registry.clone(), session_id.clone());
+        let session = async move {
+            let Some(_charge) = budget.try_charge(footprint) else {
+                budget.reject();
+                registry.audit().record(
+                    peer_addr,
+                    &session_id,
+                    Outcome::Rejected,
+                    "memory budget exhausted",
+                );
+                return warn_repeated!(
+                    tr!("memory budget exhausted", "内存预算已用完"),
+                    "Session {session_id}: rejected, memory budget exhausted",
+                    "会话 {session_id}：内存预算已用完，拒绝连接"
+                );
+            };
+            let registration = registry.register(&session_id, peer_addr);
+            // 连不上主地址时依次尝试服务端推送的备用地址
+            let mut connected = Err(std::io::ErrorKind::NotConnected.into());
+            for server in std::iter::once(&remote_addr).chain(alternates.iter()) {
+                let kcp_config = kcp_config.clone();
+                connected = match (simulate, &redundant_addr) {
+                    (Some(conditions), _) => {
+                        simulate::connect(kcp_config, server, conditions, registration.wire()).await
+                    }
+                    (None, Some(backup)) => {
+                        redundant::connect(kcp_config, server, backup, registration.wire()).await
+                    }
+                    (None, None) => match &paths {
+                        Some(paths) => paths.connect(kcp_config, server, &registration).await,
+                        // 服务端推送的其它地址只属于主服务端
+                        None if *server == remote_addr && !roam.is_empty() => {
+                            roaming::connect(kcp_config, server, &roam, registration.wire()).await
+                        }
+                        None => match dns::lookup(server).await {
+                            Ok(addr) => keepalive::connect(kcp_config, addr, &registration).await,
+                            Err(e) => Err(e),
+                        },
+                    },
+                };
+                if connected.is_ok() {
+                    if *server != remote_addr {
+                        info!(
+                            "Session {session_id}: connected to alternate server {server}",
+                            "会话 {session_id}：已连接到备用服务端 {server}"
+                        );
+                    }
+                    break;
+                }
+            }
+            if let Ok((mut kcp_stream, server_addr)) = connected {
+                registry.contact().ok();
+                registration.set_conv(kcp_stream.conv());
+                if !legacy {
+                    match timeout(
+                        protocol::HANDSHAKE_TIMEOUT,
+                        protocol::client_handshake(&mut kcp_stream, 0, &Request::Forward),
+                    )
+                    .await
+                    {
+                        Ok(Ok(_)) => {}
+                        // 服务端通过控制通道告知了拒绝的原因
+                        Ok(Err(_)) | Err(_)
+                            if let Some(CloseReason::Remote(reason)) =
+                                registration.stop_reason() =>
+                        {
+                            registry.audit().record(
+                                peer_addr,
+                                &session_id,
+                                Outcome::Rejected,
+                                format!("server refused the session ({reason})"),
+                            );
+                            return warn_repeated!(
+                                tr!("server refused the session", "服务端拒绝了会话"),
+                                "Session {session_id}: server refused it ({reason})",
+                                "会话 {session_id}：服务端拒绝了会话（{reason}）"
+                            );
+                        }
+                        Ok(Err(e)) => {
+                            registry
+                                .contact()
+                                .failed(format!("handshake failed, {e:#}"));
+                            registry.audit().record(
+                                peer_addr,
+                                &session_id,
+                                Outcome::Failed,
+                                format!("handshake failed, {e:#}"),
+                            );
+                            return warn_repeated!(
+                                tr!("handshake failed", "握手失败"),
+                                "Session {session_id}: handshake failed, {e:#}",
+                                "会话 {session_id}：握手失败，{e:#}"
+                            );
+                        }
+                        Err(_) => {
+                            registry.contact().failed("handshake timed out");
+                            registry.audit().record(
+                                peer_addr,
+                                &session_id,
+                                Outcome::Failed,
+                                "handshake timed out",
+                            );
+                            return warn_repeated!(
+                                tr!("handshake timed out", "握手超时"),
+                                "Session {session_id}: handshake timed out",
+                                "会话 {session_id}：握手超时"
+                            );
+                        }
+                    }
+                }
+                registry.audit().record(
+                    peer_addr,
+                    &session_id,
+                    Outcome::Accepted,
+                    format!("server {server_addr}"),
+                );
+                drop(slot);
+                let capture = capture.map(|capture| capture.stream(peer_addr));
+                let summary = handle_session(
+                    tcp_stream,
+                    kcp_stream,
+                    Role::Client,
+                    options,
+                    &budget,
+                    registration.control(),
+                    capture,
+                )
+                .await;
+                report_session(&registration, summary);
+            } else {
+                registry
+                    .contact()
+                    .failed(format!("failed to connect to {remote_addr}"));
+                registry.audit().record(
+                    peer_addr,
+                    &session_id,
+                    Outcome::Failed,
+                    format!("server {remote_addr} unreachable"),
+                );
+                error_repeated!(
+                    tr!(
+                        "server {remote_addr} unreachable",
+                        "服务端 {remote_addr} 无法连接"
+                    ),
+                    "Session {session_id}: Failed to connect to kcp endpoint({remote_addr})",
+                    "会话 {session_id}：无法连接到 KCP 服务端（{remote_addr}）"
+                );
+            };
+        };
+        spawn_session(
+            tracker,
+            task_registry,
+            task_id,
+            pending::guard(dropped, session),
+        );
+    }
+
+    drop(tcp_listener);
+    drain(registry).await;
+    Ok(())
+}
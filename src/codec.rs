@@ -0,0 +1,369 @@
+//! 长度前缀、定长字段等二进制格式的编解码，握手、控制消息和流量嗅探共用。
+//!
+//! 这些数据都来自网络，可能是恶意构造的：解码时每一步都检查边界，越界返回 `None` 或错误而不是 panic；
+//! 从流里读取带长度前缀的数据时先检查长度上限再分配缓冲区，一个伪造的长度字段不会导致大块内存分配。
+
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// 控制消息等可变长度帧的默认长度上限
+pub const DEFAULT_MAX_FRAME: u16 = 16 * 1024;
+
+static MAX_FRAME: AtomicUsize = AtomicUsize::new(DEFAULT_MAX_FRAME as usize);
+
+/// 设置可变长度帧的长度上限，收发两端最好一致：对端发来超过上限的帧会被当作协议错误
+pub fn init(max_frame: u16) {
+    MAX_FRAME.store(max_frame.into(), Ordering::Relaxed);
+}
+
+pub fn max_frame() -> usize {
+    MAX_FRAME.load(Ordering::Relaxed)
+}
+
+/// 按顺序读取字段，越界时返回 `None`
+pub struct Cursor<'a>(pub &'a [u8]);
+
+impl<'a> Cursor<'a> {
+    pub fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.0.len() < n {
+            return None;
+        }
+        let (head, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Some(head)
+    }
+
+    pub fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|b| b[0])
+    }
+
+    pub fn u16(&mut self) -> Option<u16> {
+        self.array().map(u16::from_be_bytes)
+    }
+
+    pub fn u32(&mut self) -> Option<u32> {
+        self.array().map(u32::from_be_bytes)
+    }
+
+    pub fn u64(&mut self) -> Option<u64> {
+        self.array().map(u64::from_be_bytes)
+    }
+
+    fn array<const N: usize>(&mut self) -> Option<[u8; N]> {
+        self.take(N).map(|b| b.try_into().unwrap())
+    }
+
+    /// 以 1 字节或 2 字节长度开头的一段数据
+    pub fn vec8(&mut self) -> Option<&'a [u8]> {
+        let len = self.u8()?;
+        self.take(len as usize)
+    }
+
+    pub fn vec16(&mut self) -> Option<&'a [u8]> {
+        let len = self.u16()?;
+        self.take(len as usize)
+    }
+
+    /// Minecraft 协议的 VarInt
+    pub fn varint(&mut self) -> Option<u32> {
+        let mut value = 0u32;
+        for i in 0..5 {
+            let byte = self.u8()?;
+            value |= u32::from(byte & 0x7f) << (7 * i);
+            if byte & 0x80 == 0 {
+                return Some(value);
+            }
+        }
+        None
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+/// `len: u8 | data`，超过 255 字节的部分截掉
+pub fn put_vec8(frame: &mut Vec<u8>, data: &[u8]) {
+    let data = &data[..data.len().min(u8::MAX as usize)];
+    frame.push(data.len() as u8);
+    frame.extend_from_slice(data);
+}
+
+/// `len: u16 | data`，超过 65535 字节的部分截掉
+pub fn put_vec16(frame: &mut Vec<u8>, data: &[u8]) {
+    let data = &data[..data.len().min(u16::MAX as usize)];
+    frame.extend_from_slice(&(data.len() as u16).to_be_bytes());
+    frame.extend_from_slice(data);
+}
+
+/// 从流里读取 `len: u16 | data`，长度超过 `max` 或者 `max_frame()` 时不读取数据，直接返回 `InvalidData`
+pub async fn read_vec16<S: AsyncRead + Unpin>(stream: &mut S, max: usize) -> io::Result<Vec<u8>> {
+    let max = max.min(max_frame());
+    let len = stream.read_u16().await? as usize;
+    if len > max {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("frame of {len} bytes exceeds the limit of {max} bytes"),
+        ));
+    }
+    let mut data = vec![0u8; len];
+    stream.read_exact(&mut data).await?;
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{self, Message, Request};
+    use crate::push::Settings;
+    use crate::session::Goodbye;
+    use std::time::Duration;
+
+    async fn read(data: &[u8], max: usize) -> io::Result<Vec<u8>> {
+        read_vec16(&mut &data[..], max).await
+    }
+
+    fn frame(len: u16, data: &[u8]) -> Vec<u8> {
+        let mut frame = len.to_be_bytes().to_vec();
+        frame.extend_from_slice(data);
+        frame
+    }
+
+    #[test]
+    fn cursor_stops_at_end() {
+        let mut cursor = Cursor(&[0, 1, 2, 3, 4]);
+        assert_eq!(cursor.u16(), Some(1));
+        assert_eq!(cursor.u32(), None);
+        // 越界时不消耗数据
+        assert_eq!(cursor.take(3), Some(&[2, 3, 4][..]));
+        assert!(cursor.is_empty());
+        assert_eq!(cursor.u8(), None);
+
+        assert_eq!(Cursor(&[3, b'a', b'b']).vec8(), None);
+        assert_eq!(Cursor(&[0, 2, b'a']).vec16(), None);
+        assert_eq!(Cursor(&[0, 2, b'a', b'b']).vec16(), Some(&b"ab"[..]));
+    }
+
+    #[test]
+    fn decodes_varint() {
+        assert_eq!(Cursor(&[0x00]).varint(), Some(0));
+        assert_eq!(Cursor(&[0xdd, 0xc7, 0x01]).varint(), Some(25565));
+        assert_eq!(
+            Cursor(&[0xff, 0xff, 0xff, 0xff, 0x0f]).varint(),
+            Some(u32::MAX)
+        );
+        // 截断和超过 5 个字节都算错误
+        assert_eq!(Cursor(&[0x80]).varint(), None);
+        assert_eq!(Cursor(&[0x80, 0x80, 0x80, 0x80, 0x80, 0x01]).varint(), None);
+    }
+
+    #[test]
+    fn truncates_long_fields() {
+        let mut frame = Vec::new();
+        put_vec8(&mut frame, &[7; 300]);
+        assert_eq!(frame.len(), 256);
+        assert_eq!(Cursor(&frame).vec8().map(<[u8]>::len), Some(255));
+
+        let mut frame = Vec::new();
+        put_vec16(&mut frame, &[7; 70000]);
+        assert_eq!(frame.len(), 2 + 65535);
+        assert_eq!(Cursor(&frame).vec16().map(<[u8]>::len), Some(65535));
+    }
+
+    #[tokio::test]
+    async fn reads_bounded_frames() {
+        assert_eq!(read(&frame(3, b"abc"), 3).await.unwrap(), b"abc");
+        assert_eq!(read(&frame(0, b""), 0).await.unwrap(), b"");
+
+        let error = read(&frame(4, b"abcd"), 3).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        // 长度超过上限时不等数据到齐就返回
+        let error = read(&frame(u16::MAX, b""), 3).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+
+        let error = read(&frame(3, b"ab"), 3).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+        let error = read(&[0], 3).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[tokio::test]
+    async fn caps_frames_at_max_frame() {
+        let max = max_frame();
+        let data = vec![0; max + 1];
+        let exact = frame(max as u16, &data[..max]);
+        assert_eq!(read(&exact, usize::MAX).await.unwrap().len(), max);
+        let error = read(&frame(max as u16 + 1, &data), usize::MAX)
+            .await
+            .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    async fn round_trip(message: Message) -> Message {
+        let mut frame = Vec::new();
+        protocol::write_message(&mut frame, &message).await.unwrap();
+        let mut reader = &frame[..];
+        let message = protocol::read_message(&mut reader).await.unwrap();
+        assert!(reader.is_empty());
+        message
+    }
+
+    #[tokio::test]
+    async fn round_trips_messages() {
+        assert!(matches!(
+            round_trip(Message::Registered { port: 30000 }).await,
+            Message::Registered { port: 30000 }
+        ));
+        assert!(matches!(
+            round_trip(Message::Rejected { reason: "port in use".into() }).await,
+            Message::Rejected { reason } if reason == "port in use"
+        ));
+        assert!(matches!(
+            round_trip(Message::Open { token: u64::MAX }).await,
+            Message::Open { token: u64::MAX }
+        ));
+        assert!(matches!(round_trip(Message::Ping).await, Message::Ping));
+        assert!(matches!(
+            round_trip(Message::Stats {
+                sessions: 3,
+                sent: 1 << 40,
+                received: 7
+            })
+            .await,
+            Message::Stats {
+                sessions: 3,
+                sent: 1099511627776,
+                received: 7
+            }
+        ));
+        let config = Message::Config {
+            idle_timeout: Some(Duration::from_secs(300)),
+            max_duration: None,
+        };
+        assert!(matches!(
+            round_trip(config).await,
+            Message::Config { idle_timeout: Some(idle), max_duration: None }
+                if idle == Duration::from_secs(300)
+        ));
+        // 超过 `MAX_TEXT` 的原因截断后发送
+        assert!(matches!(
+            round_trip(Message::Closing { reason: "x".repeat(5000) }).await,
+            Message::Closing { reason } if reason.len() == 1024
+        ));
+        assert!(matches!(
+            round_trip(Message::Goodbye {
+                conv: 42,
+                reason: Goodbye::Idle
+            })
+            .await,
+            Message::Goodbye {
+                conv: 42,
+                reason: Goodbye::Idle
+            }
+        ));
+
+        let settings = Settings {
+            kcp: Some("interval=20,nodelay=on".parse().unwrap()),
+            session_rate: Some(50),
+            servers: vec!["backup.example.com:4000".into()],
+            roam: vec!["192.0.2.1:4000".into(), "192.0.2.2:4000".into()],
+        };
+        let Message::Push(pushed) = round_trip(Message::Push(settings.clone())).await else {
+            panic!("expected a push message");
+        };
+        assert!(pushed == settings);
+    }
+
+    #[tokio::test]
+    async fn rejects_oversized_messages() {
+        let mut rejected = vec![2];
+        rejected.extend(frame(1025, &[b'x'; 1025]));
+        assert!(protocol::read_message(&mut &rejected[..]).await.is_err());
+
+        let mut push = vec![8];
+        push.extend(frame(max_frame() as u16 + 1, b""));
+        assert!(protocol::read_message(&mut &push[..]).await.is_err());
+
+        assert!(protocol::read_message(&mut &[3, 0, 0][..]).await.is_err());
+    }
+
+    /// 在内存管道上完成一次握手，返回服务端解出的连接用途和身份
+    async fn handshake(request: Request) -> (Request, Option<String>) {
+        let (mut client, mut server) = tokio::io::duplex(4096);
+        let features = protocol::FEATURE_REVERSE | protocol::FEATURE_CONTROL;
+        let (client, server) = tokio::join!(
+            protocol::client_handshake(&mut client, features, &request),
+            protocol::server_handshake(&mut server, protocol::FEATURE_CONTROL),
+        );
+        assert_eq!(client.unwrap(), protocol::FEATURE_CONTROL);
+        let protocol::Greeting::Hello(hello) = server.unwrap() else {
+            panic!("expected a handshake");
+        };
+        assert_eq!(hello.version, protocol::VERSION);
+        assert_eq!(hello.features, protocol::FEATURE_CONTROL);
+        (hello.request, hello.identity)
+    }
+
+    #[tokio::test]
+    async fn round_trips_handshakes() {
+        protocol::set_identity("alice".into());
+        let alice = Some("alice".to_string());
+        for request in [
+            Request::Forward,
+            Request::Control,
+            Request::Attach {
+                token: 0x0123_4567_89ab_cdef,
+            },
+        ] {
+            let (decoded, identity) = handshake(request.clone()).await;
+            assert!(decoded == request);
+            let expected = match request {
+                Request::Attach { .. } => None,
+                _ => alice.clone(),
+            };
+            assert_eq!(identity, expected);
+        }
+
+        let register = |host: Option<&str>, secret: Option<&str>| Request::Register {
+            name: "web".into(),
+            port: 8080,
+            host: host.map(str::to_string),
+            secret: secret.map(str::to_string),
+        };
+        for (host, secret) in [
+            (None, None),
+            (Some("a.example.com"), None),
+            (None, Some("s3cret")),
+            (Some("a.example.com"), Some("s3cret")),
+        ] {
+            let request = register(host, secret);
+            let (decoded, identity) = handshake(request.clone()).await;
+            assert!(decoded == request);
+            assert_eq!(identity, secret.and(alice.clone()));
+        }
+
+        // 最长的注册请求也在握手扩展的上限以内
+        let long = "x".repeat(255);
+        let request = Request::Register {
+            name: long.clone(),
+            port: 1,
+            host: Some(long.clone()),
+            secret: Some(long),
+        };
+        assert!(handshake(request.clone()).await.0 == request);
+    }
+
+    #[tokio::test]
+    async fn rejects_oversized_handshake() {
+        let mut hello = protocol::HELLO_MAGIC.to_vec();
+        hello.push(protocol::VERSION);
+        hello.extend_from_slice(&0u32.to_be_bytes());
+        hello.extend(frame(1025, &[0; 1025]));
+        let (mut client, mut server) = tokio::io::duplex(4096);
+        tokio::io::AsyncWriteExt::write_all(&mut client, &hello)
+            .await
+            .unwrap();
+        assert!(protocol::server_handshake(&mut server, 0).await.is_err());
+    }
+}
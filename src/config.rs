@@ -0,0 +1,949 @@
+//! `--config <file>`：从配置文件读取命令行参数，格式是 TOML 的一个子集：
+//!
+//! ```toml
+//! # 键就是去掉 `--` 的长参数名，`-` 和 `_` 都可以
+//! proxy-addr = "203.0.113.1:25565"
+//! listen-addr = "127.0.0.1:25565"
+//! udp-buffer = "auto"
+//! local-addrs = ["192.168.1.10", "10.0.0.10"]
+//!
+//! [profile.cafe]
+//! simulate = "loss=2%"
+//! keepalive-interval = 20
+//! ```
+//!
+//! 值可以是字符串、数字、`true`/`false` 或者放在一行里的数组；`true` 相当于写上这个开关，`false` 相当于不写，
+//! 数组按逗号连起来。`[profile.<name>]` 下的设置只在 `--profile <name>` 时生效，覆盖文件开头的同名设置，
+//! 适合笔记本在家里、咖啡馆之类的不同网络之间切换，不用维护好几个文件。
+//!
+//! `--profile auto` 按当前网络自动选择：配置段里写上 `gateway-mac = "aa:bb:cc:dd:ee:ff"`（可以是数组），
+//! 启动时默认网关的 MAC 地址和哪个配置段对得上就用哪个，都对不上时只用文件开头的设置；运行中网络变化的处理见 `network`。
+//!
+//! `include = ["secrets.toml"]` 把另一个文件的内容插在这一行的位置：之前写的同名设置被它覆盖，之后写的覆盖它，
+//! 其中的配置段和同名的配置段合在一起。相对路径相对于写 `include` 的文件，`include` 只能写在所有配置段之前。
+//! 密钥之类可以单独放进权限更严的文件，主配置文件就能放心地分享出去。
+//!
+//! 字符串值写成 `file:<路径>` 时读取时换成那个文件的内容（去掉末尾的换行），写成 `env:<变量名>` 时换成环境变量的值，
+//! 令牌之类就不用出现在配置文件和命令行里，`ps` 也看不到（展开后的参数只在进程内部使用）。
+//!
+//! 读取时就按参数的定义检查每个值，写错参数名、类型不对或者超出范围时指出是哪一行、期望什么，参数名写错时还会给出最像的一个。
+//!
+//! 配置文件展开成参数后放在命令行参数前面，同一个参数命令行上的值优先；多值参数（比如 `--local-addrs`）两边的值会合在一起。
+//! 和 `--lang` 一样在 clap 解析之前从原始参数里找出 `--config` 和 `--profile`。
+
+use crate::network;
+use anyhow::{Context, bail};
+use std::any::TypeId;
+use std::error::Error;
+use std::fs;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+/// 一个设置项的值
+enum Value {
+    Flag(bool),
+    Text(String),
+    /// 引用了文件或者环境变量的值，`written` 是配置文件里的写法
+    Reference {
+        text: String,
+        written: String,
+    },
+}
+
+impl Value {
+    fn text(&self) -> Option<&str> {
+        match self {
+            Value::Flag(_) => None,
+            Value::Text(text) | Value::Reference { text, .. } => Some(text),
+        }
+    }
+}
+
+/// 可能带令牌之类的参数，导出配置时不显示值
+const SECRETS: &[&str] = &["reverse-auth", "reverse-secret", "reverse-webhook"];
+
+/// 配置文件里引用了文件或环境变量的设置：参数名、展开后的值、配置文件里的写法
+static REFERENCES: OnceLock<Vec<(String, String, String)>> = OnceLock::new();
+/// 合并后实际生效的配置，见 `record_effective`
+static EFFECTIVE: OnceLock<String> = OnceLock::new();
+
+/// 按出现顺序排列的设置项，同名的只保留最后一个值
+type Settings = Vec<(String, Value)>;
+
+/// 配置文件里的一个 `[profile.<name>]` 段
+struct Profile {
+    name: String,
+    settings: Settings,
+    /// `gateway-mac`：在默认网关是这些 MAC 地址的网络里自动选用这个配置
+    gateways: Vec<String>,
+}
+
+/// `--profile auto` 的选择依据和结果，运行中网络变化时据此重新选择
+pub struct Auto {
+    /// 每个配置对应的网关 MAC 地址
+    pub gateways: Vec<(String, Vec<String>)>,
+    /// 启动时的默认网关 MAC 地址
+    pub gateway: Option<String>,
+    /// 启动时选中的配置
+    pub profile: Option<String>,
+}
+
+static AUTO: OnceLock<Auto> = OnceLock::new();
+
+/// 使用了 `--profile auto` 时返回选择的结果
+pub fn auto() -> Option<&'static Auto> {
+    AUTO.get()
+}
+
+impl Auto {
+    /// 默认网关是 `gateway` 时选用的配置
+    pub fn select(&self, gateway: &str) -> Option<&str> {
+        self.gateways
+            .iter()
+            .find(|(_, gateways)| gateways.iter().any(|mac| mac == gateway))
+            .map(|(name, _)| name.as_str())
+    }
+}
+
+/// 读取 `--config` 指定的配置文件，把其中的设置展开成参数插到命令行参数前面；没有指定时原样返回
+pub fn expand(args: Vec<String>, command: &clap::Command) -> anyhow::Result<Vec<String>> {
+    let profile = find_arg(&args, "--profile");
+    let Some(path) = find_arg(&args, "--config") else {
+        if profile.is_some() {
+            bail!("--profile selects a profile in the config file, it needs --config");
+        }
+        return Ok(args);
+    };
+    let path = Path::new(path);
+    let (mut settings, profiles) = load(path, command, &mut Vec::new())?;
+    let profile = match profile {
+        Some("auto") => {
+            let gateway = network::gateway_mac();
+            let auto = Auto {
+                gateways: profiles
+                    .iter()
+                    .map(|profile| (profile.name.clone(), profile.gateways.clone()))
+                    .collect(),
+                profile: None,
+                gateway,
+            };
+            let selected = auto
+                .gateway
+                .as_deref()
+                .and_then(|gateway| auto.select(gateway));
+            let selected = selected.map(str::to_string);
+            let _ = AUTO.set(Auto {
+                profile: selected.clone(),
+                ..auto
+            });
+            selected
+        }
+        Some(name) if !profiles.iter().any(|profile| profile.name == name) => {
+            let names: Vec<_> = profiles
+                .iter()
+                .map(|profile| profile.name.as_str())
+                .collect();
+            bail!(
+                "no profile {name:?} in {}{}, available: {}",
+                path.display(),
+                did_you_mean(name, names.iter().copied()),
+                if names.is_empty() {
+                    "none".to_string()
+                } else {
+                    names.join(", ")
+                }
+            );
+        }
+        name => name.map(str::to_string),
+    };
+    if let Some(profile) = profiles
+        .into_iter()
+        .find(|p| Some(&p.name) == profile.as_ref())
+    {
+        merge(&mut settings, profile.settings);
+    }
+
+    let mut expanded = vec![args[0].clone()];
+    let mut references = Vec::new();
+    for (key, value) in settings {
+        match value {
+            Value::Flag(true) => expanded.push(format!("--{key}")),
+            Value::Flag(false) => {}
+            Value::Text(text) => expanded.push(format!("--{key}={text}")),
+            Value::Reference { text, written } => {
+                expanded.push(format!("--{key}={text}"));
+                references.push((key, text, written));
+            }
+        }
+    }
+    let _ = REFERENCES.set(references);
+    expanded.extend(args.into_iter().skip(1));
+    Ok(expanded)
+}
+
+/// 按 clap 解析的结果记下实际生效的配置，写成配置文件的格式，供 `config dump` 和管理接口的 `config` 命令输出：
+/// 包括配置文件和命令行上的设置，以及注释掉的默认值，输出可以直接用作配置文件；引用了文件或环境变量的值保留引用的写法，
+/// `SECRETS` 里的参数不显示值。默认的开关状态不写，免得把默认打开的 `--client` 和 `--server` 一起写上
+pub fn record_effective(command: &clap::Command, matches: &clap::ArgMatches) {
+    let references = REFERENCES.get().map_or(&[][..], Vec::as_slice);
+    let mut out = format!(
+        "# tcp-kcp-wrapper {} effective configuration\n",
+        env!("CARGO_PKG_VERSION")
+    );
+    for arg in command.get_arguments() {
+        let Some(key) = arg.get_long() else {
+            continue;
+        };
+        if matches!(key, "config" | "profile" | "lang" | "help" | "version") {
+            continue;
+        }
+        let id = arg.get_id().as_str();
+        let Some(raw) = matches.get_raw(id) else {
+            continue;
+        };
+        let values: Vec<_> = raw
+            .map(|value| value.to_string_lossy().into_owned())
+            .collect();
+        let default = matches.value_source(id) == Some(clap::parser::ValueSource::DefaultValue);
+        let joined = values.join(",");
+        let value = if !arg.get_action().takes_values() {
+            if default {
+                continue;
+            }
+            joined
+        } else if let Some((_, _, written)) = references
+            .iter()
+            .find(|(name, text, _)| name == key && *text == joined)
+        {
+            written.clone()
+        } else if SECRETS.contains(&key) {
+            "\"<redacted>\"".to_string()
+        } else if let [value] = values.as_slice() {
+            quote(value)
+        } else {
+            let values: Vec<_> = values.iter().map(|value| quote(value)).collect();
+            format!("[{}]", values.join(", "))
+        };
+        match default {
+            true => out += &format!("# {key} = {value}  (default)\n"),
+            false => out += &format!("{key} = {value}\n"),
+        }
+    }
+    let _ = EFFECTIVE.set(out);
+}
+
+/// `record_effective` 记下的配置
+pub fn effective() -> Option<&'static str> {
+    EFFECTIVE.get().map(String::as_str)
+}
+
+/// 数字原样写出，其它写成带引号的字符串
+fn quote(value: &str) -> String {
+    if !value.is_empty() && value.chars().all(|c| c.is_ascii_digit()) {
+        return value.to_string();
+    }
+    let mut quoted = String::from('"');
+    for c in value.chars() {
+        match c {
+            '"' | '\\' => {
+                quoted.push('\\');
+                quoted.push(c);
+            }
+            '\n' => quoted.push_str("\\n"),
+            '\t' => quoted.push_str("\\t"),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// 从原始参数里找出一个带值的参数
+fn find_arg<'a>(args: &'a [String], name: &str) -> Option<&'a str> {
+    args.iter().enumerate().find_map(|(i, arg)| {
+        if arg == name {
+            args.get(i + 1).map(String::as_str)
+        } else {
+            arg.strip_prefix(name)?.strip_prefix('=')
+        }
+    })
+}
+
+/// 把 `overrides` 里的设置合并进 `settings`，同名的覆盖
+fn merge(settings: &mut Settings, overrides: Settings) {
+    for (key, value) in overrides {
+        match settings.iter_mut().find(|(name, _)| *name == key) {
+            Some((_, old)) => *old = value,
+            None => settings.push((key, value)),
+        }
+    }
+}
+
+/// 读取并解析配置文件，返回文件开头的设置和各个配置段；`including` 是正在读取的上层文件，用来发现循环引用
+fn load(
+    path: &Path,
+    command: &clap::Command,
+    including: &mut Vec<PathBuf>,
+) -> anyhow::Result<(Settings, Vec<Profile>)> {
+    let content = fs::read_to_string(path)
+        .with_context(|| format!("failed to read config file {}", path.display()))?;
+    let canonical = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+    if including.contains(&canonical) {
+        bail!("{} includes itself", path.display());
+    }
+    including.push(canonical);
+    let parsed = parse(path, &content, command, including);
+    including.pop();
+    parsed
+}
+
+/// 解析配置文件的内容
+fn parse(
+    path: &Path,
+    content: &str,
+    command: &clap::Command,
+    including: &mut Vec<PathBuf>,
+) -> anyhow::Result<(Settings, Vec<Profile>)> {
+    let mut settings = Vec::new();
+    let mut profiles: Vec<Profile> = Vec::new();
+    // 当前所在的配置段，文件里同名的段合在一起
+    let mut current = None;
+    let dir = path.parent().unwrap_or(Path::new(""));
+    for (number, line) in content.lines().enumerate() {
+        let at = || format!("{}:{}", path.display(), number + 1);
+        let line = strip_comment(line).trim();
+        if line.is_empty() {
+            continue;
+        }
+        if let Some(section) = line.strip_prefix('[') {
+            let Some(name) = section
+                .strip_suffix(']')
+                .and_then(|section| section.trim().strip_prefix("profile."))
+            else {
+                bail!(
+                    "{}: unknown section {line}, expected [profile.<name>]",
+                    at()
+                );
+            };
+            let name = name.trim();
+            if name == "auto" {
+                bail!("{}: profile name auto is reserved for --profile auto", at());
+            }
+            current = Some(profile_index(&mut profiles, name));
+            continue;
+        }
+        let Some((key, value)) = line.split_once('=') else {
+            bail!("{}: expected key = value", at());
+        };
+        let key = key.trim().replace('_', "-");
+        if key == "include" {
+            if current.is_some() {
+                bail!("{}: include must come before any [profile] section", at());
+            }
+            for file in parse_items(value.trim()).with_context(at)? {
+                let (included, included_profiles) = load(&dir.join(file), command, including)
+                    .with_context(|| format!("included from {}", at()))?;
+                merge(&mut settings, included);
+                for profile in included_profiles {
+                    let index = profile_index(&mut profiles, &profile.name);
+                    merge(&mut profiles[index].settings, profile.settings);
+                    if !profile.gateways.is_empty() {
+                        profiles[index].gateways = profile.gateways;
+                    }
+                }
+            }
+            continue;
+        }
+        let raw = value.trim();
+        let value = parse_value(raw, dir).with_context(at)?;
+        if key == "gateway-mac" {
+            let (Some(index), Some(macs)) = (current, value.text()) else {
+                bail!("{}: gateway-mac takes MAC addresses inside a profile", at());
+            };
+            let macs: Vec<_> = macs.split(',').map(str::to_ascii_lowercase).collect();
+            if let Some(mac) = macs.iter().find(|mac| !is_mac(mac)) {
+                bail!(
+                    "{}: invalid MAC address {mac:?} for gateway-mac, expected six hex pairs like aa:bb:cc:dd:ee:ff",
+                    at()
+                );
+            }
+            profiles[index].gateways = macs;
+            continue;
+        }
+        let Some(arg) = command
+            .get_arguments()
+            .filter(|arg| !matches!(arg.get_long(), Some("config" | "profile")))
+            .find(|arg| arg.get_long() == Some(key.as_str()))
+        else {
+            let keys = command
+                .get_arguments()
+                .filter_map(|arg| arg.get_long())
+                .filter(|long| !matches!(*long, "config" | "profile"))
+                .chain(["include", "gateway-mac"]);
+            bail!("{}: unknown option {key}{}", at(), did_you_mean(&key, keys));
+        };
+        if let Err(e) = check(arg, &value) {
+            bail!("{}: invalid value {raw} for {key}: {e}", at());
+        }
+        let settings = match current {
+            Some(index) => &mut profiles[index].settings,
+            None => &mut settings,
+        };
+        merge(settings, vec![(key, value)]);
+    }
+    Ok((settings, profiles))
+}
+
+/// 按参数的定义检查配置文件里的值，和命令行上一样交给 clap 的解析器，出错时说明期望的类型和范围
+fn check(arg: &clap::Arg, value: &Value) -> Result<(), String> {
+    let text = match (value.text(), arg.get_action().takes_values()) {
+        (None, false) => return Ok(()),
+        (None, true) => return Err(format!("expected {}", expected(arg))),
+        (Some(_), false) => {
+            return Err("this is a switch, expected true or false".to_string());
+        }
+        (Some(text), true) => text,
+    };
+    // 只带这一个参数的命令，免得牵扯到参数之间的依赖和冲突
+    let single = clap::Command::new("config").no_binary_name(true).arg(
+        clap::Arg::new("value")
+            .long("value")
+            .action(arg.get_action().clone())
+            .value_parser(arg.get_value_parser().clone())
+            .value_delimiter(arg.get_value_delimiter()),
+    );
+    if let Err(e) = single.try_get_matches_from([format!("--value={text}")]) {
+        // 自定义的解析器在原因里已经说明了期望什么
+        return Err(match e.source().map(|reason| reason.to_string()) {
+            Some(reason) if reason.contains("expected") => reason,
+            Some(reason) => format!("{reason}, expected {}", expected(arg)),
+            None => format!("expected {}", expected(arg)),
+        });
+    }
+    Ok(())
+}
+
+/// 参数期望的值：可选值的列表，或者按类型给出的说明
+fn expected(arg: &clap::Arg) -> String {
+    let values: Vec<_> = arg
+        .get_possible_values()
+        .into_iter()
+        .filter(|value| !value.is_hide_set())
+        .map(|value| value.get_name().to_string())
+        .collect();
+    if !values.is_empty() {
+        return format!("one of {}", values.join(", "));
+    }
+    let id = arg.get_value_parser().type_id();
+    if [
+        TypeId::of::<u8>(),
+        TypeId::of::<u16>(),
+        TypeId::of::<u32>(),
+        TypeId::of::<u64>(),
+        TypeId::of::<usize>(),
+    ]
+    .iter()
+    .any(|integer| id == *integer)
+    {
+        "a non-negative integer".to_string()
+    } else if id == TypeId::of::<f64>() {
+        "a number".to_string()
+    } else if id == TypeId::of::<IpAddr>() {
+        "an IP address".to_string()
+    } else if id == TypeId::of::<SocketAddr>() {
+        "an address like 127.0.0.1:25565".to_string()
+    } else {
+        match arg.get_value_names() {
+            Some([name, ..]) => format!("a value like <{name}>, see --help"),
+            _ => "a value, see --help".to_string(),
+        }
+    }
+}
+
+/// 找出和 `name` 最像的一个候选，没有足够像的时返回空字符串
+fn did_you_mean<'a>(name: &str, candidates: impl Iterator<Item = &'a str>) -> String {
+    // 和 clap 给命令行参数提示时用的算法、阈值一样
+    candidates
+        .map(|candidate| (strsim::jaro(name, candidate), candidate))
+        .filter(|(similarity, _)| *similarity > 0.7)
+        .max_by(|a, b| a.0.total_cmp(&b.0))
+        .map(|(_, candidate)| format!(" (did you mean {candidate}?)"))
+        .unwrap_or_default()
+}
+
+fn is_mac(mac: &str) -> bool {
+    let parts: Vec<_> = mac.split(':').collect();
+    parts.len() == 6
+        && parts
+            .iter()
+            .all(|part| part.len() == 2 && part.chars().all(|c| c.is_ascii_hexdigit()))
+}
+
+/// 找到名为 `name` 的配置段，没有时新建一个
+fn profile_index(profiles: &mut Vec<Profile>, name: &str) -> usize {
+    if let Some(index) = profiles.iter().position(|profile| profile.name == name) {
+        return index;
+    }
+    profiles.push(Profile {
+        name: name.to_string(),
+        settings: Vec::new(),
+        gateways: Vec::new(),
+    });
+    profiles.len() - 1
+}
+
+/// 去掉不在字符串里的 `#` 之后的注释
+fn strip_comment(line: &str) -> &str {
+    let mut quote = None;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match (quote, c) {
+            (Some('"'), '\\') if !escaped => {
+                escaped = true;
+                continue;
+            }
+            (Some(q), c) if c == q && !escaped => quote = None,
+            (None, '"' | '\'') => quote = Some(c),
+            (None, '#') => return &line[..i],
+            _ => {}
+        }
+        escaped = false;
+    }
+    line
+}
+
+/// `dir` 是配置文件所在的目录，`file:` 引用的相对路径相对于它
+fn parse_value(value: &str, dir: &Path) -> anyhow::Result<Value> {
+    match value {
+        "true" => Ok(Value::Flag(true)),
+        "false" => Ok(Value::Flag(false)),
+        _ => {
+            let items = parse_items(value)?;
+            let referenced = items
+                .iter()
+                .any(|item| item.starts_with("file:") || item.starts_with("env:"));
+            let text = items
+                .into_iter()
+                .map(|item| resolve(item, dir))
+                .collect::<anyhow::Result<Vec<_>>>()?
+                .join(",");
+            Ok(match referenced {
+                true => Value::Reference {
+                    text,
+                    written: value.to_string(),
+                },
+                false => Value::Text(text),
+            })
+        }
+    }
+}
+
+/// 把 `file:<路径>` 换成文件的内容（去掉末尾的换行），`env:<变量>` 换成环境变量的值，其它原样返回；
+/// 出错时不带出值本身
+fn resolve(item: String, dir: &Path) -> anyhow::Result<String> {
+    if let Some(file) = item.strip_prefix("file:") {
+        let path = dir.join(file);
+        let content = fs::read_to_string(&path)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        return Ok(content.trim_end_matches(['\r', '\n']).to_string());
+    }
+    if let Some(name) = item.strip_prefix("env:") {
+        return std::env::var(name)
+            .with_context(|| format!("environment variable {name} is not set"));
+    }
+    Ok(item)
+}
+
+/// 解析一个值或者一个数组里的各项
+fn parse_items(value: &str) -> anyhow::Result<Vec<String>> {
+    if let Some(items) = value.strip_prefix('[') {
+        let items = items
+            .strip_suffix(']')
+            .context("arrays must be closed on the same line")?;
+        let mut values = Vec::new();
+        let mut rest = items.trim();
+        while !rest.is_empty() {
+            let (item, after) = scalar(rest)?;
+            values.push(item);
+            rest = after.trim_start();
+            rest = match rest.strip_prefix(',') {
+                Some(after) => after.trim_start(),
+                None if rest.is_empty() => rest,
+                None => bail!("expected , between array items"),
+            };
+        }
+        return Ok(values);
+    }
+    let (text, rest) = scalar(value)?;
+    if !rest.trim().is_empty() {
+        bail!("unexpected {:?} after the value", rest.trim());
+    }
+    Ok(vec![text])
+}
+
+/// 解析开头的一个字符串或者不带引号的值（数字之类），返回它和剩下的部分
+fn scalar(input: &str) -> anyhow::Result<(String, &str)> {
+    if let Some(rest) = input.strip_prefix('\'') {
+        let end = rest.find('\'').context("unterminated string")?;
+        return Ok((rest[..end].to_string(), &rest[end + 1..]));
+    }
+    let Some(rest) = input.strip_prefix('"') else {
+        let end = input.find([',', ']']).unwrap_or(input.len());
+        let bare = input[..end].trim();
+        if bare.is_empty() || bare.contains(char::is_whitespace) {
+            bail!("invalid value {bare:?}, quote strings");
+        }
+        return Ok((bare.to_string(), &input[end..]));
+    };
+    let mut text = String::new();
+    let mut chars = rest.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Ok((text, &rest[i + 1..])),
+            '\\' => match chars.next().map(|(_, c)| c) {
+                Some('n') => text.push('\n'),
+                Some('t') => text.push('\t'),
+                Some(c @ ('"' | '\\')) => text.push(c),
+                _ => bail!("invalid escape in string"),
+            },
+            c => text.push(c),
+        }
+    }
+    bail!("unterminated string")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::CommandFactory;
+
+    /// 测试用的临时目录，每个测试一个
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "tcp-kcp-wrapper-config-{}-{name}",
+            std::process::id()
+        ));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// 把设置写成 `key=value`，开关写成 `key` 或 `!key`
+    fn show(settings: &Settings) -> Vec<String> {
+        settings
+            .iter()
+            .map(|(key, value)| match value {
+                Value::Flag(true) => key.clone(),
+                Value::Flag(false) => format!("!{key}"),
+                Value::Text(text) | Value::Reference { text, .. } => format!("{key}={text}"),
+            })
+            .collect()
+    }
+
+    fn parse_str(content: &str) -> anyhow::Result<(Settings, Vec<Profile>)> {
+        parse(
+            Path::new("test.toml"),
+            content,
+            &crate::Args::command(),
+            &mut Vec::new(),
+        )
+    }
+
+    fn parse_error(content: &str) -> String {
+        format!("{:#}", parse_str(content).err().unwrap())
+    }
+
+    #[test]
+    fn parses_scalars() {
+        let (text, rest) = scalar(r#""a\"b\\c\nd\te" , 1"#).unwrap();
+        assert_eq!(text, "a\"b\\c\nd\te");
+        assert_eq!(rest, " , 1");
+        // 单引号里没有转义
+        assert_eq!(scalar(r"'C:\temp' x").unwrap(), (r"C:\temp".into(), " x"));
+        assert_eq!(scalar("25565, 1]").unwrap(), ("25565".into(), ", 1]"));
+        assert_eq!(
+            scalar("\"abc").unwrap_err().to_string(),
+            "unterminated string"
+        );
+        assert_eq!(
+            scalar("'abc").unwrap_err().to_string(),
+            "unterminated string"
+        );
+        assert_eq!(
+            scalar(r#""\x""#).unwrap_err().to_string(),
+            "invalid escape in string"
+        );
+        assert_eq!(
+            scalar("two words").unwrap_err().to_string(),
+            "invalid value \"two words\", quote strings"
+        );
+    }
+
+    #[test]
+    fn strips_comments_outside_strings() {
+        assert_eq!(strip_comment("a = 1 # note"), "a = 1 ");
+        assert_eq!(strip_comment(r##"a = "x#y" # note"##), r#"a = "x#y" "#);
+        assert_eq!(strip_comment("a = '#' # note"), "a = '#' ");
+        // 转义的引号不结束字符串，单引号里的反斜杠不是转义
+        assert_eq!(strip_comment(r##"a = "\"#" # note"##), r##"a = "\"#" "##);
+        assert_eq!(strip_comment(r"a = '\' # note"), r"a = '\' ");
+        assert_eq!(strip_comment("# whole line"), "");
+    }
+
+    #[test]
+    fn parses_arrays() {
+        assert_eq!(
+            parse_items(r#"["a", 'b,c', 3]"#).unwrap(),
+            ["a", "b,c", "3"]
+        );
+        assert_eq!(parse_items("[]").unwrap(), Vec::<String>::new());
+        assert_eq!(parse_items(r#"[ "a", ]"#).unwrap(), ["a"]);
+        assert_eq!(parse_items(r#""single""#).unwrap(), ["single"]);
+        assert_eq!(
+            parse_items(r#"["a""#).unwrap_err().to_string(),
+            "arrays must be closed on the same line"
+        );
+        assert_eq!(
+            parse_items(r#"["a" "b"]"#).unwrap_err().to_string(),
+            "expected , between array items"
+        );
+        assert_eq!(
+            parse_items(r#""a" "b""#).unwrap_err().to_string(),
+            "unexpected \"\\\"b\\\"\" after the value"
+        );
+    }
+
+    #[test]
+    fn parses_settings() {
+        let (settings, profiles) = parse_str(
+            r#"
+            # comment
+            proxy_addr = "203.0.113.1:25565" # the server
+            listen-addr = "127.0.0.1:25565"
+            local-addrs = ["192.168.1.10", "10.0.0.10"]
+            keepalive-interval = 20
+            client = true
+            quiet = false
+            reverse-webhook = "https://example.com/hook#token"
+            keepalive-interval = 30
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            show(&settings),
+            [
+                "proxy-addr=203.0.113.1:25565",
+                "listen-addr=127.0.0.1:25565",
+                "local-addrs=192.168.1.10,10.0.0.10",
+                "keepalive-interval=30",
+                "client",
+                "!quiet",
+                "reverse-webhook=https://example.com/hook#token",
+            ]
+        );
+        assert!(profiles.is_empty());
+    }
+
+    #[test]
+    fn reports_errors_with_line_numbers() {
+        assert_eq!(
+            parse_error("\nproxy-adr = \"a:1\""),
+            "test.toml:2: unknown option proxy-adr (did you mean proxy-addr?)"
+        );
+        assert!(
+            parse_error("keepalive-interval = \"soon\"")
+                .starts_with("test.toml:1: invalid value \"soon\" for keepalive-interval")
+        );
+        assert_eq!(
+            parse_error("client = 1"),
+            "test.toml:1: invalid value 1 for client: this is a switch, expected true or false"
+        );
+        assert!(
+            parse_error("profile = \"cafe\"").starts_with("test.toml:1: unknown option profile")
+        );
+        assert_eq!(
+            parse_error("[server]"),
+            "test.toml:1: unknown section [server], expected [profile.<name>]"
+        );
+        assert_eq!(
+            parse_error("[profile.auto]"),
+            "test.toml:1: profile name auto is reserved for --profile auto"
+        );
+        assert_eq!(
+            parse_error("[profile.a]\ninclude = \"b.toml\""),
+            "test.toml:2: include must come before any [profile] section"
+        );
+        assert_eq!(
+            parse_error("gateway-mac = \"aa:bb:cc:dd:ee:ff\""),
+            "test.toml:1: gateway-mac takes MAC addresses inside a profile"
+        );
+        assert_eq!(
+            parse_error("proxy-addr"),
+            "test.toml:1: expected key = value"
+        );
+        assert_eq!(
+            parse_error("proxy-addr = \"a:1"),
+            "test.toml:1: unterminated string"
+        );
+    }
+
+    #[test]
+    fn merges_profiles() {
+        let (settings, profiles) = parse_str(
+            r#"
+            keepalive-interval = 20
+            [profile.cafe]
+            simulate = "loss=2%"
+            gateway-mac = ["AA:BB:CC:DD:EE:FF", "00:11:22:33:44:55"]
+            [profile.home]
+            keepalive-interval = 0
+            [ profile.cafe ]
+            keepalive-interval = 10
+            "#,
+        )
+        .unwrap();
+        assert_eq!(show(&settings), ["keepalive-interval=20"]);
+        let names: Vec<_> = profiles.iter().map(|p| p.name.as_str()).collect();
+        assert_eq!(names, ["cafe", "home"]);
+        assert_eq!(
+            show(&profiles[0].settings),
+            ["simulate=loss=2%", "keepalive-interval=10"]
+        );
+        assert_eq!(
+            profiles[0].gateways,
+            ["aa:bb:cc:dd:ee:ff", "00:11:22:33:44:55"]
+        );
+
+        let dir = temp_dir("profiles");
+        let path = dir.join("main.toml");
+        fs::write(
+            &path,
+            "listen-addr = \"127.0.0.1:1\"\nkeepalive-interval = 20\n\
+             [profile.cafe]\nkeepalive-interval = 10\nlocal-addrs = \"10.0.0.1\"\n",
+        )
+        .unwrap();
+        let args = |extra: &[&str]| {
+            let mut args = vec!["tcp-kcp-wrapper", "--config", path.to_str().unwrap()];
+            args.extend_from_slice(extra);
+            expand(
+                args.into_iter().map(str::to_string).collect(),
+                &crate::Args::command(),
+            )
+        };
+        assert_eq!(
+            args(&["--profile", "cafe", "--client"]).unwrap()[1..],
+            [
+                "--listen-addr=127.0.0.1:1",
+                "--keepalive-interval=10",
+                "--local-addrs=10.0.0.1",
+                "--config",
+                path.to_str().unwrap(),
+                "--profile",
+                "cafe",
+                "--client",
+            ]
+        );
+        assert_eq!(
+            args(&["--profile=caf"]).unwrap_err().to_string(),
+            format!(
+                "no profile \"caf\" in {} (did you mean cafe?), available: cafe",
+                path.display()
+            )
+        );
+        assert_eq!(
+            expand(
+                vec!["tcp-kcp-wrapper".into(), "--profile=cafe".into()],
+                &crate::Args::command()
+            )
+            .unwrap_err()
+            .to_string(),
+            "--profile selects a profile in the config file, it needs --config"
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn includes_files() {
+        let dir = temp_dir("include");
+        fs::write(
+            dir.join("main.toml"),
+            "keepalive-interval = 5\nlisten-addr = \"127.0.0.1:1\"\n\
+             include = [\"secrets.toml\"]\nkeepalive-interval = 30\n\
+             [profile.cafe]\nsimulate = \"loss=1%\"\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("secrets.toml"),
+            "listen-addr = \"127.0.0.1:2\"\nkeepalive-interval = 10\n\
+             [profile.cafe]\nkeepalive-interval = 60\n",
+        )
+        .unwrap();
+        let command = crate::Args::command();
+        let (settings, profiles) = load(&dir.join("main.toml"), &command, &mut Vec::new()).unwrap();
+        // 被包含的文件覆盖之前的设置，之后的设置覆盖它
+        assert_eq!(
+            show(&settings),
+            ["keepalive-interval=30", "listen-addr=127.0.0.1:2"]
+        );
+        assert_eq!(
+            show(&profiles[0].settings),
+            ["keepalive-interval=60", "simulate=loss=1%"]
+        );
+
+        fs::write(dir.join("a.toml"), "include = \"b.toml\"\n").unwrap();
+        fs::write(dir.join("b.toml"), "include = 'a.toml'\n").unwrap();
+        let error = load(&dir.join("a.toml"), &command, &mut Vec::new())
+            .err()
+            .unwrap();
+        let a = dir.join("a.toml");
+        let b = dir.join("b.toml");
+        assert_eq!(
+            format!("{error:#}"),
+            format!(
+                "included from {a}:1: included from {b}:1: {a} includes itself",
+                a = a.display(),
+                b = b.display(),
+            )
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn resolves_references() {
+        let dir = temp_dir("references");
+        fs::write(dir.join("secret.txt"), "s3cret\r\n").unwrap();
+        let Value::Reference { text, written } = parse_value("\"file:secret.txt\"", &dir).unwrap()
+        else {
+            panic!("expected a reference");
+        };
+        assert_eq!(text, "s3cret");
+        assert_eq!(written, "\"file:secret.txt\"");
+
+        let path = std::env::var("PATH").unwrap();
+        let Value::Reference { text, .. } = parse_value("[\"env:PATH\", \"plain\"]", &dir).unwrap()
+        else {
+            panic!("expected a reference");
+        };
+        assert_eq!(text, format!("{path},plain"));
+        assert!(matches!(
+            parse_value("\"plain\"", &dir).unwrap(),
+            Value::Text(text) if text == "plain"
+        ));
+
+        assert_eq!(
+            parse_value("\"env:TCP_KCP_WRAPPER_UNSET\"", &dir)
+                .err()
+                .unwrap()
+                .to_string(),
+            "environment variable TCP_KCP_WRAPPER_UNSET is not set"
+        );
+        assert_eq!(
+            parse_value("\"file:missing.txt\"", &dir)
+                .err()
+                .unwrap()
+                .to_string(),
+            format!("failed to read {}", dir.join("missing.txt").display())
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
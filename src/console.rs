@@ -0,0 +1,301 @@
+//! 终端输出：按级别打印日志，输出到终端时带颜色。
+//!
+//! - `error!`、`warn!`：错误和警告，写到标准错误，总是打印
+//! - `notice!`：启动、退出等整体状态，总是打印
+//! - `info!`：每个会话的建立、关闭等，`--quiet` 时不打印
+//! - `debug!`：每次转发的数据块等细节，只在 `--verbose` 时打印
+//!
+//! 运行中可以通过管理接口的 `log` 命令调整打印到哪个级别，还可以按模块单独设置，比如 `info,control=debug`；
+//! 错误、警告和 `notice!` 总是打印。
+//!
+//! 各个宏的参数同 [`tr!`](crate::tr)，先英文后中文。设置了 `NO_COLOR` 环境变量时不使用颜色。
+//!
+//! 后端挂掉时每个连接都会失败一次，这类错误用 `error_repeated!`、`warn_repeated!` 打印：
+//! 第一个参数是归类用的简短描述，同一类错误在抑制窗口内只打印第一次，
+//! 窗口结束时再汇总打印一行次数，比如 `backend 127.0.0.1:80 unreachable: ×137 more in last 60s`。
+
+use std::collections::HashMap;
+use std::io::{IsTerminal, Write};
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use std::sync::{Mutex, OnceLock, RwLock};
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error,
+    Warn,
+    Notice,
+    Info,
+    Debug,
+}
+
+impl Level {
+    fn name(self) -> &'static str {
+        match self {
+            Level::Error => "error",
+            Level::Warn => "warn",
+            Level::Notice => "notice",
+            Level::Info => "info",
+            Level::Debug => "debug",
+        }
+    }
+
+    /// 可以设置的级别：`notice` 相当于 `--quiet`，`debug` 相当于 `--verbose`
+    fn parse(name: &str) -> Option<Self> {
+        match name {
+            "notice" => Some(Level::Notice),
+            "info" => Some(Level::Info),
+            "debug" => Some(Level::Debug),
+            _ => None,
+        }
+    }
+}
+
+static MAX_LEVEL: AtomicU8 = AtomicU8::new(Level::Info as u8);
+/// 按模块设置的级别，键是去掉 crate 名的模块路径的第一段，比如 `control`
+static MODULE_LEVELS: RwLock<Vec<(String, Level)>> = RwLock::new(Vec::new());
+/// 有按模块设置的级别，没有时不用加锁
+static HAS_MODULE_LEVELS: AtomicBool = AtomicBool::new(false);
+
+/// 按 `--quiet` 和 `--verbose` 设置打印到哪个级别
+pub fn init(quiet: bool, verbose: bool) {
+    let level = if quiet {
+        Level::Notice
+    } else if verbose {
+        Level::Debug
+    } else {
+        Level::Info
+    };
+    MAX_LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+pub fn enabled(level: Level) -> bool {
+    level as u8 <= MAX_LEVEL.load(Ordering::Relaxed)
+}
+
+/// 在 `module`（`module_path!()`）里打印 `level` 级别的日志时是否输出
+pub fn enabled_in(level: Level, module: &str) -> bool {
+    if level <= Level::Notice || !HAS_MODULE_LEVELS.load(Ordering::Relaxed) {
+        return enabled(level);
+    }
+    let module = module.split("::").nth(1).unwrap_or_default();
+    match MODULE_LEVELS
+        .read()
+        .unwrap()
+        .iter()
+        .find(|(name, _)| name == module)
+    {
+        Some((_, max)) => level <= *max,
+        None => enabled(level),
+    }
+}
+
+/// 按 `info,control=debug` 这样的写法设置级别：不带模块名的一项是全局级别，省略时不变；
+/// 按模块的设置整个替换掉之前的
+pub fn set_filter(filter: &str) -> Result<(), String> {
+    let mut global = None;
+    let mut modules = Vec::new();
+    for item in filter.split(',').filter(|item| !item.is_empty()) {
+        let (module, name) = match item.split_once('=') {
+            Some((module, name)) => (Some(module), name),
+            None => (None, item),
+        };
+        let level = Level::parse(name)
+            .ok_or_else(|| format!("unknown level {name:?}, expected notice, info or debug"))?;
+        match module {
+            Some(module) => modules.push((module.to_string(), level)),
+            None => global = Some(level),
+        }
+    }
+    if let Some(level) = global {
+        MAX_LEVEL.store(level as u8, Ordering::Relaxed);
+    }
+    HAS_MODULE_LEVELS.store(!modules.is_empty(), Ordering::Relaxed);
+    *MODULE_LEVELS.write().unwrap() = modules;
+    Ok(())
+}
+
+/// 当前的级别设置，写法同 `set_filter`
+pub fn filter() -> String {
+    let global = [Level::Notice, Level::Info, Level::Debug]
+        .into_iter()
+        .rfind(|level| enabled(*level))
+        .unwrap_or(Level::Notice);
+    let mut filter = global.name().to_string();
+    for (module, level) in MODULE_LEVELS.read().unwrap().iter() {
+        filter.push_str(&format!(",{module}={}", level.name()));
+    }
+    filter
+}
+
+pub fn write(level: Level, message: &str) {
+    let prefix = match level {
+        Level::Error => tr!("error: ", "错误："),
+        Level::Warn => tr!("warning: ", "警告："),
+        _ => String::new(),
+    };
+    if level <= Level::Warn {
+        let stderr = std::io::stderr();
+        let line = paint(level, stderr.is_terminal(), &prefix, message);
+        let _ = writeln!(stderr.lock(), "{line}");
+    } else {
+        let stdout = std::io::stdout();
+        let line = paint(level, stdout.is_terminal(), &prefix, message);
+        let _ = writeln!(stdout.lock(), "{line}");
+    }
+}
+
+/// 同一类错误在窗口内被抑制的情况
+struct Repeated {
+    level: Level,
+    since: Instant,
+    suppressed: u64,
+}
+
+struct Suppressor {
+    window: Duration,
+    entries: Mutex<HashMap<String, Repeated>>,
+}
+
+static SUPPRESSOR: OnceLock<Suppressor> = OnceLock::new();
+
+/// 开启重复错误的抑制，并启动定时汇总的任务，需要在 tokio 运行时里调用
+pub fn suppress_repeats(window: Duration) {
+    let suppressor = SUPPRESSOR.get_or_init(|| Suppressor {
+        window,
+        entries: Mutex::default(),
+    });
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(1).min(window));
+        loop {
+            interval.tick().await;
+            suppressor.flush(false);
+        }
+    });
+}
+
+/// 打印被抑制但还没汇总的次数，退出前调用
+pub fn flush_repeats() {
+    if let Some(suppressor) = SUPPRESSOR.get() {
+        suppressor.flush(true);
+    }
+}
+
+impl Suppressor {
+    fn flush(&self, all: bool) {
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|key, entry| {
+            let expired = now.duration_since(entry.since) >= self.window;
+            if expired || all {
+                summarize(key, entry, now);
+            }
+            !(expired || all)
+        });
+    }
+}
+
+fn summarize(key: &str, entry: &Repeated, now: Instant) {
+    if entry.suppressed == 0 || !enabled(entry.level) {
+        return;
+    }
+    let secs = now.duration_since(entry.since).as_secs().max(1);
+    let message = tr!(
+        "{key}: ×{} more in last {secs}s",
+        "{key}：最近 {secs} 秒内又出现 {} 次",
+        entry.suppressed
+    );
+    write(entry.level, &message);
+}
+
+/// 打印一条可能大量重复的错误，`key` 相同的错误在抑制窗口内只打印第一次
+pub fn repeated(level: Level, key: String, message: impl FnOnce() -> String) {
+    if !enabled(level) {
+        return;
+    }
+    let Some(suppressor) = SUPPRESSOR.get() else {
+        return write(level, &message());
+    };
+    let now = Instant::now();
+    let mut entries = suppressor.entries.lock().unwrap();
+    match entries.get_mut(&key) {
+        Some(entry) if now.duration_since(entry.since) < suppressor.window => {
+            entry.suppressed += 1;
+        }
+        _ => {
+            if let Some(entry) = entries.remove(&key) {
+                summarize(&key, &entry, now);
+            }
+            write(level, &message());
+            entries.insert(
+                key,
+                Repeated {
+                    level,
+                    since: now,
+                    suppressed: 0,
+                },
+            );
+        }
+    }
+}
+
+fn paint(level: Level, terminal: bool, prefix: &str, message: &str) -> String {
+    if !terminal || std::env::var_os("NO_COLOR").is_some_and(|v| !v.is_empty()) {
+        return format!("{prefix}{message}");
+    }
+    match level {
+        Level::Error => format!("\x1b[1;31m{prefix}\x1b[0m{message}"),
+        Level::Warn => format!("\x1b[1;33m{prefix}\x1b[0m{message}"),
+        Level::Notice => format!("\x1b[1m{message}\x1b[0m"),
+        Level::Info => message.to_string(),
+        Level::Debug => format!("\x1b[2m{message}\x1b[0m"),
+    }
+}
+
+#[macro_export]
+macro_rules! log_tr {
+    ($level:expr, $($args:tt)*) => {
+        if $crate::console::enabled_in($level, module_path!()) {
+            $crate::console::write($level, &$crate::tr!($($args)*));
+        }
+    };
+}
+
+#[macro_export]
+macro_rules! error {
+    ($($args:tt)*) => { $crate::log_tr!($crate::console::Level::Error, $($args)*) };
+}
+
+#[macro_export]
+macro_rules! warn {
+    ($($args:tt)*) => { $crate::log_tr!($crate::console::Level::Warn, $($args)*) };
+}
+
+#[macro_export]
+macro_rules! notice {
+    ($($args:tt)*) => { $crate::log_tr!($crate::console::Level::Notice, $($args)*) };
+}
+
+#[macro_export]
+macro_rules! info {
+    ($($args:tt)*) => { $crate::log_tr!($crate::console::Level::Info, $($args)*) };
+}
+
+#[macro_export]
+macro_rules! debug {
+    ($($args:tt)*) => { $crate::log_tr!($crate::console::Level::Debug, $($args)*) };
+}
+
+#[macro_export]
+macro_rules! error_repeated {
+    ($key:expr, $($args:tt)*) => {
+        $crate::console::repeated($crate::console::Level::Error, $key, || $crate::tr!($($args)*))
+    };
+}
+
+#[macro_export]
+macro_rules! warn_repeated {
+    ($key:expr, $($args:tt)*) => {
+        $crate::console::repeated($crate::console::Level::Warn, $key, || $crate::tr!($($args)*))
+    };
+}
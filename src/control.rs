@@ -0,0 +1,412 @@
+//! 正向转发的控制通道：客户端启动后和服务端另外建立一条只传消息的 KCP 连接（握手时带上 `Control`），
+//! 不再只能从数据连接的表现去猜对端的状态。
+//!
+//! - 双方定时交换会话数和转发量（`Stats`），同时起到保活的作用，结果记在管理接口的 `peers` 里；
+//! - 服务端在通道建立时推送自己的会话设置（`Config`），客户端的超时设置比服务端宽松时提示出来；
+//! - 一方开始排空时通知另一方（`Closing`），对端可以据此区分正常关闭和网络故障；
+//! - 服务端推送建议的 KCP 参数、新会话速率和备用地址（`Push`，见 `push`），客户端从下一个会话开始使用；
+//! - 一方因为退出、空闲超时、超出配额或者被管理员关闭而结束数据会话时告知对端（`Goodbye`），
+//!   对端的这个会话以 `peer_*` 原因关闭，不会被记成对端断开或者网络出错。
+//!
+//! 客户端设置了 `--suspend-after` 时，连续这么久没有会话就关闭控制通道（同样先发送 `Closing`），
+//! 不再定时收发消息，让笔记本、手机之类的设备可以休眠网卡；下一个连接进来时立即重新建立。
+//! 每个 TCP 连接本来就使用各自的 KCP 会话，数据会话不需要等控制通道建立，所以恢复时不会变慢。
+//!
+//! 服务端不支持控制通道（没有 `FEATURE_CONTROL`）时客户端不再尝试，转发不受影响。
+
+use crate::dns;
+use crate::economy;
+use crate::protocol::{self, FEATURE_CONTROL, Message, Request};
+use crate::push::{Pushed, Settings};
+use crate::redundant;
+use crate::registry::Registry;
+use crate::session::{Goodbye, SessionOptions};
+use crate::simulate::{self, Conditions};
+use crate::udp;
+use anyhow::Context;
+use kcp::{KcpConfig, KcpStream};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+use tokio::io::{self, AsyncReadExt, WriteHalf};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::time::timeout;
+
+/// 交换统计的间隔，要明显短于 KCP 的会话过期时间
+const STATS_INTERVAL: Duration = Duration::from_secs(10);
+/// 控制通道断开后客户端重新建立的间隔
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+/// 发出 `Closing` 后等对端读到的时间
+const CLOSING_TIMEOUT: Duration = Duration::from_secs(3);
+
+static ECONOMY: OnceLock<bool> = OnceLock::new();
+
+/// 省流量模式下拉长交换统计的间隔，见 `economy`
+pub fn init(economy: bool) {
+    let _ = ECONOMY.set(economy);
+}
+
+fn stats_interval() -> Duration {
+    match ECONOMY.get() {
+        Some(true) => economy::STATS_INTERVAL,
+        _ => STATS_INTERVAL,
+    }
+}
+
+/// 服务端：处理一条控制通道，直到它断开或者进入排空状态
+pub async fn serve(
+    control: KcpStream,
+    peer: SocketAddr,
+    identity: Option<String>,
+    registry: Arc<Registry>,
+    options: SessionOptions,
+    push: Arc<Settings>,
+) {
+    let (mut reader, mut writer) = io::split(control);
+    let mut goodbyes = registry.goodbyes();
+    let peer_ip = peer.ip();
+    let peer = peer.to_string();
+    let config = Message::Config {
+        idle_timeout: options.idle_timeout,
+        max_duration: options.max_duration,
+    };
+    // 没有设置推送时也发送一次，让客户端撤销之前推送的设置
+    let push = Message::Push((*push).clone());
+    if protocol::write_message(&mut writer, &config).await.is_err()
+        || protocol::write_message(&mut writer, &push).await.is_err()
+    {
+        return;
+    }
+    let name = identity
+        .as_ref()
+        .map_or(String::new(), |identity| format!(" ({identity})"));
+    info!(
+        "Control channel from client {peer}{name} opened",
+        "客户端 {peer}{name} 建立了控制通道"
+    );
+    registry.update_peer(&peer, |info| info.identity = identity);
+    let mut stats = tokio::time::interval(stats_interval());
+    loop {
+        tokio::select! {
+            message = protocol::read_message(&mut reader) => match message {
+                Ok(Message::Ping) => {}
+                Ok(Message::Stats { sessions, sent, received }) => {
+                    registry.update_peer(&peer, |info| {
+                        info.sessions = sessions.into();
+                        info.sent = sent;
+                        info.received = received;
+                    });
+                }
+                Ok(Message::Closing { reason }) => {
+                    notice!(
+                        "Client {peer} is closing: {reason}",
+                        "客户端 {peer} 即将关闭：{reason}"
+                    );
+                    break;
+                }
+                // 只关闭这个客户端自己的会话
+                Ok(Message::Goodbye { conv, reason }) => {
+                    if registry.stop_conv(conv, Some(peer_ip), reason).is_none() {
+                        info!(
+                            "Client {peer} closed the session with conv {conv:#010x} ({reason})",
+                            "客户端 {peer} 关闭了 conv 为 {conv:#010x} 的会话（{reason}）"
+                        );
+                    }
+                }
+                _ => break,
+            },
+            goodbye = next_goodbye(&mut goodbyes, Some(peer_ip)) => {
+                if send_goodbye(&mut writer, goodbye).await.is_err() {
+                    break;
+                }
+            }
+            _ = stats.tick() => {
+                if send_stats(&mut writer, &registry).await.is_err() {
+                    break;
+                }
+            }
+            _ = registry.draining() => {
+                let closing = Message::Closing { reason: "server is draining".to_string() };
+                // 关闭 KCP 连接时还没送达的数据会被丢弃，所以等客户端读到后先断开
+                if protocol::write_message(&mut writer, &closing).await.is_ok() {
+                    let _ = timeout(CLOSING_TIMEOUT, reader.read_u8()).await;
+                }
+                break;
+            }
+        }
+    }
+    registry.remove_peer(&peer);
+    info!(
+        "Control channel from client {peer} closed",
+        "客户端 {peer} 的控制通道已关闭"
+    );
+}
+
+/// 客户端：一次控制通道结束的原因
+enum Ended {
+    /// 进入排空状态或者服务端不支持，不再重新建立
+    Stopped,
+    /// 服务端通知要关闭
+    ServerClosing,
+    /// 没有会话的时间超过了 `--suspend-after`
+    Suspended,
+    /// 系统休眠太久或者本机地址变了，通道在服务端那边已经失效
+    Woke,
+}
+
+/// 客户端：控制通道的设置
+pub struct Client {
+    pub server_addr: String,
+    pub kcp_config: Arc<KcpConfig>,
+    pub simulate: Option<Conditions>,
+    /// 同时发送一份副本的服务端备用地址
+    pub redundant_addr: Option<String>,
+    /// 本地的会话设置，用来和服务端推送的设置比较
+    pub options: SessionOptions,
+    /// 记录服务端推送的设置
+    pub pushed: Arc<Pushed>,
+    /// 连续这么久没有会话时暂时关闭控制通道
+    pub suspend_after: Option<Duration>,
+}
+
+/// 客户端：建立控制通道，断开后自动重新建立，直到进入排空状态或者发现服务端不支持
+pub async fn run_client(client: Client, registry: Arc<Registry>) {
+    // 服务端上次推送的设置，没有变化时不再重复提示
+    let mut config = None;
+    loop {
+        let ended = session(&client, &registry, &mut config).await;
+        registry.contact().heartbeat(false);
+        match ended {
+            Ok(Ended::Stopped) => return,
+            // 服务端通知过要关闭，保留记录让管理接口能看到原因
+            Ok(Ended::ServerClosing) => {}
+            Ok(Ended::Suspended) => {
+                info!(
+                    "No sessions for {:?}, suspended the control channel to {} until the next connection",
+                    "{:?} 没有会话，暂停到 {} 的控制通道，直到有新连接",
+                    client.suspend_after.unwrap_or_default(),
+                    client.server_addr
+                );
+                tokio::select! {
+                    _ = registry.wait_occupied() => continue,
+                    _ = registry.draining() => return,
+                }
+            }
+            Ok(Ended::Woke) => continue,
+            Err(e) => {
+                registry.remove_peer(&client.server_addr);
+                registry.contact().failed(format!("control channel: {e:#}"));
+                warn_repeated!(
+                    tr!("control channel lost", "控制通道断开"),
+                    "Control channel to {}: {e:#}, retrying in {RECONNECT_DELAY:?}",
+                    "到 {} 的控制通道：{e:#}，{RECONNECT_DELAY:?} 后重试",
+                    client.server_addr
+                );
+            }
+        }
+        tokio::select! {
+            _ = tokio::time::sleep(RECONNECT_DELAY) => {}
+            _ = registry.draining() => return,
+        }
+    }
+}
+
+async fn connect(client: &Client) -> anyhow::Result<Option<KcpStream>> {
+    let (mut stream, _) = match (client.simulate, &client.redundant_addr) {
+        (Some(conditions), _) => {
+            simulate::connect(
+                client.kcp_config.clone(),
+                &client.server_addr,
+                conditions,
+                Arc::default(),
+            )
+            .await?
+        }
+        (None, Some(backup)) => {
+            redundant::connect(
+                client.kcp_config.clone(),
+                &client.server_addr,
+                backup,
+                Arc::default(),
+            )
+            .await?
+        }
+        (None, None) => {
+            let addr = dns::lookup(&client.server_addr).await?;
+            udp::connect(client.kcp_config.clone(), addr, Arc::default()).await?
+        }
+    };
+    let features = timeout(
+        protocol::HANDSHAKE_TIMEOUT,
+        protocol::client_handshake(&mut stream, FEATURE_CONTROL, &Request::Control),
+    )
+    .await
+    .context("handshake timed out")??;
+    Ok((features & FEATURE_CONTROL != 0).then_some(stream))
+}
+
+/// 建立一次控制通道，直到它结束，通道意外断开时返回错误
+async fn session(
+    client: &Client,
+    registry: &Registry,
+    config: &mut Option<(Option<Duration>, Option<Duration>)>,
+) -> anyhow::Result<Ended> {
+    let control = tokio::select! {
+        control = connect(client) => control?,
+        _ = registry.draining() => return Ok(Ended::Stopped),
+    };
+    let Some(control) = control else {
+        info!(
+            "Server {} does not support control channels, forwarding without one",
+            "服务端 {} 不支持控制通道，不使用控制通道转发", client.server_addr
+        );
+        return Ok(Ended::Stopped);
+    };
+    let (mut reader, mut writer) = io::split(control);
+    let mut goodbyes = registry.goodbyes();
+    let peer = client.server_addr.as_str();
+    registry.update_peer(peer, |info| info.closing = None);
+    registry.contact().ok();
+    registry.contact().heartbeat(true);
+    let mut stats = tokio::time::interval(stats_interval());
+    loop {
+        tokio::select! {
+            message = protocol::read_message(&mut reader) => match message? {
+                Message::Stats { sessions, sent, received } => {
+                    registry.contact().ok();
+                    registry.update_peer(peer, |info| {
+                        info.sessions = sessions.into();
+                        info.sent = sent;
+                        info.received = received;
+                    });
+                }
+                Message::Config { idle_timeout, max_duration } => {
+                    let pushed = Some((idle_timeout, max_duration));
+                    if std::mem::replace(config, pushed) != pushed {
+                        check_config(&client.options, idle_timeout, max_duration);
+                    }
+                }
+                Message::Push(settings) => {
+                    if client.pushed.set(settings.clone()) {
+                        match settings.is_empty() {
+                            true => notice!(
+                                "Server {peer} withdrew its pushed settings",
+                                "服务端 {peer} 撤销了推送的设置"
+                            ),
+                            false => notice!(
+                                "Server {peer} pushed settings: {settings}",
+                                "服务端 {peer} 推送了设置：{settings}"
+                            ),
+                        }
+                    }
+                }
+                Message::Closing { reason } => {
+                    notice!(
+                        "Server {peer} is closing: {reason}",
+                        "服务端 {peer} 即将关闭：{reason}"
+                    );
+                    registry.contact().failed(format!("server is closing: {reason}"));
+                    registry.update_peer(peer, |info| info.closing = Some(reason));
+                    return Ok(Ended::ServerClosing);
+                }
+                Message::Goodbye { conv, reason } => {
+                    if registry.stop_conv(conv, None, reason).is_none() {
+                        info!(
+                            "Server {peer} closed the session with conv {conv:#010x} ({reason})",
+                            "服务端 {peer} 关闭了 conv 为 {conv:#010x} 的会话（{reason}）"
+                        );
+                    }
+                }
+                _ => anyhow::bail!("server sent an unexpected control message"),
+            },
+            goodbye = next_goodbye(&mut goodbyes, None) => send_goodbye(&mut writer, goodbye).await?,
+            _ = stats.tick() => send_stats(&mut writer, registry).await?,
+            _ = registry.draining() => {
+                let closing = Message::Closing { reason: "client is draining".to_string() };
+                if protocol::write_message(&mut writer, &closing).await.is_ok() {
+                    let _ = timeout(CLOSING_TIMEOUT, reader.read_u8()).await;
+                }
+                return Ok(Ended::Stopped);
+            }
+            _ = idle_for(registry, client.suspend_after) => {
+                let closing = Message::Closing { reason: "client is idle".to_string() };
+                if protocol::write_message(&mut writer, &closing).await.is_ok() {
+                    let _ = timeout(CLOSING_TIMEOUT, reader.read_u8()).await;
+                }
+                registry.update_peer(peer, |info| info.closing = Some("suspended while idle".to_string()));
+                return Ok(Ended::Suspended);
+            }
+            _ = registry.woke() => return Ok(Ended::Woke),
+        }
+    }
+}
+
+/// 直到连续 `after` 时间没有会话才返回，`None` 时一直等下去
+async fn idle_for(registry: &Registry, after: Option<Duration>) {
+    let Some(after) = after else {
+        return std::future::pending().await;
+    };
+    loop {
+        registry.wait_empty().await;
+        tokio::select! {
+            _ = tokio::time::sleep(after) => return,
+            _ = registry.wait_occupied() => {}
+        }
+    }
+}
+
+async fn send_stats(writer: &mut WriteHalf<KcpStream>, registry: &Registry) -> std::io::Result<()> {
+    let stats = Message::Stats {
+        sessions: registry.len().min(u32::MAX as usize) as u32,
+        sent: registry.traffic().sent(),
+        received: registry.traffic().received(),
+    };
+    protocol::write_message(writer, &stats).await
+}
+
+/// 等待下一个需要告知对端的会话关闭，指定了 `peer` 时只要这个 IP 的会话
+async fn next_goodbye(
+    goodbyes: &mut broadcast::Receiver<(IpAddr, u32, Goodbye)>,
+    peer: Option<IpAddr>,
+) -> (u32, Goodbye) {
+    loop {
+        match goodbyes.recv().await {
+            Ok((ip, conv, reason)) if peer.is_none_or(|peer| peer == ip) => return (conv, reason),
+            // 来不及转告的就算了，对端照样会看到会话断开
+            Ok(_) | Err(RecvError::Lagged(_)) => {}
+            Err(RecvError::Closed) => std::future::pending().await,
+        }
+    }
+}
+
+async fn send_goodbye(
+    writer: &mut WriteHalf<KcpStream>,
+    (conv, reason): (u32, Goodbye),
+) -> std::io::Result<()> {
+    protocol::write_message(writer, &Message::Goodbye { conv, reason }).await
+}
+
+/// 服务端会先于本地关闭会话时提示，免得把这种关闭当成网络故障
+fn check_config(
+    options: &SessionOptions,
+    idle_timeout: Option<Duration>,
+    max_duration: Option<Duration>,
+) {
+    let looser = |local: Option<Duration>, server: Option<Duration>| {
+        server.filter(|server| local.is_none_or(|local| local > *server))
+    };
+    if let Some(server) = looser(options.idle_timeout, idle_timeout) {
+        notice!(
+            "Server closes sessions idle for more than {}s, earlier than this client does",
+            "服务端会关闭空闲超过 {} 秒的会话，早于本地的设置",
+            server.as_secs()
+        );
+    }
+    if let Some(server) = looser(options.max_duration, max_duration) {
+        notice!(
+            "Server closes sessions older than {}s, earlier than this client does",
+            "服务端会关闭存活超过 {} 秒的会话，早于本地的设置",
+            server.as_secs()
+        );
+    }
+}
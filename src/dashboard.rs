@@ -0,0 +1,210 @@
+//! 内置的网页面板：一个单页应用，显示当前会话、吞吐曲线和内存使用，可以关闭会话或排空。
+//!
+//! 只实现了够用的 HTTP/1.1：每个连接处理一个请求后关闭。
+//! 页面和数据都来自管理接口的同一套实现，操作接口要求带 `X-Requested-With` 头，
+//! 这样其它网站的页面没法跨域直接提交请求。和管理接口一样，建议只监听在本地回环地址上。
+//! 请求的 Host 头必须是 localhost、回环地址或者面板监听的地址，否则拒绝：DNS 重绑定的网页
+//! 把自己的域名解析到 127.0.0.1 后就算同源了，但它发来的 Host 仍是自己的域名。
+
+use crate::admin;
+use crate::bind::{self, Protocol};
+use crate::budget::Budget;
+use crate::i18n::{self, Lang};
+use crate::json::Value;
+use crate::registry::Registry;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::timeout;
+
+const PAGE: &str = include_str!("dashboard.html");
+/// 请求头的长度上限
+const MAX_HEAD: usize = 8 * 1024;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub async fn bind(addr: &str, retry: Option<Duration>) -> anyhow::Result<TcpListener> {
+    let listener = bind::with_retry("dashboard", Protocol::Tcp, addr, retry, || {
+        TcpListener::bind(addr)
+    })
+    .await?;
+    notice!(
+        "Dashboard listening on http://{}",
+        "网页面板正在监听 http://{}",
+        listener.local_addr()?
+    );
+    Ok(listener)
+}
+
+pub async fn serve(listener: TcpListener, registry: Arc<Registry>, budget: Arc<Budget>) {
+    loop {
+        let (stream, _) = bind::accept("dashboard", &listener).await;
+        let registry = registry.clone();
+        let budget = budget.clone();
+        tokio::spawn(async move {
+            let _ = timeout(
+                REQUEST_TIMEOUT,
+                handle_connection(stream, &registry, &budget),
+            )
+            .await;
+        });
+    }
+}
+
+async fn handle_connection(
+    mut stream: TcpStream,
+    registry: &Arc<Registry>,
+    budget: &Budget,
+) -> std::io::Result<()> {
+    let mut head = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+        if head.len() > MAX_HEAD {
+            return respond(
+                &mut stream,
+                "431 Request Header Fields Too Large",
+                "text/plain",
+                "",
+            )
+            .await;
+        }
+        let n = stream.read(&mut buf).await?;
+        if n == 0 {
+            return Ok(());
+        }
+        head.extend_from_slice(&buf[..n]);
+    }
+    let head = String::from_utf8_lossy(&head);
+    let mut lines = head.lines();
+    let mut request_line = lines.next().unwrap_or_default().split_whitespace();
+    let method = request_line.next().unwrap_or_default();
+    let target = request_line.next().unwrap_or_default();
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let mut same_origin = false;
+    let mut host = None;
+    for (name, value) in lines.filter_map(|line| line.split_once(':')) {
+        let name = name.trim();
+        if name.eq_ignore_ascii_case("x-requested-with") {
+            same_origin = true;
+        } else if name.eq_ignore_ascii_case("host") {
+            host = Some(value.trim());
+        }
+    }
+    if !host.is_some_and(|host| allowed_host(host, stream.local_addr().ok())) {
+        return respond(&mut stream, "421 Misdirected Request", "text/plain", "").await;
+    }
+
+    match (method, path) {
+        ("GET", "/") => {
+            let lang = match i18n::lang() {
+                Lang::En => "en",
+                Lang::Zh => "zh",
+            };
+            let page = PAGE.replace("{{lang}}", lang);
+            respond(&mut stream, "200 OK", "text/html; charset=utf-8", &page).await
+        }
+        ("GET", "/api/status") => {
+            let body = admin::status_json(registry, budget).to_string();
+            respond(&mut stream, "200 OK", "application/json", &body).await
+        }
+        ("POST", "/api/kill" | "/api/drain") if !same_origin => {
+            respond(&mut stream, "403 Forbidden", "text/plain", "").await
+        }
+        ("POST", "/api/kill") => {
+            let reply = match param(query, "id") {
+                Some(id) => admin::execute("kill", &[id], registry, budget),
+                None => "error missing session id\n".to_string(),
+            };
+            respond_reply(&mut stream, &reply).await
+        }
+        ("POST", "/api/drain") => {
+            let reply = match param(query, "secs") {
+                Some(secs) => admin::execute("drain", &[secs], registry, budget),
+                None => admin::execute("drain", &[], registry, budget),
+            };
+            respond_reply(&mut stream, &reply).await
+        }
+        ("GET" | "POST", _) => respond(&mut stream, "404 Not Found", "text/plain", "").await,
+        _ => respond(&mut stream, "405 Method Not Allowed", "text/plain", "").await,
+    }
+}
+
+/// Host 头（可以带端口）是否指向面板本身：localhost、回环地址，或者连接到达的本机地址
+fn allowed_host(host: &str, local: Option<SocketAddr>) -> bool {
+    let name = match host.rsplit_once(':') {
+        Some((name, port)) if !name.ends_with(':') && port.bytes().all(|b| b.is_ascii_digit()) => {
+            name
+        }
+        _ => host,
+    };
+    let name = name.trim_start_matches('[').trim_end_matches(']');
+    if name.eq_ignore_ascii_case("localhost") {
+        return true;
+    }
+    name.parse::<IpAddr>()
+        .is_ok_and(|ip| ip.is_loopback() || local.is_some_and(|local| local.ip() == ip))
+}
+
+/// 取出查询串里的参数，会话 id 和秒数都不含需要转义的字符，不做 URL 解码
+fn param<'a>(query: &'a str, name: &str) -> Option<&'a str> {
+    query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|&(key, value)| key == name && !value.is_empty())
+        .map(|(_, value)| value)
+}
+
+/// 把管理命令的文本应答转成 JSON 返回给页面
+async fn respond_reply(stream: &mut TcpStream, reply: &str) -> std::io::Result<()> {
+    let body = Value::object([
+        ("ok", reply.starts_with("ok").into()),
+        ("message", reply.trim_end().into()),
+    ]);
+    respond(stream, "200 OK", "application/json", &body.to_string()).await
+}
+
+pub async fn respond(
+    stream: &mut TcpStream,
+    status: &str,
+    content_type: &str,
+    body: &str,
+) -> std::io::Result<()> {
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\n\
+         Cache-Control: no-store\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allows_only_own_hosts() {
+        let local: SocketAddr = "192.0.2.1:8080".parse().unwrap();
+        for host in [
+            "localhost",
+            "LOCALHOST:8080",
+            "127.0.0.1:8080",
+            "[::1]:8080",
+            "[::1]",
+            "192.0.2.1:8080",
+        ] {
+            assert!(allowed_host(host, Some(local)), "{host}");
+        }
+        for host in [
+            "",
+            "evil.example",
+            "evil.example:8080",
+            "192.0.2.2:8080",
+            "localhost.evil.example",
+        ] {
+            assert!(!allowed_host(host, Some(local)), "{host}");
+        }
+        assert!(!allowed_host("192.0.2.1", None));
+    }
+}
@@ -0,0 +1,330 @@
+//! 服务端监听套接字上的收发循环，移植自 kcp-rs 的 `KcpUdpStream::socket_listen`：
+//! 按 conv 和客户端地址把收到的包分给各个会话，新的握手包建立会话，各会话要发的包排队由这里发出。
+//!
+//! kcp-rs 的版本把套接字藏在内部，看不到每个会话收发了多少包；这里给每个会话的传输层套上 `Metered`，
+//! 线路上的流量记到会话自己的 `Wire` 上（见 `wire`）。KCP 控制块本身（窗口、拥塞控制、重传）仍然是 kcp-rs 的，
+//! 这里只接管套接字，服务端所有的包都经过这个循环，在 Linux 上批量收发并打开分段卸载（见 `batch`），
+//! 要发出的包按会话公平排队（见 `fair`）。
+
+use crate::batch::{RecvBatch, Socket};
+use crate::disguise;
+use crate::fair::FairQueue;
+use crate::wire::{Metered, Wire};
+use bytes::{Bytes, BytesMut};
+use futures::SinkExt;
+use futures::future::ready;
+use kcp::conv::ConvCache;
+use kcp::transport::{UdpMpscStream, UnboundedSink};
+use kcp::{Kcp, KcpConfig, KcpStream};
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc::{self, OwnedPermit, Receiver, Sender, UnboundedReceiver, UnboundedSender};
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+
+/// 每轮最多连续收发这么多个包，再去处理别的事件
+const BATCH: usize = 1024;
+/// 公平排队每次取出这么多个包一起发出，发完再看有没有新来的包
+const ROUND: usize = 64;
+/// 没有传入 conv 隔离表时，结束的 conv 保留这么久不再分配，和 kcp-rs 相同
+const CONV_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// 一个建立好的会话：KCP 流、客户端地址和它的 UDP 流量计数器
+pub type Accepted = (KcpStream, SocketAddr, Arc<Wire>);
+
+pub struct Demux {
+    accepted: Receiver<Accepted>,
+    token: CancellationToken,
+    task: Option<JoinHandle<()>>,
+}
+
+impl Demux {
+    /// 在 `udp` 上开始接受连接，最多 `backlog` 个握手完成但还没有被 `accept` 取走的会话
+    pub fn listen(
+        config: Arc<KcpConfig>,
+        udp: UdpSocket,
+        backlog: usize,
+        conv_cache: Option<ConvCache>,
+    ) -> Self {
+        let token = CancellationToken::new();
+        let (accepted_tx, accepted) = mpsc::channel(backlog.max(8));
+        let task = Task::new(config, conv_cache, accepted_tx, token.clone());
+        Self {
+            accepted,
+            token,
+            task: Some(tokio::spawn(task.run(udp))),
+        }
+    }
+
+    pub async fn accept(&mut self) -> io::Result<Accepted> {
+        self.accepted
+            .recv()
+            .await
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotConnected))
+    }
+
+    /// 停止接受连接，结束所有会话，等收发循环把排队的包发完
+    pub async fn close(&mut self) -> io::Result<()> {
+        if let Some(task) = self.task.take() {
+            self.token.cancel();
+            self.accepted.close();
+            let _ = task.await;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Demux {
+    fn drop(&mut self) {
+        self.token.cancel();
+        self.accepted.close();
+    }
+}
+
+struct Session {
+    conv: u32,
+    session_id: Bytes,
+    peer: SocketAddr,
+    sender: Sender<BytesMut>,
+    wire: Arc<Wire>,
+    /// 握手完成后用来交出会话，保证不超过 backlog
+    permit: Option<OwnedPermit<Accepted>>,
+    token: CancellationToken,
+    /// 正在握手的任务
+    task: Option<JoinHandle<()>>,
+}
+
+enum Message {
+    Connect(KcpStream),
+    Disconnect { conv: u32 },
+}
+
+struct Task {
+    config: Arc<KcpConfig>,
+    conv_cache: ConvCache,
+    accepted: Sender<Accepted>,
+    msg_tx: UnboundedSender<Message>,
+    msg_rx: UnboundedReceiver<Message>,
+    packet_tx: UnboundedSender<(Bytes, SocketAddr)>,
+    packet_rx: UnboundedReceiver<(Bytes, SocketAddr)>,
+    /// 等着发出的包，按会话轮流发送
+    queue: FairQueue,
+    /// 正在发出的一批包
+    outgoing: Vec<(Bytes, SocketAddr)>,
+    token: CancellationToken,
+    closing: bool,
+    sessions: HashMap<u32, Session>,
+    /// 会话 id 到 conv
+    session_ids: HashMap<Bytes, u32>,
+}
+
+impl Task {
+    fn new(
+        config: Arc<KcpConfig>,
+        conv_cache: Option<ConvCache>,
+        accepted: Sender<Accepted>,
+        token: CancellationToken,
+    ) -> Self {
+        let (msg_tx, msg_rx) = mpsc::unbounded_channel();
+        let (packet_tx, packet_rx) = mpsc::unbounded_channel();
+        Self {
+            config,
+            conv_cache: conv_cache.unwrap_or_else(|| ConvCache::new(0, CONV_TIMEOUT)),
+            accepted,
+            msg_tx,
+            msg_rx,
+            packet_tx,
+            packet_rx,
+            queue: FairQueue::default(),
+            outgoing: Vec::new(),
+            token,
+            closing: false,
+            sessions: HashMap::new(),
+            session_ids: HashMap::new(),
+        }
+    }
+
+    async fn run(mut self, udp: UdpSocket) {
+        let udp = Socket::new(udp);
+        let mut batch = RecvBatch::new();
+        loop {
+            if self.closing {
+                // 处理完剩下的消息，所有会话都结束后退出
+                match self.msg_rx.try_recv() {
+                    Ok(msg) => self.process(msg).await,
+                    Err(_) if self.sessions.is_empty() => break,
+                    _ => (),
+                }
+            }
+
+            tokio::select! {
+                _ = udp.readable() => self.receive(&udp, &mut batch).await,
+
+                Some((packet, peer)) = self.packet_rx.recv() => {
+                    self.queue.push(packet, peer);
+                    self.flush(&udp, BATCH).await;
+                }
+
+                // 上一轮没发完的包
+                _ = ready(()), if !self.queue.is_empty() => self.flush(&udp, BATCH).await,
+
+                Some(msg) = self.msg_rx.recv() => self.process(msg).await,
+
+                _ = self.token.cancelled(), if !self.closing => {
+                    self.closing = true;
+                }
+            }
+        }
+
+        self.msg_rx.close();
+        self.packet_rx.close();
+        self.flush(&udp, usize::MAX).await;
+    }
+
+    async fn process(&mut self, msg: Message) {
+        match msg {
+            Message::Connect(stream) => {
+                if let Some(session) = self.sessions.get_mut(&stream.conv()) {
+                    if let Some(task) = session.task.take() {
+                        let _ = task.await;
+                    }
+                    if let Some(permit) = session.permit.take() {
+                        permit.send((stream, session.peer, session.wire.clone()));
+                    }
+                }
+            }
+            Message::Disconnect { conv } => {
+                if let Some(session) = self.sessions.remove(&conv) {
+                    self.kill(session).await;
+                }
+            }
+        }
+    }
+
+    /// 收下套接字上已经到达的包，最多 `BATCH` 个，分给各个会话
+    async fn receive(&mut self, udp: &Socket, batch: &mut RecvBatch) {
+        let mut received = 0;
+        while received < BATCH {
+            // 出错时（比如 Windows 上对端不可达的 ICMP）丢掉这一批，套接字坏掉由 `listener` 检查
+            let Ok(count) = batch.recv(udp) else { break };
+            received += count;
+            for (packet, peer) in batch.packets() {
+                let Some(packet) = disguise::unwrap(packet) else {
+                    continue;
+                };
+                if let Some(session) = self.session_for(packet, peer) {
+                    let _ = session.sender.send(BytesMut::from(packet)).await;
+                }
+            }
+        }
+    }
+
+    /// 按会话轮流发出排队的包，最多 `max` 个；每发完一小批都把新来的包排进去，让它们也能及时轮到
+    async fn flush(&mut self, udp: &Socket, max: usize) {
+        let mut sent = 0;
+        while sent < max {
+            while self.queue.len() < max {
+                let Ok((packet, peer)) = self.packet_rx.try_recv() else {
+                    break;
+                };
+                self.queue.push(packet, peer);
+            }
+            self.queue.take(ROUND.min(max - sent), &mut self.outgoing);
+            if self.outgoing.is_empty() {
+                break;
+            }
+            sent += self.outgoing.len();
+            if disguise::overhead() > 0 {
+                for (packet, _) in &mut self.outgoing {
+                    *packet = disguise::wrap(packet).freeze();
+                }
+            }
+            udp.send_all(&self.outgoing).await;
+            self.outgoing.clear();
+        }
+    }
+
+    /// 收到的包属于哪个会话，是新的握手包时建立会话
+    fn session_for(&mut self, packet: &[u8], peer: SocketAddr) -> Option<&Session> {
+        let conv = Kcp::read_conv(packet)?;
+        if self.sessions.contains_key(&conv) {
+            return self.sessions.get(&conv).filter(|s| s.peer == peer);
+        }
+
+        let session_id = KcpStream::read_session_id(packet, &self.config.session_key)?;
+        if let Some(&known) = self.session_ids.get(session_id) {
+            // 握手包重传
+            if known == conv || conv == Kcp::SYN_CONV {
+                return self.sessions.get(&known).filter(|s| s.peer == peer);
+            }
+            return None;
+        }
+        if self.closing || conv != Kcp::SYN_CONV || session_id.len() != self.config.session_id_len {
+            return None;
+        }
+        // backlog 满了就不接受新连接，客户端会重发握手包
+        let permit = self.accepted.clone().try_reserve_owned().ok()?;
+
+        let conv = self.conv_cache.allocate(|x| self.sessions.contains_key(x));
+        let (sender, receiver) = mpsc::channel(self.config.snd_wnd as usize);
+        let token = self.token.child_token();
+        let wire = Arc::new(Wire::default());
+        let transport = Metered::new(
+            UdpMpscStream::new(Some(self.packet_tx.clone()), receiver, peer),
+            wire.clone(),
+            peer,
+        );
+        let task = tokio::spawn(accept(
+            self.config.clone(),
+            conv,
+            transport,
+            self.msg_tx.clone(),
+            token.clone(),
+        ));
+        let session_id = Bytes::copy_from_slice(session_id);
+        self.session_ids.insert(session_id.clone(), conv);
+        self.sessions.insert(
+            conv,
+            Session {
+                conv,
+                session_id,
+                peer,
+                sender,
+                wire,
+                permit: Some(permit),
+                token,
+                task: Some(task),
+            },
+        );
+        self.sessions.get(&conv)
+    }
+
+    async fn kill(&mut self, mut session: Session) {
+        // 结束的 conv 暂时不再分配，免得迟到的包串到新会话里
+        self.conv_cache.add(session.conv);
+        self.session_ids.remove(&session.session_id);
+        if let Some(task) = session.task.take() {
+            session.token.cancel();
+            let _ = task.await;
+        }
+    }
+}
+
+/// 完成服务端一侧的握手，成功后交回收发循环
+async fn accept(
+    config: Arc<KcpConfig>,
+    conv: u32,
+    transport: Metered<UdpMpscStream<Bytes>>,
+    msg_tx: UnboundedSender<Message>,
+    token: CancellationToken,
+) {
+    let disconnect = UnboundedSink::new(msg_tx.clone())
+        .with(|conv: u32| ready(Ok::<_, io::Error>(Message::Disconnect { conv })));
+    if let Ok(stream) = KcpStream::accept(config, conv, transport, disconnect, Some(token)).await {
+        let _ = msg_tx.send(Message::Connect(stream));
+    }
+}
@@ -0,0 +1,181 @@
+//! `--disguise dtls`：给隧道的每个 UDP 包加上一个 DTLS 1.2 记录头，让包看上去像已经握手完的 DTLS 连接里的
+//! 应用数据，对付直接丢弃不认识的 UDP 协议的中间设备。两端都要设置成一样的，不一致时连接建立不起来。
+//!
+//! 记录头 13 字节：类型 23（application_data）、版本 0xfefd（DTLS 1.2）、epoch 1、48 位递增的序号和载荷长度，
+//! 后面原样跟着 KCP 的包。收到的包头不对、长度对不上时直接丢弃。
+//! 这只是外形像：没有握手，数据也没有加密，认真检查 DTLS 状态的设备一眼就能认出来。
+//!
+//! 服务端在收发循环（见 `demux`）里加上和去掉记录头，客户端在各种传输方式的套接字收发处处理（见 `Disguised`）。
+//! KCP 的包大小上限相应减去记录头的长度，线路上的 UDP 包仍然不超过 `--mtu`。
+
+use bytes::{BufMut, BytesMut};
+use clap::ValueEnum;
+use futures::{Sink, Stream};
+use std::pin::Pin;
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::{Context, Poll};
+
+/// DTLS 记录头的长度
+const DTLS_HEADER: usize = 13;
+/// application_data
+const DTLS_APPLICATION_DATA: u8 = 23;
+const DTLS_1_2: [u8; 2] = [0xfe, 0xfd];
+/// 握手完成后的 epoch
+const DTLS_EPOCH: [u8; 2] = [0, 1];
+
+static MODE: OnceLock<Disguise> = OnceLock::new();
+/// 发出的记录的序号
+static SEQUENCE: AtomicU64 = AtomicU64::new(0);
+
+/// 把 UDP 包伪装成什么协议
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Disguise {
+    /// 不伪装
+    None,
+    /// DTLS 1.2 的应用数据记录
+    Dtls,
+}
+
+/// 设置之后收发的包使用的伪装，不调用时不伪装
+pub fn init(mode: Disguise) {
+    let _ = MODE.set(mode);
+}
+
+fn mode() -> Disguise {
+    MODE.get().copied().unwrap_or(Disguise::None)
+}
+
+/// 每个包多出来的字节数
+pub fn overhead() -> usize {
+    overhead_of(mode())
+}
+
+fn overhead_of(mode: Disguise) -> usize {
+    match mode {
+        Disguise::None => 0,
+        Disguise::Dtls => DTLS_HEADER,
+    }
+}
+
+/// 给要发出的 KCP 包加上伪装
+pub fn wrap(packet: &[u8]) -> BytesMut {
+    wrap_as(mode(), packet)
+}
+
+/// 去掉收到的包的伪装，取出 KCP 包；不是按约定伪装的包返回 `None`
+pub fn unwrap(packet: &[u8]) -> Option<&[u8]> {
+    unwrap_as(mode(), packet)
+}
+
+fn wrap_as(mode: Disguise, packet: &[u8]) -> BytesMut {
+    let mut wrapped = BytesMut::with_capacity(overhead_of(mode) + packet.len());
+    if mode == Disguise::Dtls {
+        let sequence = SEQUENCE.fetch_add(1, Ordering::Relaxed);
+        wrapped.put_u8(DTLS_APPLICATION_DATA);
+        wrapped.put_slice(&DTLS_1_2);
+        wrapped.put_slice(&DTLS_EPOCH);
+        wrapped.put_slice(&sequence.to_be_bytes()[2..]);
+        wrapped.put_u16(packet.len() as u16);
+    }
+    wrapped.put_slice(packet);
+    wrapped
+}
+
+fn unwrap_as(mode: Disguise, packet: &[u8]) -> Option<&[u8]> {
+    match mode {
+        Disguise::None => Some(packet),
+        Disguise::Dtls => {
+            let (header, payload) = packet.split_at_checked(DTLS_HEADER)?;
+            let valid = header[0] == DTLS_APPLICATION_DATA
+                && header[1..3] == DTLS_1_2
+                && u16::from_be_bytes([header[11], header[12]]) as usize == payload.len();
+            valid.then_some(payload)
+        }
+    }
+}
+
+/// 包装客户端 KCP 的传输层，发出的包加上伪装，收到的包去掉伪装，不合格的包丢弃
+pub struct Disguised<T> {
+    inner: T,
+}
+
+impl<T> Disguised<T> {
+    pub fn new(inner: T) -> Self {
+        Self { inner }
+    }
+}
+
+impl<T: Stream<Item = BytesMut> + Unpin> Stream for Disguised<T> {
+    type Item = BytesMut;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<BytesMut>> {
+        loop {
+            match Pin::new(&mut self.inner).poll_next(cx) {
+                Poll::Ready(Some(mut packet)) => {
+                    let Some(payload) = unwrap(&packet) else {
+                        continue;
+                    };
+                    let at = packet.len() - payload.len();
+                    return Poll::Ready(Some(packet.split_off(at)));
+                }
+                polled => return polled,
+            }
+        }
+    }
+}
+
+impl<T: Sink<BytesMut> + Unpin> Sink<BytesMut> for Disguised<T> {
+    type Error = T::Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), T::Error>> {
+        Pin::new(&mut self.inner).poll_ready(cx)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, packet: BytesMut) -> Result<(), T::Error> {
+        let packet = match mode() {
+            Disguise::None => packet,
+            _ => wrap(&packet),
+        };
+        Pin::new(&mut self.inner).start_send(packet)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), T::Error>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), T::Error>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wraps_and_unwraps_dtls_records() {
+        let wrap = |packet: &[u8]| wrap_as(Disguise::Dtls, packet);
+        let unwrap = |packet| unwrap_as(Disguise::Dtls, packet);
+        let first = wrap(b"kcp packet");
+        let second = wrap(b"kcp packet");
+        assert_eq!(first.len(), DTLS_HEADER + 10);
+        assert_eq!(&first[..5], &[23, 0xfe, 0xfd, 0, 1]);
+        assert_eq!(&first[11..13], &[0, 10]);
+        // 每个记录的序号不同
+        assert_ne!(first[5..11], second[5..11]);
+        assert_eq!(unwrap(&first), Some(&b"kcp packet"[..]));
+
+        // 包头不对、长度对不上、太短的包都丢弃
+        let mut wrong_type = first.clone();
+        wrong_type[0] = 22;
+        assert_eq!(unwrap(&wrong_type), None);
+        assert_eq!(unwrap(&first[..first.len() - 1]), None);
+        assert_eq!(unwrap(&first[..DTLS_HEADER - 1]), None);
+        assert_eq!(unwrap(b""), None);
+        assert_eq!(unwrap(b"kcp packet without header"), None);
+
+        assert_eq!(&wrap_as(Disguise::None, b"kcp")[..], b"kcp");
+        assert_eq!(unwrap_as(Disguise::None, b"kcp"), Some(&b"kcp"[..]));
+    }
+}
@@ -0,0 +1,410 @@
+//! 解析服务端、后端等地址里的域名。
+//!
+//! 默认交给系统的解析器；用 `--dns` 指定服务器后，改为直接向这些服务器发送 UDP 查询，
+//! 依次尝试直到有一个给出结果，结果按记录的 TTL 缓存。适合系统解析器被污染或者很慢的网络。
+//!
+//! `--prefer` 指定优先使用的地址族：解析出的地址里这一族的排在前面，没有时才用另一族。
+//! 经过 6to4 之类隧道的 IPv6 线路有时比 IPv4 差很多，不想依赖系统的地址选择时用它固定下来。
+
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::{LazyLock, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use tokio::net::{TcpStream, UdpSocket, lookup_host};
+use tokio::time::timeout;
+
+/// 等待每个 DNS 服务器应答的时间
+const QUERY_TIMEOUT: Duration = Duration::from_secs(3);
+/// 缓存时间的上限，避免记录的 TTL 太长时一直用旧地址
+const MAX_TTL: Duration = Duration::from_secs(3600);
+
+const TYPE_A: u16 = 1;
+const TYPE_AAAA: u16 = 28;
+
+static SERVERS: OnceLock<Vec<SocketAddr>> = OnceLock::new();
+static PREFER: OnceLock<Family> = OnceLock::new();
+
+/// 地址族
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum Family {
+    Ipv4,
+    Ipv6,
+}
+
+impl Family {
+    pub fn of(ip: IpAddr) -> Self {
+        match ip {
+            IpAddr::V4(_) => Family::Ipv4,
+            IpAddr::V6(_) => Family::Ipv6,
+        }
+    }
+}
+/// 查到的地址和过期时间
+type Cached = (Vec<IpAddr>, Instant);
+
+static CACHE: LazyLock<Mutex<HashMap<String, Cached>>> = LazyLock::new(Mutex::default);
+
+/// 使用指定的 DNS 服务器，不调用时使用系统的解析器；`prefer` 是优先使用的地址族
+pub fn init(servers: Vec<SocketAddr>, prefer: Option<Family>) {
+    if !servers.is_empty() {
+        let _ = SERVERS.set(servers);
+    }
+    if let Some(prefer) = prefer {
+        let _ = PREFER.set(prefer);
+    }
+}
+
+/// 命令行参数：`1.1.1.1`、`1.1.1.1:5353` 或 `[2606:4700::1111]:53`
+pub fn parse_server(s: &str) -> Result<SocketAddr, String> {
+    s.parse::<SocketAddr>()
+        .or_else(|_| s.parse::<IpAddr>().map(|ip| SocketAddr::new(ip, 53)))
+        .map_err(|_| format!("invalid DNS server {s:?}, expected an IP address"))
+}
+
+/// 把 `host:port` 解析成一个地址
+pub async fn lookup(addr: &str) -> io::Result<SocketAddr> {
+    resolve(addr)
+        .await?
+        .into_iter()
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::AddrNotAvailable, "no address found"))
+}
+
+/// 解析 `host:port` 并建立 TCP 连接，依次尝试解析出的每个地址
+pub async fn connect_tcp(addr: &str) -> io::Result<TcpStream> {
+    if SERVERS.get().is_none() {
+        return TcpStream::connect(addr).await;
+    }
+    let mut last_error = None;
+    for addr in resolve(addr).await? {
+        match TcpStream::connect(addr).await {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_error = Some(e),
+        }
+    }
+    Err(last_error
+        .unwrap_or_else(|| io::Error::new(io::ErrorKind::AddrNotAvailable, "no address found")))
+}
+
+/// 解析出所有地址，优先的地址族排在前面
+async fn resolve(addr: &str) -> io::Result<Vec<SocketAddr>> {
+    let mut addrs = resolve_all(addr).await?;
+    if let Some(prefer) = PREFER.get() {
+        addrs.sort_by_key(|addr| Family::of(addr.ip()) != *prefer);
+    }
+    Ok(addrs)
+}
+
+async fn resolve_all(addr: &str) -> io::Result<Vec<SocketAddr>> {
+    let Some(servers) = SERVERS.get() else {
+        return Ok(lookup_host(addr).await?.collect());
+    };
+    if let Ok(addr) = addr.parse::<SocketAddr>() {
+        return Ok(vec![addr]);
+    }
+    let invalid = || {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid address {addr:?}"),
+        )
+    };
+    let (host, port) = addr.rsplit_once(':').ok_or_else(invalid)?;
+    let port: u16 = port.parse().map_err(|_| invalid())?;
+    let host = host.trim_end_matches('.').to_ascii_lowercase();
+    // DNS 服务器不知道本机的名字，不查 hosts 文件，只认 localhost
+    if host == "localhost" {
+        return Ok(vec![SocketAddr::new(Ipv4Addr::LOCALHOST.into(), port)]);
+    }
+    let ips = match cached(&host) {
+        Some(ips) => ips,
+        None => query_servers(servers, &host).await?,
+    };
+    Ok(ips
+        .into_iter()
+        .map(|ip| SocketAddr::new(ip, port))
+        .collect())
+}
+
+fn cached(host: &str) -> Option<Vec<IpAddr>> {
+    let mut cache = CACHE.lock().unwrap();
+    match cache.get(host) {
+        Some((ips, expires)) if *expires > Instant::now() => Some(ips.clone()),
+        Some(_) => {
+            cache.remove(host);
+            None
+        }
+        None => None,
+    }
+}
+
+/// 依次询问每个服务器，先查 IPv4 地址，没有时再查 IPv6 地址；优先 IPv6 时反过来
+async fn query_servers(servers: &[SocketAddr], host: &str) -> io::Result<Vec<IpAddr>> {
+    let mut last_error = io::Error::new(io::ErrorKind::NotFound, "no DNS server answered");
+    for &server in servers {
+        let kinds = match PREFER.get() {
+            Some(Family::Ipv6) => [TYPE_AAAA, TYPE_A],
+            _ => [TYPE_A, TYPE_AAAA],
+        };
+        for kind in kinds {
+            match timeout(QUERY_TIMEOUT, query(server, host, kind)).await {
+                Ok(Ok((ips, ttl))) if !ips.is_empty() => {
+                    let expires = Instant::now() + ttl.min(MAX_TTL);
+                    CACHE
+                        .lock()
+                        .unwrap()
+                        .insert(host.to_string(), (ips.clone(), expires));
+                    return Ok(ips);
+                }
+                Ok(Ok(_)) => {
+                    last_error = io::Error::new(
+                        io::ErrorKind::NotFound,
+                        format!("{server} has no address for {host}"),
+                    );
+                }
+                Ok(Err(e)) => {
+                    last_error = io::Error::new(e.kind(), format!("DNS server {server}: {e}"));
+                    break;
+                }
+                Err(_) => {
+                    last_error = io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        format!("DNS server {server} timed out"),
+                    );
+                    break;
+                }
+            }
+        }
+    }
+    Err(last_error)
+}
+
+/// 发送一次查询，返回查到的地址和最短的 TTL
+async fn query(server: SocketAddr, host: &str, kind: u16) -> io::Result<(Vec<IpAddr>, Duration)> {
+    let local: SocketAddr = match server {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let socket = UdpSocket::bind(local).await?;
+    socket.connect(server).await?;
+    let id: u16 = rand::random();
+    socket.send(&encode_query(id, host, kind)?).await?;
+    let mut buf = [0u8; 1500];
+    loop {
+        let n = socket.recv(&mut buf).await?;
+        // 丢弃不是这次查询的应答
+        if n >= 2 && buf[..2] == id.to_be_bytes() {
+            return decode_answer(&buf[..n], kind).ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidData, "malformed DNS answer")
+            })?;
+        }
+    }
+}
+
+fn encode_query(id: u16, host: &str, kind: u16) -> io::Result<Vec<u8>> {
+    let mut packet = Vec::with_capacity(17 + host.len());
+    packet.extend_from_slice(&id.to_be_bytes());
+    // 期望递归查询，一个问题
+    packet.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in host.split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid hostname {host:?}"),
+            ));
+        }
+        packet.push(label.len() as u8);
+        packet.extend_from_slice(label.as_bytes());
+    }
+    packet.push(0);
+    packet.extend_from_slice(&kind.to_be_bytes());
+    packet.extend_from_slice(&1u16.to_be_bytes());
+    Ok(packet)
+}
+
+/// 解析应答，格式不对时返回 `None`，服务器报错时返回 `Some(Err)`
+fn decode_answer(packet: &[u8], kind: u16) -> Option<io::Result<(Vec<IpAddr>, Duration)>> {
+    let u16_at = |i: usize| Some(u16::from_be_bytes([*packet.get(i)?, *packet.get(i + 1)?]));
+    let flags = u16_at(2)?;
+    match flags & 0x000f {
+        0 => {}
+        // NXDOMAIN：域名不存在，按没有地址处理
+        3 => return Some(Ok((Vec::new(), Duration::ZERO))),
+        rcode => {
+            return Some(Err(io::Error::other(format!(
+                "server answered with error code {rcode}"
+            ))));
+        }
+    }
+    let questions = u16_at(4)?;
+    let answers = u16_at(6)?;
+    let mut pos = 12;
+    for _ in 0..questions {
+        pos = skip_name(packet, pos)? + 4;
+    }
+    let mut ips = Vec::new();
+    let mut ttl = MAX_TTL;
+    for _ in 0..answers {
+        pos = skip_name(packet, pos)?;
+        let record_type = u16_at(pos)?;
+        let record_ttl = u32::from_be_bytes(packet.get(pos + 4..pos + 8)?.try_into().ok()?);
+        let len = u16_at(pos + 8)? as usize;
+        let data = packet.get(pos + 10..pos + 10 + len)?;
+        pos += 10 + len;
+        // CNAME 之类的记录跳过，服务器会把最终的地址一起返回
+        let ip = match (record_type, data.len()) {
+            (TYPE_A, 4) if kind == TYPE_A => IpAddr::from(<[u8; 4]>::try_from(data).ok()?),
+            (TYPE_AAAA, 16) if kind == TYPE_AAAA => IpAddr::from(<[u8; 16]>::try_from(data).ok()?),
+            _ => continue,
+        };
+        ips.push(ip);
+        ttl = ttl.min(Duration::from_secs(record_ttl.into()));
+    }
+    Some(Ok((ips, ttl)))
+}
+
+/// 跳过一个域名（可能以压缩指针结尾），返回其后的位置
+fn skip_name(packet: &[u8], mut pos: usize) -> Option<usize> {
+    loop {
+        let len = *packet.get(pos)?;
+        match len {
+            0 => return Some(pos + 1),
+            len if len & 0xc0 == 0xc0 => return Some(pos + 2),
+            len => pos += 1 + len as usize,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CNAME: u16 = 5;
+
+    /// `example.com` 的应答，每条记录的名字都用指向问题的压缩指针
+    fn answer(id: u16, rcode: u8, kind: u16, records: &[(u16, u32, &[u8])]) -> Vec<u8> {
+        let mut packet = encode_query(id, "example.com", kind).unwrap();
+        packet[2] = 0x81;
+        packet[3] = 0x80 | rcode;
+        packet[6..8].copy_from_slice(&(records.len() as u16).to_be_bytes());
+        for &(record_type, ttl, data) in records {
+            packet.extend_from_slice(&[0xc0, 12]);
+            packet.extend_from_slice(&record_type.to_be_bytes());
+            packet.extend_from_slice(&1u16.to_be_bytes());
+            packet.extend_from_slice(&ttl.to_be_bytes());
+            packet.extend_from_slice(&(data.len() as u16).to_be_bytes());
+            packet.extend_from_slice(data);
+        }
+        packet
+    }
+
+    #[test]
+    fn decodes_addresses_after_cnames() {
+        let cname = b"\x03www\x07example\x03net\x00";
+        let packet = answer(
+            1,
+            0,
+            TYPE_A,
+            &[
+                (CNAME, 30, cname),
+                (TYPE_A, 300, &[192, 0, 2, 1]),
+                (TYPE_AAAA, 5, &[0; 16]),
+                (TYPE_A, 120, &[192, 0, 2, 2]),
+            ],
+        );
+        let (ips, ttl) = decode_answer(&packet, TYPE_A).unwrap().unwrap();
+        assert_eq!(
+            ips,
+            [
+                "192.0.2.1".parse::<IpAddr>().unwrap(),
+                "192.0.2.2".parse().unwrap()
+            ]
+        );
+        // 只算用到的记录的 TTL，CNAME 和另一族的不算
+        assert_eq!(ttl, Duration::from_secs(120));
+
+        let (ips, ttl) = decode_answer(&packet, TYPE_AAAA).unwrap().unwrap();
+        assert_eq!(ips, [IpAddr::from(Ipv6Addr::UNSPECIFIED)]);
+        assert_eq!(ttl, Duration::from_secs(5));
+
+        // 长度不对的地址记录跳过
+        let packet = answer(1, 0, TYPE_A, &[(TYPE_A, 60, &[192, 0, 2])]);
+        assert!(
+            decode_answer(&packet, TYPE_A)
+                .unwrap()
+                .unwrap()
+                .0
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn decodes_errors() {
+        let (ips, _) = decode_answer(&answer(1, 3, TYPE_A, &[]), TYPE_A)
+            .unwrap()
+            .unwrap();
+        assert!(ips.is_empty());
+        assert!(
+            decode_answer(&answer(1, 2, TYPE_A, &[]), TYPE_A)
+                .unwrap()
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_answers() {
+        let packet = answer(1, 0, TYPE_A, &[(TYPE_A, 60, &[192, 0, 2, 1])]);
+        // 截断在记录的任何位置都不算合法的应答
+        for len in [3, 11, 20, packet.len() - 1] {
+            assert!(decode_answer(&packet[..len], TYPE_A).is_none(), "{len}");
+        }
+        // 记录数比实际的多
+        let mut more = packet.clone();
+        more[7] = 2;
+        assert!(decode_answer(&more, TYPE_A).is_none());
+        // 记录的数据长度超出包尾
+        let mut long = packet.clone();
+        let at = long.len() - 5;
+        long[at] = 0xff;
+        assert!(decode_answer(&long, TYPE_A).is_none());
+    }
+
+    #[test]
+    fn skips_names_without_following_pointers() {
+        let packet = b"\x03www\x07example\x03com\x00\xc0\x00\xc0\x13";
+        assert_eq!(skip_name(packet, 0), Some(17));
+        assert_eq!(skip_name(packet, 17), Some(19));
+        // 指向自己的指针不会绕圈
+        assert_eq!(skip_name(packet, 19), Some(21));
+        // 标签长度超出包尾
+        assert_eq!(skip_name(&packet[..10], 0), None);
+        assert_eq!(skip_name(packet, packet.len()), None);
+    }
+
+    #[tokio::test]
+    async fn queries_ignore_other_ids_and_cache_by_ttl() {
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = server.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 512];
+            loop {
+                let (_, from) = server.recv_from(&mut buf).await.unwrap();
+                let id = u16::from_be_bytes([buf[0], buf[1]]);
+                // 先回一个别的查询的应答，地址不同
+                let stale = answer(id ^ 1, 0, TYPE_A, &[(TYPE_A, 60, &[198, 51, 100, 1])]);
+                server.send_to(&stale, from).await.unwrap();
+                let ttl = 2 * MAX_TTL.as_secs() as u32;
+                let reply = answer(id, 0, TYPE_A, &[(TYPE_A, ttl, &[192, 0, 2, 1])]);
+                server.send_to(&reply, from).await.unwrap();
+            }
+        });
+
+        let ips = query_servers(&[addr], "cached.example").await.unwrap();
+        assert_eq!(ips, ["192.0.2.1".parse::<IpAddr>().unwrap()]);
+        assert_eq!(cached("cached.example"), Some(ips));
+        // TTL 超过上限时按上限缓存
+        let expires = CACHE.lock().unwrap()["cached.example"].1;
+        assert!(expires <= Instant::now() + MAX_TTL);
+        assert_eq!(cached("uncached.example"), None);
+    }
+}
@@ -0,0 +1,25 @@
+//! `--economy`：按流量计费的线路（比如手机的 4G 流量）上少花流量的一组设置，和 `--low-latency` 正好相反。
+//!
+//! - KCP 关闭 nodelay，时钟间隔拉长到 `INTERVAL`，同一个间隔内写入的数据攒成整段再发出；
+//!   不做快速重传、打开拥塞控制，少发重复的包；
+//! - 不能和 `--redundant-addr` 一起使用，每个包发两份正是最费流量的做法；
+//! - 控制通道交换统计（同时起保活作用）的间隔从 10 秒拉长到 `STATS_INTERVAL`；
+//! - 退出时打印协议开销占线路上流量的比例（见 `wire`），管理接口的 `overhead` 命令随时可以查。
+
+use kcp::KcpNoDelayConfig;
+use std::time::Duration;
+
+/// 省流量模式下 KCP 的时钟间隔（毫秒）
+const INTERVAL: u32 = 100;
+/// 省流量模式下控制通道交换统计的间隔，要短于客户端健康检查认为失联的 30 秒
+pub const STATS_INTERVAL: Duration = Duration::from_secs(20);
+
+/// 省流量模式的 KCP 参数
+pub fn nodelay() -> KcpNoDelayConfig {
+    KcpNoDelayConfig {
+        nodelay: false,
+        interval: INTERVAL,
+        resend: 0,
+        nc: false,
+    }
+}
@@ -0,0 +1,120 @@
+//! 服务端发包的公平排队：所有会话的包都从同一个套接字发出（见 `demux`），发送缓冲区满了以后
+//! 包在收发循环里排队，按先来后到发送的话，一个大流量会话刷出的一长串包会挡住其它会话的每一个包。
+//!
+//! 这里按客户端地址分成一个个队列，用差额轮转（deficit round-robin）轮流发送：每一轮每个队列
+//! 得到 `QUANTUM` 字节的额度，额度够就发出队首的包，用不完的留到下一轮，队列空了就清零。
+//! 额度比一个包大得多，同一个会话的包仍然成串发出，发往同一个客户端的包还能合并发送（见 `batch`）。
+
+use bytes::Bytes;
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+
+/// 每个队列每一轮可以发出的字节数
+const QUANTUM: usize = 16 * 1024;
+
+#[derive(Default)]
+pub struct FairQueue {
+    /// 每个客户端排队的包和它剩下的额度
+    queues: HashMap<SocketAddr, (VecDeque<Bytes>, usize)>,
+    /// 有包排队的客户端，按轮转的顺序
+    active: VecDeque<SocketAddr>,
+    len: usize,
+}
+
+impl FairQueue {
+    pub fn push(&mut self, packet: Bytes, peer: SocketAddr) {
+        let (queue, _) = self.queues.entry(peer).or_insert_with(|| {
+            self.active.push_back(peer);
+            Default::default()
+        });
+        queue.push_back(packet);
+        self.len += 1;
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// 按轮转的顺序取出最多 `max` 个包，追加到 `out` 后面
+    pub fn take(&mut self, max: usize, out: &mut Vec<(Bytes, SocketAddr)>) {
+        let mut taken = 0;
+        while taken < max
+            && let Some(peer) = self.active.pop_front()
+        {
+            let (queue, deficit) = self.queues.get_mut(&peer).expect("active peer has a queue");
+            *deficit += QUANTUM;
+            while taken < max
+                && let Some(packet) = queue.front()
+                && packet.len() <= *deficit
+            {
+                *deficit -= packet.len();
+                out.push((queue.pop_front().unwrap(), peer));
+                taken += 1;
+            }
+            if queue.is_empty() {
+                self.queues.remove(&peer);
+            } else {
+                self.active.push_back(peer);
+            }
+        }
+        self.len -= taken;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn takes_turns_between_peers() {
+        let mut fair = FairQueue::default();
+        // 一个会话先排了一长串满 MTU 的包，另一个会话之后来了两个小包
+        for _ in 0..100 {
+            fair.push(Bytes::from(vec![0; 1400]), peer(1));
+        }
+        fair.push(Bytes::from_static(b"ping"), peer(2));
+        fair.push(Bytes::from_static(b"pong"), peer(2));
+        assert_eq!(fair.len(), 102);
+
+        let mut out = Vec::new();
+        fair.take(usize::MAX, &mut out);
+        assert!(fair.is_empty());
+        assert_eq!(out.len(), 102);
+        // 第一个会话先用掉一轮的额度，第二个会话的包紧接着发出，不用等到最后
+        let first_round = QUANTUM / 1400;
+        assert!(out[..first_round].iter().all(|(_, to)| *to == peer(1)));
+        assert_eq!(out[first_round].0, Bytes::from_static(b"ping"));
+        assert_eq!(out[first_round + 1].0, Bytes::from_static(b"pong"));
+        assert!(out[first_round + 2..].iter().all(|(_, to)| *to == peer(1)));
+    }
+
+    #[test]
+    fn keeps_order_and_leftovers() {
+        let mut fair = FairQueue::default();
+        for i in 0..5u8 {
+            fair.push(Bytes::from(vec![i; 10]), peer(1));
+        }
+        let mut out = Vec::new();
+        fair.take(3, &mut out);
+        assert_eq!(fair.len(), 2);
+        fair.push(Bytes::from(vec![9; 10]), peer(2));
+        fair.take(usize::MAX, &mut out);
+        let firsts: Vec<_> = out.iter().map(|(packet, _)| packet[0]).collect();
+        assert_eq!(firsts, [0, 1, 2, 3, 4, 9]);
+        assert!(fair.is_empty());
+
+        // 比额度还大的包攒够几轮的额度后照样发出
+        fair.push(Bytes::from(vec![0; QUANTUM * 2 + 1]), peer(3));
+        out.clear();
+        fair.take(usize::MAX, &mut out);
+        assert_eq!(out.len(), 1);
+    }
+}
@@ -0,0 +1,501 @@
+//! `--geoip-db`：按 MaxMind 的 IP 地址库（GeoLite2-Country、GeoIP2-City 等 `.mmdb` 文件）查出客户端所在的国家或地区，
+//! 服务端给会话打上 `country=<代码>` 标签、写进日志，并按 `--allow-country`/`--deny-country` 决定接不接受。
+//!
+//! 没有引入专门的库，这里只实现了查询用到的部分：文件末尾的元数据、按 IP 地址逐位走的搜索树，
+//! 以及数据区里的 map、字符串、整数和指针，其它类型只跳过。取的是记录里 `country.iso_code`，
+//! 没有时用 `registered_country.iso_code`（比如只登记了注册地的地址段）。
+//!
+//! 地址库在启动时整个读进内存，更新文件后要重新启动；`--sandbox` 限制文件访问之前就读入，放在哪个目录都可以。
+//! 回环、局域网、链路本地这类地址不属于任何国家，总是接受；其它查不到的地址算作未知，
+//! 只有 `--deny-country` 时接受，只有 `--allow-country` 时拒绝。
+
+use anyhow::{Context, bail};
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::OnceLock;
+
+/// 元数据前面的标记，从文件末尾往前找
+const METADATA_MARKER: &[u8] = b"\xab\xcd\xefMaxMind.com";
+/// 搜索树和数据区之间隔着 16 个字节的 0
+const SEPARATOR: usize = 16;
+
+static DATABASE: OnceLock<Database> = OnceLock::new();
+
+struct Database {
+    data: Vec<u8>,
+    node_count: usize,
+    record_size: usize,
+    /// 同时收录了 IPv6 地址
+    ipv6: bool,
+    /// 搜索树里 IPv4 地址的起点，也就是 `::/96` 对应的节点；只有 IPv4 的库从根节点开始
+    ipv4_start: usize,
+    /// 数据区在文件里的起点
+    data_start: usize,
+}
+
+/// 数据区里解码出来的值，只保留用得到的类型
+enum Value {
+    String(String),
+    Uint(u64),
+    Map(Vec<(String, Value)>),
+    Other,
+}
+
+impl Value {
+    fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Value::Map(entries) => entries.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    fn as_uint(&self) -> Option<u64> {
+        match self {
+            Value::Uint(n) => Some(*n),
+            _ => None,
+        }
+    }
+}
+
+/// 读入地址库，之后 `country` 才有结果
+pub fn load(path: &Path) -> anyhow::Result<()> {
+    let data = std::fs::read(path)
+        .with_context(|| format!("failed to read GeoIP database {}", path.display()))?;
+    let database = Database::parse(data)
+        .with_context(|| format!("{} is not a valid MaxMind database", path.display()))?;
+    notice!(
+        "Loaded GeoIP database {} ({} nodes)",
+        "已读入 IP 地址库 {}（{} 个节点）",
+        path.display(),
+        database.node_count
+    );
+    let _ = DATABASE.set(database);
+    Ok(())
+}
+
+/// `ip` 所在国家或地区的 ISO 代码，比如 `CN`；没有读入地址库或者查不到时返回 `None`
+pub fn country(ip: IpAddr) -> Option<String> {
+    DATABASE.get()?.country(ip)
+}
+
+/// 解析国家或地区代码，两个字母，统一成大写
+pub fn parse_code(s: &str) -> Result<String, String> {
+    if s.len() == 2 && s.bytes().all(|b| b.is_ascii_alphabetic()) {
+        Ok(s.to_ascii_uppercase())
+    } else {
+        Err(format!(
+            "invalid country code {s:?}, expected two letters like CN"
+        ))
+    }
+}
+
+/// 服务端按国家或地区接受客户端的规则
+pub enum Policy {
+    Any,
+    Allow(Vec<String>),
+    Deny(Vec<String>),
+}
+
+impl Policy {
+    pub fn new(allow: &[String], deny: &[String]) -> Self {
+        if !allow.is_empty() {
+            Policy::Allow(allow.to_vec())
+        } else if !deny.is_empty() {
+            Policy::Deny(deny.to_vec())
+        } else {
+            Policy::Any
+        }
+    }
+
+    /// 检查来自 `ip`、查出来在 `country` 的客户端，拒绝时返回原因
+    pub fn admit(&self, ip: IpAddr, country: Option<&str>) -> Result<(), String> {
+        if is_local(ip) {
+            return Ok(());
+        }
+        match (self, country) {
+            (Policy::Any, _) => Ok(()),
+            (Policy::Allow(codes), Some(country)) if codes.iter().any(|c| c == country) => Ok(()),
+            (Policy::Allow(_), Some(country)) => Err(format!("country {country} is not allowed")),
+            (Policy::Allow(_), None) => Err("country unknown, not allowed".to_string()),
+            (Policy::Deny(codes), Some(country)) if codes.iter().any(|c| c == country) => {
+                Err(format!("country {country} is denied"))
+            }
+            (Policy::Deny(_), _) => Ok(()),
+        }
+    }
+}
+
+/// 不属于任何国家的地址：回环、局域网、链路本地、运营商级 NAT
+fn is_local(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                // 100.64.0.0/10
+                || (ip.octets()[0] == 100 && ip.octets()[1] & 0xc0 == 64)
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_local(IpAddr::V4(ip)),
+            None => {
+                ip.is_loopback()
+                    || ip.is_unspecified()
+                    || ip.is_unique_local()
+                    || ip.is_unicast_link_local()
+            }
+        },
+    }
+}
+
+impl Database {
+    fn parse(data: Vec<u8>) -> anyhow::Result<Self> {
+        let marker = data
+            .windows(METADATA_MARKER.len())
+            .rposition(|window| window == METADATA_MARKER)
+            .context("metadata not found")?;
+        let metadata_start = marker + METADATA_MARKER.len();
+        let (metadata, _) = Decoder {
+            data: &data,
+            base: metadata_start,
+        }
+        .decode(metadata_start, 0)
+        .context("corrupt metadata")?;
+        let field = |key| {
+            metadata
+                .get(key)
+                .and_then(Value::as_uint)
+                .with_context(|| format!("metadata has no {key}"))
+        };
+        let node_count = field("node_count")? as usize;
+        let record_size = field("record_size")? as usize;
+        let ip_version = field("ip_version")?;
+        if ![24, 28, 32].contains(&record_size) {
+            bail!("unsupported record size {record_size}");
+        }
+        // 节点数来自文件，算大小时不能溢出
+        let tree_size = node_count
+            .checked_mul(record_size * 2 / 8)
+            .filter(|size| size.saturating_add(SEPARATOR) <= marker)
+            .context("search tree is larger than the file")?;
+        let mut database = Database {
+            data,
+            node_count,
+            record_size,
+            ipv6: ip_version == 6,
+            ipv4_start: 0,
+            data_start: tree_size + SEPARATOR,
+        };
+        if database.ipv6 {
+            let mut node = 0;
+            for _ in 0..96 {
+                if node >= node_count {
+                    break;
+                }
+                node = database.record(node, 0);
+            }
+            database.ipv4_start = node;
+        }
+        Ok(database)
+    }
+
+    /// 第 `node` 个节点的左（`bit` 为 0）或右子树
+    fn record(&self, node: usize, bit: usize) -> usize {
+        let bytes = self.record_size * 2 / 8;
+        let b = &self.data[node * bytes..(node + 1) * bytes];
+        match (self.record_size, bit) {
+            (24, 0) => be(&b[0..3]),
+            (24, _) => be(&b[3..6]),
+            // 中间那个字节的高 4 位属于左边，低 4 位属于右边
+            (28, 0) => (b[3] as usize & 0xf0) << 20 | be(&b[0..3]),
+            (28, _) => (b[3] as usize & 0x0f) << 24 | be(&b[4..7]),
+            (_, 0) => be(&b[0..4]),
+            (_, _) => be(&b[4..8]),
+        }
+    }
+
+    fn country(&self, ip: IpAddr) -> Option<String> {
+        let record = self.lookup(ip)?;
+        ["country", "registered_country"].iter().find_map(|key| {
+            record
+                .get(key)?
+                .get("iso_code")?
+                .as_str()
+                .map(str::to_string)
+        })
+    }
+
+    fn lookup(&self, ip: IpAddr) -> Option<Value> {
+        let (bits, start) = match ip {
+            IpAddr::V4(ip) => (ip.octets().to_vec(), self.ipv4_start),
+            IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+                Some(ip) => (ip.octets().to_vec(), self.ipv4_start),
+                None if self.ipv6 => (ip.octets().to_vec(), 0),
+                // 只有 IPv4 的库里没有 IPv6 地址
+                None => return None,
+            },
+        };
+        let mut node = start;
+        for i in 0..bits.len() * 8 {
+            if node >= self.node_count {
+                break;
+            }
+            let bit = (bits[i / 8] >> (7 - i % 8)) & 1;
+            node = self.record(node, bit as usize);
+        }
+        // 等于节点数表示没有数据，更大时指向数据区
+        if node <= self.node_count {
+            return None;
+        }
+        // 紧跟在节点数后面的 16 个值落在分隔区里，损坏的文件才会出现
+        let offset = (node - self.node_count).checked_sub(SEPARATOR)?;
+        let decoder = Decoder {
+            data: &self.data,
+            base: self.data_start,
+        };
+        decoder
+            .decode(self.data_start + offset, 0)
+            .map(|(value, _)| value)
+    }
+}
+
+/// 数据区的解码器，指针相对于 `base`
+struct Decoder<'a> {
+    data: &'a [u8],
+    base: usize,
+}
+
+/// 嵌套的层数上限，免得损坏的文件让指针绕圈
+const MAX_DEPTH: usize = 32;
+
+impl Decoder<'_> {
+    /// 解码 `pos` 处的一个值，返回值和它后面的位置
+    fn decode(&self, pos: usize, depth: usize) -> Option<(Value, usize)> {
+        if depth > MAX_DEPTH {
+            return None;
+        }
+        let control = *self.data.get(pos)?;
+        let mut pos = pos + 1;
+        let mut kind = control >> 5;
+        if kind == 1 {
+            // 指针：后面 0 到 3 个字节加上控制字节的低 3 位，解码完指向的值后接着读指针后面的内容
+            let size = (control >> 3 & 0x3) as usize;
+            let low = (control & 0x7) as usize;
+            let bytes = self.bytes(pos, size + 1)?;
+            let target = match size {
+                0 => low << 8 | bytes[0] as usize,
+                1 => (low << 16 | be(bytes)) + 2048,
+                2 => (low << 24 | be(bytes)) + 526336,
+                _ => be(bytes),
+            };
+            let (value, _) = self.decode(self.base + target, depth + 1)?;
+            return Some((value, pos + size + 1));
+        }
+        if kind == 0 {
+            kind = 7 + *self.data.get(pos)?;
+            pos += 1;
+        }
+        let mut size = (control & 0x1f) as usize;
+        if size >= 29 {
+            let extra = size - 28;
+            let bytes = be(self.bytes(pos, extra)?);
+            pos += extra;
+            size = match extra {
+                1 => 29 + bytes,
+                2 => 285 + bytes,
+                _ => 65821 + bytes,
+            };
+        }
+        match kind {
+            // 字符串
+            2 => {
+                let text = std::str::from_utf8(self.bytes(pos, size)?).ok()?;
+                Some((Value::String(text.to_string()), pos + size))
+            }
+            // 各种宽度的无符号整数
+            5 | 6 | 9 | 10 => Some((Value::Uint(be(self.bytes(pos, size)?) as u64), pos + size)),
+            // map
+            7 => {
+                let mut entries = Vec::with_capacity(size.min(64));
+                for _ in 0..size {
+                    let (key, next) = self.decode(pos, depth + 1)?;
+                    let (value, next) = self.decode(next, depth + 1)?;
+                    pos = next;
+                    if let Value::String(key) = key {
+                        entries.push((key, value));
+                    }
+                }
+                Some((Value::Map(entries), pos))
+            }
+            // 数组，只跳过
+            11 => {
+                for _ in 0..size {
+                    pos = self.decode(pos, depth + 1)?.1;
+                }
+                Some((Value::Other, pos))
+            }
+            // double 固定 8 字节，float 固定 4 字节，布尔值就是 size 本身
+            3 => Some((Value::Other, pos + 8)),
+            15 => Some((Value::Other, pos + 4)),
+            14 => Some((Value::Other, pos)),
+            // 字节串、有符号整数等
+            _ => Some((Value::Other, pos + size)),
+        }
+    }
+
+    fn bytes(&self, pos: usize, len: usize) -> Option<&[u8]> {
+        self.data.get(pos..pos.checked_add(len)?)
+    }
+}
+
+/// 大端序的无符号整数，最多 8 个字节，更长的（uint128）只取低位
+fn be(bytes: &[u8]) -> usize {
+    bytes
+        .iter()
+        .fold(0usize, |n, b| n.wrapping_shl(8) | *b as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn string(s: &str) -> Vec<u8> {
+        let mut out = vec![2 << 5 | s.len() as u8];
+        out.extend_from_slice(s.as_bytes());
+        out
+    }
+
+    /// `kind` 为 5（uint16）或 6（uint32），只写出有效的字节
+    fn uint(kind: u8, n: u32) -> Vec<u8> {
+        let bytes = n.to_be_bytes();
+        let skip = bytes.iter().take_while(|b| **b == 0).count();
+        let mut out = vec![kind << 5 | (4 - skip) as u8];
+        out.extend_from_slice(&bytes[skip..]);
+        out
+    }
+
+    fn map(entries: &[(&str, Vec<u8>)]) -> Vec<u8> {
+        let mut out = vec![7 << 5 | entries.len() as u8];
+        for (key, value) in entries {
+            out.extend(string(key));
+            out.extend_from_slice(value);
+        }
+        out
+    }
+
+    fn metadata(node_count: u32, record_size: u32, ip_version: u32) -> Vec<u8> {
+        let mut out = METADATA_MARKER.to_vec();
+        out.extend(map(&[
+            ("node_count", uint(6, node_count)),
+            ("record_size", uint(5, record_size)),
+            ("ip_version", uint(5, ip_version)),
+        ]));
+        out
+    }
+
+    /// 只有 IPv4 的小地址库，记录长 24 位：
+    /// `0.0.0.0/2` 在 CN，`64.0.0.0/2` 只登记了注册地 US（经指针引用），
+    /// `128.0.0.0/1` 没有数据；`right` 可以替换根节点右边的记录
+    fn database(right: Option<u32>) -> Vec<u8> {
+        const NODES: u32 = 2;
+        let cn = map(&[("country", map(&[("iso_code", string("CN"))]))]);
+        // 指针：类型 1，size 为 0，目标是数据区里 `offset` 处的字符串；指针长度固定，先占位再算位置
+        let pointer = |offset: usize| vec![1 << 5 | (offset >> 8) as u8, offset as u8];
+        let registered = |us| map(&[("registered_country", map(&[("iso_code", pointer(us))]))]);
+        let us = cn.len() + registered(0).len();
+        let registered = registered(us);
+        let data_pointer = |offset: usize| NODES + SEPARATOR as u32 + offset as u32;
+
+        let mut file = Vec::new();
+        for record in [
+            1,
+            right.unwrap_or(NODES),
+            data_pointer(0),
+            data_pointer(cn.len()),
+        ] {
+            file.extend_from_slice(&record.to_be_bytes()[1..]);
+        }
+        file.extend_from_slice(&[0; SEPARATOR]);
+        file.extend(cn);
+        file.extend(registered);
+        file.extend(string("US"));
+        file.extend(metadata(NODES, 24, 4));
+        file
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_metadata() {
+        let database = Database::parse(database(None)).unwrap();
+        assert_eq!(database.node_count, 2);
+        assert_eq!(database.record_size, 24);
+        assert!(!database.ipv6);
+        assert_eq!(database.ipv4_start, 0);
+        assert_eq!(database.data_start, 2 * 6 + SEPARATOR);
+    }
+
+    #[test]
+    fn rejects_bad_metadata() {
+        let error = |data: Vec<u8>| Database::parse(data).err().unwrap().to_string();
+        assert_eq!(error(vec![0; 64]), "metadata not found");
+        assert_eq!(error(metadata(1, 20, 4)), "unsupported record size 20");
+        // 节点数大到乘出来会溢出，也不能超过文件本身
+        assert_eq!(
+            error(metadata(u32::MAX, 32, 4)),
+            "search tree is larger than the file"
+        );
+        assert_eq!(
+            error(metadata(2, 24, 4)),
+            "search tree is larger than the file"
+        );
+        let mut missing = METADATA_MARKER.to_vec();
+        missing.extend(map(&[("record_size", uint(5, 24))]));
+        assert_eq!(error(missing), "metadata has no node_count");
+    }
+
+    #[test]
+    fn looks_up_countries() {
+        let database = Database::parse(database(None)).unwrap();
+        assert_eq!(database.country(ip("1.2.3.4")).as_deref(), Some("CN"));
+        assert_eq!(
+            database.country(ip("63.255.255.255")).as_deref(),
+            Some("CN")
+        );
+        // 没有 `country` 时用 `registered_country`
+        assert_eq!(database.country(ip("100.0.0.1")).as_deref(), Some("US"));
+        assert_eq!(
+            database.country(ip("::ffff:1.2.3.4")).as_deref(),
+            Some("CN")
+        );
+        assert_eq!(database.country(ip("200.1.1.1")), None);
+        // 只有 IPv4 的库里查不到 IPv6 地址
+        assert_eq!(database.country(ip("2001:db8::1")), None);
+    }
+
+    #[test]
+    fn ignores_records_inside_separator() {
+        for record in [3, 2 + 15] {
+            let database = Database::parse(database(Some(record))).unwrap();
+            assert_eq!(database.country(ip("200.1.1.1")), None);
+        }
+        let database = Database::parse(database(Some(1 << 20))).unwrap();
+        assert_eq!(database.country(ip("200.1.1.1")), None);
+    }
+
+    #[test]
+    fn parses_country_codes() {
+        assert_eq!(parse_code("cn").unwrap(), "CN");
+        assert!(parse_code("CHN").is_err());
+        assert!(parse_code("c1").is_err());
+    }
+}
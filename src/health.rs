@@ -0,0 +1,160 @@
+//! `--health-addr`：给本机的监控和 Docker 的 HEALTHCHECK 用的 HTTP 接口，只有两个路径：
+//!
+//! - `/healthz`：进程能响应就返回 200，排空期间也一样，免得被当成卡死重启，打断还没结束的会话；
+//! - `/readyz`：客户端在到服务端的 KCP 线路可用时返回 200，否则返回 503，应答正文说明原因；
+//!   服务端在接受新会话时返回 200。两边进入排空状态后都返回 503。
+//!
+//! 线路是否可用看最近一次和服务端打交道的结果：控制通道收到消息、数据会话握手成功算成功，
+//! 连不上、握手失败或者超时、控制通道断开算失败。控制通道开着时服务端每 10 秒发一次统计，
+//! 超过 `STALE` 没有收到也算不可用；控制通道暂停（`--suspend-after`）或者没有控制通道时以最近一次结果为准。
+//!
+//! 和网页面板一样只实现了够用的 HTTP/1.1，建议只监听在本地回环地址上。
+
+use crate::bind::{self, Protocol};
+use crate::dashboard;
+use crate::registry::Registry;
+use std::fmt::Display;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::AsyncReadExt;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::timeout;
+
+/// 控制通道开着时，这么久没有服务端的消息就认为线路不可用，是统计间隔的三倍
+const STALE: Duration = Duration::from_secs(30);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// 只看请求行，读这么多就够了
+const MAX_REQUEST: usize = 1024;
+
+/// 客户端最近一次和服务端打交道的结果
+#[derive(Default)]
+pub struct Contact {
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    ok: Option<Instant>,
+    error: Option<(Instant, String)>,
+    /// 控制通道开着，应该定时收到服务端的消息
+    heartbeat: bool,
+}
+
+impl Contact {
+    /// 记录一次成功
+    pub fn ok(&self) {
+        self.state.lock().unwrap().ok = Some(Instant::now());
+    }
+
+    /// 记录一次失败
+    pub fn failed(&self, error: impl Display) {
+        self.state.lock().unwrap().error = Some((Instant::now(), error.to_string()));
+    }
+
+    /// 控制通道建立或者关闭
+    pub fn heartbeat(&self, on: bool) {
+        self.state.lock().unwrap().heartbeat = on;
+    }
+
+    /// 线路可用时返回上次成功到现在的时间，不可用时返回原因
+    pub fn readiness(&self) -> Result<Duration, String> {
+        let state = self.state.lock().unwrap();
+        let Some(ok) = state.ok else {
+            return Err(match &state.error {
+                Some((_, error)) => error.clone(),
+                None => "no contact with the server yet".to_string(),
+            });
+        };
+        if let Some((at, error)) = &state.error
+            && *at > ok
+        {
+            return Err(error.clone());
+        }
+        let age = ok.elapsed();
+        if state.heartbeat && age > STALE {
+            return Err(format!("no word from the server for {}s", age.as_secs()));
+        }
+        Ok(age)
+    }
+}
+
+pub async fn bind(addr: &str, retry: Option<Duration>) -> anyhow::Result<TcpListener> {
+    let listener = bind::with_retry("health endpoint", Protocol::Tcp, addr, retry, || {
+        TcpListener::bind(addr)
+    })
+    .await?;
+    notice!(
+        "Health endpoint listening on http://{}",
+        "健康检查接口正在监听 http://{}",
+        listener.local_addr()?
+    );
+    Ok(listener)
+}
+
+/// `server` 表示运行在服务端模式
+pub async fn serve(listener: TcpListener, registry: Arc<Registry>, server: bool) {
+    loop {
+        let (stream, _) = bind::accept("health endpoint", &listener).await;
+        let registry = registry.clone();
+        tokio::spawn(async move {
+            let _ = timeout(
+                REQUEST_TIMEOUT,
+                handle_connection(stream, &registry, server),
+            )
+            .await;
+        });
+    }
+}
+
+async fn handle_connection(
+    mut stream: TcpStream,
+    registry: &Registry,
+    server: bool,
+) -> std::io::Result<()> {
+    let mut request = Vec::new();
+    let mut buf = [0u8; 256];
+    while !request.contains(&b'\n') && request.len() < MAX_REQUEST {
+        let n = stream.read(&mut buf).await?;
+        if n == 0 {
+            return Ok(());
+        }
+        request.extend_from_slice(&buf[..n]);
+    }
+    let request = String::from_utf8_lossy(&request);
+    let mut request_line = request
+        .lines()
+        .next()
+        .unwrap_or_default()
+        .split_whitespace();
+    let method = request_line.next().unwrap_or_default();
+    let target = request_line.next().unwrap_or_default();
+    let path = target.split_once('?').map_or(target, |(path, _)| path);
+
+    let (status, body) = match (method, path) {
+        ("GET" | "HEAD", "/healthz") => ("200 OK", "ok\n".to_string()),
+        ("GET" | "HEAD", "/readyz") => readiness(registry, server),
+        ("GET" | "HEAD", _) => ("404 Not Found", String::new()),
+        _ => ("405 Method Not Allowed", String::new()),
+    };
+    let body = if method == "HEAD" { "" } else { &body };
+    dashboard::respond(&mut stream, status, "text/plain; charset=utf-8", body).await
+}
+
+fn readiness(registry: &Registry, server: bool) -> (&'static str, String) {
+    if registry.is_draining() {
+        return ("503 Service Unavailable", "draining\n".to_string());
+    }
+    if server {
+        return ("200 OK", "ready\n".to_string());
+    }
+    match registry.contact().readiness() {
+        Ok(age) => (
+            "200 OK",
+            format!(
+                "ready, last contact with the server {}s ago\n",
+                age.as_secs()
+            ),
+        ),
+        Err(reason) => ("503 Service Unavailable", format!("not ready: {reason}\n")),
+    }
+}
@@ -0,0 +1,503 @@
+//! 界面语言：命令行帮助和运行日志支持中文和英文。
+//!
+//! 语言按 `--lang`、`LC_ALL`、`LC_MESSAGES`、`LANG` 的顺序决定，都没有设置时使用中文。
+//! 帮助文本的中文版本写在 `Args` 的文档注释里，英文版本在 [`HELP_EN`] 中按参数名对照；
+//! 日志用 [`tr!`](crate::tr) 以及 [`console`](crate::console) 中的宏同时给出两种语言的格式串。
+
+use clap::ValueEnum;
+use std::sync::OnceLock;
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Lang {
+    En,
+    Zh,
+}
+
+static LANG: OnceLock<Lang> = OnceLock::new();
+
+/// 当前语言，`init` 之前调用时按环境变量判断
+pub fn lang() -> Lang {
+    *LANG.get_or_init(from_env)
+}
+
+/// 在解析参数之前确定语言，这样帮助文本也能使用对应的语言
+pub fn init(args: &[String]) {
+    let lang = lang_arg(args).unwrap_or_else(from_env);
+    LANG.get_or_init(|| lang);
+}
+
+/// 从原始参数里找出 `--lang`，值不合法时交给 clap 报错
+fn lang_arg(args: &[String]) -> Option<Lang> {
+    let value = args.iter().enumerate().find_map(|(i, arg)| {
+        if arg == "--lang" {
+            args.get(i + 1).map(String::as_str)
+        } else {
+            arg.strip_prefix("--lang=")
+        }
+    })?;
+    Lang::from_str(value, true).ok()
+}
+
+fn from_env() -> Lang {
+    ["LC_ALL", "LC_MESSAGES", "LANG"]
+        .into_iter()
+        .filter_map(|name| std::env::var(name).ok())
+        .find(|value| !value.is_empty())
+        .map_or(Lang::Zh, |value| {
+            if value.to_ascii_lowercase().starts_with("zh") {
+                Lang::Zh
+            } else {
+                Lang::En
+            }
+        })
+}
+
+/// 按当前语言格式化文本，第一个格式串是英文，第二个是中文
+#[macro_export]
+macro_rules! tr {
+    ($en:literal, $zh:literal $(, $arg:expr)* $(,)?) => {
+        match $crate::i18n::lang() {
+            $crate::i18n::Lang::En => format!($en $(, $arg)*),
+            $crate::i18n::Lang::Zh => format!($zh $(, $arg)*),
+        }
+    };
+}
+
+/// 按当前语言打印到标准输出，参数同 [`tr!`](crate::tr)
+#[macro_export]
+macro_rules! println_tr {
+    ($($args:tt)*) => {
+        println!("{}", $crate::tr!($($args)*))
+    };
+}
+
+/// 英文帮助文本，键是参数名；子命令的参数写作 `子命令.参数名`，子命令本身的说明写作 `子命令.`
+pub const HELP_EN: &[(&str, &str)] = &[
+    (
+        "config",
+        "Read options from a config file, options given on the command line take precedence",
+    ),
+    (
+        "profile",
+        "Use the settings under [profile.<name>] in the config file, auto picks the profile by the default gateway's MAC address",
+    ),
+    ("server", "Run in server mode"),
+    ("client", "Run in client mode"),
+    (
+        "proxy_addr",
+        "Backend address in server mode, remote server address in client mode",
+    ),
+    (
+        "listen_addr",
+        "UDP listen address in server mode, local TCP listen address in client mode",
+    ),
+    (
+        "idle_timeout",
+        "Close a session after this many seconds without data in either direction, 0 disables",
+    ),
+    (
+        "tcp_read_timeout",
+        "Seconds to wait for data from the TCP side (TCP -> KCP), 0 disables",
+    ),
+    (
+        "kcp_read_timeout",
+        "Seconds to wait for data from the KCP side (KCP -> TCP), 0 disables",
+    ),
+    (
+        "max_session_duration",
+        "Maximum lifetime of a session in seconds, after which it is closed gracefully, 0 disables",
+    ),
+    (
+        "session_class",
+        "Session classification: auto decides by the amount of data forwarded, interactive or \
+         bulk puts every session in that class",
+    ),
+    (
+        "bulk_threshold",
+        "With auto classification, sessions that forward more than this many MB in one \
+         direction are handled as bulk sessions",
+    ),
+    (
+        "bulk_rate",
+        "While interactive sessions are active, bulk sessions together write at most this many \
+         KB/s into KCP, 0 disables",
+    ),
+    (
+        "low_latency",
+        "Low-latency mode: shortens the KCP clock interval to 10 ms and retransmits sooner, \
+         turns off Nagle's algorithm on local TCP connections, trading CPU for latency",
+    ),
+    (
+        "busy_poll",
+        "Linux: microseconds UDP sockets busy-poll the NIC queue when receiving in low-latency \
+         mode, e.g. 50; values above net.core.busy_read need CAP_NET_ADMIN",
+    ),
+    (
+        "economy",
+        "Economy mode for metered links: KCP sends full segments and skips fast retransmits, \
+         and the control channel keepalive interval is longer",
+    ),
+    (
+        "storm_reset",
+        "Close a session whose KCP connection has done almost nothing but retransmit for 15 \
+         seconds, so the local program reconnects instead of hanging until KCP times out; \
+         without it the storm is only logged",
+    ),
+    (
+        "bind_retry",
+        "Keep retrying for this many seconds when binding fails at startup \
+         (address not assigned yet, port temporarily in use), 0 disables",
+    ),
+    (
+        "user",
+        "Switch to this user after binding ports (Unix only), to drop root after binding privileged ports",
+    ),
+    (
+        "group",
+        "Switch to this group after binding ports (Unix only), defaults to the primary group of --user",
+    ),
+    (
+        "sandbox",
+        "Harden the process (Linux only): restrict filesystem access with Landlock and \
+         block dangerous syscalls with seccomp once initialized",
+    ),
+    (
+        "conv_quarantine",
+        "Server: seconds before a closed connection's KCP conv can be reused, \
+         so late packets don't leak into a new connection",
+    ),
+    (
+        "geoip_db",
+        "Server: MaxMind IP database (e.g. GeoLite2-Country.mmdb) used to label sessions with \
+         the client's country or region",
+    ),
+    (
+        "allow_country",
+        "Server: only accept clients from these countries or regions, e.g. CN,HK; \
+         LAN addresses are always accepted",
+    ),
+    (
+        "deny_country",
+        "Server: reject clients from these countries or regions, e.g. US,RU",
+    ),
+    (
+        "legacy_protocol",
+        "Use the old protocol without handshake, to connect to a 1.0.x server; \
+         servers detect 1.0.x clients on their own and rarely need it",
+    ),
+    (
+        "identity",
+        "Client: identity sent to the server in the handshake, such as the hostname or the user's \
+         name, shown in the server's logs and session list",
+    ),
+    (
+        "cpu_affinity",
+        "Pin runtime worker threads to these CPUs, e.g. 0,2-3; one worker thread per CPU",
+    ),
+    (
+        "mirror_addr",
+        "Copy the client-to-backend data of every session to this address; replies from the mirror \
+         are discarded, and if it fails or falls behind only the mirroring stops",
+    ),
+    (
+        "circuit_breaker",
+        "Server: after this many failed connects in a row to the backend, fail new sessions \
+         immediately instead of dialing, and probe the backend in the background until it is \
+         back; 0 disables",
+    ),
+    (
+        "fallback_response",
+        "Server: when the backend can't be reached (including while the circuit is open), send \
+         the contents of this file to the client before closing, e.g. an HTTP 503 response",
+    ),
+    (
+        "reverse_ports",
+        "Server: public ports clients may register reverse tunnels on, e.g. 25565,30000-30100; \
+         reverse tunnels are disabled when omitted",
+    ),
+    (
+        "reverse_auth",
+        "Server: clients allowed to register reverse tunnels and their secrets, e.g. \
+         alice=<secret>,bob=<secret>; clients match an entry with --identity and --reverse-secret; \
+         secrets travel in cleartext, so the tunnel needs an encrypted underlay",
+    ),
+    (
+        "reverse_bind",
+        "Server: listen address of reverse tunnel public ports",
+    ),
+    (
+        "reverse",
+        "Client: reverse mode, expose this local service through a public port of the server, \
+         e.g. 127.0.0.1:25565",
+    ),
+    (
+        "reverse_name",
+        "Client: name of the reverse tunnel, must be unique on the server",
+    ),
+    (
+        "remote_port",
+        "Client: public port the reverse tunnel asks the server to open, 0 lets the server \
+         pick one of its allowed ports",
+    ),
+    (
+        "reverse_host",
+        "Client: only take visitors asking for this hostname (detected from TLS SNI, HTTP Host \
+         or the Minecraft handshake), so several tunnels can share one public port",
+    ),
+    (
+        "reverse_secret",
+        "Client: secret for registering the reverse tunnel, matched together with --identity \
+         against an entry of the server's --reverse-auth; sent in cleartext, so the tunnel needs \
+         an encrypted underlay",
+    ),
+    (
+        "reverse_webhook",
+        "Client: once the reverse tunnel is registered, HTTP POST its public address as JSON \
+         to this URL, e.g. http://127.0.0.1:9000/tunnel",
+    ),
+    (
+        "dns",
+        "DNS servers used to resolve the server, backend and other hostnames, \
+         e.g. 1.1.1.1,8.8.8.8; the system resolver is used when omitted",
+    ),
+    (
+        "udp_buffer",
+        "Kernel buffer size of the UDP sockets used by KCP: auto sizes them from the KCP window, \
+         or a size in KB; system default if not set",
+    ),
+    (
+        "prefer",
+        "Address family to use first when resolving hostnames, the other one is only used \
+         when there is no address of this family; by default the system resolver's order is kept",
+    ),
+    (
+        "source_addr",
+        "Client: local address the UDP sockets to the server bind to, deciding which address \
+         and address family the tunnel goes out from",
+    ),
+    (
+        "fwmark",
+        "Linux: firewall mark set on the UDP sockets used by KCP, for ip rule policy routing, \
+         e.g. 0x10; needs CAP_NET_ADMIN",
+    ),
+    (
+        "max_frame_size",
+        "Maximum size in bytes of control messages and other variable-length frames; longer \
+         frames from the peer are rejected as a protocol error, keep it the same on both ends",
+    ),
+    (
+        "admin_addr",
+        "Listen address of the admin interface, e.g. 127.0.0.1:7070; disabled when omitted",
+    ),
+    (
+        "dashboard_addr",
+        "Listen address of the web dashboard, e.g. 127.0.0.1:8080; disabled when omitted",
+    ),
+    (
+        "health_addr",
+        "Listen address of the health endpoint serving /healthz and /readyz, \
+         e.g. 127.0.0.1:8081; disabled when omitted",
+    ),
+    (
+        "memory_limit",
+        "Total memory budget for buffered data in MB, new sessions are rejected once used up, 0 disables",
+    ),
+    (
+        "mtu",
+        "Largest UDP packet KCP sends, in bytes, 1380 by default; lower it for small path MTUs \
+         or when running on the port of a small-packet protocol such as DNS",
+    ),
+    (
+        "disguise",
+        "Make every UDP packet look like another protocol, must match on both sides: \
+         dtls adds a DTLS 1.2 record header (13 bytes, counted within --mtu)",
+    ),
+    (
+        "session_memory_limit",
+        "Per-session buffer limit in KB, enforced by shrinking the KCP windows, 0 keeps the default windows",
+    ),
+    (
+        "session_rate",
+        "Accept at most this many new sessions per second, delaying the rest instead of rejecting \
+         them, 0 means unlimited",
+    ),
+    (
+        "session_burst",
+        "New sessions accepted back to back after an idle period, defaults to --session-rate",
+    ),
+    (
+        "max_pending",
+        "Maximum sessions being set up at once (handshake, dialing the backend or server), \
+         0 means unlimited",
+    ),
+    (
+        "pending_overflow",
+        "When too many sessions are being set up: reject the new connection, or drop-oldest \
+         to give up on the one waiting longest",
+    ),
+    (
+        "redundant_addr",
+        "Client: another address of the server (e.g. its IP on a second ISP), every UDP packet \
+         is also sent there, trading bandwidth for less loss",
+    ),
+    (
+        "local_addrs",
+        "Client: local addresses of each uplink, e.g. 192.168.1.2,10.0.0.2, new sessions are \
+         spread over them by measured RTT and loss",
+    ),
+    (
+        "probe_interval",
+        "Client: ping the server every this many seconds and record the RTT and loss of the \
+         path itself, 0 disables",
+    ),
+    (
+        "keepalive_interval",
+        "Client: send the server a keepalive after a session is idle for this many seconds, \
+         so UDP mappings on routers don't expire, 0 disables",
+    ),
+    (
+        "suspend_after",
+        "Client: suspend the control channel after this many seconds without sessions and \
+         reopen it on the next connection, to save power, 0 disables",
+    ),
+    (
+        "path_max_rtt",
+        "Multi-path: stop using a path whose RTT exceeds this many milliseconds and re-probe it \
+         periodically, 0 disables",
+    ),
+    (
+        "path_max_loss",
+        "Multi-path: stop using a path whose connection failure rate exceeds this percentage \
+         and re-probe it periodically",
+    ),
+    (
+        "push_kcp",
+        "Server: KCP parameters pushed to clients, e.g. interval=20,resend=2,nodelay=on,nc=on,window=512,mtu=1200",
+    ),
+    (
+        "push_session_rate",
+        "Server: new session rate limit (per second) pushed to clients, ignored by clients that \
+         set --session-rate themselves",
+    ),
+    (
+        "push_servers",
+        "Server: alternate server addresses pushed to clients, tried in order when --proxy-addr \
+         is unreachable",
+    ),
+    (
+        "push_roam_addrs",
+        "Server: other addresses of this server pushed to clients, sessions switch to the next one \
+         mid-session when the current address stops replying",
+    ),
+    (
+        "simulate",
+        "Debug: simulate a bad network on the client, e.g. loss=2%,delay=50ms,jitter=10ms, \
+         applied in both directions",
+    ),
+    (
+        "json",
+        "Print subcommand results as JSON for scripts and monitoring agents",
+    ),
+    (
+        "quiet",
+        "Only print startup, shutdown, warnings and errors, not every session opening and closing",
+    ),
+    ("verbose", "Also print details such as every relayed chunk"),
+    (
+        "log_suppress_window",
+        "Print repeated connection errors of the same kind (e.g. backend unreachable) only once \
+         within this many seconds, then a count, 0 disables",
+    ),
+    (
+        "summary_file",
+        "On exit, write a JSON summary (uptime, sessions, traffic, close reasons by count) to this file",
+    ),
+    (
+        "trace_session",
+        "Debug: hexdump data passing through a session (or all sessions), \
+         can be toggled at runtime via the admin interface",
+    ),
+    (
+        "debug_pcap",
+        "Debug: write relayed streams to a pcap file as synthetic TCP connections, for Wireshark",
+    ),
+    (
+        "record",
+        "Debug: record both directions of every session with timestamps into this directory, \
+         one file per session, for the replay subcommand",
+    ),
+    (
+        "status.",
+        "Query a running instance through the admin interface given by --admin-addr",
+    ),
+    (
+        "drain.",
+        "Drain a running instance through the admin interface given by --admin-addr and return \
+         once all sessions have finished, usable as a preStop hook",
+    ),
+    (
+        "drain.deadline",
+        "Wait at most this many seconds, then the instance closes the remaining sessions; \
+         waits indefinitely when omitted",
+    ),
+    (
+        "selftest.",
+        "Start a server and client pair on loopback, push data through them, \
+         verify integrity and measure throughput",
+    ),
+    ("selftest.size", "Amount of data to push in MB"),
+    (
+        "selftest.sweep",
+        "Run once with each of several KCP parameter sets and recommend one by throughput, \
+         combine with --simulate to mimic the real path",
+    ),
+    (
+        "selftest.assert_min_throughput",
+        "Exit with a non-zero code if throughput stays below this many MB/s, \
+         to catch performance regressions of a build or platform",
+    ),
+    (
+        "replay.",
+        "Send the client side of a session recorded with --record to a backend at its original pace \
+         and compare the replies with the recording",
+    ),
+    ("replay.file", "Recording file"),
+    ("replay.target", "Backend address, e.g. 127.0.0.1:25565"),
+    ("config.", "Configuration tools"),
+    (
+        "config.dump.",
+        "Print the effective configuration after merging defaults, the config file and the command line, in config file format with secrets redacted",
+    ),
+];
+
+/// 加上 `--lang` 参数，并把命令行帮助替换成当前语言的版本
+pub fn localize(command: clap::Command) -> clap::Command {
+    let lang_help = match lang() {
+        Lang::En => "Language of help text and logs",
+        Lang::Zh => "帮助和日志使用的语言",
+    };
+    let mut command = command.arg(
+        clap::Arg::new("lang")
+            .long("lang")
+            .value_name("LANG")
+            .global(true)
+            .value_parser(clap::builder::EnumValueParser::<Lang>::new())
+            .help(lang_help),
+    );
+    if lang() == Lang::Zh {
+        return command;
+    }
+    for &(key, help) in HELP_EN {
+        command = translate(command, key, help);
+    }
+    command
+}
+
+/// 子命令可以嵌套，比如 `config.dump.`
+fn translate(command: clap::Command, key: &str, help: &'static str) -> clap::Command {
+    match key.split_once('.') {
+        None => command.mut_arg(key, |arg| arg.help(help)),
+        Some((sub, "")) => command.mut_subcommand(sub, |sub| sub.about(help)),
+        Some((sub, rest)) => command.mut_subcommand(sub, |sub| translate(sub, rest, help)),
+    }
+}
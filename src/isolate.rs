@@ -0,0 +1,91 @@
+//! 会话任务的 panic 隔离：某个会话里的 panic（比如编解码的 bug）只结束这一个会话，
+//! 带着会话 id 和调用栈打印出来并计数，不影响其它会话和进程本身。
+
+use futures::FutureExt;
+use std::backtrace::Backtrace;
+use std::cell::{Cell, RefCell};
+use std::fmt;
+use std::future::Future;
+use std::panic::{self, AssertUnwindSafe, PanicHookInfo};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+thread_local! {
+    /// 当前线程是否正在执行被隔离的会话
+    static ISOLATED: Cell<bool> = const { Cell::new(false) };
+    /// panic 钩子记下的信息，由 `isolate` 取走
+    static CAUGHT: RefCell<Option<Panic>> = const { RefCell::new(None) };
+}
+
+/// 会话中发生的 panic
+pub struct Panic {
+    message: String,
+    location: String,
+    pub backtrace: Backtrace,
+}
+
+impl fmt::Display for Panic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at {}", self.message, self.location)
+    }
+}
+
+/// 安装 panic 钩子：被隔离的会话里的 panic 由 `isolate` 负责打印，其它的仍交给默认钩子
+pub fn install_hook() {
+    let default = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        if ISOLATED.get() {
+            CAUGHT.set(Some(capture(info)));
+        } else {
+            default(info);
+        }
+    }));
+}
+
+fn capture(info: &PanicHookInfo) -> Panic {
+    let payload = info.payload();
+    let message = payload
+        .downcast_ref::<&str>()
+        .map(|s| s.to_string())
+        .or_else(|| payload.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "Box<dyn Any>".to_string());
+    Panic {
+        message,
+        location: info
+            .location()
+            .map_or_else(|| "unknown location".to_string(), ToString::to_string),
+        backtrace: Backtrace::force_capture(),
+    }
+}
+
+/// 运行一个会话，其中的 panic 被捕获并作为 `Err` 返回
+pub async fn isolate<F: Future>(future: F) -> Result<F::Output, Panic> {
+    AssertUnwindSafe(Isolated(Box::pin(future)))
+        .catch_unwind()
+        .await
+        .map_err(|_| {
+            CAUGHT.take().unwrap_or_else(|| Panic {
+                message: "unknown panic".to_string(),
+                location: "unknown location".to_string(),
+                backtrace: Backtrace::disabled(),
+            })
+        })
+}
+
+/// 轮询期间把当前线程标记为正在执行被隔离的会话
+struct Isolated<F>(Pin<Box<F>>);
+
+impl<F: Future> Future for Isolated<F> {
+    type Output = F::Output;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<F::Output> {
+        struct Reset(bool);
+        impl Drop for Reset {
+            fn drop(&mut self) {
+                ISOLATED.set(self.0);
+            }
+        }
+        let _reset = Reset(ISOLATED.replace(true));
+        self.0.as_mut().poll(cx)
+    }
+}
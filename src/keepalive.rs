@@ -0,0 +1,99 @@
+//! `--keepalive-interval`：客户端的会话空闲时定时给服务端发一个保活包，
+//! 让家用路由器、防火墙上的 UDP 映射（常见的超时是 30 秒）不会在两波数据之间过期，
+//! 否则映射一换端口，服务端的应答就再也回不来，隧道悄无声息地断掉。
+//!
+//! 保活包是一个 KCP 的窗口探测（`WASK`），只有 24 字节的包头，不带数据，不进入数据流：
+//! 服务端的 KCP 回复一个窗口通知（`WINS`），两个方向各走一个包，两端的 KCP 会话也都因此不会过期。
+//! 包里的窗口填的是本端的接收窗口，只在没有数据经过时发送，不会打乱正在进行的传输。
+//!
+//! 保活包从会话自己的 UDP 套接字发出（用的是复制出来的描述符），只支持普通的直连方式，
+//! 模拟弱网、`--redundant-addr`、`--local-addrs` 和漫游时不发送。
+
+use crate::disguise;
+use crate::registry::Registration;
+use crate::udp;
+use crate::wire::Wire;
+use kcp::{KcpConfig, KcpStream};
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+
+/// KCP 的窗口探测命令
+const CMD_WASK: u8 = 83;
+
+static INTERVAL: OnceLock<Duration> = OnceLock::new();
+
+/// 设置保活间隔，不调用时不发送保活包
+pub fn init(interval: Option<Duration>) {
+    if let Some(interval) = interval {
+        let _ = INTERVAL.set(interval);
+    }
+}
+
+/// 一个会话的保活包发送端
+pub struct Keepalive {
+    socket: UdpSocket,
+    addr: SocketAddr,
+    packet: [u8; 24],
+    wire: Arc<Wire>,
+    pub interval: Duration,
+}
+
+impl Keepalive {
+    fn new(
+        socket: UdpSocket,
+        addr: SocketAddr,
+        conv: u32,
+        config: &KcpConfig,
+        wire: Arc<Wire>,
+    ) -> Self {
+        // 和 KCP 一样是小端序：conv | cmd | frg | wnd | ts | sn | una | len，除窗口外都是 0
+        let mut packet = [0u8; 24];
+        packet[..4].copy_from_slice(&conv.to_le_bytes());
+        packet[4] = CMD_WASK;
+        let wnd = config.rcv_wnd.min(u16::MAX.into()) as u16;
+        packet[6..8].copy_from_slice(&wnd.to_le_bytes());
+        Self {
+            socket,
+            addr,
+            packet,
+            wire,
+            interval: *INTERVAL.get().expect("keepalive interval is set"),
+        }
+    }
+
+    /// 发送一个保活包，发送缓冲区满之类的错误直接忽略，下一轮再发
+    pub fn send(&self) {
+        match self
+            .socket
+            .send_to(&disguise::wrap(&self.packet), self.addr)
+        {
+            Ok(_) => self.wire.send(self.packet.len(), self.addr),
+            Err(e) => debug!(
+                "Failed to send keepalive to {}: {e}",
+                "向 {} 发送保活包失败：{e}", self.addr
+            ),
+        }
+    }
+}
+
+/// 和 `udp::connect` 一样建立 KCP 连接；设置了保活间隔时复制一份套接字，登记到会话上
+pub async fn connect(
+    config: Arc<KcpConfig>,
+    addr: SocketAddr,
+    registration: &Registration<'_>,
+) -> io::Result<(KcpStream, SocketAddr)> {
+    let socket = udp::bind_for(addr, &config).await?;
+    let wire = registration.wire();
+    if INTERVAL.get().is_none() {
+        return udp::socket_connect(config, addr, socket, wire).await;
+    }
+    // 复制出来的描述符保持非阻塞，只用来发送，不会和 KCP 抢着接收
+    let socket = socket.into_std()?;
+    let sender = socket.try_clone()?;
+    let socket = tokio::net::UdpSocket::from_std(socket)?;
+    let (stream, addr) = udp::socket_connect(config.clone(), addr, socket, wire.clone()).await?;
+    registration.set_keepalive(Keepalive::new(sender, addr, stream.conv(), &config, wire));
+    Ok((stream, addr))
+}
@@ -0,0 +1,101 @@
+//! 转发延迟的统计：每段数据从读到到写完用了多久，按会话和全局分别记入直方图，
+//! 管理接口据此给出 p50/p95/p99，把“感觉卡”变成能比较的数字。
+//!
+//! 写入 KCP 的一侧在发送窗口塞满时会等待，所以这个延迟也反映了线路的拥塞；
+//! kcp-rs 不公开 KCP 内部测得的 RTT，隧道的往返时间由 `wire` 从确认段估算，线路本身的 RTT 看 `probe` 和 `paths`。
+//!
+//! 直方图按 2 的幂分段、每段再平分成 8 份，误差不超过 12.5%，记录时只做一次原子加法。
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// 小于这么多微秒的值每个微秒一个桶
+const LINEAR: usize = 16;
+/// 每个 2 的幂区间再分成这么多份
+const SUB_BUCKETS: usize = 8;
+/// 超过 2^32 微秒（约 71 分钟）的都记在最后一个桶里
+const MAX_POWER: usize = 32;
+const BUCKETS: usize = LINEAR + (MAX_POWER - LINEAR.ilog2() as usize) * SUB_BUCKETS;
+
+pub struct Histogram {
+    buckets: [AtomicU64; BUCKETS],
+}
+
+/// 延迟的分位数
+#[derive(Clone, Copy)]
+pub struct Percentiles {
+    pub samples: u64,
+    pub p50: Duration,
+    pub p95: Duration,
+    pub p99: Duration,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self {
+            buckets: std::array::from_fn(|_| AtomicU64::new(0)),
+        }
+    }
+}
+
+impl Histogram {
+    pub fn record(&self, latency: Duration) {
+        let micros = latency.as_micros().min(u64::MAX as u128) as u64;
+        self.buckets[index(micros)].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// 还没有记录过数据时返回 `None`
+    pub fn percentiles(&self) -> Option<Percentiles> {
+        let counts: Vec<u64> = self
+            .buckets
+            .iter()
+            .map(|bucket| bucket.load(Ordering::Relaxed))
+            .collect();
+        let samples: u64 = counts.iter().sum();
+        if samples == 0 {
+            return None;
+        }
+        let percentile = |p: f64| {
+            let rank = ((samples as f64 * p).ceil() as u64).max(1);
+            let mut seen = 0;
+            let index = counts
+                .iter()
+                .position(|&count| {
+                    seen += count;
+                    seen >= rank
+                })
+                .unwrap_or(BUCKETS - 1);
+            Duration::from_micros(upper(index))
+        };
+        Some(Percentiles {
+            samples,
+            p50: percentile(0.50),
+            p95: percentile(0.95),
+            p99: percentile(0.99),
+        })
+    }
+}
+
+fn index(micros: u64) -> usize {
+    if micros < LINEAR as u64 {
+        return micros as usize;
+    }
+    let power = micros.ilog2() as usize;
+    if power >= MAX_POWER {
+        return BUCKETS - 1;
+    }
+    let shift = power - SUB_BUCKETS.ilog2() as usize;
+    let sub = (micros >> shift) as usize % SUB_BUCKETS;
+    LINEAR + (power - LINEAR.ilog2() as usize) * SUB_BUCKETS + sub
+}
+
+/// 桶里最大的值，分位数取它，宁可偏大
+fn upper(index: usize) -> u64 {
+    if index < LINEAR {
+        return index as u64;
+    }
+    let power = LINEAR.ilog2() as usize + (index - LINEAR) / SUB_BUCKETS;
+    let sub = (index - LINEAR) % SUB_BUCKETS;
+    let shift = power - SUB_BUCKETS.ilog2() as usize;
+    (((SUB_BUCKETS + sub) as u64) << shift) + (1 << shift) - 1
+}
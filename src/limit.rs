@@ -0,0 +1,55 @@
+//! 单个会话的带宽上限：运行中通过管理接口的 `limit` 命令设置，比如只给一个占满带宽的玩家限速，不影响其他会话。
+//!
+//! 两个方向各自按上限计算，写出数据之前等到按速率可以写入；和 `Shaper` 一样按 GCRA 计算，
+//! 空闲后可以先写入 `BURST`。上限只在内存里，会话结束就没有了。
+
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// 限速时允许一次性写入的数据量
+const BURST: u64 = 64 * 1024;
+
+#[derive(Default)]
+pub struct Limit {
+    /// 每个方向的上限，字节每秒，0 表示不限制
+    rate: AtomicU64,
+    /// 两个方向按速率计算的下一次可以写入的时间
+    next: [Mutex<Option<Instant>>; 2],
+}
+
+impl Limit {
+    /// 设置上限，字节每秒，0 表示取消
+    pub fn set(&self, rate: u64) {
+        self.rate.store(rate, Ordering::Relaxed);
+        // 改了速率后从头计算，不背着按旧速率欠下的时间
+        for next in &self.next {
+            *next.lock().unwrap() = None;
+        }
+    }
+
+    pub fn rate(&self) -> u64 {
+        self.rate.load(Ordering::Relaxed)
+    }
+
+    /// 向 `direction`（0 或 1）写入 `bytes` 字节之前调用，超过上限时等到按速率可以写入
+    pub async fn pace(&self, direction: usize, bytes: usize) {
+        let rate = self.rate();
+        if rate == 0 {
+            return;
+        }
+        let delay = {
+            let mut next = self.next[direction].lock().unwrap();
+            let now = Instant::now();
+            let cost = Duration::from_secs_f64(bytes as f64 / rate as f64);
+            let burst = Duration::from_secs_f64(BURST as f64 / rate as f64);
+            let start = next.map_or(now, |next| next.max(now));
+            *next = Some(start + cost);
+            start.saturating_duration_since(now + burst)
+        };
+        if !delay.is_zero() {
+            tokio::time::sleep(delay).await;
+        }
+    }
+}
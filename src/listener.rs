@@ -0,0 +1,167 @@
+//! 服务端的 KCP 监听器，UDP 套接字坏掉后自动重建。
+//!
+//! 收发循环（见 `demux`）会吞掉套接字错误：监听的网卡被移除、系统休眠唤醒之后，套接字可能再也收不到包，
+//! 但 `accept` 不会报错，只能手动重启进程。这里保留同一个套接字的一个副本，定期从它给自己发一个空包，
+//! 发送失败、绑定的地址已经不在本机上、或者检测到系统刚从休眠中唤醒时，关闭旧的监听器，
+//! 重新绑定并建立新的监听器。会话表、内存预算、conv 隔离表等进程级的状态都保留，
+//! 旧套接字上的会话随旧监听器一起结束。
+
+use crate::bind::{self, Protocol};
+use crate::demux::{Accepted, Demux};
+use crate::udp;
+use kcp::KcpConfig;
+use kcp::conv::ConvCache;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use tokio::net::UdpSocket;
+use tokio::time::{self, Interval, MissedTickBehavior};
+
+/// 每隔这么久检查一次套接字
+const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+/// 墙上时间比单调时间多走了这么多，说明系统休眠过
+const SLEEP_GAP: Duration = Duration::from_secs(30);
+/// 关闭旧监听器时最多等待这么久
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+/// 重新绑定时最多重试这么久，比如等待网卡重新接上
+const REBIND_PERIOD: Duration = Duration::from_secs(600);
+
+pub struct Listener {
+    addr: String,
+    config: Arc<KcpConfig>,
+    conv_cache: ConvCache,
+    inner: Demux,
+    /// 和 `inner` 使用的是同一个套接字，用于检查；重建期间为 `None`，免得占着端口
+    watch: Option<std::net::UdpSocket>,
+    ticker: Interval,
+    /// 上次检查时的墙上时间和单调时间
+    checked: (SystemTime, Instant),
+}
+
+impl Listener {
+    /// 在已经绑定到 `addr` 的套接字上建立监听器
+    pub fn new(
+        addr: &str,
+        config: Arc<KcpConfig>,
+        udp: UdpSocket,
+        conv_cache: ConvCache,
+    ) -> io::Result<Self> {
+        let (inner, watch) = listen(&config, udp, &conv_cache)?;
+        let mut ticker = time::interval(CHECK_INTERVAL);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        Ok(Self {
+            addr: addr.to_string(),
+            config,
+            conv_cache,
+            inner,
+            watch: Some(watch),
+            ticker,
+            checked: (SystemTime::now(), Instant::now()),
+        })
+    }
+
+    /// 等待新连接，期间发现套接字坏掉就重建监听器；只有重新绑定失败时才返回错误
+    pub async fn accept(&mut self) -> anyhow::Result<Accepted> {
+        loop {
+            let broken = tokio::select! {
+                accepted = self.inner.accept() => match accepted {
+                    Ok(accepted) => return Ok(accepted),
+                    Err(e) => format!("listener stopped: {e}"),
+                },
+                _ = self.ticker.tick() => match self.check() {
+                    Some(broken) => broken,
+                    None => continue,
+                },
+            };
+            warn!(
+                "KCP listener on {} is broken ({broken}), restarting it",
+                "{} 上的 KCP 监听器已失效（{broken}），正在重建", self.addr
+            );
+            self.restart().await?;
+        }
+    }
+
+    pub async fn close(&mut self) -> io::Result<()> {
+        self.inner.close().await
+    }
+
+    /// 检查套接字是否还能用，不能用时返回原因
+    fn check(&mut self) -> Option<String> {
+        let (wall, mono) = (SystemTime::now(), Instant::now());
+        let (last_wall, last_mono) = std::mem::replace(&mut self.checked, (wall, mono));
+        // 单调时钟在休眠期间不走，墙上时间照走
+        let slept = wall
+            .duration_since(last_wall)
+            .unwrap_or_default()
+            .saturating_sub(mono - last_mono);
+        if slept > SLEEP_GAP {
+            return Some(format!(
+                "system resumed after sleeping {}s",
+                slept.as_secs()
+            ));
+        }
+        let watch = self.watch.as_ref()?;
+        let local = match watch.local_addr() {
+            Ok(local) => local,
+            Err(e) => return Some(e.to_string()),
+        };
+        // 绑定的地址被移除后，套接字还在但收不到发往这个地址的包
+        let ip = local.ip();
+        if !ip.is_unspecified()
+            && let Err(e) = std::net::UdpSocket::bind((ip, 0))
+            && e.kind() == io::ErrorKind::AddrNotAvailable
+        {
+            return Some(format!("{ip} is no longer assigned to this host"));
+        }
+        // 空包到了 kcp-rs 那边读不出 conv，直接被丢弃
+        let target = match ip {
+            IpAddr::V4(ip) if ip.is_unspecified() => (Ipv4Addr::LOCALHOST.into(), local.port()),
+            IpAddr::V6(ip) if ip.is_unspecified() => (Ipv6Addr::LOCALHOST.into(), local.port()),
+            ip => (ip, local.port()),
+        };
+        match watch.send_to(&[], SocketAddr::from(target)) {
+            Err(e) if e.kind() != io::ErrorKind::WouldBlock => Some(e.to_string()),
+            _ => None,
+        }
+    }
+
+    /// 关闭旧监听器，重新绑定同一个地址并建立新的监听器
+    async fn restart(&mut self) -> anyhow::Result<()> {
+        self.watch = None;
+        let _ = time::timeout(CLOSE_TIMEOUT, self.inner.close()).await;
+        let udp = bind::with_retry(
+            "UDP listener",
+            Protocol::Udp,
+            &self.addr,
+            Some(REBIND_PERIOD),
+            || UdpSocket::bind(&self.addr),
+        )
+        .await?;
+        udp::tune_listener(&udp, &self.config);
+        let (inner, watch) = listen(&self.config, udp, &self.conv_cache)?;
+        self.inner = inner;
+        self.watch = Some(watch);
+        self.checked = (SystemTime::now(), Instant::now());
+        notice!(
+            "KCP listener restarted on {}",
+            "KCP 监听器已在 {} 上重建",
+            self.addr
+        );
+        Ok(())
+    }
+}
+
+/// 建立监听器，同时返回套接字的一个副本
+fn listen(
+    config: &Arc<KcpConfig>,
+    udp: UdpSocket,
+    conv_cache: &ConvCache,
+) -> io::Result<(Demux, std::net::UdpSocket)> {
+    let udp = udp.into_std()?;
+    let watch = udp.try_clone()?;
+    watch.set_nonblocking(true)?;
+    let udp = UdpSocket::from_std(udp)?;
+    let listener = Demux::listen(config.clone(), udp, 5, Some(conv_cache.clone()));
+    Ok((listener, watch))
+}
@@ -1,12 +1,103 @@
-use clap::Parser;
-use kcp::{KcpConfig, KcpNoDelayConfig, KcpStream, KcpUdpStream};
+#[macro_use]
+mod i18n;
+#[macro_use]
+mod console;
+mod admin;
+mod affinity;
+mod audit;
+mod batch;
+mod bind;
+mod budget;
+mod circuit;
+mod class;
+mod client;
+mod codec;
+mod config;
+mod control;
+mod dashboard;
+mod demux;
+mod disguise;
+mod dns;
+mod economy;
+mod fair;
+mod geoip;
+mod health;
+mod isolate;
+mod json;
+mod keepalive;
+mod latency;
+mod limit;
+mod listener;
+mod mirror;
+mod multipath;
+mod network;
+mod pcap;
+mod pending;
+mod privilege;
+mod probe;
+mod protocol;
+mod push;
+mod rebind;
+mod record;
+mod redundant;
+mod registry;
+mod reverse;
+mod roaming;
+mod sandbox;
+mod selftest;
+mod server;
+mod session;
+mod simulate;
+mod sniff;
+mod storm;
+mod throttle;
+mod udp;
+mod wake;
+mod webhook;
+mod wire;
+
+use anyhow::Context;
+use budget::Budget;
+use clap::{CommandFactory, FromArgMatches, Parser, Subcommand};
+use class::{INTERACTIVE_WINDOW, Shaper};
+use kcp::{KcpConfig, KcpNoDelayConfig};
+use pcap::Capture;
+use pending::Pending;
+use probe::Probe;
+use push::KcpTuning;
+use registry::{Registration, Registry};
+use session::{CloseReason, SessionOptions, SessionSummary, session_footprint};
+use std::fs::File;
+use std::io::Write;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, LazyLock};
-use tokio::io::{self, AsyncWriteExt};
-use tokio::net::{TcpListener, TcpStream, UdpSocket};
-use uuid::Uuid;
+use std::time::Duration;
+use throttle::Throttle;
+use tokio::signal;
+use tokio_util::task::TaskTracker;
+
+/// 退出时等待现有会话收尾的最长时间
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(5);
+
+/// KCP 接收窗口的下限，更小的值会被 KCP 自动调大
+const MIN_KCP_WINDOW: u32 = 128;
 
 #[derive(Parser)]
+#[command(subcommand_negates_reqs = true, args_override_self = true)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// 从配置文件读取参数，命令行上的同名参数优先
+    #[arg(long, value_name = "FILE")]
+    config: Option<PathBuf>,
+
+    /// 使用配置文件里 [profile.<名字>] 下的设置，auto 按默认网关的 MAC 地址自动选择
+    #[arg(long, value_name = "NAME", requires = "config")]
+    profile: Option<String>,
+
     /// 运行服务端模式
     #[arg(short, long, default_value_t = false, group = "mode")]
     server: bool,
@@ -16,110 +107,968 @@ struct Args {
     client: bool,
 
     /// 服务端模式下的代理地址，客户端模式下的远程连接地址
-    #[arg(long)]
-    proxy_addr: String,
+    #[arg(long, required = true)]
+    proxy_addr: Option<String>,
 
     /// 服务端模式下的监听地址，客户端模式下的本地监听地址
     #[arg(long, default_value = "0.0.0.0:25565")]
     listen_addr: String,
+
+    /// 会话两个方向都没有数据时的超时秒数，0 表示不限制
+    #[arg(long, default_value_t = 0)]
+    idle_timeout: u64,
+
+    /// 等待 TCP 一端发来数据的超时秒数（TCP -> KCP 方向），0 表示不限制
+    #[arg(long, default_value_t = 0)]
+    tcp_read_timeout: u64,
+
+    /// 等待 KCP 一端发来数据的超时秒数（KCP -> TCP 方向），0 表示不限制
+    #[arg(long, default_value_t = 0)]
+    kcp_read_timeout: u64,
+
+    /// 会话的最长存活秒数，超过后会正常关闭会话，0 表示不限制
+    #[arg(long, default_value_t = 0)]
+    max_session_duration: u64,
+
+    /// 会话分类：auto 按转发的数据量判断，interactive 或 bulk 把所有会话固定为一类
+    #[arg(long, value_enum, default_value_t = class::Mode::Auto)]
+    session_class: class::Mode,
+
+    /// auto 分类时，某个方向转发超过这么多 MB 的会话按批量会话处理
+    #[arg(long, value_name = "MB", default_value_t = 4)]
+    bulk_threshold: u64,
+
+    /// 有交互会话在收发数据时，所有批量会话写入 KCP 的速率合计不超过这么多 KB/s，0 表示不限制
+    #[arg(long, value_name = "KB/s", default_value_t = 0)]
+    bulk_rate: u64,
+
+    /// 低延迟模式：KCP 时钟间隔缩短到 10 毫秒、更早快速重传，本地 TCP 连接关闭 Nagle 算法立即发出，用更多的 CPU 换更低的延迟
+    #[arg(long)]
+    low_latency: bool,
+
+    /// Linux：低延迟模式下 UDP 套接字收包时在网卡队列上忙等的微秒数，比如 50；超过 net.core.busy_read 时需要 CAP_NET_ADMIN
+    #[arg(long, value_name = "MICROSECONDS", requires = "low_latency")]
+    busy_poll: Option<u32>,
+
+    /// 省流量模式：KCP 攒满一段再发、不快速重传，控制通道的保活间隔拉长，适合按流量计费的线路
+    #[arg(long, conflicts_with_all = ["low_latency", "redundant_addr"])]
+    economy: bool,
+
+    /// 会话的 KCP 连续 15 秒几乎只在重传时关闭这个会话，让本地程序重连，而不是卡到 KCP 超时；不打开时只记日志
+    #[arg(long)]
+    storm_reset: bool,
+
+    /// 启动时绑定地址失败（地址尚未分配、端口暂时被占用）后持续重试的秒数，0 表示不重试
+    #[arg(long, default_value_t = 0)]
+    bind_retry: u64,
+
+    /// 绑定端口后切换到的用户（仅 Unix），用于以 root 绑定特权端口后降权运行
+    #[arg(long)]
+    user: Option<String>,
+
+    /// 绑定端口后切换到的用户组（仅 Unix），不填则使用 --user 的主组
+    #[arg(long)]
+    group: Option<String>,
+
+    /// 开启进程加固（仅 Linux）：用 Landlock 限制文件访问，初始化完成后用 seccomp 禁止危险的系统调用
+    #[arg(long, default_value_t = false)]
+    sandbox: bool,
+
+    /// 服务端：已关闭连接的 KCP conv 在多少秒内不会被分配给新连接，避免迟到的旧数据包串到新连接上
+    #[arg(long, default_value_t = 120)]
+    conv_quarantine: u64,
+
+    /// 服务端：MaxMind 的 IP 地址库（比如 GeoLite2-Country.mmdb），给会话标上客户端所在的国家或地区
+    #[arg(long, value_name = "FILE")]
+    geoip_db: Option<PathBuf>,
+
+    /// 服务端：只接受这些国家或地区的客户端，比如 CN,HK；局域网地址总是接受
+    #[arg(
+        long,
+        value_delimiter = ',',
+        value_name = "CODE",
+        value_parser = geoip::parse_code,
+        requires = "geoip_db",
+        conflicts_with = "deny_country"
+    )]
+    allow_country: Vec<String>,
+
+    /// 服务端：拒绝这些国家或地区的客户端，比如 US,RU
+    #[arg(
+        long,
+        value_delimiter = ',',
+        value_name = "CODE",
+        value_parser = geoip::parse_code,
+        requires = "geoip_db"
+    )]
+    deny_country: Vec<String>,
+
+    /// 使用不带握手的旧版协议，用于连接 1.0.x 版本的服务端；服务端能自动识别 1.0.x 的客户端，一般不需要
+    #[arg(long, default_value_t = false)]
+    legacy_protocol: bool,
+
+    /// 客户端：握手时告诉服务端的身份，比如主机名或者使用者的名字，显示在服务端的日志和会话列表里
+    #[arg(long, value_name = "NAME", value_parser = protocol::parse_identity, conflicts_with = "legacy_protocol")]
+    identity: Option<String>,
+
+    /// 把运行时工作线程绑定到指定的 CPU 核心上，比如 0,2-3；工作线程数等于核心数
+    #[arg(long)]
+    cpu_affinity: Option<String>,
+
+    /// 把每个会话里客户端发往后端的数据复制一份发到这个地址，镜像端的应答被丢弃，它出错或跟不上时只停止镜像
+    #[arg(long, value_name = "ADDR")]
+    mirror_addr: Option<String>,
+
+    /// 服务端：连续这么多次连不上后端后熔断，新会话不再去连、立即失败，后台探测到后端恢复后自动解除；0 表示不熔断
+    #[arg(long, value_name = "FAILURES", default_value_t = 0)]
+    circuit_breaker: u32,
+
+    /// 服务端：连不上后端（包括熔断期间）时回给客户端这个文件的内容再关闭连接，比如一个 HTTP 503 应答
+    #[arg(long, value_name = "FILE")]
+    fallback_response: Option<PathBuf>,
+
+    /// 服务端：允许客户端注册反向隧道的公网端口，比如 25565,30000-30100；不填则不允许反向隧道
+    #[arg(long, requires = "reverse_auth")]
+    reverse_ports: Option<reverse::Ports>,
+
+    /// 服务端：允许注册反向隧道的客户端和口令，比如 alice=口令1,bob=口令2，客户端用 --identity 和 --reverse-secret 对应；口令明文传输，两端之间需要加密的底层线路
+    #[arg(long, value_name = "NAME=SECRET", value_delimiter = ',', value_parser = reverse::parse_credential)]
+    reverse_auth: Vec<(String, String)>,
+
+    /// 服务端：反向隧道公网端口的监听地址
+    #[arg(long, default_value = "0.0.0.0")]
+    reverse_bind: String,
+
+    /// 客户端：反向模式，把本地的这个服务通过服务端的公网端口暴露出去，比如 127.0.0.1:25565
+    #[arg(long, value_name = "LOCAL_ADDR")]
+    reverse: Option<String>,
+
+    /// 客户端：反向隧道的名字，同一个服务端上不能重复
+    #[arg(long, default_value = "default")]
+    reverse_name: String,
+
+    /// 客户端：反向隧道请求服务端开放的公网端口，0 表示由服务端从允许的端口里分配
+    #[arg(long, default_value_t = 0, requires = "reverse")]
+    remote_port: u16,
+
+    /// 客户端：反向隧道只接收访问这个域名的访客（按 TLS SNI、HTTP Host 或 Minecraft 握手识别），多个隧道可以共用同一个公网端口
+    #[arg(long, value_name = "HOSTNAME", requires = "reverse")]
+    reverse_host: Option<String>,
+
+    /// 客户端：注册反向隧道用的口令，和 --identity 一起对应服务端 --reverse-auth 里的一项；口令明文传输，两端之间需要加密的底层线路
+    #[arg(long, value_name = "SECRET", value_parser = reverse::parse_secret, requires_all = ["reverse", "identity"])]
+    reverse_secret: Option<String>,
+
+    /// 客户端：反向隧道注册成功后，把公网地址以 JSON 通过 HTTP POST 发到这个地址，比如 http://127.0.0.1:9000/tunnel
+    #[arg(long, value_name = "URL", requires = "reverse")]
+    reverse_webhook: Option<String>,
+
+    /// 解析服务端、后端等地址里的域名时使用的 DNS 服务器，比如 1.1.1.1,8.8.8.8；不指定时使用系统的解析器
+    #[arg(long, value_delimiter = ',', value_parser = dns::parse_server)]
+    dns: Vec<SocketAddr>,
+
+    /// KCP 使用的 UDP 套接字的收发缓冲区：auto 按 KCP 窗口计算，或者以 KB 为单位的大小；不指定时使用系统默认值
+    #[arg(long, value_name = "KB|auto")]
+    udp_buffer: Option<udp::BufferSize>,
+
+    /// 解析域名时优先使用的地址族，没有这一族的地址时才用另一族；不指定时按系统解析器给出的顺序
+    #[arg(long, value_name = "FAMILY")]
+    prefer: Option<dns::Family>,
+
+    /// 客户端：连接服务端的 UDP 套接字绑定的本机地址，决定从哪个地址、哪个地址族出去
+    #[arg(long, value_name = "IP", conflicts_with = "local_addrs")]
+    source_addr: Option<IpAddr>,
+
+    /// Linux：给 KCP 使用的 UDP 套接字发出的包打上防火墙标记，配合 ip rule 做策略路由，比如 0x10；需要 CAP_NET_ADMIN
+    #[arg(long, value_name = "MARK", value_parser = udp::parse_mark)]
+    fwmark: Option<u32>,
+
+    /// 控制消息等可变长度帧的长度上限（字节），对端发来更长的帧时按协议错误断开，两端最好设置一致
+    #[arg(long, value_name = "BYTES", default_value_t = codec::DEFAULT_MAX_FRAME, value_parser = clap::value_parser!(u16).range(1024..))]
+    max_frame_size: u16,
+
+    /// 管理接口的监听地址，比如 127.0.0.1:7070，不填则不开启
+    #[arg(long)]
+    admin_addr: Option<String>,
+
+    /// 网页面板的监听地址，比如 127.0.0.1:8080，不指定时不开启
+    #[arg(long)]
+    dashboard_addr: Option<String>,
+
+    /// 健康检查接口的监听地址，比如 127.0.0.1:8081，提供 /healthz 和 /readyz，不指定时不开启
+    #[arg(long)]
+    health_addr: Option<String>,
+
+    /// 缓冲数据的总内存上限（MB），用完后拒绝新会话，0 表示不限制
+    #[arg(long, default_value_t = 0)]
+    memory_limit: usize,
+
+    /// KCP 发出的 UDP 包的大小上限（字节），默认 1380；路径 MTU 小或者在 DNS 这类小包协议的端口上运行时调小
+    #[arg(long, value_name = "BYTES", value_parser = clap::value_parser!(u32).range(push::MIN_MTU as i64..=push::MAX_MTU as i64))]
+    mtu: Option<u32>,
+
+    /// 把每个 UDP 包伪装成别的协议，两端必须一致：dtls 加上 DTLS 1.2 的记录头（13 字节，算在 --mtu 以内）
+    #[arg(long, value_enum, default_value_t = disguise::Disguise::None)]
+    disguise: disguise::Disguise,
+
+    /// 单个会话的缓冲内存上限（KB），通过缩小 KCP 收发窗口实现，0 表示使用默认窗口
+    #[arg(long, default_value_t = 0)]
+    session_memory_limit: usize,
+
+    /// 每秒最多接受多少个新会话，超过时推迟接受而不是拒绝，0 表示不限制
+    #[arg(long, default_value_t = 0)]
+    session_rate: u32,
+
+    /// 空闲后可以连续接受的新会话数，默认等于 --session-rate
+    #[arg(long, requires = "session_rate")]
+    session_burst: Option<u32>,
+
+    /// 同时处于建立阶段（握手、连接后端或服务端）的会话数上限，0 表示不限制
+    #[arg(long, default_value_t = 0)]
+    max_pending: usize,
+
+    /// 建立中的会话已满时：reject 拒绝新连接，drop-oldest 放弃等待最久的那个
+    #[arg(long, value_enum, default_value_t = pending::Overflow::Reject)]
+    pending_overflow: pending::Overflow,
+
+    /// 客户端：服务端的另一个地址（比如另一条线路上的 IP），每个 UDP 包同时发往它和 --proxy-addr，用带宽换更低的丢包率
+    #[arg(long, conflicts_with = "simulate")]
+    redundant_addr: Option<String>,
+
+    /// 客户端：本机各条线路的地址，比如 192.168.1.2,10.0.0.2，新会话按各线路的 RTT 和丢包分散到这些地址上发出
+    #[arg(
+        long,
+        value_delimiter = ',',
+        conflicts_with_all = ["simulate", "redundant_addr", "reverse"]
+    )]
+    local_addrs: Vec<IpAddr>,
+
+    /// 客户端：每隔这么多秒 ping 一次服务端，记录线路本身的 RTT 和丢包，0 表示不探测
+    #[arg(long, default_value_t = 0)]
+    probe_interval: u64,
+
+    /// 客户端：会话空闲这么多秒后给服务端发保活包，防止路由器上的 UDP 映射过期，0 表示不发送
+    #[arg(long, default_value_t = 0)]
+    keepalive_interval: u64,
+
+    /// 客户端：连续这么多秒没有会话时暂停控制通道，有新连接时再建立，用于省电，0 表示不暂停
+    #[arg(long, default_value_t = 0)]
+    suspend_after: u64,
+
+    /// 多线路：RTT 超过这么多毫秒的线路暂停使用，之后定期试探，0 表示不限制
+    #[arg(long, default_value_t = 0)]
+    path_max_rtt: u64,
+
+    /// 多线路：建立连接的失败率超过这个百分比的线路暂停使用，之后定期试探
+    #[arg(long, default_value_t = 30.0)]
+    path_max_loss: f64,
+
+    /// 服务端：推送给客户端的 KCP 参数，比如 interval=20,resend=2,nodelay=on,nc=on,window=512
+    #[arg(long, value_name = "PARAMS")]
+    push_kcp: Option<push::KcpTuning>,
+
+    /// 服务端：推送给客户端的新会话速率限制（每秒），客户端自己指定了 --session-rate 时不生效
+    #[arg(long, value_name = "RATE")]
+    push_session_rate: Option<u32>,
+
+    /// 服务端：推送给客户端的备用服务端地址，客户端连不上 --proxy-addr 时依次尝试
+    #[arg(long, value_delimiter = ',', value_name = "ADDR")]
+    push_servers: Vec<String>,
+
+    /// 服务端：推送给客户端的本服务端其它地址（必须到达同一个监听套接字，比如换 IP 期间的新 IP），当前地址没有回应时会话中途切换过去
+    #[arg(long, value_delimiter = ',', value_name = "ADDR")]
+    push_roam_addrs: Vec<String>,
+
+    /// 调试用：在客户端模拟糟糕的网络，比如 loss=2%,delay=50ms,jitter=10ms，两个方向都会生效
+    #[arg(long)]
+    simulate: Option<simulate::Conditions>,
+
+    /// 子命令以 JSON 格式输出结果，方便脚本和监控程序读取
+    #[arg(long, global = true, default_value_t = false)]
+    json: bool,
+
+    /// 只打印启动、退出和警告错误，不打印每个会话的建立和关闭
+    #[arg(short, long, global = true, conflicts_with = "verbose")]
+    quiet: bool,
+
+    /// 额外打印每次转发的数据块等细节
+    #[arg(short, long, global = true)]
+    verbose: bool,
+
+    /// 同一类连接错误（比如后端无法连接）在这么多秒内只打印第一次，之后汇总打印次数，0 表示不合并
+    #[arg(long, default_value_t = 60)]
+    log_suppress_window: u64,
+
+    /// 退出时把运行汇总（运行时间、会话数、流量、各类关闭原因的次数）以 JSON 格式写入这个文件
+    #[arg(long)]
+    summary_file: Option<PathBuf>,
+
+    /// 调试用：以十六进制打印指定会话（或 all 表示所有会话）经过的数据，运行中可以通过管理接口开关
+    #[arg(long, value_name = "ID|all")]
+    trace_session: Option<String>,
+
+    /// 调试用：把转发的数据流以合成 TCP 连接的形式写入 pcap 文件，可以用 Wireshark 打开
+    #[arg(long)]
+    debug_pcap: Option<PathBuf>,
+
+    /// 调试用：把每个会话两个方向的数据连同时间记录到这个目录，一个会话一个文件，可以用 replay 子命令回放
+    #[arg(long, value_name = "DIR")]
+    record: Option<PathBuf>,
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let args = Args::parse();
+#[derive(Subcommand)]
+enum Command {
+    /// 通过 --admin-addr 指定的管理接口查询正在运行的实例的状态
+    Status,
 
-    if !args.client && !args.server {
-        eprintln!("Error: You should specify one mode")
-    } else if args.server {
-        println!("Run in server mode...");
-        run_server(&args).await?;
-    } else {
-        println!("Run in client mode...");
-        run_client(&args).await?;
-    }
+    /// 通过 --admin-addr 指定的管理接口让正在运行的实例排空，等会话全部结束后返回，可以用作 preStop 钩子
+    Drain {
+        /// 最多等这么多秒，之后实例关闭剩余的会话，不指定时一直等下去
+        #[arg(long)]
+        deadline: Option<u64>,
+    },
 
-    Ok(())
+    /// 在本机回环地址上启动一对服务端和客户端，推送数据校验完整性并测试吞吐
+    #[command(alias = "bench")]
+    Selftest {
+        /// 推送的数据量（MB）
+        #[arg(long, default_value_t = 16)]
+        size: usize,
+        /// 用几组 KCP 参数各跑一遍，按吞吐推荐一组，可以配合 --simulate 模拟实际线路
+        #[arg(long, default_value_t = false)]
+        sweep: bool,
+        /// 吞吐低于这么多 MB/s 时以非零退出码结束，用于发现构建或平台上的性能退化
+        #[arg(long, value_name = "MB/s", conflicts_with = "sweep")]
+        assert_min_throughput: Option<f64>,
+    },
+
+    /// 按原来的节奏把 --record 记录的会话里客户端发出的数据发给一个后端，并和记录里的应答比较
+    Replay {
+        /// 记录文件
+        file: PathBuf,
+        /// 后端地址，比如 127.0.0.1:25565
+        target: String,
+    },
+
+    /// 配置相关的操作
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommand,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum ConfigCommand {
+    /// 按配置文件的格式打印合并了默认值、配置文件和命令行之后实际生效的配置，不显示密钥
+    Dump,
 }
 
-async fn run_server(args: &Args) -> anyhow::Result<()> {
-    let udp_socket = UdpSocket::bind(&args.listen_addr).await?;
-    println!("Server UDP bound to {:?}", udp_socket.local_addr()?);
-    let mut kcp_listener = KcpUdpStream::socket_listen(KCP_CONFIG.clone(), udp_socket, 5, None)?;
+impl Args {
+    /// 只有使用子命令时才可以不填 --proxy-addr，转发模式下 clap 已经保证它存在
+    fn proxy_addr(&self) -> &str {
+        self.proxy_addr
+            .as_deref()
+            .expect("--proxy-addr is required outside subcommands")
+    }
 
-    println!(
-        "Begin forward task: tcp://{} <-> kcp://{}",
-        &args.proxy_addr, &args.listen_addr
-    );
+    /// 只在一端生效的参数出现在另一端时报错，免得用户以为设置生效了
+    fn check_mode(&self) -> anyhow::Result<()> {
+        let client_only = [
+            ("--simulate", self.simulate.is_some()),
+            ("--redundant-addr", self.redundant_addr.is_some()),
+            ("--local-addrs", !self.local_addrs.is_empty()),
+            ("--probe-interval", self.probe_interval > 0),
+            ("--source-addr", self.source_addr.is_some()),
+            ("--keepalive-interval", self.keepalive_interval > 0),
+            ("--suspend-after", self.suspend_after > 0),
+            ("--identity", self.identity.is_some()),
+        ];
+        let server_only = [
+            ("--reverse-auth", !self.reverse_auth.is_empty()),
+            ("--geoip-db", self.geoip_db.is_some()),
+            ("--push-kcp", self.push_kcp.is_some()),
+            ("--push-session-rate", self.push_session_rate.is_some()),
+            ("--push-servers", !self.push_servers.is_empty()),
+            ("--push-roam-addrs", !self.push_roam_addrs.is_empty()),
+        ];
+        let set = |&&(_, set): &&(&str, bool)| set;
+        if self.server
+            && let Some((name, _)) = client_only.iter().find(set)
+        {
+            anyhow::bail!(tr!(
+                "{name} only works in client mode, use it on the client side",
+                "{name} 只能在客户端模式下使用，请在客户端设置"
+            ));
+        }
+        if !self.server
+            && let Some((name, _)) = server_only.iter().find(set)
+        {
+            anyhow::bail!(tr!(
+                "{name} only works in server mode, use it on the server side",
+                "{name} 只能在服务端模式下使用，请在服务端设置"
+            ));
+        }
+        Ok(())
+    }
+
+    /// 所有端口绑定完成后调用：降权并收紧系统调用
+    fn harden(&self) -> anyhow::Result<()> {
+        privilege::drop_privileges(self.user.as_deref(), self.group.as_deref())?;
+        if self.sandbox {
+            sandbox::restrict_syscalls()?;
+        }
+        Ok(())
+    }
+
+    /// 按单会话内存上限调整 KCP 收发窗口
+    fn kcp_config(&self) -> Arc<KcpConfig> {
+        self.tuned_kcp_config(None)
+    }
+
+    /// 先按服务端推送的参数和会话分类调整，再按单会话内存上限调整 KCP 收发窗口
+    fn tuned_kcp_config(&self, tuning: Option<&KcpTuning>) -> Arc<KcpConfig> {
+        let mut base = match tuning {
+            Some(tuning) => tuning.apply(&KCP_CONFIG),
+            None => (**KCP_CONFIG).clone(),
+        };
+        // 本地指定的包大小优先于推送的
+        if let Some(mtu) = self.mtu {
+            base.mtu = mtu;
+        }
+        // --mtu 和推送的 mtu 都指线路上的 UDP 包，伪装的包头也要算在里面
+        base.mtu -= disguise::overhead() as u32;
+        // 低延迟模式同样优先于推送的参数
+        if self.low_latency {
+            base.nodelay = KcpNoDelayConfig::fastest();
+        }
+        if self.economy {
+            base.nodelay = economy::nodelay();
+        }
+        let base = Arc::new(base);
+        // 固定为交互会话时用小窗口，在途的数据少，排队延迟也小
+        let base = match self.session_class {
+            class::Mode::Interactive => Arc::new(KcpConfig {
+                snd_wnd: base.snd_wnd.min(INTERACTIVE_WINDOW),
+                rcv_wnd: base.rcv_wnd.min(INTERACTIVE_WINDOW),
+                ..(*base).clone()
+            }),
+            _ => base,
+        };
+        if self.session_memory_limit == 0 {
+            return base;
+        }
+        let limit = self.session_memory_limit * 1024;
+        let window = (limit / (2 * base.mtu as usize)) as u32;
+        let window = window.clamp(MIN_KCP_WINDOW, base.snd_wnd.max(MIN_KCP_WINDOW));
+        Arc::new(KcpConfig {
+            snd_wnd: window,
+            rcv_wnd: window,
+            ..(*base).clone()
+        })
+    }
+
+    /// 服务端：通过控制通道推送给客户端的设置
+    fn push_settings(&self) -> push::Settings {
+        push::Settings {
+            kcp: self.push_kcp.clone(),
+            session_rate: self.push_session_rate,
+            servers: self.push_servers.clone(),
+            roam: self.push_roam_addrs.clone(),
+        }
+    }
+
+    fn throttle(&self) -> Option<Throttle> {
+        (self.session_rate > 0).then(|| {
+            Throttle::new(
+                self.session_rate,
+                self.session_burst.unwrap_or(self.session_rate),
+            )
+        })
+    }
+
+    fn pending(&self) -> Option<Arc<Pending>> {
+        (self.max_pending > 0).then(|| Pending::new(self.max_pending, self.pending_overflow))
+    }
+
+    fn session_options(&self) -> SessionOptions {
+        SessionOptions {
+            idle_timeout: seconds(self.idle_timeout),
+            tcp_read_timeout: seconds(self.tcp_read_timeout),
+            kcp_read_timeout: seconds(self.kcp_read_timeout),
+            max_duration: seconds(self.max_session_duration),
+            class: self.session_class,
+            bulk_threshold: self.bulk_threshold * 1024 * 1024,
+            low_latency: self.low_latency,
+        }
+    }
+}
 
-    loop {
-        println!("Waiting for new client connection...");
-        let (income_stream, income_addr) = kcp_listener.accept().await?;
-        let session_id = Uuid::new_v4().to_string();
-        println!("New connection from client {income_addr}, with session id {session_id}",);
-        let proxy_addr = args.proxy_addr.clone();
-        tokio::spawn(async move {
-            if let Ok(tcp_stream) = TcpStream::connect(&proxy_addr).await {
-                let session_result =
-                    handle_session(tcp_stream, income_stream, session_id.clone()).await;
-                handle_session_result(session_id, session_result);
-            } else {
-                eprintln!(
-                    "Session {session_id}: Failed to connection to tcp endpoint({proxy_addr})"
+/// 隧道运行中要用到的文件，在 `--sandbox` 限制文件访问之前打开
+#[derive(Default)]
+struct Files {
+    capture: Option<Arc<Capture>>,
+    /// `--summary-file`，退出时写入
+    summary: Option<(PathBuf, File)>,
+    /// `--fallback-response` 的内容
+    fallback: Option<Arc<[u8]>>,
+}
+
+impl Files {
+    fn open(args: &Args) -> anyhow::Result<Self> {
+        let capture = match &args.debug_pcap {
+            Some(path) => {
+                let capture = Capture::create(path)
+                    .with_context(|| format!("failed to create {}", path.display()))?;
+                notice!(
+                    "Capturing relayed streams to {}",
+                    "转发的数据流将写入 {}",
+                    path.display()
                 );
-            };
-        });
+                Some(Arc::new(capture))
+            }
+            None => None,
+        };
+        if let Some(dir) = &args.record {
+            record::init(dir).with_context(|| format!("failed to create {}", dir.display()))?;
+            notice!(
+                "Recording sessions to {}",
+                "会话数据将记录到 {}",
+                dir.display()
+            );
+        }
+        // 先不清空，上次运行的汇总保留到这次退出时才被替换，启动后没能正常退出时也不会丢
+        let summary = match &args.summary_file {
+            Some(path) => Some((
+                path.clone(),
+                File::options()
+                    .create(true)
+                    .write(true)
+                    .truncate(false)
+                    .open(path)
+                    .with_context(|| format!("failed to create {}", path.display()))?,
+            )),
+            None => None,
+        };
+        let fallback = match &args.fallback_response {
+            Some(path) => Some(
+                std::fs::read(path)
+                    .with_context(|| format!("failed to read {}", path.display()))?
+                    .into(),
+            ),
+            None => None,
+        };
+        Ok(Self {
+            capture,
+            summary,
+            fallback,
+        })
     }
 }
 
-async fn run_client(args: &Args) -> anyhow::Result<()> {
-    let tcp_listener = TcpListener::bind(&args.listen_addr).await?;
-    println!("Client TCP listening on {:?}", tcp_listener.local_addr()?);
-    loop {
-        println!("Waiting for new connection...");
-        let session_id = Uuid::new_v4().to_string();
-        let (tcp_stream, _) = tcp_listener.accept().await?;
-        println!(
-            "New connection from {:?}, with session id {session_id}",
-            tcp_stream.peer_addr()?
+/// 把秒数转换为超时设置，0 表示不限制
+fn seconds(secs: u64) -> Option<Duration> {
+    (secs > 0).then(|| Duration::from_secs(secs))
+}
+
+fn main() -> anyhow::Result<()> {
+    let raw_args = config::expand(std::env::args().collect(), &Args::command())?;
+    i18n::init(&raw_args);
+    let command = i18n::localize(Args::command());
+    let mut matches = command.clone().get_matches_from(raw_args);
+    config::record_effective(&command, &matches);
+    let args = Args::from_arg_matches_mut(&mut matches).unwrap_or_else(|e| e.exit());
+    console::init(args.quiet, args.verbose);
+    isolate::install_hook();
+
+    if !args.client && !args.server {
+        error!("You should specify one mode", "需要指定一种运行模式");
+        return Ok(());
+    }
+
+    // 地址库在 Landlock 限制文件访问之前读入
+    if args.server
+        && let Some(path) = &args.geoip_db
+    {
+        geoip::load(path)?;
+    }
+    // 子命令用不到这些文件，而且只是一次性的查询和测试（`replay` 还要读记录文件），不加沙箱
+    let files = match args.command {
+        None => Files::open(&args)?,
+        Some(_) => Files::default(),
+    };
+    // Landlock 只对之后创建的线程生效，必须在创建运行时之前调用
+    if args.sandbox && args.command.is_none() {
+        let writable: Vec<&Path> = args.record.as_deref().into_iter().collect();
+        sandbox::restrict_filesystem(&writable)?;
+    }
+
+    let mut runtime = tokio::runtime::Builder::new_multi_thread();
+    runtime.enable_all();
+    if let Some(list) = &args.cpu_affinity {
+        let cpus = affinity::parse_cpu_list(list)?;
+        notice!(
+            "Pinning worker threads to CPUs {cpus:?}",
+            "把工作线程绑定到 CPU {cpus:?}"
         );
-        let remote_addr = args.proxy_addr.clone();
-        tokio::spawn(async move {
-            if let Ok(kcp_stream) = KcpUdpStream::connect(KCP_CONFIG.clone(), &remote_addr).await {
-                let session_result =
-                    handle_session(tcp_stream, kcp_stream.0, session_id.clone()).await;
-                handle_session_result(session_id, session_result);
-            } else {
-                eprintln!("Session {session_id}: Failed to connect to kcp endpoint({remote_addr})");
-            };
+        // 工作线程最先创建，依次占用列表中的核心；之后创建的阻塞线程按顺序轮流绑定
+        let next = AtomicUsize::new(0);
+        runtime.worker_threads(cpus.len()).on_thread_start(move || {
+            let cpu = cpus[next.fetch_add(1, Ordering::Relaxed) % cpus.len()];
+            if let Err(e) = affinity::pin_current_thread(cpu) {
+                warn!(
+                    "Failed to pin thread to CPU {cpu}: {e}",
+                    "无法把线程绑定到 CPU {cpu}：{e}"
+                );
+            }
         });
     }
+    runtime.build()?.block_on(run(args, files))
 }
 
-async fn handle_session(
-    mut tcp_stream: TcpStream,
-    mut kcp_stream: KcpStream,
-    session_id: String,
-) -> anyhow::Result<()> {
-    let (writed, readed) = io::copy_bidirectional(&mut tcp_stream, &mut kcp_stream).await?;
+async fn run(args: Args, files: Files) -> anyhow::Result<()> {
+    args.check_mode()?;
+    // 客户端每个会话都新建套接字，降权之后就没有权限再设置标记了
+    if !args.server && args.fwmark.is_some() && (args.user.is_some() || args.group.is_some()) {
+        anyhow::bail!(tr!(
+            "--fwmark cannot be combined with --user or --group in client mode",
+            "客户端模式下 --fwmark 不能和 --user 或 --group 同时使用"
+        ));
+    }
+    if args.legacy_protocol && !args.push_settings().is_empty() {
+        anyhow::bail!(tr!(
+            "pushed settings travel over the control channel, they cannot be used with --legacy-protocol",
+            "下发的设置通过控制通道传递，不能和 --legacy-protocol 同时使用"
+        ));
+    }
+    if args.legacy_protocol && (args.reverse_ports.is_some() || args.reverse.is_some()) {
+        anyhow::bail!(tr!(
+            "reverse tunnels need the handshake, they cannot be used with --legacy-protocol",
+            "反向隧道依赖握手，不能和 --legacy-protocol 同时使用"
+        ));
+    }
+    let prefer = match (args.prefer, args.source_addr.map(dns::Family::of)) {
+        (Some(prefer), Some(source)) if prefer != source => {
+            anyhow::bail!(tr!(
+                "--prefer and --source-addr ask for different address families",
+                "--prefer 和 --source-addr 指定的地址族不一致"
+            ));
+        }
+        (prefer, source) => prefer.or(source),
+    };
+    dns::init(args.dns.clone(), prefer);
+    codec::init(args.max_frame_size);
+    mirror::init(args.mirror_addr.clone());
+    keepalive::init(seconds(args.keepalive_interval));
+    control::init(args.economy);
+    disguise::init(args.disguise);
+    if let Some(identity) = &args.identity {
+        protocol::set_identity(identity.clone());
+    }
+    if let Some(url) = &args.reverse_webhook {
+        webhook::validate(url)?;
+    }
+    match args.command {
+        Some(Command::Status) => {
+            let admin_addr = args
+                .admin_addr
+                .as_deref()
+                .context("status needs --admin-addr of the running instance")?;
+            return admin::status(admin_addr, args.json).await;
+        }
+        Some(Command::Drain { deadline }) => {
+            let admin_addr = args
+                .admin_addr
+                .as_deref()
+                .context("drain needs --admin-addr of the running instance")?;
+            return admin::drain(admin_addr, deadline).await;
+        }
+        Some(Command::Selftest {
+            size,
+            sweep: false,
+            assert_min_throughput,
+        }) => {
+            return selftest::run(
+                size,
+                args.kcp_config(),
+                args.simulate,
+                assert_min_throughput,
+                args.json,
+            )
+            .await;
+        }
+        Some(Command::Selftest {
+            size, sweep: true, ..
+        }) => {
+            return selftest::sweep(size, args.kcp_config(), args.simulate, args.json).await;
+        }
+        Some(Command::Replay { file, target }) => {
+            return record::replay(&file, &target, args.json).await;
+        }
+        Some(Command::Config {
+            command: ConfigCommand::Dump,
+        }) => {
+            print!("{}", config::effective().unwrap_or_default());
+            return Ok(());
+        }
+        None => {}
+    }
+    udp::init(args.udp_buffer, args.fwmark).context("failed to set --fwmark")?;
+    if let Some(ip) = args.source_addr {
+        udp::set_source(ip).with_context(|| format!("cannot use --source-addr {ip}"))?;
+    }
+    if let Some(micros) = args.busy_poll {
+        udp::set_busy_poll(micros).context("failed to set --busy-poll")?;
+    }
+    if let Some(window) = seconds(args.log_suppress_window) {
+        console::suppress_repeats(window);
+    }
+    if let Some(conditions) = &args.simulate {
+        notice!(
+            "Simulating network conditions: {conditions}",
+            "模拟网络条件：{conditions}"
+        );
+    }
 
-    if let Err(e) = tcp_stream.shutdown().await {
-        eprintln!("Session {session_id}: TCP shutdown error (ignored): {e}");
+    let registry = Arc::new(Registry::default());
+    registry.set_shaper(Arc::new(Shaper::new(args.bulk_rate)));
+    match args.trace_session.as_deref() {
+        Some("all") => registry.trace_all(true),
+        Some(id) => registry.trace_later(id),
+        None => {}
+    }
+    if let Some(interval) = seconds(args.probe_interval) {
+        // 在降权和系统调用过滤之前打开 ICMP 套接字
+        let target = dns::lookup(args.proxy_addr()).await?.ip();
+        match Probe::start(target, interval) {
+            Ok(probe) => registry.set_probe(probe),
+            Err(e) => warn!(
+                "Cannot ping {target}, path probing disabled: {e}",
+                "无法 ping {target}，不进行线路探测：{e}"
+            ),
+        }
+    }
+    let budget = Arc::new(Budget::new(
+        (args.memory_limit > 0).then(|| args.memory_limit * 1024 * 1024),
+    ));
+    let footprint = session_footprint(&args.kcp_config());
+    match budget.limit() {
+        Some(limit) => notice!(
+            "Memory budget {} MB, about {} KB per session, room for {} sessions",
+            "内存预算 {} MB，每个会话约 {} KB，最多容纳 {} 个会话",
+            args.memory_limit,
+            footprint / 1024,
+            limit / footprint
+        ),
+        None => notice!(
+            "About {} KB of buffers per session",
+            "每个会话约占用 {} KB 缓冲区",
+            footprint / 1024
+        ),
+    }
+    if let Some(admin_addr) = &args.admin_addr {
+        let listener = admin::bind(admin_addr, seconds(args.bind_retry)).await?;
+        tokio::spawn(admin::serve(listener, registry.clone(), budget.clone()));
     }
-    if let Err(e) = kcp_stream.shutdown().await {
-        eprintln!("Session {session_id}: KCP shutdown error (ignored): {e}");
+    if let Some(dashboard_addr) = &args.dashboard_addr {
+        let listener = dashboard::bind(dashboard_addr, seconds(args.bind_retry)).await?;
+        tokio::spawn(dashboard::serve(listener, registry.clone(), budget.clone()));
     }
+    if let Some(health_addr) = &args.health_addr {
+        let listener = health::bind(health_addr, seconds(args.bind_retry)).await?;
+        tokio::spawn(health::serve(listener, registry.clone(), args.server));
+    }
+    tokio::spawn(storm::watch(registry.clone(), args.storm_reset));
+
+    let capture = files.capture;
+
+    // --profile auto 时网络变了要重新启动，--sandbox 禁止执行程序，只在启动时选择一次
+    let auto = config::auto();
+    if let Some(auto) = auto {
+        network::report(auto);
+        if args.sandbox {
+            info!(
+                "--sandbox forbids restarting, profile is only selected at startup",
+                "--sandbox 禁止重新启动，只在启动时选择一次配置"
+            );
+        }
+    }
+    let network_changed = async {
+        match auto {
+            Some(auto) if !args.sandbox => network::watch(auto).await,
+            _ => std::future::pending().await,
+        }
+    };
 
-    println!("Session {session_id} closed, wtited {writed} bytes, readed {readed} bytes");
+    let tracker = TaskTracker::new();
+    let run = async {
+        if args.server {
+            notice!("Run in server mode...", "以服务端模式运行...");
+            server::run(
+                &args,
+                &registry,
+                &budget,
+                &capture,
+                &files.fallback,
+                &tracker,
+            )
+            .await
+        } else {
+            notice!("Run in client mode...", "以客户端模式运行...");
+            client::run(&args, &registry, &budget, &capture, &tracker).await
+        }
+    };
+
+    let mut restart = false;
+    tokio::select! {
+        result = run => result?,
+        _ = signal::ctrl_c() => notice!("Received Ctrl-C, shutting down...", "收到 Ctrl-C，正在退出..."),
+        _ = terminate() => notice!("Received SIGTERM, shutting down...", "收到 SIGTERM，正在退出..."),
+        _ = network_changed => restart = true,
+    }
+
+    // 控制通道看到排空后通知对端再结束
+    registry.start_drain();
+    registry.stop_all(CloseReason::Shutdown);
+    tracker.close();
+    if tokio::time::timeout(SHUTDOWN_GRACE, tracker.wait())
+        .await
+        .is_err()
+    {
+        warn!(
+            "Some sessions did not finish in time, exiting anyway",
+            "部分会话没能及时结束，直接退出"
+        );
+    }
+    console::flush_repeats();
+    report_shutdown(&registry, &budget, files.summary);
 
+    if restart {
+        network::restart()?;
+    }
     Ok(())
 }
 
-fn handle_session_result(session_id: String, result: anyhow::Result<()>) {
-    match result {
-        Err(e) => {
-            eprintln!("Session {session_id}: occurred an error, {e}")
+/// 等待 SIGTERM（`kill`、systemd 和 Kubernetes 停止进程时发送），其它平台上一直等下去
+#[cfg(unix)]
+async fn terminate() {
+    match signal::unix::signal(signal::unix::SignalKind::terminate()) {
+        Ok(mut terminate) => {
+            terminate.recv().await;
+        }
+        Err(_) => std::future::pending().await,
+    }
+}
+
+#[cfg(not(unix))]
+async fn terminate() {
+    std::future::pending().await
+}
+
+/// 排空状态下监听已关闭，等待现有会话全部结束
+async fn drain(registry: &Registry) {
+    notice!(
+        "Draining: stopped accepting new sessions, waiting for {} sessions to finish...",
+        "排空中：已停止接受新会话，等待 {} 个会话结束...",
+        registry.len()
+    );
+    registry.wait_empty().await;
+    notice!("All sessions finished, exiting", "所有会话已结束，正在退出");
+}
+
+/// 在 `tracker` 上运行一个会话，其中的 panic 只结束这个会话，打印出来并计入统计
+fn spawn_session(
+    tracker: &TaskTracker,
+    registry: Arc<Registry>,
+    session_id: String,
+    session: impl Future<Output = ()> + Send + 'static,
+) {
+    tracker.spawn(async move {
+        if let Err(panic) = isolate::isolate(session).await {
+            registry.record_panic();
+            error!(
+                "Session {session_id} panicked: {panic}\n{}",
+                "会话 {session_id} 发生 panic：{panic}\n{}", panic.backtrace
+            );
+        }
+    });
+}
+
+fn report_session(registration: &Registration, summary: SessionSummary) {
+    let session_id = registration.id();
+    registration.closed(summary.reason);
+    let SessionSummary {
+        reason,
+        sent,
+        received,
+        error,
+    } = summary;
+    match error {
+        Some(e) => warn!(
+            "Session {session_id} closed ({reason}): {e}, sent {sent} bytes, received {received} bytes",
+            "会话 {session_id} 已关闭（{reason}）：{e}，发送 {sent} 字节，接收 {received} 字节"
+        ),
+        None => info!(
+            "Session {session_id} closed ({reason}), sent {sent} bytes, received {received} bytes",
+            "会话 {session_id} 已关闭（{reason}），发送 {sent} 字节，接收 {received} 字节"
+        ),
+    }
+}
+
+/// 退出时打印运行汇总，指定了文件时同时写入 JSON
+fn report_shutdown(registry: &Registry, budget: &Budget, summary_file: Option<(PathBuf, File)>) {
+    let traffic = registry.traffic();
+    notice!(
+        "Ran for {}s: {} sessions (peak {} at once), sent {} bytes, received {} bytes",
+        "共运行 {} 秒：{} 个会话（最多同时 {} 个），发送 {} 字节，接收 {} 字节",
+        registry.uptime().as_secs(),
+        registry.total(),
+        registry.peak(),
+        traffic.sent(),
+        traffic.received()
+    );
+    let total = wire::total();
+    if let Some(overhead) = wire::overhead(
+        total.sent() + total.received(),
+        traffic.sent() + traffic.received(),
+    ) {
+        notice!(
+            "UDP traffic: sent {} bytes, received {} bytes, protocol overhead {:.1}%",
+            "UDP 流量：发送 {} 字节，接收 {} 字节，协议开销 {:.1}%",
+            total.sent(),
+            total.received(),
+            overhead * 100.0
+        );
+    }
+    let closes = registry.closes();
+    if !closes.is_empty() {
+        let closes = closes
+            .iter()
+            .map(|(reason, n)| format!("{reason} {n}"))
+            .collect::<Vec<_>>()
+            .join(", ");
+        notice!(
+            "Sessions closed by reason: {closes}",
+            "会话关闭原因：{closes}"
+        );
+    }
+    if registry.panics() > 0 || budget.rejected() > 0 {
+        notice!(
+            "{} sessions panicked, {} rejected for lack of memory",
+            "{} 个会话 panic，{} 个会话因内存不足被拒绝",
+            registry.panics(),
+            budget.rejected()
+        );
+    }
+    if let Some((path, mut file)) = summary_file {
+        let summary = admin::summary_json(registry, budget).to_string();
+        let written = file.set_len(0).and_then(|()| writeln!(file, "{summary}"));
+        if let Err(e) = written {
+            error!(
+                "Failed to write summary to {}: {e}",
+                "无法把运行汇总写入 {}：{e}",
+                path.display()
+            );
         }
-        Ok(()) => println!("Session {session_id}: End of life."),
     }
 }
 
@@ -0,0 +1,109 @@
+//! `--mirror-addr`：把每个会话里客户端发往后端的数据复制一份，通过单独的 TCP 连接发到另一个地址，
+//! 用于流量分析，或者用真实流量试跑新版本的后端。
+//!
+//! 镜像只管发不管收：镜像端的应答读出来直接丢掉；镜像端连不上、出错或者跟不上时，
+//! 这个会话停止镜像，正常的转发不受影响，不会因为镜像端慢而变慢。
+//! 停止镜像后镜像端收到的数据流是不完整的，所以直接关闭镜像连接，而不是跳过一段继续发。
+
+use crate::dns;
+use bytes::Bytes;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::mpsc;
+
+/// 每个会话最多积压这么多字节还没发给镜像端的数据，超过时停止镜像
+const MAX_BACKLOG: usize = 4 * 1024 * 1024;
+
+static ADDR: OnceLock<String> = OnceLock::new();
+
+/// 设置镜像地址，不调用时不镜像
+pub fn init(addr: Option<String>) {
+    if let Some(addr) = addr {
+        let _ = ADDR.set(addr);
+    }
+}
+
+/// 一个会话的镜像连接
+pub struct Mirror {
+    id: String,
+    /// 停止镜像后为 `None`
+    tx: Mutex<Option<mpsc::UnboundedSender<Bytes>>>,
+    /// 已经交给镜像连接、还没发出去的字节数
+    backlog: Arc<AtomicUsize>,
+}
+
+impl Mirror {
+    /// 设置了镜像地址时为会话 `id` 建立镜像连接，连接在后台建立，建立之前的数据先积压着
+    pub fn start(id: &str) -> Option<Self> {
+        let addr = ADDR.get()?;
+        let (tx, rx) = mpsc::unbounded_channel();
+        let backlog = Arc::new(AtomicUsize::new(0));
+        tokio::spawn(run(addr.clone(), id.to_string(), rx, backlog.clone()));
+        Some(Self {
+            id: id.to_string(),
+            tx: Mutex::new(Some(tx)),
+            backlog,
+        })
+    }
+
+    /// 复制一段客户端发往后端的数据，镜像端跟不上或者已经断开时停止镜像
+    pub fn data(&self, data: &[u8]) {
+        let mut tx = self.tx.lock().unwrap();
+        let Some(sender) = tx.as_ref() else { return };
+        if self.backlog.fetch_add(data.len(), Ordering::Relaxed) + data.len() > MAX_BACKLOG {
+            *tx = None;
+            return warn_repeated!(
+                tr!("mirror too slow", "镜像端跟不上"),
+                "Session {}: mirror fell behind, stopped mirroring this session",
+                "会话 {}：镜像端跟不上，停止镜像这个会话",
+                self.id
+            );
+        }
+        if sender.send(Bytes::copy_from_slice(data)).is_err() {
+            *tx = None;
+        }
+    }
+}
+
+/// 连接镜像端并把积压的数据依次发过去，会话结束（发送端全部释放）后关闭连接
+async fn run(
+    addr: String,
+    id: String,
+    mut rx: mpsc::UnboundedReceiver<Bytes>,
+    backlog: Arc<AtomicUsize>,
+) {
+    let stream = match dns::connect_tcp(&addr).await {
+        Ok(stream) => stream,
+        Err(e) => {
+            return warn_repeated!(
+                tr!("mirror {addr} unreachable", "镜像端 {addr} 无法连接"),
+                "Session {id}: failed to connect to mirror {addr}: {e}",
+                "会话 {id}：无法连接到镜像端 {addr}：{e}"
+            );
+        }
+    };
+    let (mut reader, mut writer) = stream.into_split();
+    let forward = async {
+        while let Some(data) = rx.recv().await {
+            if let Err(e) = writer.write_all(&data).await {
+                return debug!(
+                    "Session {id}: mirror connection closed: {e}",
+                    "会话 {id}：镜像连接断开：{e}"
+                );
+            }
+            backlog.fetch_sub(data.len(), Ordering::Relaxed);
+        }
+        let _ = writer.shutdown().await;
+    };
+    // 镜像端的应答没人要，读出来丢掉，免得它写不出应答卡住；它关闭写方向后还可以继续给它发
+    let discard = async {
+        let mut buf = [0u8; 4096];
+        while reader.read(&mut buf).await.is_ok_and(|n| n > 0) {}
+        std::future::pending::<()>().await
+    };
+    tokio::select! {
+        _ = forward => {}
+        _ = discard => {}
+    }
+}
@@ -0,0 +1,262 @@
+//! 多线路：客户端有多个出口（比如 Wi-Fi 和 LTE，各有自己的 IP）时，把新会话分散到各条线路上，
+//! 会话很多时总吞吐量可以超过单条线路。
+//!
+//! 服务端按 conv 和客户端地址把收到的包分给会话（见 `demux`），每个会话只认一个对端地址，
+//! 所以分配的单位是会话而不是数据包：同一个会话的包始终走同一条线路。
+//! 每条线路按建立连接的耗时估计 RTT、按建立失败的比例估计丢包，RTT 越低、丢包越少的线路分到的会话越多。
+//!
+//! RTT 或丢包超过上限的线路暂停使用，之后每隔一段时间让一个新会话试探它，成功就恢复；
+//! 试探失败的会话会换一条线路重试，不会因此建立失败。
+
+use crate::dns;
+use crate::registry::{Registration, Traffic};
+use crate::udp;
+use crate::wire::Wire;
+use kcp::{KcpConfig, KcpStream};
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+
+/// 所有线路都还没有测量过时按这个 RTT 计算
+const INITIAL_RTT: Duration = Duration::from_millis(100);
+/// 暂停使用的线路每隔这么久试探一次
+const PROBE_INTERVAL: Duration = Duration::from_secs(10);
+
+pub struct Paths {
+    paths: Vec<Path>,
+    limits: Limits,
+}
+
+/// 线路质量的下限，超过后暂停使用
+#[derive(Clone, Copy)]
+pub struct Limits {
+    pub max_rtt: Option<Duration>,
+    /// 0 到 1
+    pub max_loss: f64,
+}
+
+struct Path {
+    /// 这条线路在本机上的地址，UDP 套接字绑定到它
+    local: IpAddr,
+    traffic: Arc<Traffic>,
+    stats: Mutex<Stats>,
+}
+
+#[derive(Default)]
+struct Stats {
+    /// 平滑后的 RTT，没有测量过时为 `None`
+    srtt: Option<Duration>,
+    /// 建立连接失败比例的移动平均，0 到 1
+    loss: f64,
+    sessions: u64,
+    /// 暂停使用时为上次试探的时间
+    down: Option<Instant>,
+}
+
+/// 线路列表中的一项
+pub struct PathInfo {
+    pub local: IpAddr,
+    pub up: bool,
+    pub rtt: Option<Duration>,
+    pub loss: f64,
+    /// 经这条线路建立的会话数
+    pub sessions: u64,
+    pub sent: u64,
+    pub received: u64,
+}
+
+impl Paths {
+    pub fn new(locals: Vec<IpAddr>, limits: Limits) -> Self {
+        let paths = locals
+            .into_iter()
+            .map(|local| Path {
+                local,
+                traffic: Arc::default(),
+                stats: Mutex::default(),
+            })
+            .collect();
+        Self { paths, limits }
+    }
+
+    pub fn list(&self) -> Vec<PathInfo> {
+        self.paths
+            .iter()
+            .map(|path| {
+                let stats = path.stats.lock().unwrap();
+                PathInfo {
+                    local: path.local,
+                    up: stats.down.is_none(),
+                    rtt: stats.srtt,
+                    loss: stats.loss,
+                    sessions: stats.sessions,
+                    sent: path.traffic.sent(),
+                    received: path.traffic.received(),
+                }
+            })
+            .collect()
+    }
+
+    /// 选一条线路：到了试探时间的暂停线路优先，否则在可用的线路中按权重随机选择
+    fn pick(&self) -> usize {
+        for (i, path) in self.paths.iter().enumerate() {
+            let mut stats = path.stats.lock().unwrap();
+            if stats
+                .down
+                .is_some_and(|probed| probed.elapsed() >= PROBE_INTERVAL)
+            {
+                stats.down = Some(Instant::now());
+                return i;
+            }
+        }
+        let weights = self.weights(None);
+        let mut point = rand::random::<f64>() * weights.iter().sum::<f64>();
+        for (i, weight) in weights.into_iter().enumerate() {
+            if point < weight {
+                return i;
+            }
+            point -= weight;
+        }
+        self.paths.len() - 1
+    }
+
+    /// 除 `failed` 以外权重最大的线路
+    fn fallback(&self, failed: usize) -> Option<usize> {
+        self.weights(Some(failed))
+            .into_iter()
+            .enumerate()
+            .filter(|&(i, weight)| i != failed && weight > 0.0)
+            .max_by(|a, b| a.1.total_cmp(&b.1))
+            .map(|(i, _)| i)
+    }
+
+    /// 各线路的权重，暂停使用的为 0；全部暂停时不区分，免得没有线路可用
+    fn weights(&self, exclude: Option<usize>) -> Vec<f64> {
+        // 没有测量过的线路按目前最好的 RTT 计算，保证它很快能分到会话、得到测量
+        let best = self
+            .paths
+            .iter()
+            .filter_map(|path| path.stats.lock().unwrap().srtt)
+            .min()
+            .unwrap_or(INITIAL_RTT);
+        let weights: Vec<f64> = self.paths.iter().map(|path| path.weight(best)).collect();
+        let usable = |i: usize| Some(i) != exclude && self.paths[i].is_up();
+        if (0..self.paths.len()).any(usable) {
+            let up = |(i, weight)| if usable(i) { weight } else { 0.0 };
+            weights.into_iter().enumerate().map(up).collect()
+        } else {
+            weights
+        }
+    }
+
+    /// 和 `KcpUdpStream::connect` 一样建立 KCP 连接，从选中的线路发出，失败时换一条线路再试一次；
+    /// 会话经过的数据同时计入所在线路
+    pub async fn connect(
+        &self,
+        config: Arc<KcpConfig>,
+        addr: &str,
+        registration: &Registration<'_>,
+    ) -> io::Result<(KcpStream, SocketAddr)> {
+        let addr = dns::lookup(addr).await?;
+        let first = self.pick();
+        let wire = registration.wire();
+        let result = self
+            .connect_via(first, config.clone(), addr, wire.clone())
+            .await;
+        let (result, used) = match (result, self.fallback(first)) {
+            (Err(_), Some(second)) => (self.connect_via(second, config, addr, wire).await, second),
+            (result, _) => (result, first),
+        };
+        if result.is_ok() {
+            registration.count_traffic(self.paths[used].traffic.clone());
+            registration.tag(format!("path={}", self.paths[used].local));
+        }
+        result
+    }
+
+    async fn connect_via(
+        &self,
+        i: usize,
+        config: Arc<KcpConfig>,
+        addr: SocketAddr,
+        wire: Arc<Wire>,
+    ) -> io::Result<(KcpStream, SocketAddr)> {
+        let path = &self.paths[i];
+        let start = Instant::now();
+        let connected = async {
+            let udp = UdpSocket::bind((path.local, 0)).await?;
+            udp::tune(&udp, &config, 1);
+            udp::socket_connect(config, addr, udp, wire).await
+        }
+        .await;
+        path.record(
+            connected.as_ref().ok().map(|_| start.elapsed()),
+            &self.limits,
+        );
+        if let Err(e) = &connected {
+            debug!(
+                "Path {} failed to reach {addr}: {e}",
+                "线路 {} 无法连接到 {addr}：{e}", path.local
+            );
+        }
+        connected
+    }
+}
+
+impl Path {
+    fn is_up(&self) -> bool {
+        self.stats.lock().unwrap().down.is_none()
+    }
+
+    /// RTT 越低、丢包越少权重越大，没有测量过的线路按 `unmeasured` 计算 RTT
+    fn weight(&self, unmeasured: Duration) -> f64 {
+        let stats = self.stats.lock().unwrap();
+        let rtt = stats.srtt.unwrap_or(unmeasured).as_secs_f64().max(0.001);
+        (1.0 - stats.loss).max(0.01) / rtt
+    }
+
+    /// 记录一次建立连接的结果，成功时给出耗时，并按质量下限暂停或恢复这条线路
+    fn record(&self, rtt: Option<Duration>, limits: &Limits) {
+        let mut stats = self.stats.lock().unwrap();
+        match rtt {
+            Some(rtt) => {
+                stats.sessions += 1;
+                stats.loss *= 7.0 / 8.0;
+                stats.srtt = Some(match stats.srtt {
+                    // 试探成功时以这次的测量为准，旧的数据已经过时
+                    Some(srtt) if stats.down.is_none() => (srtt * 7 + rtt) / 8,
+                    _ => rtt,
+                });
+            }
+            None => stats.loss = stats.loss * 7.0 / 8.0 + 1.0 / 8.0,
+        }
+        let srtt = stats.srtt.unwrap_or_default();
+        let bad = stats.loss > limits.max_loss || limits.max_rtt.is_some_and(|max| srtt > max);
+        match stats.down {
+            None if bad => {
+                stats.down = Some(Instant::now());
+                let rtt = stats
+                    .srtt
+                    .map_or("-".to_string(), |srtt| format!("{}ms", srtt.as_millis()));
+                warn!(
+                    "Path {} stopped: rtt {rtt}, loss {:.0}%",
+                    "线路 {} 暂停使用：RTT {rtt}，丢包 {:.0}%",
+                    self.local,
+                    stats.loss * 100.0
+                );
+            }
+            Some(_) if rtt.is_some() && limits.max_rtt.is_none_or(|max| srtt <= max) => {
+                stats.down = None;
+                stats.loss = 0.0;
+                notice!(
+                    "Path {} recovered, rtt {}ms",
+                    "线路 {} 已恢复，RTT {}ms",
+                    self.local,
+                    srtt.as_millis()
+                );
+            }
+            _ => {}
+        }
+    }
+}
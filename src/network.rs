@@ -0,0 +1,112 @@
+//! `--profile auto`：认出当前所在的网络，运行中网络变了就换用对应的配置重新启动。
+//!
+//! 网络按默认网关的 MAC 地址区分：家里、公司、咖啡馆的路由器各不相同，而且不像网关的 IP 地址那样到处都是 192.168.1.1。
+//! 目前只支持 Linux，从 `/proc/net/route` 找出默认网关，再到 `/proc/net/arp` 里查它的 MAC 地址；
+//! 其它平台上认不出网络，总是只用配置文件开头的设置。按无线网络名称（SSID）选择需要各平台的无线接口，没有支持。
+//!
+//! 运行中每隔 `CHECK_INTERVAL` 检查一次，换到的网络对应另一个配置时，像收到 Ctrl-C 一样关闭所有会话，
+//! 然后用原来的命令行参数重新执行自己，按新的网络重新选择配置；网关暂时查不到（比如断网、正在换网）时不做处理。
+//! `--sandbox` 禁止了执行程序，`--user` 降权后也可能无法再绑定原来的端口，这两种情况下只在启动时选择一次。
+
+use crate::config::Auto;
+use std::time::Duration;
+
+/// 运行中检查网络变化的间隔
+const CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// 当前默认网关的 MAC 地址，小写、冒号分隔；查不到时返回 `None`
+#[cfg(target_os = "linux")]
+pub fn gateway_mac() -> Option<String> {
+    use std::fs;
+    use std::net::Ipv4Addr;
+
+    // Iface Destination Gateway Flags ...，地址是按本机字节序打印的十六进制
+    let routes = fs::read_to_string("/proc/net/route").ok()?;
+    let gateway = routes.lines().skip(1).find_map(|line| {
+        let fields: Vec<_> = line.split_whitespace().collect();
+        if fields.get(1) != Some(&"00000000") {
+            return None;
+        }
+        let gateway = u32::from_str_radix(fields.get(2)?, 16).ok()?;
+        Some(Ipv4Addr::from(gateway.to_ne_bytes()))
+    })?;
+    // IP address HW type Flags HW address Mask Device，Flags 为 0 表示还没解析出来
+    let arp = fs::read_to_string("/proc/net/arp").ok()?;
+    arp.lines().skip(1).find_map(|line| {
+        let fields: Vec<_> = line.split_whitespace().collect();
+        if fields.first()?.parse::<Ipv4Addr>().ok()? != gateway || fields.get(2)? == &"0x0" {
+            return None;
+        }
+        Some(fields.get(3)?.to_ascii_lowercase())
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn gateway_mac() -> Option<String> {
+    None
+}
+
+/// 记录启动时选择配置的结果
+pub fn report(auto: &Auto) {
+    match (&auto.gateway, &auto.profile) {
+        (Some(gateway), Some(profile)) => notice!(
+            "Default gateway is {gateway}, using profile {profile}",
+            "默认网关是 {gateway}，使用配置 {profile}"
+        ),
+        (Some(gateway), None) => notice!(
+            "No profile matches default gateway {gateway}, using base settings",
+            "没有配置对应默认网关 {gateway}，只使用基本设置"
+        ),
+        (None, _) => notice!(
+            "Could not find the default gateway, using base settings",
+            "找不到默认网关，只使用基本设置"
+        ),
+    }
+}
+
+/// 等到网络变成对应另一个配置时返回
+pub async fn watch(auto: &Auto) {
+    let mut current = auto.gateway.clone();
+    loop {
+        tokio::time::sleep(CHECK_INTERVAL).await;
+        let Some(gateway) = gateway_mac() else {
+            continue;
+        };
+        if current.as_ref() == Some(&gateway) {
+            continue;
+        }
+        let profile = auto.select(&gateway);
+        if profile == auto.profile.as_deref() {
+            info!(
+                "Default gateway changed to {gateway}, profile unchanged",
+                "默认网关换成了 {gateway}，配置不变"
+            );
+            current = Some(gateway);
+            continue;
+        }
+        notice!(
+            "Default gateway changed to {gateway}, restarting with profile {}",
+            "默认网关换成了 {gateway}，换用配置 {} 重新启动",
+            profile.unwrap_or("(base)")
+        );
+        return;
+    }
+}
+
+/// 用原来的命令行参数重新执行自己，成功时不会返回
+#[cfg(unix)]
+pub fn restart() -> anyhow::Result<()> {
+    use anyhow::Context;
+    use std::os::unix::process::CommandExt;
+
+    let exe = std::env::current_exe().context("failed to locate the executable")?;
+    let error = std::process::Command::new(&exe)
+        .args(std::env::args_os().skip(1))
+        .exec();
+    Err(error).with_context(|| format!("failed to restart {}", exe.display()))
+}
+
+#[cfg(not(unix))]
+pub fn restart() -> anyhow::Result<()> {
+    anyhow::bail!("restarting is only supported on unix, start the program again")
+}
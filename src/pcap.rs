@@ -0,0 +1,171 @@
+//! `--debug-pcap`：把隧道中转发的数据流写入 pcap 文件，方便用 Wireshark 分析上层协议。
+//!
+//! 每个会话被还原成一条合成的 TCP 连接（IPv4，LINKTYPE_RAW），带有握手和挥手，
+//! 在 Wireshark 里可以直接“追踪流”。客户端一侧使用会话的对端地址（IPv6 地址映射为 10.0.0.1），
+//! 服务端一侧固定为 10.0.0.2，端口按会话编号递增，保证每个会话的四元组不同。
+//!
+//! 隧道本身不加密，记录的就是原始数据；UDP 层的 KCP 数据包不在记录范围内，需要时用 tcpdump 在网卡上抓。
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicU16, Ordering};
+use std::time::SystemTime;
+
+const LINKTYPE_RAW: u32 = 101;
+const SNAPLEN: u32 = 65535;
+/// IPv4 头和 TCP 头各 20 字节
+const HEADERS_LEN: usize = 40;
+const MAX_PAYLOAD: usize = SNAPLEN as usize - HEADERS_LEN;
+
+const TCP_FIN: u8 = 0x01;
+const TCP_SYN: u8 = 0x02;
+const TCP_PSH: u8 = 0x08;
+const TCP_ACK: u8 = 0x10;
+
+const CLIENT_FALLBACK_IP: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+const SERVER_IP: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+
+pub struct Capture {
+    file: Mutex<BufWriter<File>>,
+    sessions: AtomicU16,
+    failed: AtomicBool,
+}
+
+impl Capture {
+    pub fn create(path: &Path) -> io::Result<Self> {
+        let mut file = BufWriter::new(File::create(path)?);
+        file.write_all(&0xa1b2_c3d4u32.to_le_bytes())?;
+        file.write_all(&2u16.to_le_bytes())?;
+        file.write_all(&4u16.to_le_bytes())?;
+        file.write_all(&0i32.to_le_bytes())?;
+        file.write_all(&0u32.to_le_bytes())?;
+        file.write_all(&SNAPLEN.to_le_bytes())?;
+        file.write_all(&LINKTYPE_RAW.to_le_bytes())?;
+        file.flush()?;
+        Ok(Self {
+            file: Mutex::new(file),
+            sessions: AtomicU16::new(0),
+            failed: AtomicBool::new(false),
+        })
+    }
+
+    /// 为一个会话开始记录，`client` 是会话客户端一侧的地址
+    pub fn stream(self: &Arc<Self>, client: SocketAddr) -> StreamCapture {
+        let client = match client {
+            SocketAddr::V4(addr) => addr,
+            SocketAddr::V6(addr) => SocketAddrV4::new(CLIENT_FALLBACK_IP, addr.port()),
+        };
+        let number = self.sessions.fetch_add(1, Ordering::Relaxed);
+        let server = SocketAddrV4::new(SERVER_IP, 1024 + number % (u16::MAX - 1024));
+        let stream = StreamCapture {
+            capture: self.clone(),
+            client,
+            server,
+            seq: Mutex::new([0, 0]),
+        };
+        stream.packet(true, TCP_SYN, &[]);
+        stream.packet(false, TCP_SYN | TCP_ACK, &[]);
+        stream.packet(true, TCP_ACK, &[]);
+        stream
+    }
+
+    fn write(&self, packet: &[u8]) {
+        let result = (|| {
+            let now = SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default();
+            let mut file = self.file.lock().unwrap();
+            file.write_all(&(now.as_secs() as u32).to_le_bytes())?;
+            file.write_all(&now.subsec_micros().to_le_bytes())?;
+            file.write_all(&(packet.len() as u32).to_le_bytes())?;
+            file.write_all(&(packet.len() as u32).to_le_bytes())?;
+            file.write_all(packet)?;
+            file.flush()
+        })();
+        if let Err(e) = result
+            && !self.failed.swap(true, Ordering::Relaxed)
+        {
+            error!(
+                "Failed to write packet capture: {e}",
+                "写入抓包文件失败：{e}"
+            );
+        }
+    }
+}
+
+/// 一个会话的记录，drop 时写入两个方向的 FIN
+pub struct StreamCapture {
+    capture: Arc<Capture>,
+    client: SocketAddrV4,
+    server: SocketAddrV4,
+    /// 两个方向下一个字节的序号：[客户端发出, 服务端发出]
+    seq: Mutex<[u32; 2]>,
+}
+
+impl StreamCapture {
+    /// 记录一段转发的数据，`from_client` 表示数据由客户端一侧发往服务端一侧
+    pub fn data(&self, from_client: bool, data: &[u8]) {
+        for chunk in data.chunks(MAX_PAYLOAD) {
+            self.packet(from_client, TCP_PSH | TCP_ACK, chunk);
+        }
+    }
+
+    fn packet(&self, from_client: bool, flags: u8, payload: &[u8]) {
+        let (src, dst) = if from_client {
+            (self.client, self.server)
+        } else {
+            (self.server, self.client)
+        };
+        let (seq, ack) = {
+            let mut seqs = self.seq.lock().unwrap();
+            let (mine, theirs) = if from_client { (0, 1) } else { (1, 0) };
+            let seq = seqs[mine];
+            let consumed = payload.len() as u32 + u32::from(flags & (TCP_SYN | TCP_FIN) != 0);
+            seqs[mine] = seq.wrapping_add(consumed);
+            (seq, seqs[theirs])
+        };
+
+        let total_len = (HEADERS_LEN + payload.len()) as u16;
+        let mut packet = Vec::with_capacity(total_len as usize);
+        // IPv4 头
+        packet.extend_from_slice(&[0x45, 0]);
+        packet.extend_from_slice(&total_len.to_be_bytes());
+        packet.extend_from_slice(&[0, 0, 0x40, 0, 64, 6, 0, 0]);
+        packet.extend_from_slice(&src.ip().octets());
+        packet.extend_from_slice(&dst.ip().octets());
+        let checksum = ipv4_checksum(&packet);
+        packet[10..12].copy_from_slice(&checksum.to_be_bytes());
+        // TCP 头，校验和留空，Wireshark 默认不校验
+        packet.extend_from_slice(&src.port().to_be_bytes());
+        packet.extend_from_slice(&dst.port().to_be_bytes());
+        packet.extend_from_slice(&seq.to_be_bytes());
+        let ack = if flags & TCP_ACK != 0 { ack } else { 0 };
+        packet.extend_from_slice(&ack.to_be_bytes());
+        packet.extend_from_slice(&[5 << 4, flags, 0xff, 0xff, 0, 0, 0, 0]);
+        packet.extend_from_slice(payload);
+        self.capture.write(&packet);
+    }
+}
+
+impl Drop for StreamCapture {
+    fn drop(&mut self) {
+        self.packet(true, TCP_FIN | TCP_ACK, &[]);
+        self.packet(false, TCP_FIN | TCP_ACK, &[]);
+        self.packet(true, TCP_ACK, &[]);
+    }
+}
+
+fn ipv4_checksum(header: &[u8]) -> u16 {
+    let mut sum: u32 = header
+        .chunks(2)
+        .map(|word| u32::from(u16::from_be_bytes([word[0], word[1]])))
+        .sum();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
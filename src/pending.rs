@@ -0,0 +1,108 @@
+//! 正在建立的会话（握手、连接后端或服务端）的数量上限。
+//!
+//! 接受连接后会话立即在自己的任务里建立，接受循环不会被慢的后端拖住；
+//! 但后端很慢时建立中的会话会越积越多，这里限制它们的数量，超出时按策略拒绝新连接或者放弃最早的那个。
+
+use clap::ValueEnum;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use tokio_util::sync::{CancellationToken, WaitForCancellationFutureOwned};
+
+/// 建立中的会话已满时的处理方式
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Overflow {
+    /// 拒绝新连接
+    Reject,
+    /// 放弃等待最久的会话，接受新连接
+    DropOldest,
+}
+
+pub struct Pending {
+    limit: usize,
+    overflow: Overflow,
+    /// 按开始建立的先后排列
+    queue: Mutex<VecDeque<(String, CancellationToken)>>,
+}
+
+/// 建立中的会话占用的名额，会话建立完成（或失败）后 drop 释放
+pub struct Slot {
+    pending: Arc<Pending>,
+    session_id: String,
+    cancel: CancellationToken,
+}
+
+impl Pending {
+    pub fn new(limit: usize, overflow: Overflow) -> Arc<Self> {
+        Arc::new(Self {
+            limit,
+            overflow,
+            queue: Mutex::default(),
+        })
+    }
+
+    /// 为新会话占一个名额，已满且策略为拒绝时返回 `None`
+    pub fn admit(self: &Arc<Self>, session_id: &str) -> Option<Slot> {
+        let mut queue = self.queue.lock().unwrap();
+        if queue.len() >= self.limit {
+            match self.overflow {
+                Overflow::Reject => {
+                    warn_repeated!(
+                        tr!("too many pending sessions", "建立中的会话太多"),
+                        "Session {session_id}: rejected, {} sessions are still being set up",
+                        "会话 {session_id}：已有 {} 个会话正在建立，拒绝连接",
+                        queue.len()
+                    );
+                    return None;
+                }
+                Overflow::DropOldest => {
+                    if let Some((oldest, cancel)) = queue.pop_front() {
+                        warn_repeated!(
+                            tr!("too many pending sessions", "建立中的会话太多"),
+                            "Session {oldest}: dropped, {} sessions are still being set up",
+                            "会话 {oldest}：已有 {} 个会话正在建立，放弃这个最早的",
+                            queue.len() + 1
+                        );
+                        cancel.cancel();
+                    }
+                }
+            }
+        }
+        let cancel = CancellationToken::new();
+        queue.push_back((session_id.to_string(), cancel.clone()));
+        Some(Slot {
+            pending: self.clone(),
+            session_id: session_id.to_string(),
+            cancel,
+        })
+    }
+}
+
+impl Slot {
+    /// 这个会话因为名额被新连接挤占而应当放弃时完成
+    pub fn dropped(&self) -> WaitForCancellationFutureOwned {
+        self.cancel.clone().cancelled_owned()
+    }
+}
+
+/// 运行会话，会话建立期间名额被新连接挤占时提前结束
+pub async fn guard(
+    dropped: Option<WaitForCancellationFutureOwned>,
+    session: impl Future<Output = ()>,
+) {
+    match dropped {
+        Some(dropped) => tokio::select! {
+            _ = session => {}
+            _ = dropped => {}
+        },
+        None => session.await,
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        let mut queue = self.pending.queue.lock().unwrap();
+        if let Some(i) = queue.iter().position(|(id, _)| *id == self.session_id) {
+            queue.remove(i);
+        }
+    }
+}
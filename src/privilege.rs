@@ -0,0 +1,97 @@
+/// 绑定完端口后切换到普通用户，这样可以用 root 绑定 443/53 这类特权端口，但不以 root 身份处理流量
+#[cfg(unix)]
+pub fn drop_privileges(user: Option<&str>, group: Option<&str>) -> anyhow::Result<()> {
+    use anyhow::{Context, bail};
+    use std::ffi::{CStr, CString};
+    use std::mem::MaybeUninit;
+
+    if user.is_none() && group.is_none() {
+        return Ok(());
+    }
+
+    let mut buf = vec![0 as libc::c_char; 16 * 1024];
+
+    let passwd = match user {
+        Some(name) => {
+            let c_name = CString::new(name)?;
+            let mut passwd = MaybeUninit::<libc::passwd>::uninit();
+            let mut result = std::ptr::null_mut();
+            let ret = unsafe {
+                libc::getpwnam_r(
+                    c_name.as_ptr(),
+                    passwd.as_mut_ptr(),
+                    buf.as_mut_ptr(),
+                    buf.len(),
+                    &mut result,
+                )
+            };
+            if ret != 0 || result.is_null() {
+                bail!("Unknown user {name}");
+            }
+            Some(unsafe { passwd.assume_init() })
+        }
+        None => None,
+    };
+
+    let gid = match group {
+        Some(name) => {
+            let c_name = CString::new(name)?;
+            let mut group = MaybeUninit::<libc::group>::uninit();
+            let mut group_buf = vec![0 as libc::c_char; 16 * 1024];
+            let mut result = std::ptr::null_mut();
+            let ret = unsafe {
+                libc::getgrnam_r(
+                    c_name.as_ptr(),
+                    group.as_mut_ptr(),
+                    group_buf.as_mut_ptr(),
+                    group_buf.len(),
+                    &mut result,
+                )
+            };
+            if ret != 0 || result.is_null() {
+                bail!("Unknown group {name}");
+            }
+            Some(unsafe { group.assume_init() }.gr_gid)
+        }
+        None => passwd.as_ref().map(|passwd| passwd.pw_gid),
+    };
+
+    if let Some(gid) = gid {
+        // 先清掉 root 的附加组，再切换组，最后切换用户，顺序不能颠倒
+        if unsafe { libc::setgroups(1, &gid) } != 0 {
+            return Err(std::io::Error::last_os_error()).context("setgroups failed");
+        }
+        if unsafe { libc::setgid(gid) } != 0 {
+            return Err(std::io::Error::last_os_error()).context("setgid failed");
+        }
+    }
+    if let Some(passwd) = &passwd {
+        if unsafe { libc::setuid(passwd.pw_uid) } != 0 {
+            return Err(std::io::Error::last_os_error()).context("setuid failed");
+        }
+        if passwd.pw_uid != 0 && unsafe { libc::setuid(0) } == 0 {
+            bail!("Still able to regain root after dropping privileges");
+        }
+    }
+
+    let user = passwd.map(|passwd| {
+        unsafe { CStr::from_ptr(passwd.pw_name) }
+            .to_string_lossy()
+            .into_owned()
+    });
+    notice!(
+        "Dropped privileges to user {}, group {}",
+        "已降权为用户 {}，用户组 {}",
+        user.as_deref().unwrap_or("(unchanged)"),
+        gid.map_or("(unchanged)".to_string(), |gid| gid.to_string())
+    );
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn drop_privileges(user: Option<&str>, group: Option<&str>) -> anyhow::Result<()> {
+    if user.is_some() || group.is_some() {
+        anyhow::bail!("--user/--group are only supported on Unix");
+    }
+    Ok(())
+}
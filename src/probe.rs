@@ -0,0 +1,193 @@
+//! 线路探测：客户端按固定间隔向服务端发送 ICMP echo（ping），和隧道里有没有数据无关，
+//! 记录 RTT 的基线和丢包率。隧道变慢时对照这里的数据，可以分清是隧道自身的问题还是整条线路变差了。
+//!
+//! Linux 上优先使用不需要特权的 ping 套接字（受 `net.ipv4.ping_group_range` 限制），
+//! 不允许时改用原始套接字（需要 root 或 CAP_NET_RAW）。套接字在启动时创建，之后降权、开启系统调用过滤都不影响探测。
+
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+use tokio::time::timeout;
+
+const ECHO_REQUEST_V4: u8 = 8;
+const ECHO_REPLY_V4: u8 = 0;
+const ECHO_REQUEST_V6: u8 = 128;
+const ECHO_REPLY_V6: u8 = 129;
+
+pub struct Probe {
+    target: IpAddr,
+    stats: Mutex<Stats>,
+}
+
+#[derive(Default)]
+struct Stats {
+    sent: u64,
+    received: u64,
+    srtt: Option<Duration>,
+    /// 观察到的最小 RTT，作为线路的基线
+    min_rtt: Option<Duration>,
+    /// 丢包比例的移动平均，0 到 1
+    loss: f64,
+}
+
+/// 探测结果
+pub struct ProbeInfo {
+    pub target: IpAddr,
+    pub sent: u64,
+    pub received: u64,
+    pub rtt: Option<Duration>,
+    pub min_rtt: Option<Duration>,
+    pub loss: f64,
+}
+
+impl Probe {
+    /// 打开 ICMP 套接字并开始每隔 `interval` 探测一次 `target`
+    pub fn start(target: IpAddr, interval: Duration) -> io::Result<Arc<Self>> {
+        let (socket, raw) = open(target)?;
+        let probe = Arc::new(Self {
+            target,
+            stats: Mutex::default(),
+        });
+        tokio::spawn(probe.clone().run(socket, raw, interval));
+        Ok(probe)
+    }
+
+    pub fn info(&self) -> ProbeInfo {
+        let stats = self.stats.lock().unwrap();
+        ProbeInfo {
+            target: self.target,
+            sent: stats.sent,
+            received: stats.received,
+            rtt: stats.srtt,
+            min_rtt: stats.min_rtt,
+            loss: stats.loss,
+        }
+    }
+
+    async fn run(self: Arc<Self>, socket: UdpSocket, raw: bool, interval: Duration) {
+        let target = SocketAddr::new(self.target, 0);
+        let token: [u8; 8] = rand::random();
+        let mut ticker = tokio::time::interval(interval);
+        let mut buf = [0u8; 1500];
+        let mut seq: u16 = 0;
+        loop {
+            ticker.tick().await;
+            seq = seq.wrapping_add(1);
+            let request = echo_request(self.target.is_ipv4(), seq, &token);
+            let sent = Instant::now();
+            if let Err(e) = socket.send_to(&request, target).await {
+                warn_repeated!(
+                    tr!("path probe failed", "线路探测失败"),
+                    "Failed to ping {}: {e}",
+                    "无法 ping {}：{e}",
+                    self.target
+                );
+                continue;
+            }
+            // 等到下一次探测之前，期间收到的其它包（比如迟到的旧应答）丢弃
+            let reply = timeout(interval, async {
+                loop {
+                    let Ok((n, _)) = socket.recv_from(&mut buf).await else {
+                        return None;
+                    };
+                    if is_reply(&buf[..n], raw, self.target.is_ipv4(), seq, &token) {
+                        return Some(sent.elapsed());
+                    }
+                }
+            })
+            .await
+            .ok()
+            .flatten();
+            self.record(reply);
+        }
+    }
+
+    fn record(&self, rtt: Option<Duration>) {
+        let mut stats = self.stats.lock().unwrap();
+        stats.sent += 1;
+        match rtt {
+            Some(rtt) => {
+                stats.received += 1;
+                stats.loss *= 15.0 / 16.0;
+                stats.srtt = Some(stats.srtt.map_or(rtt, |srtt| (srtt * 7 + rtt) / 8));
+                stats.min_rtt = Some(stats.min_rtt.map_or(rtt, |min| min.min(rtt)));
+            }
+            None => stats.loss = stats.loss * 15.0 / 16.0 + 1.0 / 16.0,
+        }
+    }
+}
+
+/// | type | code | checksum u16 | id u16 | seq u16 | token |
+fn echo_request(v4: bool, seq: u16, token: &[u8; 8]) -> Vec<u8> {
+    let kind = if v4 { ECHO_REQUEST_V4 } else { ECHO_REQUEST_V6 };
+    let mut packet = vec![kind, 0, 0, 0];
+    packet.extend_from_slice(&rand::random::<u16>().to_be_bytes());
+    packet.extend_from_slice(&seq.to_be_bytes());
+    packet.extend_from_slice(token);
+    // ICMPv6 的校验和包含 IP 伪首部，由内核计算
+    if v4 {
+        let checksum = checksum(&packet);
+        packet[2..4].copy_from_slice(&checksum.to_be_bytes());
+    }
+    packet
+}
+
+fn is_reply(packet: &[u8], raw: bool, v4: bool, seq: u16, token: &[u8; 8]) -> bool {
+    // IPv4 原始套接字收到的包带着 IP 首部
+    let packet = match (raw && v4, packet.first()) {
+        (true, Some(first)) => packet
+            .get(usize::from(first & 0x0f) * 4..)
+            .unwrap_or_default(),
+        _ => packet,
+    };
+    let reply = if v4 { ECHO_REPLY_V4 } else { ECHO_REPLY_V6 };
+    packet.len() >= 16
+        && packet[0] == reply
+        && packet[6..8] == seq.to_be_bytes()
+        && packet[8..16] == token[..]
+}
+
+fn checksum(data: &[u8]) -> u16 {
+    let mut sum: u32 = data
+        .chunks(2)
+        .map(|pair| u32::from(u16::from_be_bytes([pair[0], *pair.get(1).unwrap_or(&0)])))
+        .sum();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// 打开 ICMP 套接字，返回是否为原始套接字
+#[cfg(unix)]
+fn open(target: IpAddr) -> io::Result<(UdpSocket, bool)> {
+    use std::os::fd::FromRawFd;
+
+    let (domain, protocol) = match target {
+        IpAddr::V4(_) => (libc::AF_INET, libc::IPPROTO_ICMP),
+        IpAddr::V6(_) => (libc::AF_INET6, libc::IPPROTO_ICMPV6),
+    };
+    let mut raw = false;
+    let mut fd = unsafe { libc::socket(domain, libc::SOCK_DGRAM, protocol) };
+    if fd < 0 {
+        raw = true;
+        fd = unsafe { libc::socket(domain, libc::SOCK_RAW, protocol) };
+    }
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // ICMP 套接字和 UDP 套接字一样按数据报收发，借用 UdpSocket 的异步读写
+    let socket = unsafe { std::net::UdpSocket::from_raw_fd(fd) };
+    socket.set_nonblocking(true)?;
+    Ok((UdpSocket::from_std(socket)?, raw))
+}
+
+#[cfg(not(unix))]
+fn open(_target: IpAddr) -> io::Result<(UdpSocket, bool)> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "ICMP probing is only supported on Unix",
+    ))
+}
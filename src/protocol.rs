@@ -0,0 +1,652 @@
+//! 每条 KCP 连接建立后，客户端先发送握手帧，服务端回复后才开始转发数据。
+//!
+//! 客户端握手帧：`HELLO_MAGIC | version: u8 | features: u32 | ext_len: u16 | ext`
+//! 服务端回复帧：`REPLY_MAGIC | version: u8 | status: u8 | features: u32 | ext_len: u16 | ext`
+//!
+//! 两个方向的魔数不同，这样旧版服务端把握手原样转发给回显类后端时不会被误认为是回复。
+//!
+//! 1.0.x 的客户端不发握手，连接一建立就直接转发数据。服务端收到的开头不是 `HELLO_MAGIC`，
+//! 或者 `LEGACY_WAIT` 内什么都没收到（服务端先说话的协议，比如 SSH），就当作旧版客户端，
+//! 把已经读到的字节交给后端，照旧版的方式转发，不需要 `--legacy-protocol`。
+//! 反过来新版客户端连不上旧版服务端：旧版服务端不认识握手，客户端只能加上 `--legacy-protocol`。
+//!
+//! 多字节整数均为大端序。`features` 是双方支持的可选功能位，协商结果取交集；
+//! 服务端回复的 `ext` 留给之后的扩展字段，不认识的内容直接跳过。
+//!
+//! 客户端握手的 `ext` 说明这条连接的用途，为空时是普通的正向转发：
+//!
+//! - 注册反向隧道：`1 | port: u16 | name_len: u8 | name [| host_len: u8 | host [| identity_len: u8 | identity | secret_len: u8 | secret]]`，
+//!   之后这条连接作为控制通道，服务端先回复 `Registered`/`Rejected`，再在有访客连入时发送 `Open`；
+//!   带 `host` 时只接收访问这个域名的访客，同一端口可以由多条隧道按域名分用，`host_len` 为 0 表示不带域名；
+//!   `identity` 和 `secret` 是 `--identity` 和 `--reverse-secret`，服务端按 `--reverse-auth` 核对
+//! - 接入反向隧道：`2 | token: u64`，用于响应 `Open`，之后转发这名访客的数据
+//! - 控制通道：`3 [| identity_len: u8 | identity]`，之后这条连接只传消息，不转发数据
+//! - 带身份的正向转发：`4 | identity_len: u8 | identity`
+//!
+//! 客户端用 `--identity` 设置了身份时，正向转发和控制通道带上它，服务端记到日志、会话标签和 `peers` 里。
+//! 旧版服务端不认识 `4`，会拒绝握手；控制通道后面多出的内容旧版直接忽略。
+//!
+//! 控制通道上服务端发给客户端的消息：`Registered`：`1 | port: u16`；
+//! `Rejected`：`2 | len: u16 | reason`；`Open`：`3 | token: u64`。
+//! 客户端定时发送 `Ping`：`4`，让空闲的控制通道不会因为 KCP 会话过期而断开。
+//!
+//! 正向转发的控制通道上双方都可以发送的消息：`Stats`：`5 | sessions: u32 | sent: u64 | received: u64`；
+//! `Closing`：`7 | len: u16 | reason`。服务端在通道建立后发送一次 `Config`：
+//! `6 | idle_timeout: u32 | max_duration: u32`，以秒为单位，0 表示不限制；之后发送一次 `Push`：
+//! `8 | len: u16 | entries`，每项是 `key: u8 | len: u8 | value`，不认识的项直接跳过：
+//! KCP 参数 `1 | text`、新会话速率 `2 | rate: u32`、备用服务端地址 `3 | text`（可以有多项）、
+//! 本服务端的其它地址 `4 | text`（可以有多项）。
+//! 一方主动关闭数据会话时发送 `Goodbye`：`9 | conv: u32 | reason: u8`，原因是退出 `1`、空闲超时 `2`、
+//! 超出配额（会话存活时间、内存预算）`3`、被管理员关闭 `4`，对端据此记录真正的关闭原因，不认识的原因记为未知。
+//!
+//! 带长度前缀的字段都有上限（握手扩展和文字 1 KB，`Push` 由 `--max-frame-size` 指定，默认 16 KB），
+//! 超出时按协议错误断开，编解码见 `codec`。
+
+use crate::codec::{self, Cursor};
+use crate::push::Settings;
+use crate::session::Goodbye;
+use anyhow::{Context, bail};
+use std::sync::OnceLock;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::time::timeout;
+
+pub const HELLO_MAGIC: [u8; 4] = *b"TKWH";
+pub const REPLY_MAGIC: [u8; 4] = *b"TKWR";
+/// 当前协议版本
+pub const VERSION: u8 = 1;
+/// 服务端能接受的最低客户端协议版本
+pub const MIN_VERSION: u8 = 1;
+
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// 新版客户端建立连接后立即发送握手，等这么久还没有收到任何数据的当作旧版客户端
+pub const LEGACY_WAIT: Duration = Duration::from_secs(3);
+
+/// 功能位：服务端允许注册反向隧道
+pub const FEATURE_REVERSE: u32 = 1 << 0;
+/// 功能位：服务端接受正向转发的控制通道
+pub const FEATURE_CONTROL: u32 = 1 << 1;
+
+const STATUS_OK: u8 = 0;
+const STATUS_UNSUPPORTED_VERSION: u8 = 1;
+
+const REQUEST_REGISTER: u8 = 1;
+const REQUEST_ATTACH: u8 = 2;
+const REQUEST_CONTROL: u8 = 3;
+const REQUEST_FORWARD: u8 = 4;
+
+/// 身份的长度上限
+const MAX_IDENTITY: usize = 64;
+
+static IDENTITY: OnceLock<String> = OnceLock::new();
+
+const MESSAGE_REGISTERED: u8 = 1;
+const MESSAGE_REJECTED: u8 = 2;
+const MESSAGE_OPEN: u8 = 3;
+const MESSAGE_PING: u8 = 4;
+const MESSAGE_STATS: u8 = 5;
+const MESSAGE_CONFIG: u8 = 6;
+const MESSAGE_CLOSING: u8 = 7;
+const MESSAGE_PUSH: u8 = 8;
+const MESSAGE_GOODBYE: u8 = 9;
+
+/// 握手扩展字段的长度上限，注册反向隧道时最长，约 520 字节
+const MAX_EXT: usize = 1024;
+/// 控制消息里原因等文字的长度上限
+const MAX_TEXT: usize = 1024;
+
+const PUSH_KCP: u8 = 1;
+const PUSH_SESSION_RATE: u8 = 2;
+const PUSH_SERVER: u8 = 3;
+const PUSH_ROAM: u8 = 4;
+
+/// 服务端在连接开头读到的内容
+pub enum Greeting {
+    Hello(Hello),
+    /// 没有握手的旧版客户端，里面是已经读出来的数据，要原样转发给后端
+    Legacy(Vec<u8>),
+}
+
+/// 客户端发来的握手信息
+pub struct Hello {
+    pub version: u8,
+    pub features: u32,
+    pub request: Request,
+    /// 客户端用 `--identity` 设置的身份
+    pub identity: Option<String>,
+}
+
+/// 客户端：之后的握手带上这个身份
+pub fn set_identity(identity: String) {
+    let _ = IDENTITY.set(identity);
+}
+
+/// 检查身份：不超过 `MAX_IDENTITY` 字节，不含空白和逗号，免得在会话列表和标签里分不开
+pub fn parse_identity(s: &str) -> Result<String, String> {
+    if s.is_empty() || s.len() > MAX_IDENTITY {
+        return Err(format!("identity must be 1 to {MAX_IDENTITY} bytes long"));
+    }
+    if s.chars()
+        .any(|c| c.is_whitespace() || c.is_control() || c == ',')
+    {
+        return Err(format!(
+            "invalid identity {s:?}, it cannot contain spaces or commas"
+        ));
+    }
+    Ok(s.to_string())
+}
+
+/// 客户端握手时说明的连接用途
+#[derive(Clone, PartialEq, Eq)]
+pub enum Request {
+    /// 正向转发到服务端的后端
+    Forward,
+    /// 注册一个反向隧道，请求服务端在 `port` 上对外开放，指定 `host` 时只接收访问这个域名的访客
+    Register {
+        name: String,
+        port: u16,
+        host: Option<String>,
+        /// `--reverse-secret`，和身份一起发送
+        secret: Option<String>,
+    },
+    /// 接入服务端通过 `Open` 发来的访客连接
+    Attach { token: u64 },
+    /// 建立正向转发的控制通道
+    Control,
+}
+
+impl Request {
+    fn encode(&self) -> Vec<u8> {
+        let mut ext = Vec::new();
+        let identity = IDENTITY.get();
+        match self {
+            Request::Forward => {
+                if let Some(identity) = identity {
+                    ext.push(REQUEST_FORWARD);
+                    codec::put_vec8(&mut ext, identity.as_bytes());
+                }
+            }
+            Request::Register {
+                name,
+                port,
+                host,
+                secret,
+            } => {
+                ext.push(REQUEST_REGISTER);
+                ext.extend_from_slice(&port.to_be_bytes());
+                codec::put_vec8(&mut ext, name.as_bytes());
+                if host.is_some() || secret.is_some() {
+                    codec::put_vec8(&mut ext, host.as_deref().unwrap_or_default().as_bytes());
+                }
+                if let Some(secret) = secret {
+                    codec::put_vec8(&mut ext, identity.map_or("", String::as_str).as_bytes());
+                    codec::put_vec8(&mut ext, secret.as_bytes());
+                }
+            }
+            Request::Attach { token } => {
+                ext.push(REQUEST_ATTACH);
+                ext.extend_from_slice(&token.to_be_bytes());
+            }
+            Request::Control => {
+                ext.push(REQUEST_CONTROL);
+                if let Some(identity) = identity {
+                    codec::put_vec8(&mut ext, identity.as_bytes());
+                }
+            }
+        }
+        ext
+    }
+
+    /// 解出连接用途和客户端带上的身份
+    fn decode(ext: &[u8]) -> anyhow::Result<(Self, Option<String>)> {
+        let mut ext = Cursor(ext);
+        let Some(kind) = ext.u8() else {
+            return Ok((Request::Forward, None));
+        };
+        let request = match kind {
+            REQUEST_REGISTER => {
+                let (Some(port), Some(name)) = (ext.u16(), ext.vec8()) else {
+                    bail!("client sent a malformed reverse tunnel request");
+                };
+                let name =
+                    std::str::from_utf8(name).context("reverse tunnel name is not valid UTF-8")?;
+                let host = match ext.is_empty() {
+                    true => None,
+                    false => {
+                        let host = ext
+                            .vec8()
+                            .context("client sent a malformed reverse tunnel hostname")?;
+                        let host = std::str::from_utf8(host)
+                            .context("reverse tunnel hostname is not valid UTF-8")?;
+                        (!host.is_empty()).then(|| host.to_string())
+                    }
+                };
+                let credentials = match ext.is_empty() {
+                    true => None,
+                    false => {
+                        let (Some(identity), Some(secret)) = (ext.vec8(), ext.vec8()) else {
+                            bail!("client sent malformed reverse tunnel credentials");
+                        };
+                        let identity = std::str::from_utf8(identity)
+                            .ok()
+                            .and_then(|identity| parse_identity(identity).ok())
+                            .context("client sent an invalid identity")?;
+                        let secret = std::str::from_utf8(secret)
+                            .context("reverse tunnel secret is not valid UTF-8")?;
+                        Some((identity, secret.to_string()))
+                    }
+                };
+                let (identity, secret) = credentials.unzip();
+                return Ok((
+                    Request::Register {
+                        name: name.to_string(),
+                        port,
+                        host,
+                        secret,
+                    },
+                    identity,
+                ));
+            }
+            REQUEST_ATTACH => Request::Attach {
+                token: ext
+                    .u64()
+                    .context("client sent a malformed attach request")?,
+            },
+            REQUEST_CONTROL => Request::Control,
+            REQUEST_FORWARD => Request::Forward,
+            _ => bail!("client sent an unknown request {kind}"),
+        };
+        // 控制通道和正向转发的身份在最后（注册反向隧道的和口令一起在上面解出），控制通道不带身份时后面没有内容
+        if !matches!(request, Request::Control | Request::Forward) || ext.is_empty() {
+            return Ok((request, None));
+        }
+        let identity = ext.vec8().context("client sent a malformed identity")?;
+        let identity = std::str::from_utf8(identity)
+            .ok()
+            .and_then(|identity| parse_identity(identity).ok())
+            .context("client sent an invalid identity")?;
+        Ok((request, Some(identity)))
+    }
+}
+
+/// 控制通道上的消息：反向隧道的除 `Ping` 外都由服务端发给客户端，
+/// 正向转发的 `Stats`、`Closing`、`Goodbye` 双方都会发送，`Config`、`Push` 由服务端发送
+pub enum Message {
+    /// 注册成功，对外开放的端口
+    Registered { port: u16 },
+    /// 注册被拒绝
+    Rejected { reason: String },
+    /// 有访客连入，客户端需要用这个 token 发起一条 `Attach` 连接
+    Open { token: u64 },
+    /// 客户端的保活消息
+    Ping,
+    /// 发送方当前的会话数和累计转发的字节数
+    Stats {
+        sessions: u32,
+        sent: u64,
+        received: u64,
+    },
+    /// 服务端的会话设置，客户端据此检查自己的设置是否合适
+    Config {
+        idle_timeout: Option<Duration>,
+        max_duration: Option<Duration>,
+    },
+    /// 发送方即将关闭，比如正在排空
+    Closing { reason: String },
+    /// 服务端推送给客户端的设置
+    Push(Settings),
+    /// 发送方主动关闭了使用这个 conv 的数据会话
+    Goodbye { conv: u32, reason: Goodbye },
+}
+
+pub async fn write_message<S: AsyncWrite + Unpin>(
+    stream: &mut S,
+    message: &Message,
+) -> std::io::Result<()> {
+    let mut frame = Vec::new();
+    match message {
+        Message::Registered { port } => {
+            frame.push(MESSAGE_REGISTERED);
+            frame.extend_from_slice(&port.to_be_bytes());
+        }
+        Message::Rejected { reason } => {
+            frame.push(MESSAGE_REJECTED);
+            write_text(&mut frame, reason);
+        }
+        Message::Open { token } => {
+            frame.push(MESSAGE_OPEN);
+            frame.extend_from_slice(&token.to_be_bytes());
+        }
+        Message::Ping => frame.push(MESSAGE_PING),
+        Message::Stats {
+            sessions,
+            sent,
+            received,
+        } => {
+            frame.push(MESSAGE_STATS);
+            frame.extend_from_slice(&sessions.to_be_bytes());
+            frame.extend_from_slice(&sent.to_be_bytes());
+            frame.extend_from_slice(&received.to_be_bytes());
+        }
+        Message::Config {
+            idle_timeout,
+            max_duration,
+        } => {
+            let secs = |timeout: &Option<Duration>| {
+                timeout.map_or(0, |timeout| timeout.as_secs().min(u32::MAX as u64) as u32)
+            };
+            frame.push(MESSAGE_CONFIG);
+            frame.extend_from_slice(&secs(idle_timeout).to_be_bytes());
+            frame.extend_from_slice(&secs(max_duration).to_be_bytes());
+        }
+        Message::Closing { reason } => {
+            frame.push(MESSAGE_CLOSING);
+            write_text(&mut frame, reason);
+        }
+        Message::Goodbye { conv, reason } => {
+            frame.push(MESSAGE_GOODBYE);
+            frame.extend_from_slice(&conv.to_be_bytes());
+            frame.push(reason.code());
+        }
+        Message::Push(settings) => {
+            let mut entries = Vec::new();
+            // 超出长度上限的项整项丢掉，不留半截
+            let mut entry = |key: u8, value: &[u8]| {
+                if entries.len() + 2 + value.len().min(u8::MAX as usize) <= codec::max_frame() {
+                    entries.push(key);
+                    codec::put_vec8(&mut entries, value);
+                }
+            };
+            if let Some(kcp) = &settings.kcp {
+                entry(PUSH_KCP, kcp.to_string().as_bytes());
+            }
+            if let Some(rate) = settings.session_rate {
+                entry(PUSH_SESSION_RATE, &rate.to_be_bytes());
+            }
+            for server in &settings.servers {
+                entry(PUSH_SERVER, server.as_bytes());
+            }
+            for addr in &settings.roam {
+                entry(PUSH_ROAM, addr.as_bytes());
+            }
+            frame.push(MESSAGE_PUSH);
+            codec::put_vec16(&mut frame, &entries);
+        }
+    }
+    stream.write_all(&frame).await?;
+    stream.flush().await
+}
+
+pub async fn read_message<S: AsyncRead + Unpin>(stream: &mut S) -> anyhow::Result<Message> {
+    match stream.read_u8().await? {
+        MESSAGE_REGISTERED => Ok(Message::Registered {
+            port: stream.read_u16().await?,
+        }),
+        MESSAGE_REJECTED => Ok(Message::Rejected {
+            reason: read_text(stream).await?,
+        }),
+        MESSAGE_OPEN => Ok(Message::Open {
+            token: stream.read_u64().await?,
+        }),
+        MESSAGE_PING => Ok(Message::Ping),
+        MESSAGE_STATS => Ok(Message::Stats {
+            sessions: stream.read_u32().await?,
+            sent: stream.read_u64().await?,
+            received: stream.read_u64().await?,
+        }),
+        MESSAGE_CONFIG => {
+            let secs = |secs: u32| (secs > 0).then(|| Duration::from_secs(secs.into()));
+            Ok(Message::Config {
+                idle_timeout: secs(stream.read_u32().await?),
+                max_duration: secs(stream.read_u32().await?),
+            })
+        }
+        MESSAGE_CLOSING => Ok(Message::Closing {
+            reason: read_text(stream).await?,
+        }),
+        MESSAGE_PUSH => Ok(Message::Push(decode_push(
+            &codec::read_vec16(stream, usize::MAX).await?,
+        ))),
+        MESSAGE_GOODBYE => Ok(Message::Goodbye {
+            conv: stream.read_u32().await?,
+            reason: Goodbye::from_code(stream.read_u8().await?),
+        }),
+        other => bail!("unknown control message {other}"),
+    }
+}
+
+fn decode_push(entries: &[u8]) -> Settings {
+    let mut settings = Settings::default();
+    let mut entries = Cursor(entries);
+    while let (Some(key), Some(value)) = (entries.u8(), entries.vec8()) {
+        let text = || std::str::from_utf8(value).ok();
+        match key {
+            // 解析不了的参数（比如更新版本的服务端加了新的项）当作没有推送
+            PUSH_KCP => settings.kcp = text().and_then(|text| text.parse().ok()),
+            PUSH_SESSION_RATE => settings.session_rate = Cursor(value).u32(),
+            PUSH_SERVER => settings.servers.extend(text().map(str::to_string)),
+            PUSH_ROAM => settings.roam.extend(text().map(str::to_string)),
+            _ => {}
+        }
+    }
+    settings
+}
+
+/// `len: u16 | text`，超过 `MAX_TEXT` 的部分截掉
+fn write_text(frame: &mut Vec<u8>, text: &str) {
+    codec::put_vec16(frame, &text.as_bytes()[..text.len().min(MAX_TEXT)]);
+}
+
+async fn read_text<S: AsyncRead + Unpin>(stream: &mut S) -> std::io::Result<String> {
+    let text = codec::read_vec16(stream, MAX_TEXT).await?;
+    Ok(String::from_utf8_lossy(&text).into_owned())
+}
+
+/// 客户端：发送握手并等待服务端确认，返回协商后的功能位
+pub async fn client_handshake<S>(
+    stream: &mut S,
+    features: u32,
+    request: &Request,
+) -> anyhow::Result<u32>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let ext = request.encode();
+    let mut frame = Vec::with_capacity(11 + ext.len());
+    frame.extend_from_slice(&HELLO_MAGIC);
+    frame.push(VERSION);
+    frame.extend_from_slice(&features.to_be_bytes());
+    codec::put_vec16(&mut frame, &ext);
+    stream.write_all(&frame).await?;
+    stream.flush().await?;
+
+    let magic = read_magic(stream).await.context(
+        "server did not answer the handshake, it may be running an older version \
+         (use --legacy-protocol to talk to it)",
+    )?;
+    if magic != REPLY_MAGIC {
+        bail!(
+            "server answered with an unknown handshake, it may be running an older version \
+             (use --legacy-protocol to talk to it)"
+        );
+    }
+    let server_version = stream.read_u8().await?;
+    let status = stream.read_u8().await?;
+    let server_features = stream.read_u32().await?;
+    skip_ext(stream).await?;
+
+    match status {
+        STATUS_OK => Ok(features & server_features),
+        STATUS_UNSUPPORTED_VERSION => bail!(
+            "server speaks protocol v{server_version} and rejected our v{VERSION}, \
+             please upgrade the client"
+        ),
+        other => bail!("server rejected the handshake with unknown status {other}"),
+    }
+}
+
+/// 服务端：读取客户端握手并回复，版本不兼容时回复错误并返回 Err；没有握手的旧版客户端返回 `Greeting::Legacy`
+pub async fn server_handshake<S>(stream: &mut S, features: u32) -> anyhow::Result<Greeting>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut magic = Vec::with_capacity(HELLO_MAGIC.len());
+    while magic.len() < HELLO_MAGIC.len() {
+        let mut buf = [0u8; HELLO_MAGIC.len()];
+        let want = HELLO_MAGIC.len() - magic.len();
+        let n = match timeout(LEGACY_WAIT, stream.read(&mut buf[..want])).await {
+            Ok(read) => read?,
+            Err(_) => return Ok(Greeting::Legacy(magic)),
+        };
+        if n == 0 {
+            if magic.is_empty() {
+                bail!("client closed before sending a handshake");
+            }
+            return Ok(Greeting::Legacy(magic));
+        }
+        magic.extend_from_slice(&buf[..n]);
+        if !HELLO_MAGIC.starts_with(&magic) {
+            return Ok(Greeting::Legacy(magic));
+        }
+    }
+    let version = stream.read_u8().await?;
+    let client_features = stream.read_u32().await?;
+    let ext = read_ext(stream).await?;
+
+    let status = if version < MIN_VERSION {
+        STATUS_UNSUPPORTED_VERSION
+    } else {
+        STATUS_OK
+    };
+    let mut frame = Vec::with_capacity(12);
+    frame.extend_from_slice(&REPLY_MAGIC);
+    frame.push(VERSION);
+    frame.push(status);
+    frame.extend_from_slice(&features.to_be_bytes());
+    frame.extend_from_slice(&0u16.to_be_bytes());
+    stream.write_all(&frame).await?;
+    stream.flush().await?;
+
+    if status != STATUS_OK {
+        bail!("client speaks protocol v{version}, which is older than the minimum v{MIN_VERSION}");
+    }
+    let (request, identity) = Request::decode(&ext)?;
+    Ok(Greeting::Hello(Hello {
+        version,
+        features: client_features & features,
+        request,
+        identity,
+    }))
+}
+
+async fn read_magic<S: AsyncRead + Unpin>(stream: &mut S) -> std::io::Result<[u8; 4]> {
+    let mut magic = [0u8; 4];
+    stream.read_exact(&mut magic).await?;
+    Ok(magic)
+}
+
+async fn read_ext<S: AsyncRead + Unpin>(stream: &mut S) -> std::io::Result<Vec<u8>> {
+    codec::read_vec16(stream, MAX_EXT).await
+}
+
+async fn skip_ext<S: AsyncRead + Unpin>(stream: &mut S) -> std::io::Result<()> {
+    read_ext(stream).await.map(drop)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 客户端发来 `data` 后关闭写方向，返回服务端的判断
+    async fn greet(data: &[u8]) -> anyhow::Result<Greeting> {
+        let (mut client, mut server) = tokio::io::duplex(4096);
+        client.write_all(data).await.unwrap();
+        client.shutdown().await.unwrap();
+        server_handshake(&mut server, 0).await
+    }
+
+    #[tokio::test]
+    async fn detects_legacy_clients() {
+        // 旧版客户端直接发送的数据原样保留，最多只读魔数那么长
+        let Greeting::Legacy(data) = greet(b"GET / HTTP/1.1\r\n").await.unwrap() else {
+            panic!("expected a legacy client");
+        };
+        assert_eq!(data, b"GET ");
+        let Greeting::Legacy(data) = greet(b"TKxx").await.unwrap() else {
+            panic!("expected a legacy client");
+        };
+        assert_eq!(data, b"TKxx");
+        // 和魔数开头一样但是数据就这么多
+        let Greeting::Legacy(data) = greet(b"TK").await.unwrap() else {
+            panic!("expected a legacy client");
+        };
+        assert_eq!(data, b"TK");
+        // 什么都没发就关闭了
+        assert!(greet(b"").await.is_err());
+    }
+
+    fn entries(items: &[(u8, &[u8])]) -> Vec<u8> {
+        let mut entries = Vec::new();
+        for &(key, value) in items {
+            entries.push(key);
+            codec::put_vec8(&mut entries, value);
+        }
+        entries
+    }
+
+    #[test]
+    fn decodes_push_entries() {
+        let settings = decode_push(&entries(&[
+            (PUSH_KCP, b"interval=20,mtu=1200"),
+            (PUSH_SESSION_RATE, &50u32.to_be_bytes()),
+            (PUSH_SERVER, b"a.example:4000"),
+            // 更新版本加的项跳过
+            (200, b"whatever"),
+            (PUSH_SERVER, b"b.example:4000"),
+            (PUSH_ROAM, b"192.0.2.1:4000"),
+        ]));
+        assert!(settings.kcp == Some("interval=20,mtu=1200".parse().unwrap()));
+        assert_eq!(settings.session_rate, Some(50));
+        assert_eq!(settings.servers, ["a.example:4000", "b.example:4000"]);
+        assert_eq!(settings.roam, ["192.0.2.1:4000"]);
+        assert!(decode_push(&[]).is_empty());
+    }
+
+    #[test]
+    fn skips_bad_push_entries() {
+        let settings = decode_push(&entries(&[
+            // 解析不了的 KCP 参数当作没有推送
+            (PUSH_KCP, b"interval=20,turbo=on"),
+            // 太短的速率
+            (PUSH_SESSION_RATE, &[0, 1]),
+            // 不是 UTF-8 的地址
+            (PUSH_SERVER, &[0xff, 0xfe]),
+            (PUSH_ROAM, b"192.0.2.1:4000"),
+        ]));
+        assert!(settings.kcp.is_none());
+        assert_eq!(settings.session_rate, None);
+        assert!(settings.servers.is_empty());
+        assert_eq!(settings.roam, ["192.0.2.1:4000"]);
+
+        // 后面的 KCP 参数覆盖前面的，哪怕解析不了
+        let settings = decode_push(&entries(&[(PUSH_KCP, b"interval=20"), (PUSH_KCP, b"?")]));
+        assert!(settings.kcp.is_none());
+    }
+
+    #[test]
+    fn stops_at_truncated_push_entries() {
+        let mut data = entries(&[
+            (PUSH_SERVER, b"a.example:4000"),
+            (PUSH_ROAM, b"192.0.2.1:4000"),
+        ]);
+        // 最后一项的值不完整，前面完整的项保留
+        data.pop();
+        let settings = decode_push(&data);
+        assert_eq!(settings.servers, ["a.example:4000"]);
+        assert!(settings.roam.is_empty());
+        // 只有键没有长度
+        assert!(decode_push(&[PUSH_SERVER]).is_empty());
+        // 长度比剩下的数据长
+        assert!(decode_push(&[PUSH_SERVER, 10, b'a']).is_empty());
+    }
+}
@@ -0,0 +1,188 @@
+//! 服务端通过控制通道推送给客户端的设置：建议的 KCP 参数、新会话的速率限制、备用的服务端地址
+//! 和会话中途可以切换过去的本服务端地址，
+//! 用户各自运行的大量客户端不用逐台修改就能调整。
+//!
+//! 客户端在本地明确指定了同一项设置时以本地为准（KCP 窗口仍受 `--session-memory-limit` 限制）。
+//! 推送的 KCP 参数只作用于客户端一侧，服务端一侧按服务端自己的设置。
+
+use anyhow::{Context, bail};
+use kcp::KcpConfig;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// KCP 包大小的允许范围：太小时 KCP 头的开销占比太大，太大时加上 IP 和 UDP 头会超过以太网的 MTU
+pub const MIN_MTU: u32 = 256;
+pub const MAX_MTU: u32 = 1400;
+
+/// KCP 参数的调整，格式如 `interval=20,resend=2,nodelay=on,nc=on,window=512`，没有写的项保持不变
+#[derive(Clone, Default, PartialEq)]
+pub struct KcpTuning {
+    /// 内部时钟的间隔，毫秒
+    interval: Option<u32>,
+    /// 快速重传的触发次数，0 表示关闭
+    resend: Option<u32>,
+    nodelay: Option<bool>,
+    /// 关闭拥塞控制
+    nc: Option<bool>,
+    /// 收发窗口，以包为单位
+    window: Option<u32>,
+    /// 包的大小上限，字节
+    mtu: Option<u32>,
+}
+
+impl FromStr for KcpTuning {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let mut tuning = KcpTuning::default();
+        for item in s.split(',').map(str::trim).filter(|item| !item.is_empty()) {
+            let (key, value) = item
+                .split_once('=')
+                .with_context(|| format!("expected key=value, got {item:?}"))?;
+            let number = || {
+                value
+                    .parse::<u32>()
+                    .with_context(|| format!("invalid number in {item:?}"))
+            };
+            let switch = || match value {
+                "on" => Ok(true),
+                "off" => Ok(false),
+                _ => bail!("expected on or off in {item:?}"),
+            };
+            match key {
+                "interval" => match number()? {
+                    interval @ 10..=1000 => tuning.interval = Some(interval),
+                    _ => bail!("interval must be between 10 and 1000 ms"),
+                },
+                "resend" => tuning.resend = Some(number()?),
+                "nodelay" => tuning.nodelay = Some(switch()?),
+                "nc" => tuning.nc = Some(switch()?),
+                "window" => match number()? {
+                    0 => bail!("window must not be 0"),
+                    window => tuning.window = Some(window),
+                },
+                "mtu" => match number()? {
+                    mtu @ MIN_MTU..=MAX_MTU => tuning.mtu = Some(mtu),
+                    _ => bail!("mtu must be between {MIN_MTU} and {MAX_MTU} bytes"),
+                },
+                _ => bail!(
+                    "unknown KCP parameter {key:?}, expected interval, resend, nodelay, nc, window or mtu"
+                ),
+            }
+        }
+        Ok(tuning)
+    }
+}
+
+impl fmt::Display for KcpTuning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let switch = |on: bool| if on { "on" } else { "off" };
+        let items: Vec<String> = [
+            self.interval.map(|v| format!("interval={v}")),
+            self.resend.map(|v| format!("resend={v}")),
+            self.nodelay.map(|v| format!("nodelay={}", switch(v))),
+            self.nc.map(|v| format!("nc={}", switch(v))),
+            self.window.map(|v| format!("window={v}")),
+            self.mtu.map(|v| format!("mtu={v}")),
+        ]
+        .into_iter()
+        .flatten()
+        .collect();
+        f.write_str(&items.join(","))
+    }
+}
+
+impl KcpTuning {
+    /// 在 `base` 的基础上调整
+    pub fn apply(&self, base: &KcpConfig) -> KcpConfig {
+        let mut config = base.clone();
+        if let Some(interval) = self.interval {
+            config.nodelay.interval = interval;
+        }
+        if let Some(resend) = self.resend {
+            config.nodelay.resend = resend;
+        }
+        if let Some(nodelay) = self.nodelay {
+            config.nodelay.nodelay = nodelay;
+        }
+        if let Some(nc) = self.nc {
+            config.nodelay.nc = nc;
+        }
+        if let Some(window) = self.window {
+            config.snd_wnd = window;
+            config.rcv_wnd = window;
+        }
+        if let Some(mtu) = self.mtu {
+            config.mtu = mtu;
+        }
+        config
+    }
+}
+
+/// 服务端推送的设置，没有推送的项为空
+#[derive(Clone, Default, PartialEq)]
+pub struct Settings {
+    pub kcp: Option<KcpTuning>,
+    /// 每秒最多新建的会话数，0 表示不限制
+    pub session_rate: Option<u32>,
+    /// 连不上主地址时依次尝试的服务端地址
+    pub servers: Vec<String>,
+    /// 本服务端的其它地址，会话中途当前地址没有回应时切换过去（见 `roaming`）
+    pub roam: Vec<String>,
+}
+
+impl Settings {
+    pub fn is_empty(&self) -> bool {
+        *self == Settings::default()
+    }
+}
+
+impl fmt::Display for Settings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut items = Vec::new();
+        if let Some(kcp) = &self.kcp {
+            items.push(format!("kcp {kcp}"));
+        }
+        if let Some(rate) = self.session_rate {
+            items.push(format!("session rate {rate}/s"));
+        }
+        if !self.servers.is_empty() {
+            items.push(format!("alternate servers {}", self.servers.join(",")));
+        }
+        if !self.roam.is_empty() {
+            items.push(format!("roaming addresses {}", self.roam.join(",")));
+        }
+        f.write_str(&items.join("; "))
+    }
+}
+
+/// 客户端：最近一次收到的推送，会话建立时读取
+#[derive(Default)]
+pub struct Pushed {
+    settings: Mutex<Settings>,
+    /// 每次推送的内容变化时加一，让使用方知道需要重新读取
+    version: AtomicU64,
+}
+
+impl Pushed {
+    /// 记录新的推送，内容有变化时返回 true
+    pub fn set(&self, settings: Settings) -> bool {
+        let mut current = self.settings.lock().unwrap();
+        if *current == settings {
+            return false;
+        }
+        *current = settings;
+        self.version.fetch_add(1, Ordering::Relaxed);
+        true
+    }
+
+    pub fn get(&self) -> Settings {
+        self.settings.lock().unwrap().clone()
+    }
+
+    pub fn version(&self) -> u64 {
+        self.version.load(Ordering::Relaxed)
+    }
+}
@@ -0,0 +1,67 @@
+//! 客户端：发现本机连接服务端用的地址变了（DHCP 续租换了地址、VPN 连上或者断开），及早换到新地址上。
+//!
+//! 会话的 UDP 套接字绑定在通配地址上，地址变了以后系统改从新地址发包，但服务端按 conv 和客户端的地址识别会话，
+//! 从新地址来的包都被丢掉；绑定了 `--source-addr` 时地址一消失就发不出去。两种情况都要等 KCP 重传用完或者过期才发现，
+//! 隧道里的 TCP 连接要卡上好几分钟。已经建立的 KCP 会话没法搬到新地址上继续，能做的是尽快重建。
+//!
+//! 这里定时用一个不发包的 UDP 套接字 `connect` 到服务端，读出系统为它选的源地址，和上次的比较。
+//! 换成另一个地址时关闭所有会话（原因记为 `address_change`），让本地程序马上看到断开、自己重连，
+//! 新会话在新地址上绑定新的套接字；控制通道和反向隧道也立即重新建立。
+//! 暂时没有到服务端的路由（断网、正在换网）时不做处理，等有了地址再和断网前的比较，换回原来的地址时会话多半还在。
+//! 只看得到本机这一侧的地址，路由器的外网地址变了还是要等 KCP 自己超时。
+
+use crate::dns;
+use crate::registry::Registry;
+use crate::session::CloseReason;
+use crate::udp;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// 检查的间隔
+const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// 一直运行到进入排空状态，`server_addr` 是服务端的地址
+pub async fn watch(registry: Arc<Registry>, server_addr: String) {
+    let mut server: Option<SocketAddr> = None;
+    // 最近一次查到的源地址，断网期间保持断网前的值
+    let mut current: Option<IpAddr> = None;
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep(CHECK_INTERVAL) => {}
+            _ = registry.draining() => return,
+        }
+        let addr = match server {
+            Some(addr) => addr,
+            None => match dns::lookup(&server_addr).await {
+                Ok(addr) => *server.insert(addr),
+                Err(_) => continue,
+            },
+        };
+        let source = match udp::source_for(addr) {
+            Ok(source) => source,
+            Err(e) => {
+                debug!(
+                    "No local address to reach server {addr}: {e}",
+                    "没有可以到达服务端 {addr} 的本机地址：{e}"
+                );
+                continue;
+            }
+        };
+        let Some(previous) = current.replace(source) else {
+            continue;
+        };
+        if previous == source {
+            continue;
+        }
+        notice!(
+            "Local address changed from {previous} to {source}, closing {} sessions and reconnecting",
+            "本机地址从 {previous} 换成了 {source}，关闭 {} 个会话并重新连接",
+            registry.len()
+        );
+        registry.stop_all(CloseReason::AddressChange);
+        registry.wake();
+        // 换了网络之后服务端的域名可能解析到别的地址，比如 VPN 内外的地址不同
+        server = None;
+    }
+}
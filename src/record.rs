@@ -0,0 +1,246 @@
+//! `--record <dir>`：把每个会话两个方向的数据连同时间记录到目录里，一个会话一个文件；
+//! `replay` 子命令按原来的节奏把记录里客户端发出的数据重新发给一个后端，用来复现通过隧道观察到的问题。
+//!
+//! 文件格式（整数都是大端）：开头是 `TKWR`、版本号 1 字节和会话开始时的 Unix 毫秒时间 8 字节，
+//! 之后每段数据是 `相对会话开始的毫秒数: u64 | 方向: u8 | 长度: u32 | 数据`，
+//! 方向 0 是客户端发往后端，1 是后端发往客户端。
+//!
+//! 隧道本身不加密，记录的就是原始数据，里面可能有密码之类的敏感内容，注意目录的权限。
+
+use crate::codec::Cursor;
+use crate::dns;
+use crate::json::Value;
+use anyhow::{Context, bail};
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, SystemTime};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::time::{self, Instant};
+
+const MAGIC: &[u8; 4] = b"TKWR";
+const VERSION: u8 = 1;
+const FROM_CLIENT: u8 = 0;
+const FROM_BACKEND: u8 = 1;
+/// 回放完最后一段数据后，后端这么久没有应答就结束
+const REPLY_GRACE: Duration = Duration::from_secs(2);
+
+static DIR: OnceLock<PathBuf> = OnceLock::new();
+
+/// 设置记录目录，不存在时创建；不调用时不记录
+pub fn init(dir: &Path) -> io::Result<()> {
+    fs::create_dir_all(dir)?;
+    let _ = DIR.set(dir.to_path_buf());
+    Ok(())
+}
+
+/// 一个会话的记录文件
+pub struct Recorder {
+    id: String,
+    start: Instant,
+    /// 写入失败后为 `None`，不再记录
+    file: Mutex<Option<BufWriter<File>>>,
+}
+
+impl Recorder {
+    /// 设置了记录目录时为会话 `id` 创建记录文件
+    pub fn start(id: &str) -> Option<Self> {
+        let path = DIR.get()?.join(format!("{id}.rec"));
+        let created = File::create(&path).and_then(|file| {
+            let mut file = BufWriter::new(file);
+            let now = SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default();
+            file.write_all(MAGIC)?;
+            file.write_all(&[VERSION])?;
+            file.write_all(&(now.as_millis() as u64).to_be_bytes())?;
+            Ok(file)
+        });
+        let file = match created {
+            Ok(file) => Some(file),
+            Err(e) => {
+                warn_repeated!(
+                    tr!("recording failed", "记录会话失败"),
+                    "Session {id}: failed to create {}: {e}",
+                    "会话 {id}：无法创建 {}：{e}",
+                    path.display()
+                );
+                None
+            }
+        };
+        Some(Self {
+            id: id.to_string(),
+            start: Instant::now(),
+            file: Mutex::new(file),
+        })
+    }
+
+    /// 记录一段数据，`from_client` 表示客户端发往后端的方向
+    pub fn data(&self, from_client: bool, data: &[u8]) {
+        let mut file = self.file.lock().unwrap();
+        let Some(writer) = file.as_mut() else { return };
+        let at = self.start.elapsed().as_millis() as u64;
+        let direction = if from_client {
+            FROM_CLIENT
+        } else {
+            FROM_BACKEND
+        };
+        let result = (|| {
+            writer.write_all(&at.to_be_bytes())?;
+            writer.write_all(&[direction])?;
+            writer.write_all(&(data.len() as u32).to_be_bytes())?;
+            writer.write_all(data)
+        })();
+        if let Err(e) = result {
+            *file = None;
+            warn_repeated!(
+                tr!("recording failed", "记录会话失败"),
+                "Session {}: failed to write recording, stopped recording: {e}",
+                "会话 {}：写入记录失败，停止记录：{e}",
+                self.id
+            );
+        }
+    }
+}
+
+/// 记录里的一段数据
+struct Chunk {
+    at: Duration,
+    from_client: bool,
+    data: Vec<u8>,
+}
+
+/// 读取记录文件；进程异常退出时最后一段可能不完整，丢弃它并提示
+fn load(path: &Path) -> anyhow::Result<Vec<Chunk>> {
+    let content = fs::read(path).with_context(|| format!("failed to read {}", path.display()))?;
+    let mut cursor = Cursor(&content);
+    if cursor.take(MAGIC.len()) != Some(MAGIC) {
+        bail!("{} is not a session recording", path.display());
+    }
+    match cursor.u8() {
+        Some(VERSION) => {}
+        Some(version) => bail!("unsupported recording version {version}"),
+        None => bail!("{} is truncated", path.display()),
+    }
+    cursor.u64().context("recording header is truncated")?;
+    let mut chunks = Vec::new();
+    while !cursor.is_empty() {
+        let chunk = (|| {
+            let at = Duration::from_millis(cursor.u64()?);
+            let from_client = cursor.u8()? == FROM_CLIENT;
+            let len = cursor.u32()? as usize;
+            let data = cursor.take(len)?.to_vec();
+            Some(Chunk {
+                at,
+                from_client,
+                data,
+            })
+        })();
+        match chunk {
+            Some(chunk) => chunks.push(chunk),
+            None => {
+                warn!(
+                    "Recording ends with an incomplete chunk, ignored it",
+                    "记录的最后一段不完整，已忽略"
+                );
+                break;
+            }
+        }
+    }
+    Ok(chunks)
+}
+
+/// `replay` 子命令：按记录里的时间把客户端发出的数据发给 `target`，收下后端的应答并和记录里的比较
+pub async fn replay(path: &Path, target: &str, json: bool) -> anyhow::Result<()> {
+    let chunks = load(path)?;
+    let (requests, replies): (Vec<_>, Vec<_>) = chunks.iter().partition(|chunk| chunk.from_client);
+    let recorded: Vec<u8> = replies
+        .iter()
+        .flat_map(|chunk| &chunk.data)
+        .copied()
+        .collect();
+    let last = chunks.last().map_or(Duration::ZERO, |chunk| chunk.at);
+    if !json {
+        notice!(
+            "Replaying {} chunks from {} to {target}, about {}s...",
+            "回放 {} 段数据（来自 {}）到 {target}，大约需要 {} 秒...",
+            requests.len(),
+            path.display(),
+            last.as_secs()
+        );
+    }
+
+    let stream = dns::connect_tcp(target)
+        .await
+        .with_context(|| format!("failed to connect to {target}"))?;
+    let (mut reader, mut writer) = stream.into_split();
+    let start = Instant::now();
+    let mut received = Vec::new();
+    let mut buf = vec![0u8; 16 * 1024];
+    let mut next = requests.iter().peekable();
+    let mut sent = 0;
+    let mut last_reply = start;
+    let mut eof = false;
+    loop {
+        // 发完之后至少等到记录里最后一段数据的时间，再等后端安静一会
+        let wake = match next.peek() {
+            Some(chunk) => start + chunk.at,
+            None => (start + last).max(last_reply + REPLY_GRACE),
+        };
+        if eof && next.peek().is_none() {
+            break;
+        }
+        tokio::select! {
+            _ = time::sleep_until(wake) => match next.next() {
+                Some(chunk) => {
+                    writer.write_all(&chunk.data).await.with_context(|| {
+                        format!("backend closed the connection after {sent} bytes were replayed")
+                    })?;
+                    sent += chunk.data.len();
+                }
+                None => break,
+            },
+            n = reader.read(&mut buf), if !eof => match n {
+                Ok(0) | Err(_) => eof = true,
+                Ok(n) => {
+                    received.extend_from_slice(&buf[..n]);
+                    last_reply = Instant::now();
+                }
+            },
+        }
+    }
+    let elapsed = start.elapsed();
+    let diverged = received
+        .iter()
+        .zip(&recorded)
+        .position(|(a, b)| a != b)
+        .or_else(|| (received.len() != recorded.len()).then(|| received.len().min(recorded.len())));
+
+    if json {
+        let report = Value::object([
+            ("sent", sent.into()),
+            ("received", received.len().into()),
+            ("recorded_received", recorded.len().into()),
+            ("diverged_at", diverged.into()),
+            ("seconds", elapsed.as_secs_f64().into()),
+        ]);
+        println!("{report}");
+        return Ok(());
+    }
+    notice!(
+        "Replayed {sent} bytes in {:.1}s, backend replied {} bytes, {} bytes in the recording",
+        "回放了 {sent} 字节，用时 {:.1} 秒，后端应答 {} 字节，记录里是 {} 字节",
+        elapsed.as_secs_f64(),
+        received.len(),
+        recorded.len()
+    );
+    match diverged {
+        Some(offset) => notice!(
+            "Replies differ from the recording starting at byte {offset}",
+            "应答从第 {offset} 字节开始和记录不同"
+        ),
+        None => notice!("Replies match the recording", "应答和记录一致"),
+    }
+    Ok(())
+}
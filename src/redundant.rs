@@ -0,0 +1,85 @@
+//! 冗余发送：客户端发出的每个 UDP 包同时发往服务端的两个地址（比如接在两家运营商上的两个 IP），
+//! 一条线路丢包时另一条线路上的副本仍能送达，用带宽换取更低的丢包率，适合对延迟敏感的游戏。
+//!
+//! 重复到达的包由 KCP 按序号丢弃，服务端不需要额外处理，只要在两个地址上都能收到（监听 `0.0.0.0`）。
+//! 服务端按 conv 和客户端地址把收到的包分给会话（见 `demux`），每个会话只认一个对端地址，
+//! 所以客户端始终从同一个套接字发出；服务端发回的包只走一条线路，客户端接受来自任意一个地址的应答。
+
+use crate::disguise;
+use crate::dns;
+use crate::udp;
+use crate::wire::Wire;
+use bytes::BytesMut;
+use kcp::{KcpConfig, KcpStream};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+
+/// 和 `KcpUdpStream::connect` 一样建立 KCP 连接，但每个包同时发往 `addr` 和 `backup`，两份都记到 `wire` 上
+pub async fn connect(
+    config: Arc<KcpConfig>,
+    addr: &str,
+    backup: &str,
+    wire: Arc<Wire>,
+) -> io::Result<(KcpStream, SocketAddr)> {
+    let addrs = [dns::lookup(addr).await?, dns::lookup(backup).await?];
+    if addrs[0].is_ipv4() != addrs[1].is_ipv4() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{addr} and {backup} must both be IPv4 or both be IPv6"),
+        ));
+    }
+    let udp = udp::bind_for(addrs[0], &config).await?;
+
+    let window = config.snd_wnd.max(8) as usize;
+    let (outgoing_tx, outgoing_rx) = mpsc::channel(window);
+    let (incoming_tx, incoming_rx) = mpsc::channel(window);
+    tokio::spawn(relay(udp, addrs, outgoing_rx, incoming_tx, wire));
+
+    let transport = kcp::transport::tokio_mpsc_stream(outgoing_tx, incoming_rx);
+    let stream =
+        KcpStream::connect::<_, BytesMut, _>(config, transport, futures::sink::drain(), None)
+            .await?;
+    Ok((stream, addrs[0]))
+}
+
+/// 在 KCP 和 UDP 套接字之间转发数据包，发出的包复制到两个地址；KCP 连接关闭后结束
+async fn relay(
+    udp: UdpSocket,
+    addrs: [SocketAddr; 2],
+    mut outgoing: mpsc::Receiver<BytesMut>,
+    incoming: mpsc::Sender<BytesMut>,
+    wire: Arc<Wire>,
+) {
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        tokio::select! {
+            packet = outgoing.recv() => {
+                let Some(packet) = packet else { break };
+                wire.segments_in(&packet);
+                let disguised = disguise::wrap(&packet);
+                // 一条线路出错（比如网卡断开）不影响另一条
+                for addr in addrs {
+                    if udp.send_to(&disguised, addr).await.is_ok() {
+                        wire.send(packet.len(), addr);
+                    }
+                }
+            }
+            received = udp.recv_from(&mut buf) => {
+                let Ok((n, from)) = received else { continue };
+                // 套接字没有 connect 到固定地址，丢弃其它来源的包
+                if !addrs.contains(&from) {
+                    continue;
+                }
+                let Some(packet) = disguise::unwrap(&buf[..n]) else { continue };
+                wire.receive(packet.len(), from);
+                wire.acks_in(packet);
+                if incoming.send(BytesMut::from(packet)).await.is_err() {
+                    break;
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,831 @@
+use crate::audit::Audit;
+use crate::circuit::{Breaker, CircuitInfo};
+use crate::class::Shaper;
+use crate::health::Contact;
+use crate::keepalive::Keepalive;
+use crate::latency::{Histogram, Percentiles};
+use crate::limit::Limit;
+use crate::multipath::{PathInfo, Paths};
+use crate::probe::{Probe, ProbeInfo};
+use crate::session::{CloseReason, Goodbye};
+use crate::wire::Wire;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::hash::{BuildHasher, RandomState};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use tokio::sync::{Notify, broadcast, watch};
+use tokio_util::sync::CancellationToken;
+
+/// 分片数量，会话按 id 的哈希分散到各个分片，登记和注销只锁一个分片
+const SHARDS: usize = 16;
+
+/// 正在运行的会话表，供管理接口查询和操作
+///
+/// 数据转发路径不访问这张表；建立和结束会话时只锁对应的分片，
+/// 会话总数用原子计数维护，查询数量不需要加锁。
+pub struct Registry {
+    shards: [Shard; SHARDS],
+    hasher: RandomState,
+    count: AtomicUsize,
+    drain: CancellationToken,
+    emptied: Notify,
+    /// 没有会话时来了新会话
+    occupied: Notify,
+    /// 客户端从长时间的休眠中醒来
+    woke: Notify,
+    /// 新会话默认开启跟踪
+    trace_all: AtomicBool,
+    /// 还没有建立、但已经要求跟踪的会话
+    trace_pending: Mutex<HashSet<String>>,
+    /// 因为 panic 而结束的会话数
+    panics: AtomicUsize,
+    /// 所有会话（包括已经结束的）转发的字节数
+    traffic: Arc<Traffic>,
+    /// 所有会话（包括已经结束的）每段数据的转发延迟
+    latency: Arc<Histogram>,
+    /// 已注册的反向隧道，按名字排序
+    tunnels: Mutex<BTreeMap<String, TunnelInfo>>,
+    /// 控制通道另一端报告的状态，按对端地址排序
+    peers: Mutex<BTreeMap<String, PeerInfo>>,
+    /// 主动关闭的会话，由控制通道转告对端
+    goodbyes: broadcast::Sender<(IpAddr, u32, Goodbye)>,
+    /// 客户端的多条线路
+    paths: OnceLock<Arc<Paths>>,
+    /// 客户端对服务端的 ping 探测
+    probe: OnceLock<Arc<Probe>>,
+    /// 客户端最近一次和服务端打交道的结果
+    contact: Contact,
+    /// 最近的连接尝试
+    audit: Audit,
+    /// 交互会话和批量会话的调度
+    shaper: OnceLock<Arc<Shaper>>,
+    /// 服务端：新会话连接的后端地址，可以通过管理接口切换
+    backend: Mutex<Option<String>>,
+    /// 服务端：后端熔断
+    breaker: OnceLock<Arc<Breaker>>,
+    /// 以下统计从启动开始累计，退出时汇总输出
+    started: Instant,
+    total: AtomicU64,
+    peak: AtomicUsize,
+    /// 按关闭原因统计的会话数，没有正常结束的记为 `unfinished`
+    closes: Mutex<BTreeMap<&'static str, u64>>,
+}
+
+#[derive(Default)]
+struct Shard {
+    sessions: Mutex<HashMap<String, Entry>>,
+}
+
+struct Entry {
+    peer: SocketAddr,
+    conv: Option<u32>,
+    started: Instant,
+    stop: watch::Sender<Option<CloseReason>>,
+    trace: Arc<AtomicBool>,
+    traffic: Arc<Traffic>,
+    /// 线路上收发的 UDP 字节数
+    wire: Arc<Wire>,
+    latency: Arc<Histogram>,
+    bulk: Arc<AtomicBool>,
+    limit: Arc<Limit>,
+    /// 会话连接的后端
+    backend: Option<String>,
+    /// 会话的标签，见 `Registration::tag`
+    tags: Vec<String>,
+}
+
+/// 转发的字节数，会话运行中随时更新
+#[derive(Default)]
+pub struct Traffic {
+    /// TCP -> KCP 方向
+    sent: AtomicU64,
+    /// KCP -> TCP 方向
+    received: AtomicU64,
+}
+
+impl Traffic {
+    pub fn add_sent(&self, bytes: u64) {
+        self.sent.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn add_received(&self, bytes: u64) {
+        self.received.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn sent(&self) -> u64 {
+        self.sent.load(Ordering::Relaxed)
+    }
+
+    pub fn received(&self) -> u64 {
+        self.received.load(Ordering::Relaxed)
+    }
+}
+
+/// 会话列表中的一项
+pub struct SessionInfo {
+    pub id: String,
+    pub peer: SocketAddr,
+    pub conv: Option<u32>,
+    pub age: Duration,
+    pub traced: bool,
+    /// 按批量会话处理
+    pub bulk: bool,
+    /// 每个方向的带宽上限，字节每秒，0 表示不限制
+    pub limit: u64,
+    pub backend: Option<String>,
+    pub tags: Vec<String>,
+    pub sent: u64,
+    pub received: u64,
+    /// 线路上收发的 UDP 字节数，见 `wire`
+    pub wire_sent: u64,
+    pub wire_received: u64,
+    /// 发出的 KCP 数据段和其中重传的
+    pub segments: u64,
+    pub retransmits: u64,
+    /// 从确认段估算的 KCP 往返时间，见 `wire`
+    pub rtt: Option<Duration>,
+    /// 还没有转发过数据时为 `None`
+    pub latency: Option<Percentiles>,
+}
+
+/// 反向隧道列表中的一项
+#[derive(Clone)]
+pub struct TunnelInfo {
+    pub name: String,
+    /// 服务端开放的公网端口
+    pub port: u16,
+    /// 访客连接的地址，指定了域名时访客需要用域名访问
+    pub public_addr: String,
+    pub host: Option<String>,
+    /// 服务端一侧是注册隧道的客户端，客户端一侧是服务端
+    pub peer: String,
+}
+
+/// 控制通道另一端的状态
+#[derive(Clone, Default)]
+pub struct PeerInfo {
+    /// 服务端一侧是客户端的地址，客户端一侧是服务端的地址
+    pub addr: String,
+    /// 客户端在握手里带上的身份，只在服务端一侧有
+    pub identity: Option<String>,
+    /// 以下是对端最近一次报告的会话数和转发字节数
+    pub sessions: u64,
+    pub sent: u64,
+    pub received: u64,
+    /// 对端通知即将关闭时的原因
+    pub closing: Option<String>,
+}
+
+/// 会话运行中需要响应的控制信号
+pub struct Control {
+    /// 会话 id，用于日志
+    pub id: String,
+    /// 收到停止请求时给出停止原因
+    pub stop: watch::Receiver<Option<CloseReason>>,
+    /// 是否以十六进制打印经过的数据
+    pub trace: Arc<AtomicBool>,
+    /// 依次是这个会话的、所有会话合计的，以及会话所在线路（如果有）的转发字节数
+    pub traffic: Vec<Arc<Traffic>>,
+    /// 依次是这个会话的和所有会话合计的转发延迟
+    pub latency: Vec<Arc<Histogram>>,
+    /// 会话结束时用来告知对端原因，KCP 连接还没建立时为 `None`
+    pub farewell: Option<Farewell>,
+    /// 是否按批量会话处理，会话运行中可能改变
+    pub bulk: Arc<AtomicBool>,
+    pub shaper: Option<Arc<Shaper>>,
+    /// 通过管理接口设置的带宽上限
+    pub limit: Arc<Limit>,
+    /// 空闲时用来发送保活包，没有开启保活或者不是直连时为 `None`
+    pub keepalive: Option<Arc<Keepalive>>,
+}
+
+/// 用来告知对端某个会话的关闭原因
+pub struct Farewell {
+    goodbyes: broadcast::Sender<(IpAddr, u32, Goodbye)>,
+    /// 服务端一侧是 KCP 对端的 IP，用来找到同一个客户端的控制通道
+    peer: IpAddr,
+    conv: u32,
+}
+
+impl Farewell {
+    /// 关闭原因需要告知对端时交给控制通道，有控制通道接手时返回 true
+    pub fn send(&self, reason: CloseReason) -> bool {
+        reason
+            .goodbye()
+            .is_some_and(|reason| self.goodbyes.send((self.peer, self.conv, reason)).is_ok())
+    }
+}
+
+/// 会话在表中的登记，drop 时自动移除
+pub struct Registration<'a> {
+    registry: &'a Registry,
+    shard: &'a Shard,
+    id: String,
+    stop: watch::Receiver<Option<CloseReason>>,
+    trace: Arc<AtomicBool>,
+    traffic: Arc<Traffic>,
+    latency: Arc<Histogram>,
+    bulk: Arc<AtomicBool>,
+    limit: Arc<Limit>,
+    path_traffic: OnceLock<Arc<Traffic>>,
+    keepalive: OnceLock<Arc<Keepalive>>,
+    closed: AtomicBool,
+}
+
+impl Registration<'_> {
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// 记录会话的关闭原因，没有记录就注销的会话算作没有正常结束
+    pub fn closed(&self, reason: CloseReason) {
+        self.closed.store(true, Ordering::Relaxed);
+        self.registry.record_close(reason.as_str());
+    }
+
+    /// 连接交给反向隧道使用，不是普通会话，不计入关闭原因的统计
+    pub fn hand_off(&self) {
+        self.closed.store(true, Ordering::Relaxed);
+    }
+
+    /// 已经收到的停止请求，比如握手期间对端告知拒绝了这个会话
+    pub fn stop_reason(&self) -> Option<CloseReason> {
+        *self.stop.borrow()
+    }
+
+    /// KCP 连接建立后记录它的 conv
+    pub fn set_conv(&self, conv: u32) {
+        if let Some(entry) = self.shard.sessions.lock().unwrap().get_mut(&self.id) {
+            entry.conv = Some(conv);
+        }
+    }
+
+    /// 连接到后端后记录它的地址，切换后端时据此找到还在旧后端上的会话
+    pub fn set_backend(&self, addr: &str) {
+        if let Some(entry) = self.shard.sessions.lock().unwrap().get_mut(&self.id) {
+            entry.backend = Some(addr.to_string());
+        }
+    }
+
+    /// 给会话打上标签，管理接口和网页面板可以按标签筛选、排序会话，见 `add_tag`
+    pub fn tag(&self, tag: String) {
+        if let Some(entry) = self.shard.sessions.lock().unwrap().get_mut(&self.id) {
+            add_tag(&mut entry.tags, tag);
+        }
+    }
+
+    /// 统计会话在线路上收发的 UDP 字节数用的计数器，建立 KCP 连接时交给传输层
+    pub fn wire(&self) -> Arc<Wire> {
+        self.shard
+            .sessions
+            .lock()
+            .unwrap()
+            .get(&self.id)
+            .map_or_else(Arc::default, |entry| entry.wire.clone())
+    }
+
+    /// 服务端：改用监听器为这个连接建立的计数器
+    pub fn set_wire(&self, wire: Arc<Wire>) {
+        if let Some(entry) = self.shard.sessions.lock().unwrap().get_mut(&self.id) {
+            entry.wire = wire;
+        }
+    }
+
+    /// 会话经过的数据同时计入所在线路的统计
+    pub fn count_traffic(&self, traffic: Arc<Traffic>) {
+        let _ = self.path_traffic.set(traffic);
+    }
+
+    /// KCP 连接建立后记录发送保活包的方式
+    pub fn set_keepalive(&self, keepalive: Keepalive) {
+        let _ = self.keepalive.set(Arc::new(keepalive));
+    }
+
+    pub fn control(&self) -> Control {
+        let mut traffic = vec![self.traffic.clone(), self.registry.traffic.clone()];
+        traffic.extend(self.path_traffic.get().cloned());
+        let farewell = self
+            .shard
+            .sessions
+            .lock()
+            .unwrap()
+            .get(&self.id)
+            .and_then(|entry| Some((entry.peer, entry.conv?)))
+            .map(|(peer, conv)| Farewell {
+                goodbyes: self.registry.goodbyes.clone(),
+                peer: peer.ip(),
+                conv,
+            });
+        Control {
+            id: self.id.clone(),
+            stop: self.stop.clone(),
+            trace: self.trace.clone(),
+            traffic,
+            latency: vec![self.latency.clone(), self.registry.latency.clone()],
+            farewell,
+            bulk: self.bulk.clone(),
+            shaper: self.registry.shaper.get().cloned(),
+            limit: self.limit.clone(),
+            keepalive: self.keepalive.get().cloned(),
+        }
+    }
+}
+
+impl Drop for Registration<'_> {
+    fn drop(&mut self) {
+        if !self.closed.load(Ordering::Relaxed) {
+            self.registry.record_close("unfinished");
+        }
+        if self
+            .shard
+            .sessions
+            .lock()
+            .unwrap()
+            .remove(&self.id)
+            .is_some()
+            && self.registry.count.fetch_sub(1, Ordering::AcqRel) == 1
+        {
+            self.registry.emptied.notify_waiters();
+        }
+    }
+}
+
+impl Default for Registry {
+    fn default() -> Self {
+        Self {
+            shards: std::array::from_fn(|_| Shard::default()),
+            hasher: RandomState::new(),
+            count: AtomicUsize::new(0),
+            drain: CancellationToken::new(),
+            emptied: Notify::new(),
+            occupied: Notify::new(),
+            woke: Notify::new(),
+            trace_all: AtomicBool::new(false),
+            trace_pending: Mutex::default(),
+            panics: AtomicUsize::new(0),
+            traffic: Arc::default(),
+            latency: Arc::default(),
+            tunnels: Mutex::default(),
+            peers: Mutex::default(),
+            goodbyes: broadcast::channel(256).0,
+            paths: OnceLock::new(),
+            probe: OnceLock::new(),
+            contact: Contact::default(),
+            audit: Audit::default(),
+            shaper: OnceLock::new(),
+            backend: Mutex::default(),
+            breaker: OnceLock::new(),
+            started: Instant::now(),
+            total: AtomicU64::new(0),
+            peak: AtomicUsize::new(0),
+            closes: Mutex::default(),
+        }
+    }
+}
+
+impl Registry {
+    fn shard(&self, id: &str) -> &Shard {
+        &self.shards[self.hasher.hash_one(id) as usize % SHARDS]
+    }
+
+    pub fn register(&self, id: &str, peer: SocketAddr) -> Registration<'_> {
+        let (stop, stop_rx) = watch::channel(None);
+        let shard = self.shard(id);
+        let traced =
+            self.trace_all.load(Ordering::Relaxed) || self.trace_pending.lock().unwrap().remove(id);
+        let trace = Arc::new(AtomicBool::new(traced));
+        let traffic = Arc::new(Traffic::default());
+        let latency = Arc::new(Histogram::default());
+        let bulk = Arc::new(AtomicBool::new(false));
+        let limit = Arc::new(Limit::default());
+        let entry = Entry {
+            peer,
+            conv: None,
+            started: Instant::now(),
+            stop,
+            trace: trace.clone(),
+            traffic: traffic.clone(),
+            wire: Arc::default(),
+            latency: latency.clone(),
+            bulk: bulk.clone(),
+            limit: limit.clone(),
+            backend: None,
+            tags: Vec::new(),
+        };
+        if shard
+            .sessions
+            .lock()
+            .unwrap()
+            .insert(id.to_string(), entry)
+            .is_none()
+        {
+            let count = self.count.fetch_add(1, Ordering::AcqRel) + 1;
+            self.peak.fetch_max(count, Ordering::Relaxed);
+            if count == 1 {
+                self.occupied.notify_waiters();
+            }
+        }
+        self.total.fetch_add(1, Ordering::Relaxed);
+        Registration {
+            registry: self,
+            shard,
+            id: id.to_string(),
+            stop: stop_rx,
+            trace,
+            traffic,
+            latency,
+            bulk,
+            limit,
+            path_traffic: OnceLock::new(),
+            keepalive: OnceLock::new(),
+            closed: AtomicBool::new(false),
+        }
+    }
+
+    pub fn list(&self) -> Vec<SessionInfo> {
+        let mut list = Vec::with_capacity(self.len());
+        for shard in &self.shards {
+            list.extend(
+                shard
+                    .sessions
+                    .lock()
+                    .unwrap()
+                    .iter()
+                    .map(|(id, entry)| SessionInfo {
+                        id: id.clone(),
+                        peer: entry.peer,
+                        conv: entry.conv,
+                        age: entry.started.elapsed(),
+                        traced: entry.trace.load(Ordering::Relaxed),
+                        bulk: entry.bulk.load(Ordering::Relaxed),
+                        limit: entry.limit.rate(),
+                        backend: entry.backend.clone(),
+                        tags: entry.tags.clone(),
+                        sent: entry.traffic.sent(),
+                        received: entry.traffic.received(),
+                        wire_sent: entry.wire.sent(),
+                        wire_received: entry.wire.received(),
+                        segments: entry.wire.segments(),
+                        retransmits: entry.wire.retransmits(),
+                        rtt: entry.wire.rtt(),
+                        latency: entry.latency.percentiles(),
+                    }),
+            );
+        }
+        list.sort_by_key(|session| Reverse(session.age));
+        list
+    }
+
+    /// 查找使用指定 conv 的会话
+    pub fn find_conv(&self, conv: u32) -> Option<String> {
+        self.shards.iter().find_map(|shard| {
+            shard
+                .sessions
+                .lock()
+                .unwrap()
+                .iter()
+                .find(|(_, entry)| entry.conv == Some(conv))
+                .map(|(id, _)| id.clone())
+        })
+    }
+
+    /// 对端告知关闭了某个会话：找到使用这个 conv 的会话并关闭，返回它的 id；
+    /// 指定了 `peer` 时只关闭来自这个 IP 的会话，免得一个客户端关掉别人的会话
+    pub fn stop_conv(&self, conv: u32, peer: Option<IpAddr>, reason: Goodbye) -> Option<String> {
+        self.shards.iter().find_map(|shard| {
+            let sessions = shard.sessions.lock().unwrap();
+            let (id, entry) = sessions.iter().find(|(_, entry)| {
+                entry.conv == Some(conv) && peer.is_none_or(|peer| entry.peer.ip() == peer)
+            })?;
+            entry.stop.send_replace(Some(CloseReason::Remote(reason)));
+            Some(id.clone())
+        })
+    }
+
+    /// 告知对端一个还没有登记就被拒绝的会话的原因
+    pub fn say_goodbye(&self, peer: IpAddr, conv: u32, reason: Goodbye) {
+        let _ = self.goodbyes.send((peer, conv, reason));
+    }
+
+    /// 订阅主动关闭的会话，控制通道据此告知对端
+    pub fn goodbyes(&self) -> broadcast::Receiver<(IpAddr, u32, Goodbye)> {
+        self.goodbyes.subscribe()
+    }
+
+    pub fn len(&self) -> usize {
+        self.count.load(Ordering::Acquire)
+    }
+
+    /// 记录一个因为 panic 而结束的会话
+    pub fn record_panic(&self) {
+        self.panics.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn panics(&self) -> usize {
+        self.panics.load(Ordering::Relaxed)
+    }
+
+    fn record_close(&self, reason: &'static str) {
+        *self.closes.lock().unwrap().entry(reason).or_default() += 1;
+    }
+
+    /// 启动以来的运行时间
+    pub fn uptime(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    /// 启动以来建立过的会话数
+    pub fn total(&self) -> u64 {
+        self.total.load(Ordering::Relaxed)
+    }
+
+    /// 同时运行的会话数的最大值
+    pub fn peak(&self) -> usize {
+        self.peak.load(Ordering::Relaxed)
+    }
+
+    /// 已结束的会话按关闭原因分类的数量
+    pub fn closes(&self) -> Vec<(&'static str, u64)> {
+        let closes = self.closes.lock().unwrap();
+        closes.iter().map(|(&reason, &n)| (reason, n)).collect()
+    }
+
+    /// 所有会话合计的转发字节数
+    pub fn traffic(&self) -> &Traffic {
+        &self.traffic
+    }
+
+    /// 所有会话合计的转发延迟，还没有转发过数据时为 `None`
+    pub fn latency(&self) -> Option<Percentiles> {
+        self.latency.percentiles()
+    }
+
+    /// 记录注册成功的反向隧道，同名的旧记录被替换
+    pub fn add_tunnel(&self, tunnel: TunnelInfo) {
+        self.tunnels
+            .lock()
+            .unwrap()
+            .insert(tunnel.name.clone(), tunnel);
+    }
+
+    pub fn remove_tunnel(&self, name: &str) {
+        self.tunnels.lock().unwrap().remove(name);
+    }
+
+    pub fn tunnels(&self) -> Vec<TunnelInfo> {
+        self.tunnels.lock().unwrap().values().cloned().collect()
+    }
+
+    /// 更新控制通道另一端的状态，没有记录时先加上
+    pub fn update_peer(&self, addr: &str, update: impl FnOnce(&mut PeerInfo)) {
+        let mut peers = self.peers.lock().unwrap();
+        let peer = peers.entry(addr.to_string()).or_insert_with(|| PeerInfo {
+            addr: addr.to_string(),
+            ..PeerInfo::default()
+        });
+        update(peer);
+    }
+
+    pub fn remove_peer(&self, addr: &str) {
+        self.peers.lock().unwrap().remove(addr);
+    }
+
+    pub fn peers(&self) -> Vec<PeerInfo> {
+        self.peers.lock().unwrap().values().cloned().collect()
+    }
+
+    /// 记录客户端的多条线路，供管理接口查询
+    pub fn set_paths(&self, paths: Arc<Paths>) {
+        let _ = self.paths.set(paths);
+    }
+
+    pub fn paths(&self) -> Vec<PathInfo> {
+        self.paths
+            .get()
+            .map(|paths| paths.list())
+            .unwrap_or_default()
+    }
+
+    /// 设置交互会话和批量会话的调度，之后建立的会话生效
+    pub fn set_shaper(&self, shaper: Arc<Shaper>) {
+        let _ = self.shaper.set(shaper);
+    }
+
+    /// 服务端：设置新会话连接的后端地址，返回之前的地址
+    pub fn set_backend(&self, addr: &str) -> Option<String> {
+        let old = self.backend.lock().unwrap().replace(addr.to_string());
+        if let (Some(breaker), Some(old)) = (self.breaker.get(), &old) {
+            breaker.forget(old);
+        }
+        old
+    }
+
+    /// 记录后端熔断，供管理接口查询
+    pub fn set_breaker(&self, breaker: Arc<Breaker>) {
+        let _ = self.breaker.set(breaker);
+    }
+
+    /// 各个后端的熔断状态，没有开启熔断时为空
+    pub fn circuits(&self) -> Vec<CircuitInfo> {
+        self.breaker
+            .get()
+            .map(|breaker| breaker.info())
+            .unwrap_or_default()
+    }
+
+    pub fn backend(&self) -> Option<String> {
+        self.backend.lock().unwrap().clone()
+    }
+
+    /// 连接到指定后端的会话数
+    pub fn count_backend(&self, addr: &str) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| {
+                shard
+                    .sessions
+                    .lock()
+                    .unwrap()
+                    .values()
+                    .filter(|entry| entry.backend.as_deref() == Some(addr))
+                    .count()
+            })
+            .sum()
+    }
+
+    /// 关闭连接到指定后端的会话，返回关闭的数量
+    pub fn stop_backend(&self, addr: &str, reason: CloseReason) -> usize {
+        let mut stopped = 0;
+        for shard in &self.shards {
+            for entry in shard.sessions.lock().unwrap().values() {
+                if entry.backend.as_deref() == Some(addr) {
+                    entry.stop.send_replace(Some(reason));
+                    stopped += 1;
+                }
+            }
+        }
+        stopped
+    }
+
+    /// 记录线路探测，供管理接口查询
+    pub fn set_probe(&self, probe: Arc<Probe>) {
+        let _ = self.probe.set(probe);
+    }
+
+    pub fn probe(&self) -> Option<ProbeInfo> {
+        self.probe.get().map(|probe| probe.info())
+    }
+
+    pub fn contact(&self) -> &Contact {
+        &self.contact
+    }
+
+    pub fn audit(&self) -> &Audit {
+        &self.audit
+    }
+
+    /// 请求关闭指定会话，会话不存在时返回 false
+    pub fn stop(&self, id: &str, reason: CloseReason) -> bool {
+        match self.shard(id).sessions.lock().unwrap().get(id) {
+            Some(entry) => {
+                entry.stop.send_replace(Some(reason));
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// 请求关闭所有会话
+    pub fn stop_all(&self, reason: CloseReason) {
+        for shard in &self.shards {
+            for entry in shard.sessions.lock().unwrap().values() {
+                entry.stop.send_replace(Some(reason));
+            }
+        }
+    }
+
+    /// 开启或关闭指定会话的数据跟踪，会话不存在时返回 false
+    pub fn trace(&self, id: &str, on: bool) -> bool {
+        match self.shard(id).sessions.lock().unwrap().get(id) {
+            Some(entry) => {
+                entry.trace.store(on, Ordering::Relaxed);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// 设置指定会话每个方向的带宽上限，字节每秒，0 表示取消；会话不存在时返回 false
+    pub fn limit(&self, id: &str, rate: u64) -> bool {
+        match self.shard(id).sessions.lock().unwrap().get(id) {
+            Some(entry) => {
+                entry.limit.set(rate);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// 给指定会话打上标签，会话不存在时返回 false
+    pub fn tag(&self, id: &str, tag: String) -> bool {
+        match self.shard(id).sessions.lock().unwrap().get_mut(id) {
+            Some(entry) => {
+                add_tag(&mut entry.tags, tag);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// 去掉指定会话上名为 `key` 的标签，会话不存在或者没有这个标签时返回 false
+    pub fn untag(&self, id: &str, key: &str) -> bool {
+        self.shard(id)
+            .sessions
+            .lock()
+            .unwrap()
+            .get_mut(id)
+            .is_some_and(|entry| {
+                let before = entry.tags.len();
+                entry.tags.retain(|tag| tag_key(tag) != key);
+                entry.tags.len() < before
+            })
+    }
+
+    /// 会话建立时再开始跟踪，用于启动参数里指定的会话
+    pub fn trace_later(&self, id: &str) {
+        self.trace_pending.lock().unwrap().insert(id.to_string());
+    }
+
+    /// 开启或关闭所有现有会话和之后新会话的数据跟踪
+    pub fn trace_all(&self, on: bool) {
+        self.trace_all.store(on, Ordering::Relaxed);
+        if !on {
+            self.trace_pending.lock().unwrap().clear();
+        }
+        for shard in &self.shards {
+            for entry in shard.sessions.lock().unwrap().values() {
+                entry.trace.store(on, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// 进入排空状态：不再接受新会话，等现有会话自然结束
+    pub fn start_drain(&self) {
+        self.drain.cancel();
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.drain.is_cancelled()
+    }
+
+    /// 直到进入排空状态才返回
+    pub async fn draining(&self) {
+        self.drain.cancelled().await
+    }
+
+    /// 直到没有会话在运行才返回
+    pub async fn wait_empty(&self) {
+        loop {
+            let emptied = self.emptied.notified();
+            if self.len() == 0 {
+                return;
+            }
+            emptied.await;
+        }
+    }
+
+    /// 通知控制通道等从休眠中醒来了或者本机地址变了，需要重新建立
+    pub fn wake(&self) {
+        self.woke.notify_waiters();
+    }
+
+    /// 直到从长时间的休眠中醒来或者本机地址变了才返回
+    pub async fn woke(&self) {
+        self.woke.notified().await
+    }
+
+    /// 直到有会话在运行才返回
+    pub async fn wait_occupied(&self) {
+        loop {
+            let occupied = self.occupied.notified();
+            if self.len() > 0 {
+                return;
+            }
+            occupied.await;
+        }
+    }
+}
+
+/// 标签写作 `key=value` 或者只有 `key`，同一个会话上每个 `key` 只有一个标签，新的替换旧的
+fn add_tag(tags: &mut Vec<String>, tag: String) {
+    tags.retain(|old| tag_key(old) != tag_key(&tag));
+    tags.push(tag);
+    tags.sort();
+}
+
+pub fn tag_key(tag: &str) -> &str {
+    tag.split_once('=').map_or(tag, |(key, _)| key)
+}
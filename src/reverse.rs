@@ -0,0 +1,901 @@
+//! 反向隧道：客户端把自己这边的服务注册到服务端，服务端在公网端口上替它接受连接，
+//! 适合在没有公网 IP、不能做端口映射的家里开服。
+//!
+//! 客户端先建立一条控制通道（握手时带上 `Register`），服务端按请求的端口开始监听。
+//! 有访客连入时，服务端通过控制通道发送 `Open { token }`，客户端再发起一条新的 KCP 连接，
+//! 握手时带上 `Attach { token }`，服务端把它和等待中的访客配对，之后按普通会话转发。
+//! 每个访客占用一条独立的 KCP 连接，和正向转发一样。
+//!
+//! 多条隧道可以注册同一个公网端口，各自带上不同的域名，服务端识别访客访问的域名（见 `sniff`）
+//! 后分给对应的隧道；端口上不带域名的那条隧道接收其余的访客。
+//!
+//! 注册要带上客户端的身份和口令，和服务端 `--reverse-auth` 里的一项对上才行，否则谁都能让服务端在公网端口上监听。
+//! 隧道断开后，它的端口和域名在 `RESERVE_TIME` 内只留给同一个身份，客户端重连期间不会被别人抢走。
+//!
+//! 访客和正向转发的客户端一样要经过新会话限速、建立中会话的名额、内存预算和国家或地区的检查（见 `Gates`）。
+
+use crate::audit::Outcome;
+use crate::bind;
+use crate::budget::Budget;
+use crate::dns;
+use crate::geoip;
+use crate::json::Value;
+use crate::pcap::Capture;
+use crate::pending::{self, Pending, Slot};
+use crate::protocol::{self, FEATURE_REVERSE, Message, Request};
+use crate::redundant;
+use crate::registry::{Registry, TunnelInfo};
+use crate::session::{Role, SessionOptions, handle_session};
+use crate::simulate::{self, Conditions};
+use crate::sniff;
+use crate::throttle::Throttle;
+use crate::udp;
+use crate::webhook;
+use crate::wire::Wire;
+use anyhow::{Context, bail};
+use kcp::{KcpConfig, KcpStream};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::net::SocketAddr;
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+use tokio::io::{self, AsyncReadExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tokio::sync::oneshot;
+use tokio::task::AbortHandle;
+use tokio::time::timeout;
+use tokio_util::task::TaskTracker;
+use uuid::Uuid;
+
+/// 服务端通知客户端后，等待它接入访客连接的时间
+const ATTACH_TIMEOUT: Duration = Duration::from_secs(10);
+/// 客户端在控制通道上发送保活消息的间隔，要明显短于 KCP 的会话过期时间
+const PING_INTERVAL: Duration = Duration::from_secs(20);
+/// 控制通道断开后客户端重新注册的间隔
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+/// 隧道断开后，它的端口和域名为原来的身份保留这么久
+const RESERVE_TIME: Duration = Duration::from_secs(120);
+/// 每条隧道最多积压这么多还没发给客户端的接入通知，控制通道跟不上时多出来的访客直接断开
+const MAX_QUEUED_OPENS: usize = 64;
+
+/// 端口列表，格式如 `25565,30000-30100`
+#[derive(Clone)]
+pub struct Ports(Vec<RangeInclusive<u16>>);
+
+impl Ports {
+    fn contains(&self, port: u16) -> bool {
+        self.0.iter().any(|range| range.contains(&port))
+    }
+
+    fn iter(&self) -> impl Iterator<Item = u16> + '_ {
+        self.0.iter().flat_map(|range| range.clone())
+    }
+}
+
+impl FromStr for Ports {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let mut ranges = Vec::new();
+        for part in s.split(',').map(str::trim).filter(|part| !part.is_empty()) {
+            let (start, end) = part.split_once('-').unwrap_or((part, part));
+            let start: u16 = start
+                .trim()
+                .parse()
+                .with_context(|| format!("invalid port {part:?}"))?;
+            let end: u16 = end
+                .trim()
+                .parse()
+                .with_context(|| format!("invalid port {part:?}"))?;
+            if start == 0 || start > end {
+                bail!("invalid port range {part:?}");
+            }
+            ranges.push(start..=end);
+        }
+        if ranges.is_empty() {
+            bail!("empty port list");
+        }
+        Ok(Ports(ranges))
+    }
+}
+
+/// 建立转发会话需要的共享状态
+#[derive(Clone)]
+pub struct Sessions {
+    pub registry: Arc<Registry>,
+    pub budget: Arc<Budget>,
+    pub capture: Option<Arc<Capture>>,
+    pub tracker: TaskTracker,
+    pub options: SessionOptions,
+    pub footprint: usize,
+}
+
+/// 服务端：访客建立会话前要经过的检查，限速和名额与正向转发共用
+pub struct Gates {
+    pub throttle: Option<Arc<Throttle>>,
+    pub pending: Option<Arc<Pending>>,
+    pub countries: geoip::Policy,
+}
+
+/// 刚连入公网端口、还没分给隧道的访客
+struct Visitor {
+    stream: TcpStream,
+    addr: SocketAddr,
+    session_id: String,
+    country: Option<String>,
+}
+
+/// 客户端为访客发起的连接和它的 UDP 流量计数器
+type Attached = (KcpStream, Arc<Wire>);
+
+/// 断开的隧道留下的端口和域名，到保留给的身份和到期时间
+type Reservations = HashMap<(u16, Option<String>), (String, Instant)>;
+
+/// 解析 `--reverse-auth` 的一项 `身份=口令`
+pub fn parse_credential(s: &str) -> Result<(String, String), String> {
+    let (identity, secret) = s
+        .split_once('=')
+        .ok_or("expected NAME=SECRET, for example alice=<secret>")?;
+    Ok((protocol::parse_identity(identity)?, parse_secret(secret)?))
+}
+
+/// 检查口令：握手里用一个字节记长度，最长 255 字节
+pub fn parse_secret(s: &str) -> Result<String, String> {
+    if s.is_empty() || s.len() > u8::MAX as usize {
+        return Err("secret must be 1 to 255 bytes long".to_string());
+    }
+    Ok(s.to_string())
+}
+
+/// 比较口令，耗时只和长度有关，免得按响应时间逐字节猜出口令
+fn same_secret(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// 客户端注册隧道时的请求
+pub struct Claim {
+    pub name: String,
+    pub port: u16,
+    pub host: Option<String>,
+    /// 客户端的身份和口令，按 `--reverse-auth` 核对
+    pub identity: Option<String>,
+    pub secret: Option<String>,
+}
+
+/// 服务端：已注册的反向隧道和等待客户端接入的访客
+pub struct Hub {
+    bind_host: String,
+    ports: Ports,
+    /// 身份到口令
+    auth: HashMap<String, String>,
+    /// 正在监听的公网端口
+    listeners: Mutex<HashMap<u16, Listener>>,
+    /// 断开的隧道留下的端口和域名，保留给原来的身份，到期时间之前别的身份不能注册
+    reserved: Mutex<Reservations>,
+    /// 让注册依次进行，避免两条隧道同时为同一个端口开始监听
+    opening: tokio::sync::Mutex<()>,
+    pending: Mutex<HashMap<u64, oneshot::Sender<Attached>>>,
+    gates: Gates,
+    sessions: Sessions,
+}
+
+/// 一个公网端口，可以按访客访问的域名分给多条隧道
+struct Listener {
+    /// 域名到隧道，`None` 接收没有指定域名或者没有匹配到域名的访客
+    routes: HashMap<Option<String>, Route>,
+    accept: AbortHandle,
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        self.accept.abort();
+    }
+}
+
+#[derive(Clone)]
+struct Route {
+    name: String,
+    /// 注册这条隧道的客户端身份
+    identity: String,
+    /// 让控制通道通知客户端接入访客
+    open: mpsc::Sender<u64>,
+}
+
+impl Hub {
+    pub fn new(
+        bind_host: String,
+        ports: Ports,
+        auth: HashMap<String, String>,
+        gates: Gates,
+        sessions: Sessions,
+    ) -> Self {
+        Self {
+            bind_host,
+            ports,
+            auth,
+            listeners: Mutex::default(),
+            reserved: Mutex::default(),
+            opening: tokio::sync::Mutex::default(),
+            pending: Mutex::default(),
+            gates,
+            sessions,
+        }
+    }
+
+    /// 处理一条注册隧道的控制通道，直到它断开或者进入排空状态
+    pub async fn serve(self: Arc<Self>, control: KcpStream, peer: SocketAddr, claim: Claim) {
+        let name = claim.name.clone();
+        let (mut reader, mut writer) = io::split(control);
+        let (open_tx, mut open_rx) = mpsc::channel(MAX_QUEUED_OPENS);
+        let (port, host, identity) = match self.open(claim, open_tx).await {
+            Ok(opened) => opened,
+            Err(e) => {
+                warn!(
+                    "Reverse tunnel {name:?} from {peer} rejected: {e:#}",
+                    "拒绝 {peer} 注册反向隧道 {name:?}：{e:#}"
+                );
+                let reason = format!("{e:#}");
+                // 关闭 KCP 连接时还没送达的数据会被丢弃，所以等客户端读到拒绝原因后先断开
+                if protocol::write_message(&mut writer, &Message::Rejected { reason })
+                    .await
+                    .is_ok()
+                {
+                    let _ = timeout(ATTACH_TIMEOUT, reader.read_u8()).await;
+                }
+                return;
+            }
+        };
+        if protocol::write_message(&mut writer, &Message::Registered { port })
+            .await
+            .is_ok()
+        {
+            match &host {
+                Some(host) => notice!(
+                    "Reverse tunnel {name:?} registered by {identity} from {peer}, public port {}:{port} for hostname {host}",
+                    "{identity}（{peer}）注册了反向隧道 {name:?}，公网端口 {}:{port}，域名 {host}",
+                    self.bind_host
+                ),
+                None => notice!(
+                    "Reverse tunnel {name:?} registered by {identity} from {peer}, public port {}:{port}",
+                    "{identity}（{peer}）注册了反向隧道 {name:?}，公网端口 {}:{port}",
+                    self.bind_host
+                ),
+            }
+            self.sessions.registry.add_tunnel(TunnelInfo {
+                name: name.clone(),
+                port,
+                public_addr: format!("{}:{port}", self.bind_host),
+                host: host.clone(),
+                peer: peer.to_string(),
+            });
+            let closed = async {
+                while let Ok(Message::Ping) = protocol::read_message(&mut reader).await {}
+            };
+            tokio::pin!(closed);
+            loop {
+                let token = tokio::select! {
+                    Some(token) = open_rx.recv() => token,
+                    _ = &mut closed => break,
+                    _ = self.sessions.registry.draining() => break,
+                };
+                if protocol::write_message(&mut writer, &Message::Open { token })
+                    .await
+                    .is_err()
+                {
+                    self.pending.lock().unwrap().remove(&token);
+                    break;
+                }
+            }
+        }
+        self.sessions.registry.remove_tunnel(&name);
+        if self.close(port, &host) {
+            notice!(
+                "Reverse tunnel {name:?} closed, public port {port} released",
+                "反向隧道 {name:?} 已关闭，释放公网端口 {port}"
+            );
+        } else {
+            notice!("Reverse tunnel {name:?} closed", "反向隧道 {name:?} 已关闭");
+        }
+    }
+
+    /// 核对身份和口令，检查名字、端口和域名，把隧道加到公网端口上，这个端口还没有监听时开始监听；
+    /// 端口为 0 时从允许的端口里挑一个空闲的。返回实际的端口、规范化后的域名和客户端的身份
+    async fn open(
+        self: &Arc<Self>,
+        claim: Claim,
+        open: mpsc::Sender<u64>,
+    ) -> anyhow::Result<(u16, Option<String>, String)> {
+        let Claim {
+            name,
+            port,
+            host,
+            identity,
+            secret,
+        } = claim;
+        let identity = self.authenticate(identity, secret)?;
+        if port != 0 && !self.ports.contains(port) {
+            bail!("port {port} is not allowed by the server");
+        }
+        let host = match host {
+            Some(host) => Some(
+                sniff::normalize(&host).with_context(|| format!("invalid hostname {host:?}"))?,
+            ),
+            None => None,
+        };
+        let route = Route {
+            name: name.clone(),
+            identity: identity.clone(),
+            open,
+        };
+        let _opening = self.opening.lock().await;
+        if port != 0
+            && let Some(owner) = self.reserved_for(port, &host)
+            && owner != identity
+        {
+            bail!(
+                "port {port} ({}) was just released by another client and is reserved for it for a while",
+                host.as_deref().unwrap_or("all hostnames")
+            );
+        }
+        {
+            let mut listeners = self.listeners.lock().unwrap();
+            if listeners
+                .values()
+                .flat_map(|listener| listener.routes.values())
+                .any(|route| route.name == name)
+            {
+                bail!("tunnel name {name:?} is already registered");
+            }
+            if let Some(listener) = listeners.get_mut(&port) {
+                if let Some(used) = listener.routes.get(&host) {
+                    bail!(
+                        "port {port} is already used by tunnel {:?} for {}",
+                        used.name,
+                        host.as_deref().unwrap_or("all hostnames")
+                    );
+                }
+                listener.routes.insert(host.clone(), route);
+                self.reserved.lock().unwrap().remove(&(port, host.clone()));
+                return Ok((port, host, identity));
+            }
+        }
+        let (port, listener) = match port {
+            0 => self.allocate(&identity).await?,
+            port => {
+                let listener = TcpListener::bind((self.bind_host.as_str(), port))
+                    .await
+                    .with_context(|| format!("failed to listen on port {port}"))?;
+                (port, listener)
+            }
+        };
+        let accept = tokio::spawn(self.clone().accept(port, listener)).abort_handle();
+        self.listeners.lock().unwrap().insert(
+            port,
+            Listener {
+                routes: HashMap::from([(host.clone(), route)]),
+                accept,
+            },
+        );
+        self.reserved.lock().unwrap().remove(&(port, host.clone()));
+        Ok((port, host, identity))
+    }
+
+    /// 核对客户端的身份和口令，返回身份；身份不存在和口令不对给出同样的错误
+    fn authenticate(
+        &self,
+        identity: Option<String>,
+        secret: Option<String>,
+    ) -> anyhow::Result<String> {
+        let (Some(identity), Some(secret)) = (identity, secret) else {
+            bail!(
+                "the server requires --identity and --reverse-secret to register reverse tunnels"
+            );
+        };
+        match self.auth.get(&identity) {
+            Some(expected) if same_secret(expected.as_bytes(), secret.as_bytes()) => Ok(identity),
+            _ => bail!("wrong identity or secret"),
+        }
+    }
+
+    /// 保留中的端口和域名，顺便清掉过期的
+    fn reservations(&self) -> MutexGuard<'_, Reservations> {
+        let mut reserved = self.reserved.lock().unwrap();
+        let now = Instant::now();
+        reserved.retain(|_, (_, until)| *until > now);
+        reserved
+    }
+
+    /// 端口和域名还保留给哪个身份
+    fn reserved_for(&self, port: u16, host: &Option<String>) -> Option<String> {
+        self.reservations()
+            .get(&(port, host.clone()))
+            .map(|(identity, _)| identity.clone())
+    }
+
+    /// 按顺序找到第一个没有被隧道占用、没有保留给别的身份、也能监听的端口
+    async fn allocate(&self, identity: &str) -> anyhow::Result<(u16, TcpListener)> {
+        let mut used: HashSet<u16> = self.listeners.lock().unwrap().keys().copied().collect();
+        used.extend(
+            self.reservations()
+                .iter()
+                .filter(|(_, (owner, _))| owner != identity)
+                .map(|((port, _), _)| *port),
+        );
+        for port in self.ports.iter().filter(|port| !used.contains(port)) {
+            if let Ok(listener) = TcpListener::bind((self.bind_host.as_str(), port)).await {
+                return Ok((port, listener));
+            }
+        }
+        bail!("no free port left among the ports allowed by the server")
+    }
+
+    /// 从公网端口上去掉一条隧道，把端口和域名保留给它的身份；端口上没有隧道时停止监听并返回 true
+    fn close(&self, port: u16, host: &Option<String>) -> bool {
+        let mut listeners = self.listeners.lock().unwrap();
+        let Some(listener) = listeners.get_mut(&port) else {
+            return false;
+        };
+        if let Some(route) = listener.routes.remove(host) {
+            self.reserved.lock().unwrap().insert(
+                (port, host.clone()),
+                (route.identity, Instant::now() + RESERVE_TIME),
+            );
+        }
+        if listener.routes.is_empty() {
+            listeners.remove(&port);
+            return true;
+        }
+        false
+    }
+
+    async fn accept(self: Arc<Self>, port: u16, listener: TcpListener) {
+        let what = format!("reverse tunnel port {port}");
+        loop {
+            if let Some(throttle) = &self.gates.throttle {
+                throttle.acquire().await;
+            }
+            let (stream, addr) = bind::accept(&what, &listener).await;
+            let session_id = Uuid::new_v4().to_string();
+            let country = geoip::country(addr.ip());
+            if let Err(reason) = self.gates.countries.admit(addr.ip(), country.as_deref()) {
+                warn_repeated!(
+                    tr!("visitor country rejected", "访客所在地区被拒绝"),
+                    "Session {session_id}: rejected visitor {addr} on port {port}, {reason}",
+                    "会话 {session_id}：拒绝端口 {port} 上的访客 {addr}，{reason}"
+                );
+                self.sessions
+                    .registry
+                    .audit()
+                    .record(addr, &session_id, Outcome::Rejected, reason);
+                continue;
+            }
+            let visitor = Visitor {
+                stream,
+                addr,
+                session_id,
+                country,
+            };
+            tokio::spawn(self.clone().route(port, visitor));
+        }
+    }
+
+    /// 为访客找到对应的隧道：端口上有按域名区分的隧道时先识别访客访问的域名，
+    /// 没有匹配的域名时交给这个端口上不区分域名的隧道
+    async fn route(self: Arc<Self>, port: u16, visitor: Visitor) {
+        let by_host = self
+            .listeners
+            .lock()
+            .unwrap()
+            .get(&port)
+            .is_some_and(|listener| listener.routes.keys().any(Option::is_some));
+        let host = match by_host {
+            true => sniff::hostname(&visitor.stream).await,
+            false => None,
+        };
+        // 域名对应的隧道正在重连时，不把它的访客交给不区分域名的隧道
+        let held = host.is_some() && self.reserved_for(port, &host).is_some();
+        let route = self
+            .listeners
+            .lock()
+            .unwrap()
+            .get(&port)
+            .and_then(|listener| {
+                host.clone()
+                    .and_then(|host| listener.routes.get(&Some(host)))
+                    .or_else(|| listener.routes.get(&None).filter(|_| !held))
+                    .cloned()
+            });
+        let Some(route) = route else {
+            return info!(
+                "Visitor {} on port {port} asked for hostname {}, which matches no reverse tunnel",
+                "访客 {} 在端口 {port} 上访问的域名 {} 没有对应的反向隧道",
+                visitor.addr,
+                host.as_deref().unwrap_or("(unknown)")
+            );
+        };
+        let audit = self.sessions.registry.audit();
+        let slot = match self
+            .gates
+            .pending
+            .as_ref()
+            .map(|pending| pending.admit(&visitor.session_id))
+        {
+            Some(None) => {
+                return audit.record(
+                    visitor.addr,
+                    &visitor.session_id,
+                    Outcome::Rejected,
+                    "too many pending sessions",
+                );
+            }
+            Some(slot) => slot,
+            None => None,
+        };
+        let token = rand::random();
+        let (tx, rx) = oneshot::channel();
+        self.pending.lock().unwrap().insert(token, tx);
+        match route.open.try_send(token) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                self.pending.lock().unwrap().remove(&token);
+                let name = &route.name;
+                warn_repeated!(
+                    tr!(
+                        "reverse tunnel {name:?} is backed up",
+                        "反向隧道 {name:?} 积压的访客太多"
+                    ),
+                    "Session {}: rejected, {MAX_QUEUED_OPENS} visitors of reverse tunnel {name:?} are still waiting to be announced",
+                    "会话 {}：反向隧道 {name:?} 还有 {MAX_QUEUED_OPENS} 个访客没通知到客户端，拒绝连接",
+                    visitor.session_id
+                );
+                return audit.record(
+                    visitor.addr,
+                    &visitor.session_id,
+                    Outcome::Rejected,
+                    format!("reverse tunnel {name} is backed up"),
+                );
+            }
+            Err(TrySendError::Closed(_)) => {
+                self.pending.lock().unwrap().remove(&token);
+                return;
+            }
+        }
+        self.spawn_visitor(visitor, &route.name, host, token, rx, slot);
+    }
+
+    /// 客户端为访客发起的连接到达，连同它的 UDP 流量计数器交给等待中的访客会话；找不到对应的访客时返回 false
+    pub fn attach(&self, token: u64, stream: KcpStream, wire: Arc<Wire>) -> bool {
+        match self.pending.lock().unwrap().remove(&token) {
+            Some(tx) => tx.send((stream, wire)).is_ok(),
+            None => false,
+        }
+    }
+
+    fn spawn_visitor(
+        self: &Arc<Self>,
+        visitor: Visitor,
+        name: &str,
+        host: Option<String>,
+        token: u64,
+        attached: oneshot::Receiver<Attached>,
+        slot: Option<Slot>,
+    ) {
+        let Visitor {
+            stream: visitor,
+            addr: visitor_addr,
+            session_id,
+            country,
+        } = visitor;
+        info!(
+            "New visitor {visitor_addr} on reverse tunnel {name:?}, with session id {session_id}",
+            "访客 {visitor_addr} 连入反向隧道 {name:?}，会话 id {session_id}"
+        );
+        let hub = self.clone();
+        let Sessions {
+            registry,
+            budget,
+            capture,
+            tracker,
+            options,
+            footprint,
+        } = self.sessions.clone();
+        let name = name.to_string();
+        let dropped = slot.as_ref().map(Slot::dropped);
+        let (task_registry, task_id) = (registry.clone(), session_id.clone());
+        let session = async move {
+            let Some(_charge) = budget.try_charge(footprint) else {
+                budget.reject();
+                return warn_repeated!(
+                    tr!("memory budget exhausted", "内存预算已用完"),
+                    "Session {session_id}: rejected, memory budget exhausted",
+                    "会话 {session_id}：内存预算已用完，拒绝连接"
+                );
+            };
+            let registration = registry.register(&session_id, visitor_addr);
+            registration.tag(format!("tunnel={name}"));
+            if let Some(host) = host {
+                registration.tag(format!("host={host}"));
+            }
+            if let Some(country) = country {
+                registration.tag(format!("country={country}"));
+            }
+            let kcp_stream = match timeout(ATTACH_TIMEOUT, attached).await {
+                Ok(Ok((stream, wire))) => {
+                    registration.set_wire(wire);
+                    stream
+                }
+                _ => {
+                    return warn_repeated!(
+                        tr!(
+                            "reverse tunnel {name:?} did not connect back",
+                            "反向隧道 {name:?} 的客户端没有接入"
+                        ),
+                        "Session {session_id}: client of reverse tunnel {name:?} did not connect back in time",
+                        "会话 {session_id}：反向隧道 {name:?} 的客户端没有及时接入"
+                    );
+                }
+            };
+            registration.set_conv(kcp_stream.conv());
+            drop(slot);
+            let capture = capture.map(|capture| capture.stream(visitor_addr));
+            // 访客一侧相当于正向转发中客户端的本地程序
+            let summary = handle_session(
+                visitor,
+                kcp_stream,
+                Role::Client,
+                options,
+                &budget,
+                registration.control(),
+                capture,
+            )
+            .await;
+            crate::report_session(&registration, summary);
+        };
+        // 会话在客户端接入之前结束时（包括名额被新访客挤占）不再等待它的接入
+        crate::spawn_session(&tracker, task_registry, task_id, async move {
+            pending::guard(dropped, session).await;
+            hub.pending.lock().unwrap().remove(&token);
+        });
+    }
+}
+
+/// 客户端：反向隧道的设置
+pub struct Tunnel {
+    pub server_addr: String,
+    pub local_addr: String,
+    pub name: String,
+    pub port: u16,
+    pub host: Option<String>,
+    /// `--reverse-secret`
+    pub secret: Option<String>,
+    /// 注册成功后通知的地址
+    pub webhook: Option<String>,
+    pub kcp_config: Arc<KcpConfig>,
+    pub simulate: Option<Conditions>,
+    /// 同时发送一份副本的服务端备用地址
+    pub redundant_addr: Option<String>,
+}
+
+/// 服务端拒绝了注册，重试也没有用
+#[derive(Debug)]
+struct Rejected(String);
+
+impl fmt::Display for Rejected {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "server rejected the tunnel: {}", self.0)
+    }
+}
+
+impl std::error::Error for Rejected {}
+
+/// 客户端：注册反向隧道并为访客接入连接，控制通道断开后自动重新注册，直到进入排空状态；
+/// 服务端拒绝注册时返回错误
+pub async fn run_client(tunnel: Tunnel, sessions: Sessions) -> anyhow::Result<()> {
+    let tunnel = Arc::new(tunnel);
+    // 服务端分配的端口，重新注册时继续请求它，让公网地址尽量保持不变
+    let mut assigned = None;
+    loop {
+        let result = tokio::select! {
+            result = register(&tunnel, &sessions, &mut assigned) => result,
+            _ = sessions.registry.draining() => return Ok(()),
+        };
+        sessions.registry.remove_tunnel(&tunnel.name);
+        if let Err(e) = result {
+            // 之前分配到的端口被拒绝时（比如被别的隧道占用了）让服务端重新分配
+            if e.is::<Rejected>() && assigned.take().is_none() {
+                return Err(e);
+            }
+            warn_repeated!(
+                tr!("reverse tunnel registration failed", "反向隧道注册失败"),
+                "Reverse tunnel {:?}: {e:#}, retrying in {RECONNECT_DELAY:?}",
+                "反向隧道 {:?}：{e:#}，{RECONNECT_DELAY:?} 后重试",
+                tunnel.name
+            );
+        }
+        tokio::select! {
+            _ = tokio::time::sleep(RECONNECT_DELAY) => {}
+            _ = sessions.registry.draining() => return Ok(()),
+        }
+    }
+}
+
+/// 连接服务端并握手，收发的包记到 `wire` 上
+async fn connect(tunnel: &Tunnel, request: &Request, wire: Arc<Wire>) -> anyhow::Result<KcpStream> {
+    let config = tunnel.kcp_config.clone();
+    let (mut stream, _) = match (tunnel.simulate, &tunnel.redundant_addr) {
+        (Some(conditions), _) => {
+            simulate::connect(config, &tunnel.server_addr, conditions, wire).await?
+        }
+        (None, Some(backup)) => {
+            redundant::connect(config, &tunnel.server_addr, backup, wire).await?
+        }
+        (None, None) => {
+            let addr = dns::lookup(&tunnel.server_addr).await?;
+            udp::connect(config, addr, wire).await?
+        }
+    };
+    let features = timeout(
+        protocol::HANDSHAKE_TIMEOUT,
+        protocol::client_handshake(&mut stream, FEATURE_REVERSE, request),
+    )
+    .await
+    .context("handshake timed out")??;
+    if features & FEATURE_REVERSE == 0 {
+        bail!("server does not allow reverse tunnels (start it with --reverse-ports)");
+    }
+    Ok(stream)
+}
+
+/// 建立一次控制通道，返回时说明通道已经断开
+async fn register(
+    tunnel: &Arc<Tunnel>,
+    sessions: &Sessions,
+    assigned: &mut Option<u16>,
+) -> anyhow::Result<()> {
+    let request = Request::Register {
+        name: tunnel.name.clone(),
+        port: assigned.unwrap_or(tunnel.port),
+        host: tunnel.host.clone(),
+        secret: tunnel.secret.clone(),
+    };
+    let control = connect(tunnel, &request, Arc::default()).await?;
+    let (mut reader, mut writer) = io::split(control);
+    match protocol::read_message(&mut reader).await? {
+        Message::Registered { port } => {
+            if tunnel.port == 0 {
+                *assigned = Some(port);
+            }
+            announce(tunnel, &sessions.registry, port);
+        }
+        Message::Rejected { reason } => return Err(Rejected(reason).into()),
+        _ => bail!("server sent an unexpected reply to the registration"),
+    }
+
+    let mut ping = tokio::time::interval(PING_INTERVAL);
+    loop {
+        tokio::select! {
+            message = protocol::read_message(&mut reader) => match message? {
+                Message::Open { token } => spawn_attach(tunnel.clone(), sessions.clone(), token),
+                _ => bail!("server sent an unexpected control message"),
+            },
+            _ = ping.tick() => protocol::write_message(&mut writer, &Message::Ping).await?,
+            _ = sessions.registry.woke() => bail!("woke up from a long sleep or the local address changed"),
+        }
+    }
+}
+
+/// 注册成功后告诉用户访客该连哪个地址：打印日志、记到管理接口的隧道列表里，设置了 webhook 时再发一次通知
+fn announce(tunnel: &Tunnel, registry: &Registry, port: u16) {
+    let server_host = tunnel
+        .server_addr
+        .rsplit_once(':')
+        .map_or(tunnel.server_addr.as_str(), |(host, _)| host);
+    let public_addr = format!("{}:{port}", tunnel.host.as_deref().unwrap_or(server_host));
+    notice!(
+        "Reverse tunnel {:?} registered, {} is reachable at {public_addr}",
+        "反向隧道 {:?} 注册成功，{} 可以通过 {public_addr} 访问",
+        tunnel.name,
+        tunnel.local_addr
+    );
+    registry.add_tunnel(TunnelInfo {
+        name: tunnel.name.clone(),
+        port,
+        public_addr: public_addr.clone(),
+        host: tunnel.host.clone(),
+        peer: tunnel.server_addr.clone(),
+    });
+    let Some(url) = tunnel.webhook.clone() else {
+        return;
+    };
+    let body = Value::object([
+        ("event", "registered".into()),
+        ("name", tunnel.name.as_str().into()),
+        ("port", port.into()),
+        ("public_addr", public_addr.into()),
+        ("host", tunnel.host.clone().into()),
+        ("local_addr", tunnel.local_addr.as_str().into()),
+    ]);
+    tokio::spawn(async move {
+        if let Err(e) = webhook::post(&url, &body).await {
+            warn!(
+                "Failed to notify webhook {url}: {e:#}",
+                "通知 webhook {url} 失败：{e:#}"
+            );
+        }
+    });
+}
+
+/// 为一名访客发起连接，并转发到本地的服务
+fn spawn_attach(tunnel: Arc<Tunnel>, sessions: Sessions, token: u64) {
+    let session_id = Uuid::new_v4().to_string();
+    let Sessions {
+        registry,
+        budget,
+        capture,
+        tracker,
+        options,
+        footprint,
+    } = sessions;
+    crate::spawn_session(&tracker, registry.clone(), session_id.clone(), async move {
+        let Some(_charge) = budget.try_charge(footprint) else {
+            budget.reject();
+            return warn_repeated!(
+                tr!("memory budget exhausted", "内存预算已用完"),
+                "Session {session_id}: rejected, memory budget exhausted",
+                "会话 {session_id}：内存预算已用完，拒绝连接"
+            );
+        };
+        let wire = Arc::new(Wire::default());
+        let kcp_stream = match connect(&tunnel, &Request::Attach { token }, wire.clone()).await {
+            Ok(stream) => stream,
+            Err(e) => {
+                return warn_repeated!(
+                    tr!("reverse tunnel attach failed", "反向隧道接入失败"),
+                    "Session {session_id}: failed to attach to the server: {e:#}",
+                    "会话 {session_id}：接入服务端失败：{e:#}"
+                );
+            }
+        };
+        let local_addr = &tunnel.local_addr;
+        let tcp_stream = match dns::connect_tcp(local_addr).await {
+            Ok(stream) => stream,
+            Err(e) => {
+                return error_repeated!(
+                    tr!(
+                        "local service {local_addr} unreachable",
+                        "本地服务 {local_addr} 无法连接"
+                    ),
+                    "Session {session_id}: failed to connect to local service {local_addr}: {e}",
+                    "会话 {session_id}：无法连接到本地服务 {local_addr}：{e}"
+                );
+            }
+        };
+        let peer = tcp_stream
+            .peer_addr()
+            .unwrap_or_else(|_| ([0, 0, 0, 0], 0).into());
+        info!(
+            "Session {session_id}: visitor of reverse tunnel {:?} forwarded to {local_addr}",
+            "会话 {session_id}：反向隧道 {:?} 的访客转发到 {local_addr}", tunnel.name
+        );
+        let registration = registry.register(&session_id, peer);
+        registration.set_wire(wire);
+        registration.tag(format!("tunnel={}", tunnel.name));
+        registration.set_conv(kcp_stream.conv());
+        let capture = capture.map(|capture| capture.stream(peer));
+        // 本地服务相当于正向转发中服务端的后端
+        let summary = handle_session(
+            tcp_stream,
+            kcp_stream,
+            Role::Server,
+            options,
+            &budget,
+            registration.control(),
+            capture,
+        )
+        .await;
+        crate::report_session(&registration, summary);
+    });
+}
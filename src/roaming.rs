@@ -0,0 +1,125 @@
+//! 会话中途切换服务端地址：服务端有多个地址（比如换 IP 期间新旧两个 IP，或者接在两家运营商上的两个 IP）
+//! 并通过控制通道推送给客户端时，客户端在当前地址一段时间没有任何回应后把 UDP 包改发到下一个地址，
+//! 会话不用重新建立。
+//!
+//! 服务端按 conv 和客户端的地址识别会话（见 `demux`），和包发到服务端的哪个地址无关，
+//! 所以客户端始终从同一个套接字发出，各个地址必须到达同一个监听套接字（服务端监听 `0.0.0.0` 或 `[::]`）。
+//! 服务端的应答从哪个地址发出由它的路由决定，客户端接受来自任意一个已知地址的应答。
+
+use crate::disguise;
+use crate::dns;
+use crate::udp;
+use crate::wire::Wire;
+use bytes::BytesMut;
+use kcp::{KcpConfig, KcpStream};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+
+/// 发出包后这么久没有收到任何应答时切换到下一个地址
+const SWITCH_AFTER: Duration = Duration::from_secs(3);
+
+/// 和 `KcpUdpStream::connect` 一样建立 KCP 连接，`addr` 没有回应时依次改用 `alternates` 里的地址，收发的包记到 `wire` 上
+pub async fn connect(
+    config: Arc<KcpConfig>,
+    addr: &str,
+    alternates: &[String],
+    wire: Arc<Wire>,
+) -> io::Result<(KcpStream, SocketAddr)> {
+    let primary = dns::lookup(addr).await?;
+    let mut addrs = vec![primary];
+    for alternate in alternates {
+        match dns::lookup(alternate).await {
+            // 地址族不同的地址没法从同一个套接字发出
+            Ok(alternate) if alternate.is_ipv4() == primary.is_ipv4() => {
+                if !addrs.contains(&alternate) {
+                    addrs.push(alternate);
+                }
+            }
+            Ok(_) => {}
+            Err(e) => debug!(
+                "Failed to resolve alternate server address {alternate}: {e}",
+                "无法解析服务端的备用地址 {alternate}：{e}"
+            ),
+        }
+    }
+    let udp = udp::bind_for(primary, &config).await?;
+
+    let window = config.snd_wnd.max(8) as usize;
+    let (outgoing_tx, outgoing_rx) = mpsc::channel(window);
+    let (incoming_tx, incoming_rx) = mpsc::channel(window);
+    tokio::spawn(relay(udp, addrs, outgoing_rx, incoming_tx, wire));
+
+    let transport = kcp::transport::tokio_mpsc_stream(outgoing_tx, incoming_rx);
+    let stream =
+        KcpStream::connect::<_, BytesMut, _>(config, transport, futures::sink::drain(), None)
+            .await?;
+    Ok((stream, primary))
+}
+
+/// 在 KCP 和 UDP 套接字之间转发数据包，当前地址没有回应时切换到下一个；KCP 连接关闭后结束
+async fn relay(
+    udp: UdpSocket,
+    addrs: Vec<SocketAddr>,
+    mut outgoing: mpsc::Receiver<BytesMut>,
+    incoming: mpsc::Sender<BytesMut>,
+    wire: Arc<Wire>,
+) {
+    let mut buf = vec![0u8; 64 * 1024];
+    let mut current = 0;
+    // 上次收到应答之后第一次发出包的时间，空闲期间没有应答不算异常；切换后从切换时算起
+    let mut waiting: Option<Instant> = None;
+    // 切换后还没有收到过应答
+    let mut switched = false;
+    loop {
+        tokio::select! {
+            packet = outgoing.recv() => {
+                let Some(packet) = packet else { break };
+                wire.segments_in(&packet);
+                let since = *waiting.get_or_insert_with(Instant::now);
+                if addrs.len() > 1 && since.elapsed() >= SWITCH_AFTER {
+                    let next = (current + 1) % addrs.len();
+                    notice!(
+                        "No reply from server {} for {}s, switching to {}",
+                        "服务端 {} 已经 {} 秒没有回应，切换到 {}",
+                        addrs[current],
+                        since.elapsed().as_secs(),
+                        addrs[next]
+                    );
+                    current = next;
+                    waiting = Some(Instant::now());
+                    switched = true;
+                }
+                if udp.send_to(&disguise::wrap(&packet), addrs[current]).await.is_ok() {
+                    wire.send(packet.len(), addrs[current]);
+                }
+            }
+            received = udp.recv_from(&mut buf) => {
+                let Ok((n, from)) = received else { continue };
+                // 套接字没有 connect 到固定地址，丢弃其它来源的包
+                if !addrs.contains(&from) {
+                    continue;
+                }
+                let Some(packet) = disguise::unwrap(&buf[..n]) else { continue };
+                wire.receive(packet.len(), from);
+                wire.acks_in(packet);
+                waiting = None;
+                if switched {
+                    switched = false;
+                    notice!(
+                        "Connection resumed through server address {}",
+                        "连接已通过服务端地址 {} 恢复",
+                        addrs[current]
+                    );
+                }
+                if incoming.send(BytesMut::from(packet)).await.is_err() {
+                    break;
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,307 @@
+//! 可选的进程加固（仅 Linux）：
+//! 1. 启动时用 Landlock 把文件系统访问限制为若干系统目录的只读访问，外加 `--record` 目录里新建和写入文件；
+//! 2. 初始化完成后用 seccomp 拒绝进程用不到且危险的系统调用（执行程序、调试、挂载、切换身份等）。
+//!
+//! Landlock 只作用于调用线程及之后创建的线程，因此必须在创建 tokio 运行时之前调用；
+//! seccomp 使用 TSYNC 同步到所有线程，可以在运行时内调用。
+
+use std::path::Path;
+
+/// 允许只读访问的目录，用于域名解析（resolv.conf、hosts、nsswitch 以及 NSS 模块）
+#[cfg(target_os = "linux")]
+const READ_ONLY_PATHS: &[&str] = &["/etc", "/usr", "/lib", "/lib64"];
+
+/// `writable` 是运行中还要在里面新建文件的目录
+#[cfg(target_os = "linux")]
+pub fn restrict_filesystem(writable: &[&Path]) -> anyhow::Result<()> {
+    landlock::restrict(READ_ONLY_PATHS, writable)
+}
+
+#[cfg(target_os = "linux")]
+pub fn restrict_syscalls() -> anyhow::Result<()> {
+    seccomp::restrict()
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn restrict_filesystem(_writable: &[&Path]) -> anyhow::Result<()> {
+    anyhow::bail!("--sandbox is only supported on Linux")
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn restrict_syscalls() -> anyhow::Result<()> {
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+mod landlock {
+    use anyhow::Context;
+    use std::ffi::CString;
+    use std::io;
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::Path;
+
+    const CREATE_RULESET_VERSION: u32 = 1 << 0;
+    const RULE_PATH_BENEATH: libc::c_int = 1;
+
+    const ACCESS_FS_WRITE_FILE: u64 = 1 << 1;
+    const ACCESS_FS_READ_FILE: u64 = 1 << 2;
+    const ACCESS_FS_READ_DIR: u64 = 1 << 3;
+    const ACCESS_FS_MAKE_REG: u64 = 1 << 8;
+    /// ABI v3 起才有，`File::create` 带 `O_TRUNC`
+    const ACCESS_FS_TRUNCATE: u64 = 1 << 14;
+
+    const READ: u64 = ACCESS_FS_READ_FILE | ACCESS_FS_READ_DIR;
+    const WRITE: u64 = READ | ACCESS_FS_WRITE_FILE | ACCESS_FS_MAKE_REG | ACCESS_FS_TRUNCATE;
+
+    #[repr(C)]
+    struct RulesetAttr {
+        handled_access_fs: u64,
+    }
+
+    #[repr(C, packed)]
+    struct PathBeneathAttr {
+        allowed_access: u64,
+        parent_fd: i32,
+    }
+
+    /// 各 ABI 版本能处理的全部文件系统访问权限
+    fn handled_access(abi: i64) -> u64 {
+        match abi {
+            1 => (1 << 13) - 1,
+            2 => (1 << 14) - 1,
+            3 | 4 => (1 << 15) - 1,
+            _ => (1 << 16) - 1,
+        }
+    }
+
+    pub fn restrict(read_only_paths: &[&str], writable: &[&Path]) -> anyhow::Result<()> {
+        let abi = unsafe {
+            libc::syscall(
+                libc::SYS_landlock_create_ruleset,
+                std::ptr::null::<RulesetAttr>(),
+                0,
+                CREATE_RULESET_VERSION,
+            )
+        };
+        if abi < 1 {
+            warn!(
+                "Landlock is not available on this kernel ({}), filesystem access is not restricted",
+                "当前内核不支持 Landlock（{}），不限制文件访问",
+                io::Error::last_os_error()
+            );
+            return Ok(());
+        }
+
+        let handled = handled_access(abi);
+        let attr = RulesetAttr {
+            handled_access_fs: handled,
+        };
+        let ruleset_fd = unsafe {
+            libc::syscall(
+                libc::SYS_landlock_create_ruleset,
+                &attr,
+                size_of::<RulesetAttr>(),
+                0,
+            )
+        } as libc::c_int;
+        if ruleset_fd < 0 {
+            return Err(io::Error::last_os_error()).context("landlock_create_ruleset failed");
+        }
+
+        let result = read_only_paths
+            .iter()
+            .map(|path| add_rule(ruleset_fd, Path::new(path), READ, true))
+            .chain(
+                writable
+                    .iter()
+                    .map(|path| add_rule(ruleset_fd, path, WRITE & handled, false)),
+            )
+            .collect::<anyhow::Result<()>>()
+            .and_then(|()| {
+                if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } != 0 {
+                    return Err(io::Error::last_os_error()).context("PR_SET_NO_NEW_PRIVS failed");
+                }
+                if unsafe { libc::syscall(libc::SYS_landlock_restrict_self, ruleset_fd, 0) } != 0 {
+                    return Err(io::Error::last_os_error())
+                        .context("landlock_restrict_self failed");
+                }
+                Ok(())
+            });
+        unsafe { libc::close(ruleset_fd) };
+        result?;
+
+        notice!(
+            "Landlock filesystem sandbox enabled (ABI v{abi})",
+            "已启用 Landlock 文件系统沙箱（ABI v{abi}）"
+        );
+        Ok(())
+    }
+
+    /// 允许访问 `path` 下的文件；`optional` 时路径不存在就跳过
+    fn add_rule(
+        ruleset_fd: libc::c_int,
+        path: &Path,
+        allowed_access: u64,
+        optional: bool,
+    ) -> anyhow::Result<()> {
+        let c_path = CString::new(path.as_os_str().as_bytes())?;
+        let fd = unsafe { libc::open(c_path.as_ptr(), libc::O_PATH | libc::O_CLOEXEC) };
+        if fd < 0 {
+            // 比如没有 /lib64 的系统
+            if optional {
+                return Ok(());
+            }
+            return Err(io::Error::last_os_error())
+                .with_context(|| format!("cannot open {}", path.display()));
+        }
+        let rule = PathBeneathAttr {
+            allowed_access,
+            parent_fd: fd,
+        };
+        let ret = unsafe {
+            libc::syscall(
+                libc::SYS_landlock_add_rule,
+                ruleset_fd,
+                RULE_PATH_BENEATH,
+                &rule,
+                0,
+            )
+        };
+        let error = io::Error::last_os_error();
+        unsafe { libc::close(fd) };
+        if ret != 0 {
+            return Err(error)
+                .with_context(|| format!("landlock_add_rule({}) failed", path.display()));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod seccomp {
+    use anyhow::Context;
+    use std::io;
+
+    #[cfg(target_arch = "x86_64")]
+    const AUDIT_ARCH: Option<u32> = Some(0xC000_003E);
+    #[cfg(target_arch = "aarch64")]
+    const AUDIT_ARCH: Option<u32> = Some(0xC000_00B7);
+    #[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+    const AUDIT_ARCH: Option<u32> = None;
+
+    /// x86_64 上的 x32 系统调用号带有这个标记位
+    const X32_SYSCALL_BIT: u32 = 0x4000_0000;
+
+    const BPF_LD_W_ABS: u16 = 0x20;
+    const BPF_JEQ_K: u16 = 0x15;
+    const BPF_JGE_K: u16 = 0x35;
+    const BPF_RET_K: u16 = 0x06;
+
+    const OFFSET_NR: u32 = 0;
+    const OFFSET_ARCH: u32 = 4;
+
+    const SECCOMP_RET_KILL_PROCESS: u32 = 0x8000_0000;
+
+    /// 程序运行中不会用到的危险系统调用
+    const DENIED: &[libc::c_long] = &[
+        libc::SYS_execve,
+        libc::SYS_execveat,
+        #[cfg(target_arch = "x86_64")]
+        libc::SYS_fork,
+        #[cfg(target_arch = "x86_64")]
+        libc::SYS_vfork,
+        libc::SYS_ptrace,
+        libc::SYS_process_vm_readv,
+        libc::SYS_process_vm_writev,
+        libc::SYS_mount,
+        libc::SYS_umount2,
+        libc::SYS_pivot_root,
+        libc::SYS_chroot,
+        libc::SYS_unshare,
+        libc::SYS_setns,
+        libc::SYS_init_module,
+        libc::SYS_finit_module,
+        libc::SYS_delete_module,
+        libc::SYS_kexec_load,
+        libc::SYS_kexec_file_load,
+        libc::SYS_reboot,
+        libc::SYS_swapon,
+        libc::SYS_swapoff,
+        libc::SYS_bpf,
+        libc::SYS_perf_event_open,
+        libc::SYS_keyctl,
+        libc::SYS_add_key,
+        libc::SYS_request_key,
+        libc::SYS_setuid,
+        libc::SYS_setgid,
+        libc::SYS_setreuid,
+        libc::SYS_setregid,
+        libc::SYS_setresuid,
+        libc::SYS_setresgid,
+        libc::SYS_setgroups,
+    ];
+
+    fn stmt(code: u16, k: u32) -> libc::sock_filter {
+        libc::sock_filter {
+            code,
+            jt: 0,
+            jf: 0,
+            k,
+        }
+    }
+
+    fn jump(code: u16, k: u32, jt: u8, jf: u8) -> libc::sock_filter {
+        libc::sock_filter { code, jt, jf, k }
+    }
+
+    pub fn restrict() -> anyhow::Result<()> {
+        let Some(arch) = AUDIT_ARCH else {
+            warn!(
+                "seccomp filter is not supported on this architecture, skipped",
+                "当前架构不支持 seccomp 过滤，已跳过"
+            );
+            return Ok(());
+        };
+
+        let deny = libc::SECCOMP_RET_ERRNO | libc::EPERM as u32;
+        let mut filter = vec![
+            stmt(BPF_LD_W_ABS, OFFSET_ARCH),
+            jump(BPF_JEQ_K, arch, 1, 0),
+            stmt(BPF_RET_K, SECCOMP_RET_KILL_PROCESS),
+            stmt(BPF_LD_W_ABS, OFFSET_NR),
+            jump(BPF_JGE_K, X32_SYSCALL_BIT, 0, 1),
+            stmt(BPF_RET_K, deny),
+        ];
+        for &nr in DENIED {
+            filter.push(jump(BPF_JEQ_K, nr as u32, 0, 1));
+            filter.push(stmt(BPF_RET_K, deny));
+        }
+        filter.push(stmt(BPF_RET_K, libc::SECCOMP_RET_ALLOW));
+
+        let prog = libc::sock_fprog {
+            len: filter.len() as u16,
+            filter: filter.as_mut_ptr(),
+        };
+        if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } != 0 {
+            return Err(io::Error::last_os_error()).context("PR_SET_NO_NEW_PRIVS failed");
+        }
+        let ret = unsafe {
+            libc::syscall(
+                libc::SYS_seccomp,
+                libc::SECCOMP_SET_MODE_FILTER,
+                libc::SECCOMP_FILTER_FLAG_TSYNC,
+                &prog,
+            )
+        };
+        if ret != 0 {
+            return Err(io::Error::last_os_error()).context("seccomp filter install failed");
+        }
+
+        notice!(
+            "seccomp filter enabled ({} syscalls denied)",
+            "已启用 seccomp 过滤（禁止 {} 个系统调用）",
+            DENIED.len()
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,354 @@
+//! `selftest` 子命令：在本机回环地址上同时启动服务端、客户端和一个回显后端，
+//! 推送一段固定模式的数据并校验回来的内容，顺便测一下吞吐，
+//! 用于打包后的冒烟测试，也方便用户反馈问题时先确认程序本身工作正常。
+//!
+//! 加上 `--sweep` 时用几组不同的 KCP 参数各跑一遍，配合 `--simulate` 模拟实际线路的丢包和延迟，
+//! 按吞吐给出推荐的参数，格式和 `--push-kcp` 一样，可以直接推送给客户端。
+
+use crate::budget::Budget;
+use crate::json::Value;
+use crate::protocol;
+use crate::push::KcpTuning;
+use crate::registry::Registry;
+use crate::session::{Role, SessionOptions, SessionSummary, handle_session};
+use crate::simulate::{self, Conditions};
+use anyhow::{Context, bail};
+use kcp::{KcpConfig, KcpUdpStream};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::time::timeout;
+
+/// 整个自检的时间上限
+const SELFTEST_TIMEOUT: Duration = Duration::from_secs(60);
+
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// `--assert-min-throughput` 时吞吐不够最多跑这么多遍，取最好的一次，免得偶尔一次抖动就被当成退化
+const ASSERT_RUNS: usize = 3;
+
+/// `--sweep` 依次尝试的 KCP 参数，第一组是默认值，作为对照
+const SWEEP: &[&str] = &[
+    "interval=60,resend=3",
+    "interval=10,resend=2",
+    "interval=20,resend=2",
+    "interval=40,resend=2",
+    "interval=10,resend=0",
+    "interval=20,resend=0",
+    "interval=40,resend=0",
+];
+
+/// `json` 为 true 时只在标准输出打印一行 JSON 结果，失败时也是如此；
+/// 给了 `min_throughput`（MB/s）时吞吐不够也算失败
+pub async fn run(
+    megabytes: usize,
+    config: Arc<KcpConfig>,
+    simulate: Option<Conditions>,
+    min_throughput: Option<f64>,
+    json: bool,
+) -> anyhow::Result<()> {
+    let size = megabytes * 1024 * 1024;
+    if !json {
+        if let Some(conditions) = &simulate {
+            notice!(
+                "Simulating network conditions: {conditions}",
+                "模拟网络条件：{conditions}"
+            );
+        }
+        notice!(
+            "Self-test: relaying {megabytes} MB through a loopback server and client...",
+            "自检：通过本机回环上的服务端和客户端转发 {megabytes} MB 数据..."
+        );
+    }
+    let throughput_of = |elapsed: Duration| size as f64 / 1024.0 / 1024.0 / elapsed.as_secs_f64();
+    let runs = if min_throughput.is_some() {
+        ASSERT_RUNS
+    } else {
+        1
+    };
+    let mut best: Option<Duration> = None;
+    for run in 1..=runs {
+        let elapsed = match measure(size, config.clone(), config.clone(), simulate).await {
+            Ok(elapsed) => elapsed,
+            Err(e) if json => {
+                let report =
+                    Value::object([("ok", false.into()), ("error", format!("{e:#}").into())]);
+                println!("{report}");
+                std::process::exit(1);
+            }
+            Err(e) => return Err(e),
+        };
+        let elapsed = *best.insert(best.map_or(elapsed, |best| best.min(elapsed)));
+        let Some(min) = min_throughput.filter(|min| throughput_of(elapsed) < *min) else {
+            break;
+        };
+        if !json && run < runs {
+            info!(
+                "Run {run} reached {:.1} MB/s, below {min} MB/s, running again",
+                "第 {run} 遍只有 {:.1} MB/s，低于 {min} MB/s，再跑一遍",
+                throughput_of(elapsed)
+            );
+        }
+    }
+    let elapsed = best.expect("at least one run");
+    let throughput = throughput_of(elapsed);
+    let too_slow = min_throughput.filter(|min| throughput < *min);
+    if json {
+        let report = Value::object([
+            ("ok", too_slow.is_none().into()),
+            ("bytes", size.into()),
+            ("seconds", elapsed.as_secs_f64().into()),
+            ("throughput_mib_per_sec", throughput.into()),
+            ("min_throughput_mib_per_sec", min_throughput.into()),
+        ]);
+        println!("{report}");
+        if too_slow.is_some() {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+    if let Some(min) = too_slow {
+        bail!(
+            "data was echoed intact, but throughput was only {throughput:.1} MB/s \
+             after {runs} runs, below the required {min} MB/s"
+        );
+    }
+    notice!(
+        "Self-test passed: {megabytes} MB echoed intact in {:.2}s ({throughput:.1} MB/s each way)",
+        "自检通过：{megabytes} MB 数据在 {:.2} 秒内完整回显（每个方向 {throughput:.1} MB/s）",
+        elapsed.as_secs_f64()
+    );
+    Ok(())
+}
+
+/// `--sweep`：用 `SWEEP` 里的每组参数各跑一遍，按吞吐推荐一组
+pub async fn sweep(
+    megabytes: usize,
+    config: Arc<KcpConfig>,
+    simulate: Option<Conditions>,
+    json: bool,
+) -> anyhow::Result<()> {
+    let size = megabytes * 1024 * 1024;
+    if !json {
+        if let Some(conditions) = &simulate {
+            notice!(
+                "Simulating network conditions: {conditions}",
+                "模拟网络条件：{conditions}"
+            );
+        }
+        notice!(
+            "Sweeping {} KCP parameter sets with {megabytes} MB each...",
+            "用 {} 组 KCP 参数各转发 {megabytes} MB...",
+            SWEEP.len()
+        );
+    }
+    let mut results = Vec::with_capacity(SWEEP.len());
+    for params in SWEEP {
+        let tuning: KcpTuning = params.parse()?;
+        // 推送的参数只作用于客户端一侧，服务端一侧保持不变
+        let tuned = Arc::new(tuning.apply(&config));
+        let result = measure(size, config.clone(), tuned, simulate)
+            .await
+            .map(|elapsed| size as f64 / 1024.0 / 1024.0 / elapsed.as_secs_f64());
+        if !json {
+            match &result {
+                Ok(throughput) => notice!(
+                    "  {params}: {throughput:.1} MB/s",
+                    "  {params}：{throughput:.1} MB/s"
+                ),
+                Err(e) => warn!("  {params}: failed, {e:#}", "  {params}：失败，{e:#}"),
+            }
+        }
+        results.push((params, result));
+    }
+    let best = results
+        .iter()
+        .filter_map(|(params, result)| Some((params, *result.as_ref().ok()?)))
+        .max_by(|a, b| a.1.total_cmp(&b.1))
+        .map(|(params, _)| **params);
+
+    if json {
+        let results: Vec<Value> = results
+            .iter()
+            .map(|(params, result)| {
+                Value::object([
+                    ("kcp", (**params).into()),
+                    (
+                        "throughput_mib_per_sec",
+                        result.as_ref().ok().copied().into(),
+                    ),
+                    (
+                        "error",
+                        result.as_ref().err().map(|e| format!("{e:#}")).into(),
+                    ),
+                ])
+            })
+            .collect();
+        let report = Value::object([
+            ("ok", best.is_some().into()),
+            ("results", Value::Array(results)),
+            ("recommended", best.into()),
+        ]);
+        println!("{report}");
+        if best.is_none() {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+    match best {
+        Some(best) => {
+            notice!(
+                "Recommended: --push-kcp {best} on the server",
+                "推荐参数：在服务端使用 --push-kcp {best}"
+            );
+            Ok(())
+        }
+        None => bail!("every parameter set failed"),
+    }
+}
+
+/// 跑一遍自检，返回转发用的时间
+async fn measure(
+    size: usize,
+    server_config: Arc<KcpConfig>,
+    client_config: Arc<KcpConfig>,
+    simulate: Option<Conditions>,
+) -> anyhow::Result<Duration> {
+    timeout(
+        SELFTEST_TIMEOUT,
+        relay(size, server_config, client_config, simulate),
+    )
+    .await
+    .context("self-test timed out")
+    .and_then(|result| result)
+}
+
+/// 第 `i` 个字节的取值，周期不是 2 的幂，错位或丢失的数据都能被发现
+fn pattern(i: usize) -> u8 {
+    (i % 251) as u8
+}
+
+async fn relay(
+    size: usize,
+    server_config: Arc<KcpConfig>,
+    client_config: Arc<KcpConfig>,
+    simulate: Option<Conditions>,
+) -> anyhow::Result<Duration> {
+    let budget = Arc::new(Budget::default());
+
+    // 回显后端
+    let backend = TcpListener::bind("127.0.0.1:0").await?;
+    let backend_addr = backend.local_addr()?;
+    tokio::spawn(async move {
+        if let Ok((mut stream, _)) = backend.accept().await {
+            let (mut reader, mut writer) = stream.split();
+            let _ = tokio::io::copy(&mut reader, &mut writer).await;
+        }
+    });
+
+    // 服务端
+    let udp = UdpSocket::bind("127.0.0.1:0").await?;
+    let server_addr = udp.local_addr()?;
+    let mut listener = KcpUdpStream::socket_listen(server_config, udp, 1, None)?;
+    let server_budget = budget.clone();
+    let server = tokio::spawn(async move {
+        let (mut kcp_stream, peer) = listener.accept().await?;
+        protocol::server_handshake(&mut kcp_stream, 0).await?;
+        let tcp_stream = TcpStream::connect(backend_addr).await?;
+        let registry = Registry::default();
+        let registration = registry.register("selftest-server", peer);
+        let summary = handle_session(
+            tcp_stream,
+            kcp_stream,
+            Role::Server,
+            SessionOptions::default(),
+            &server_budget,
+            registration.control(),
+            None,
+        )
+        .await;
+        anyhow::Ok(summary)
+    });
+
+    // 客户端
+    let entry = TcpListener::bind("127.0.0.1:0").await?;
+    let entry_addr = entry.local_addr()?;
+    let client = tokio::spawn(async move {
+        let (tcp_stream, peer) = entry.accept().await?;
+        let server_addr = server_addr.to_string();
+        let (mut kcp_stream, _) = match simulate {
+            Some(conditions) => {
+                simulate::connect(client_config, &server_addr, conditions, Arc::default()).await?
+            }
+            None => KcpUdpStream::connect(client_config, &server_addr).await?,
+        };
+        protocol::client_handshake(&mut kcp_stream, 0, &protocol::Request::Forward).await?;
+        let registry = Registry::default();
+        let registration = registry.register("selftest-client", peer);
+        let summary = handle_session(
+            tcp_stream,
+            kcp_stream,
+            Role::Client,
+            SessionOptions::default(),
+            &budget,
+            registration.control(),
+            None,
+        )
+        .await;
+        anyhow::Ok(summary)
+    });
+
+    let start = Instant::now();
+    let stream = TcpStream::connect(entry_addr).await?;
+    let (mut reader, mut writer) = stream.into_split();
+    // kcp-rs 不支持半关闭，关闭写方向会连读方向一起断开，所以发完后不关闭，读够数据再结束
+    let send = async {
+        let mut chunk = vec![0u8; CHUNK_SIZE];
+        let mut offset = 0;
+        while offset < size {
+            let n = CHUNK_SIZE.min(size - offset);
+            for (i, byte) in chunk[..n].iter_mut().enumerate() {
+                *byte = pattern(offset + i);
+            }
+            writer.write_all(&chunk[..n]).await?;
+            offset += n;
+        }
+        anyhow::Ok(())
+    };
+    let verify = async {
+        let mut buf = vec![0u8; CHUNK_SIZE];
+        let mut offset = 0;
+        while offset < size {
+            let n = reader.read(&mut buf).await?;
+            if n == 0 {
+                bail!("connection closed after {offset} of {size} bytes");
+            }
+            if let Some(i) = (0..n).find(|&i| buf[i] != pattern(offset + i)) {
+                bail!("data corrupted at byte {}", offset + i);
+            }
+            offset += n;
+        }
+        anyhow::Ok(())
+    };
+    let transfer = async { tokio::try_join!(send, verify) };
+    tokio::pin!(client, server);
+    tokio::select! {
+        result = transfer => result?,
+        result = &mut client => bail!("client side stopped early: {}", summarize(result?)),
+        result = &mut server => bail!("server side stopped early: {}", summarize(result?)),
+    };
+    Ok(start.elapsed())
+}
+
+fn summarize(result: anyhow::Result<SessionSummary>) -> String {
+    match result {
+        Ok(SessionSummary {
+            reason,
+            error: Some(e),
+            ..
+        }) => format!("{reason}, {e}"),
+        Ok(summary) => summary.reason.to_string(),
+        Err(e) => format!("{e:#}"),
+    }
+}
@@ -0,0 +1,472 @@
+//! 服务端模式：在 `--listen-addr` 上接受客户端的 KCP 连接，握手后按请求分流：
+//! 普通会话连到 `--proxy-addr` 的后端转发，控制通道交给 `control`，反向隧道交给 `reverse`。
+//!
+//! 每个新连接依次经过国家或地区的检查、建立中会话的名额和内存预算，后端连不上或者熔断时
+//! 按 `--fallback-response` 回复客户端。排空时继续接受并直接拒绝新连接，等现有会话结束后返回。
+
+use crate::audit::Outcome;
+use crate::bind::{self, Protocol};
+use crate::budget::Budget;
+use crate::circuit::{self, Breaker};
+use crate::control;
+use crate::dns;
+use crate::geoip;
+use crate::listener::Listener;
+use crate::pcap::Capture;
+use crate::pending::{self, Slot};
+use crate::protocol::{self, Greeting, Request};
+use crate::registry::Registry;
+use crate::reverse::{self, Claim};
+use crate::session::{CloseReason, Goodbye, Role, handle_session, session_footprint};
+use crate::udp;
+use crate::{Args, SHUTDOWN_GRACE, drain, report_session, seconds, spawn_session};
+use kcp::conv::ConvCache;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::time::timeout;
+use tokio_util::task::TaskTracker;
+use uuid::Uuid;
+
+/// 服务端：在 `--listen-addr` 上接受 KCP 连接，转发到 `--proxy-addr` 的 TCP 后端，排空后返回
+pub async fn run(
+    args: &Args,
+    registry: &Arc<Registry>,
+    budget: &Arc<Budget>,
+    capture: &Option<Arc<Capture>>,
+    fallback: &Option<Arc<[u8]>>,
+    tracker: &TaskTracker,
+) -> anyhow::Result<()> {
+    let udp_socket = bind::with_retry(
+        "UDP listener",
+        Protocol::Udp,
+        &args.listen_addr,
+        seconds(args.bind_retry),
+        || UdpSocket::bind(&args.listen_addr),
+    )
+    .await?;
+    notice!(
+        "Server UDP bound to {:?}",
+        "服务端 UDP 已绑定到 {:?}",
+        udp_socket.local_addr()?
+    );
+    let conv_cache = ConvCache::new(0, Duration::from_secs(args.conv_quarantine));
+    let kcp_config = args.kcp_config();
+    // 设置防火墙标记需要的权限在降权之后就没有了
+    udp::tune_listener(&udp_socket, &kcp_config);
+    args.harden()?;
+    let footprint = session_footprint(&kcp_config);
+    let mut kcp_listener = Listener::new(&args.listen_addr, kcp_config, udp_socket, conv_cache)?;
+    let options = args.session_options();
+    // 反向隧道的访客和正向转发的客户端共用新会话限速和建立中会话的名额
+    let throttle = args.throttle().map(Arc::new);
+    let pending = args.pending();
+    let hub = args.reverse_ports.clone().map(|ports| {
+        let sessions = reverse::Sessions {
+            registry: registry.clone(),
+            budget: budget.clone(),
+            capture: capture.clone(),
+            tracker: tracker.clone(),
+            options,
+            footprint,
+        };
+        Arc::new(reverse::Hub::new(
+            args.reverse_bind.clone(),
+            ports,
+            args.reverse_auth.iter().cloned().collect(),
+            reverse::Gates {
+                throttle: throttle.clone(),
+                pending: pending.clone(),
+                countries: geoip::Policy::new(&args.allow_country, &args.deny_country),
+            },
+            sessions,
+        ))
+    });
+    if hub.is_some() {
+        warn!(
+            "Reverse tunnel secrets travel in cleartext, run the tunnel over an encrypted underlay (WireGuard, IPsec, SSH...)",
+            "反向隧道的口令是明文传输的，请让隧道跑在加密的底层线路上（WireGuard、IPsec、SSH 等）"
+        );
+    }
+    let features = if hub.is_some() {
+        protocol::FEATURE_REVERSE | protocol::FEATURE_CONTROL
+    } else {
+        protocol::FEATURE_CONTROL
+    };
+
+    notice!(
+        "Begin forward task: tcp://{} <-> kcp://{}",
+        "开始转发：tcp://{} <-> kcp://{}",
+        args.proxy_addr(),
+        &args.listen_addr
+    );
+
+    registry.set_backend(args.proxy_addr());
+    let breaker = (args.circuit_breaker > 0).then(|| Breaker::new(args.circuit_breaker));
+    if let Some(breaker) = &breaker {
+        registry.set_breaker(breaker.clone());
+    }
+    let countries = geoip::Policy::new(&args.allow_country, &args.deny_country);
+    // 控制通道，排空时等它们通知完客户端再关闭监听
+    let controls = TaskTracker::new();
+    let push = Arc::new(args.push_settings());
+    loop {
+        info!(
+            "Waiting for new client connection...",
+            "等待新的客户端连接..."
+        );
+        if let Some(throttle) = &throttle {
+            tokio::select! {
+                _ = throttle.acquire() => {}
+                _ = registry.draining() => break,
+            }
+        }
+        let (mut income_stream, income_addr, wire) = tokio::select! {
+            accepted = kcp_listener.accept() => accepted?,
+            _ = registry.draining() => break,
+        };
+        let session_id = Uuid::new_v4().to_string();
+        let conv = income_stream.conv();
+        let country = geoip::country(income_addr.ip());
+        let location = country
+            .as_ref()
+            .map_or(String::new(), |country| format!(" ({country})"));
+        info!(
+            "New connection from client {income_addr}{location}, with session id {session_id}, conv {conv:#010x}",
+            "客户端 {income_addr}{location} 发起新连接，会话 id {session_id}，conv {conv:#010x}"
+        );
+        if let Err(reason) = countries.admit(income_addr.ip(), country.as_deref()) {
+            warn_repeated!(
+                tr!("client country rejected", "客户端所在地区被拒绝"),
+                "Session {session_id}: rejected client {income_addr}, {reason}",
+                "会话 {session_id}：拒绝客户端 {income_addr}，{reason}"
+            );
+            registry
+                .audit()
+                .record(income_addr, &session_id, Outcome::Rejected, reason);
+            income_stream.shutdown_immediately();
+            continue;
+        }
+        // 同一个 UDP 套接字上 conv 必须唯一，表里还有同 conv 的会话说明它的 KCP 连接其实已经断了
+        if let Some(stale) = registry.find_conv(conv) {
+            warn!(
+                "Session {stale}: conv {conv:#010x} was reassigned, closing stale session",
+                "会话 {stale}：conv {conv:#010x} 已被重新分配，关闭失效的会话"
+            );
+            registry.stop(&stale, CloseReason::KcpError);
+        }
+        let slot = match pending.as_ref().map(|pending| pending.admit(&session_id)) {
+            Some(None) => {
+                registry.audit().record(
+                    income_addr,
+                    &session_id,
+                    Outcome::Rejected,
+                    "too many pending sessions",
+                );
+                income_stream.shutdown_immediately();
+                continue;
+            }
+            Some(slot) => slot,
+            None => None,
+        };
+        let dropped = slot.as_ref().map(Slot::dropped);
+        let legacy = args.legacy_protocol;
+        let registry = registry.clone();
+        let budget = budget.clone();
+        let capture = capture.clone();
+        let hub = hub.clone();
+        let controls = controls.clone();
+        let push = push.clone();
+        let breaker = breaker.clone();
+        let fallback = fallback.clone();
+        let (task_registry, task_id) = (registry.clone(), session_id.clone());
+        let session = async move {
+            let mut income_stream = income_stream;
+            let Some(_charge) = budget.try_charge(footprint) else {
+                budget.reject();
+                registry.audit().record(
+                    income_addr,
+                    &session_id,
+                    Outcome::Rejected,
+                    "memory budget exhausted",
+                );
+                registry.say_goodbye(income_addr.ip(), conv, Goodbye::QuotaExceeded);
+                income_stream.shutdown_immediately();
+                return warn_repeated!(
+                    tr!("memory budget exhausted", "内存预算已用完"),
+                    "Session {session_id}: rejected, memory budget exhausted",
+                    "会话 {session_id}：内存预算已用完，拒绝连接"
+                );
+            };
+            let registration = registry.register(&session_id, income_addr);
+            registration.set_conv(conv);
+            registration.set_wire(wire);
+            if let Some(country) = country {
+                registration.tag(format!("country={country}"));
+            }
+            // 客户端在握手里带上的身份
+            let mut identity = None;
+            // 旧版客户端没有握手，判断时已经读出来的数据
+            let mut early_data = Vec::new();
+            if !legacy {
+                match timeout(
+                    protocol::HANDSHAKE_TIMEOUT,
+                    protocol::server_handshake(&mut income_stream, features),
+                )
+                .await
+                {
+                    Ok(Ok(Greeting::Legacy(data))) => {
+                        info!(
+                            "Session {session_id}: client sent no handshake, serving it as a 1.0.x client",
+                            "会话 {session_id}：客户端没有发送握手，按 1.0.x 版本的客户端处理"
+                        );
+                        registration.tag("legacy".to_string());
+                        early_data = data;
+                    }
+                    Ok(Ok(Greeting::Hello(hello))) => {
+                        info!(
+                            "Session {session_id}: client speaks protocol v{}, features {:#x}",
+                            "会话 {session_id}：客户端协议版本 v{}，功能位 {:#x}",
+                            hello.version,
+                            hello.features
+                        );
+                        if let Some(identity) = &hello.identity {
+                            info!(
+                                "Session {session_id}: client identifies as {identity}",
+                                "会话 {session_id}：客户端的身份是 {identity}"
+                            );
+                            registration.tag(format!("client={identity}"));
+                        }
+                        identity = hello.identity;
+                        // 反向隧道和控制通道的连接不是普通会话，交给各自处理
+                        match (hello.request, &hub) {
+                            (Request::Forward, _) => {}
+                            (Request::Control, _) => {
+                                registry.audit().record(
+                                    income_addr,
+                                    &session_id,
+                                    Outcome::Accepted,
+                                    "control channel",
+                                );
+                                registration.hand_off();
+                                controls.spawn(control::serve(
+                                    income_stream,
+                                    income_addr,
+                                    identity,
+                                    registry.clone(),
+                                    options,
+                                    push.clone(),
+                                ));
+                                return;
+                            }
+                            (
+                                Request::Register {
+                                    name,
+                                    port,
+                                    host,
+                                    secret,
+                                },
+                                Some(hub),
+                            ) => {
+                                registry.audit().record(
+                                    income_addr,
+                                    &session_id,
+                                    Outcome::Accepted,
+                                    format!("reverse tunnel {name}"),
+                                );
+                                registration.hand_off();
+                                tokio::spawn(hub.clone().serve(
+                                    income_stream,
+                                    income_addr,
+                                    Claim {
+                                        name,
+                                        port,
+                                        host,
+                                        identity,
+                                        secret,
+                                    },
+                                ));
+                                return;
+                            }
+                            (Request::Attach { token }, Some(hub)) => {
+                                registry.audit().record(
+                                    income_addr,
+                                    &session_id,
+                                    Outcome::Accepted,
+                                    "reverse tunnel visitor",
+                                );
+                                registration.hand_off();
+                                if !hub.attach(token, income_stream, registration.wire()) {
+                                    warn!(
+                                        "Session {session_id}: no visitor is waiting for token {token:#x}",
+                                        "会话 {session_id}：没有访客在等待 token {token:#x}"
+                                    );
+                                }
+                                return;
+                            }
+                            (_, None) => {
+                                registry.audit().record(
+                                    income_addr,
+                                    &session_id,
+                                    Outcome::Rejected,
+                                    "reverse tunnels not enabled",
+                                );
+                                income_stream.shutdown_immediately();
+                                return warn!(
+                                    "Session {session_id}: client asked for a reverse tunnel, which is not enabled",
+                                    "会话 {session_id}：客户端请求反向隧道，但服务端没有开启"
+                                );
+                            }
+                        }
+                    }
+                    Ok(Err(e)) => {
+                        registry.audit().record(
+                            income_addr,
+                            &session_id,
+                            Outcome::Failed,
+                            format!("handshake failed, {e:#}"),
+                        );
+                        return warn_repeated!(
+                            tr!("handshake failed", "握手失败"),
+                            "Session {session_id}: handshake failed, {e:#}",
+                            "会话 {session_id}：握手失败，{e:#}"
+                        );
+                    }
+                    Err(_) => {
+                        registry.audit().record(
+                            income_addr,
+                            &session_id,
+                            Outcome::Failed,
+                            "handshake timed out",
+                        );
+                        return warn_repeated!(
+                            tr!("handshake timed out", "握手超时"),
+                            "Session {session_id}: handshake timed out",
+                            "会话 {session_id}：握手超时"
+                        );
+                    }
+                }
+            }
+            // 管理接口可能切换了后端，按连接时的地址
+            let proxy_addr = registry
+                .backend()
+                .expect("backend is set before accepting sessions");
+            let tripped = breaker
+                .as_ref()
+                .is_some_and(|breaker| !breaker.allow(&proxy_addr));
+            let connected = if tripped {
+                None
+            } else {
+                let connected = dns::connect_tcp(&proxy_addr).await.ok();
+                if let Some(breaker) = &breaker {
+                    match connected {
+                        Some(_) => breaker.succeeded(&proxy_addr),
+                        None => breaker.failed(&proxy_addr),
+                    }
+                }
+                connected
+            };
+            if let Some(tcp_stream) = connected {
+                registry.audit().record(
+                    income_addr,
+                    &session_id,
+                    Outcome::Accepted,
+                    match &identity {
+                        Some(identity) => format!("backend {proxy_addr} client={identity}"),
+                        None => format!("backend {proxy_addr}"),
+                    },
+                );
+                registration.set_backend(&proxy_addr);
+                drop(slot);
+                let capture = capture.map(|capture| capture.stream(income_addr));
+                let mut tcp_stream = tcp_stream;
+                // 后端这时就关闭的话，下面转发时会发现
+                let _ = tokio::io::AsyncWriteExt::write_all(&mut tcp_stream, &early_data).await;
+                let summary = handle_session(
+                    tcp_stream,
+                    income_stream,
+                    Role::Server,
+                    options,
+                    &budget,
+                    registration.control(),
+                    capture,
+                )
+                .await;
+                report_session(&registration, summary);
+            } else {
+                if tripped {
+                    registry.audit().record(
+                        income_addr,
+                        &session_id,
+                        Outcome::Rejected,
+                        format!("backend {proxy_addr} circuit open"),
+                    );
+                    warn_repeated!(
+                        tr!(
+                            "backend {proxy_addr} circuit open",
+                            "后端 {proxy_addr} 已熔断"
+                        ),
+                        "Session {session_id}: backend {proxy_addr} is down, failing fast",
+                        "会话 {session_id}：后端 {proxy_addr} 已熔断，直接按失败处理"
+                    );
+                } else {
+                    registry.audit().record(
+                        income_addr,
+                        &session_id,
+                        Outcome::Failed,
+                        format!("backend {proxy_addr} unreachable"),
+                    );
+                    error_repeated!(
+                        tr!(
+                            "backend {proxy_addr} unreachable",
+                            "后端 {proxy_addr} 无法连接"
+                        ),
+                        "Session {session_id}: Failed to connection to tcp endpoint({proxy_addr})",
+                        "会话 {session_id}：无法连接到 TCP 后端（{proxy_addr}）"
+                    );
+                }
+                if let Some(fallback) = &fallback {
+                    circuit::fall_back(&mut income_stream, fallback).await;
+                }
+            };
+        };
+        spawn_session(
+            tracker,
+            task_registry,
+            task_id,
+            pending::guard(dropped, session),
+        );
+    }
+
+    // 关闭 KCP 监听会连带断开所有已接受的连接，所以排空期间继续接受并直接拒绝新连接
+    let rejecting = async {
+        loop {
+            let Ok((mut stream, addr, _)) = kcp_listener.accept().await else {
+                // 监听器没能重建，等排空结束
+                return std::future::pending().await;
+            };
+            info!(
+                "Rejected connection from client {addr}: draining",
+                "拒绝客户端 {addr} 的连接：正在排空"
+            );
+            registry
+                .audit()
+                .record(addr, "", Outcome::Rejected, "draining");
+            stream.shutdown_immediately();
+        }
+    };
+    tokio::select! {
+        _ = drain(registry) => {}
+        _ = rejecting => {}
+    }
+    controls.close();
+    let _ = timeout(SHUTDOWN_GRACE, controls.wait()).await;
+    // 收发循环关闭时会等所有 conv 断开，不能让它拖住退出
+    if timeout(SHUTDOWN_GRACE, kcp_listener.close()).await.is_err() {
+        warn!(
+            "KCP listener did not close in time, exiting anyway",
+            "KCP 监听没能及时关闭，直接退出"
+        );
+    }
+    Ok(())
+}
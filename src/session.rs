@@ -0,0 +1,701 @@
+use crate::budget::{Budget, Charge};
+use crate::class::{self, Shaper};
+use crate::keepalive::Keepalive;
+use crate::latency::Histogram;
+use crate::limit::Limit;
+use crate::mirror::Mirror;
+use crate::pcap::StreamCapture;
+use crate::record::Recorder;
+use crate::registry::{Control, Traffic};
+use kcp::{KcpConfig, KcpStream};
+use std::convert::Infallible;
+use std::fmt;
+use std::future::poll_fn;
+use std::io::IoSlice;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::task::Poll;
+use std::time::Duration;
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::net::TcpStream;
+use tokio::time::{self, Instant};
+
+const RELAY_BUFFER_SIZE: usize = 16 * 1024;
+/// KCP -> TCP 方向一次写入最多聚合的缓冲区个数
+const MAX_WRITE_BATCH: usize = 4;
+/// 把关闭原因告知对端后，等这么久再关闭 KCP 连接
+const FAREWELL_HEAD_START: Duration = Duration::from_millis(100);
+
+/// 一个会话预计占用的缓冲内存：两个方向的转发缓冲区，加上 KCP 收发窗口塞满时的数据量
+pub fn session_footprint(config: &KcpConfig) -> usize {
+    2 * RELAY_BUFFER_SIZE + (config.snd_wnd + config.rcv_wnd) as usize * config.mtu as usize
+}
+
+/// 会话中 TCP 一端的角色：客户端模式下是本地程序，服务端模式下是被代理的后端
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Client,
+    Server,
+}
+
+/// 会话结束的原因
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CloseReason {
+    /// 客户端一侧先关闭了连接
+    ClientEof,
+    /// 后端一侧先关闭了连接
+    BackendEof,
+    /// TCP 连接读写出错
+    TcpError,
+    /// KCP 连接读写出错
+    KcpError,
+    /// 两个方向都长时间没有数据
+    IdleTimeout,
+    /// 某一方向等待数据超时
+    ReadTimeout,
+    /// 会话存活时间超过上限
+    MaxDuration,
+    /// 被管理接口关闭
+    AdminKill,
+    /// 程序退出
+    Shutdown,
+    /// 系统休眠太久，对端的 KCP 会话已经过期
+    SystemSleep,
+    /// 本机连接服务端的地址变了，对端不再认得这个会话
+    AddressChange,
+    /// KCP 一直在重传却没有进展，见 `storm`
+    RetransmitStorm,
+    /// 对端通过控制通道告知它关闭了这个会话
+    Remote(Goodbye),
+}
+
+impl CloseReason {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            CloseReason::ClientEof => "client_eof",
+            CloseReason::BackendEof => "backend_eof",
+            CloseReason::TcpError => "tcp_error",
+            CloseReason::KcpError => "kcp_error",
+            CloseReason::IdleTimeout => "idle_timeout",
+            CloseReason::ReadTimeout => "read_timeout",
+            CloseReason::MaxDuration => "max_duration",
+            CloseReason::AdminKill => "admin_kill",
+            CloseReason::Shutdown => "shutdown",
+            CloseReason::SystemSleep => "system_sleep",
+            CloseReason::AddressChange => "address_change",
+            CloseReason::RetransmitStorm => "retransmit_storm",
+            CloseReason::Remote(Goodbye::Shutdown) => "peer_shutdown",
+            CloseReason::Remote(Goodbye::Idle) => "peer_idle",
+            CloseReason::Remote(Goodbye::QuotaExceeded) => "peer_quota_exceeded",
+            CloseReason::Remote(Goodbye::Revoked) => "peer_revoked",
+            CloseReason::Remote(Goodbye::Other) => "peer_closed",
+        }
+    }
+
+    /// 需要告知对端的关闭原因，对端自己能看到的（EOF、读写出错）和对端告知的不再转告
+    pub fn goodbye(self) -> Option<Goodbye> {
+        match self {
+            CloseReason::Shutdown => Some(Goodbye::Shutdown),
+            CloseReason::IdleTimeout | CloseReason::ReadTimeout => Some(Goodbye::Idle),
+            CloseReason::MaxDuration => Some(Goodbye::QuotaExceeded),
+            CloseReason::AdminKill => Some(Goodbye::Revoked),
+            _ => None,
+        }
+    }
+}
+
+/// 主动关闭会话的一方通过控制通道告知对端的原因
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Goodbye {
+    /// 程序退出
+    Shutdown,
+    /// 会话空闲超时
+    Idle,
+    /// 超出了会话存活时间、内存预算等配额，稍后重试可能成功
+    QuotaExceeded,
+    /// 被管理员关闭
+    Revoked,
+    /// 更新版本的对端使用的、这里不认识的原因
+    Other,
+}
+
+impl Goodbye {
+    pub fn code(self) -> u8 {
+        match self {
+            Goodbye::Other => 0,
+            Goodbye::Shutdown => 1,
+            Goodbye::Idle => 2,
+            Goodbye::QuotaExceeded => 3,
+            Goodbye::Revoked => 4,
+        }
+    }
+
+    pub fn from_code(code: u8) -> Self {
+        match code {
+            1 => Goodbye::Shutdown,
+            2 => Goodbye::Idle,
+            3 => Goodbye::QuotaExceeded,
+            4 => Goodbye::Revoked,
+            _ => Goodbye::Other,
+        }
+    }
+}
+
+impl fmt::Display for Goodbye {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Goodbye::Shutdown => "shutting down",
+            Goodbye::Idle => "idle",
+            Goodbye::QuotaExceeded => "quota exceeded",
+            Goodbye::Revoked => "revoked",
+            Goodbye::Other => "unknown reason",
+        })
+    }
+}
+
+impl fmt::Display for CloseReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// 会话的超时设置，`None` 表示不限制
+#[derive(Clone, Copy, Default)]
+pub struct SessionOptions {
+    /// 两个方向都没有数据时的超时
+    pub idle_timeout: Option<Duration>,
+    /// 等待 TCP 一端数据的超时（TCP -> KCP 方向）
+    pub tcp_read_timeout: Option<Duration>,
+    /// 等待 KCP 一端数据的超时（KCP -> TCP 方向）
+    pub kcp_read_timeout: Option<Duration>,
+    /// 会话的最长存活时间
+    pub max_duration: Option<Duration>,
+    /// 交互会话和批量会话的分类方式
+    pub class: class::Mode,
+    /// `auto` 模式下某个方向转发超过这么多字节后按批量会话处理
+    pub bulk_threshold: u64,
+    /// 低延迟模式：TCP 一端关闭 Nagle 算法
+    pub low_latency: bool,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Side {
+    Tcp,
+    Kcp,
+}
+
+impl Side {
+    /// 这一端是否连着客户端一侧（客户端模式下的本地程序，服务端模式下的 KCP 对端）
+    fn is_client(self, role: Role) -> bool {
+        matches!(
+            (self, role),
+            (Side::Tcp, Role::Client) | (Side::Kcp, Role::Server)
+        )
+    }
+
+    fn eof_reason(self, role: Role) -> CloseReason {
+        if self.is_client(role) {
+            CloseReason::ClientEof
+        } else {
+            CloseReason::BackendEof
+        }
+    }
+
+    fn error_reason(self) -> CloseReason {
+        match self {
+            Side::Tcp => CloseReason::TcpError,
+            Side::Kcp => CloseReason::KcpError,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Side::Tcp => "TCP",
+            Side::Kcp => "KCP",
+        }
+    }
+}
+
+enum PumpError {
+    Io(Side, io::Error),
+    Timeout(Side),
+}
+
+/// 记录最近一次有数据经过的时间，单位是相对会话开始的毫秒数；
+/// 开启抓包、记录、跟踪或镜像时顺便记录、复制经过的数据
+struct Activity {
+    start: Instant,
+    last: AtomicU64,
+    id: String,
+    role: Role,
+    capture: Option<StreamCapture>,
+    mirror: Option<Mirror>,
+    recorder: Option<Recorder>,
+    trace: Arc<AtomicBool>,
+    traffic: Vec<Arc<Traffic>>,
+    latency: Vec<Arc<Histogram>>,
+    bulk: Arc<AtomicBool>,
+    shaper: Option<Arc<Shaper>>,
+    limit: Arc<Limit>,
+    keepalive: Option<Arc<Keepalive>>,
+    class: class::Mode,
+    bulk_threshold: u64,
+}
+
+impl Activity {
+    fn new(
+        control: Control,
+        role: Role,
+        capture: Option<StreamCapture>,
+        options: &SessionOptions,
+    ) -> Self {
+        control
+            .bulk
+            .store(options.class == class::Mode::Bulk, Ordering::Relaxed);
+        Self {
+            start: Instant::now(),
+            last: AtomicU64::new(0),
+            mirror: Mirror::start(&control.id),
+            recorder: Recorder::start(&control.id),
+            id: control.id,
+            role,
+            capture,
+            trace: control.trace,
+            traffic: control.traffic,
+            latency: control.latency,
+            bulk: control.bulk,
+            shaper: control.shaper,
+            limit: control.limit,
+            keepalive: control.keepalive,
+            class: options.class,
+            bulk_threshold: options.bulk_threshold,
+        }
+    }
+
+    fn is_bulk(&self) -> bool {
+        self.bulk.load(Ordering::Relaxed)
+    }
+
+    /// 某个方向累计转发了 `total` 字节，`auto` 模式下超过阈值时改按批量会话处理
+    fn classify(&self, total: u64) {
+        if self.is_bulk() {
+            return;
+        }
+        if self.class == class::Mode::Auto && total >= self.bulk_threshold {
+            self.bulk.store(true, Ordering::Relaxed);
+            debug!(
+                "Session {}: forwarded {} bytes, handling it as a bulk session",
+                "会话 {}：已转发 {} 字节，改按批量会话处理", self.id, total
+            );
+        } else if let Some(shaper) = &self.shaper {
+            shaper.interactive();
+        }
+    }
+
+    /// 批量会话写入 KCP 之前按需要让路，设置了带宽上限时按上限等待
+    async fn pace(&self, write_side: Side, bytes: usize) {
+        if let (Side::Kcp, true, Some(shaper)) = (write_side, self.is_bulk(), &self.shaper) {
+            shaper.pace(bytes).await;
+        }
+        self.limit.pace(write_side as usize, bytes).await;
+    }
+
+    /// 记录从 `read_side` 读到的数据已经写出了 `bytes` 字节
+    fn transferred(&self, read_side: Side, bytes: u64) {
+        for traffic in &self.traffic {
+            match read_side {
+                Side::Tcp => traffic.add_sent(bytes),
+                Side::Kcp => traffic.add_received(bytes),
+            }
+        }
+    }
+
+    /// 记录一段数据从读到到写完用的时间
+    fn forwarded(&self, latency: Duration) {
+        for histogram in &self.latency {
+            histogram.record(latency);
+        }
+    }
+
+    /// 记录从 `read_side` 读到、即将转发的数据，`offset` 是这段数据在该方向上的起始偏移
+    fn observe(&self, (read_side, write_side): (Side, Side), offset: u64, slices: &[IoSlice]) {
+        if let Some(capture) = &self.capture {
+            for slice in slices {
+                capture.data(read_side.is_client(self.role), slice);
+            }
+        }
+        if let Some(recorder) = &self.recorder {
+            for slice in slices {
+                recorder.data(read_side.is_client(self.role), slice);
+            }
+        }
+        if let Some(mirror) = &self.mirror
+            && read_side.is_client(self.role)
+        {
+            for slice in slices {
+                mirror.data(slice);
+            }
+        }
+        debug!(
+            "Session {} {} -> {}: {} bytes at offset {offset}",
+            "会话 {} {} -> {}：{} 字节，偏移 {offset}",
+            self.id,
+            read_side.name(),
+            write_side.name(),
+            slices.iter().map(|slice| slice.len()).sum::<usize>()
+        );
+        if self.trace.load(Ordering::Relaxed) {
+            let mut offset = offset;
+            for slice in slices {
+                println_tr!(
+                    "Session {} {} -> {} offset {offset:#x}, {} bytes\n{}",
+                    "会话 {} {} -> {} 偏移 {offset:#x}，{} 字节\n{}",
+                    self.id,
+                    read_side.name(),
+                    write_side.name(),
+                    slice.len(),
+                    hexdump(offset, slice)
+                );
+                offset += slice.len() as u64;
+            }
+        }
+    }
+
+    fn touch(&self) {
+        let elapsed = self.start.elapsed().as_millis() as u64;
+        self.last.store(elapsed, Ordering::Relaxed);
+    }
+
+    fn last(&self) -> Instant {
+        self.start + Duration::from_millis(self.last.load(Ordering::Relaxed))
+    }
+
+    /// 直到连续 `timeout` 时间没有活动才返回
+    async fn idle(&self, timeout: Option<Duration>) {
+        let Some(timeout) = timeout else {
+            return std::future::pending().await;
+        };
+        loop {
+            let deadline = self.last() + timeout;
+            if Instant::now() >= deadline {
+                return;
+            }
+            time::sleep_until(deadline).await;
+        }
+    }
+
+    /// 连续一个保活间隔没有数据经过时发送保活包，一直运行到会话结束，不会返回
+    async fn keepalive(&self) -> Infallible {
+        let Some(keepalive) = &self.keepalive else {
+            return std::future::pending().await;
+        };
+        let mut sent = self.start;
+        loop {
+            let deadline = self.last().max(sent) + keepalive.interval;
+            if Instant::now() >= deadline {
+                keepalive.send();
+                sent = Instant::now();
+                continue;
+            }
+            time::sleep_until(deadline).await;
+        }
+    }
+}
+
+/// 会话结束时的统计
+pub struct SessionSummary {
+    pub reason: CloseReason,
+    pub sent: u64,
+    pub received: u64,
+    pub error: Option<io::Error>,
+}
+
+pub async fn handle_session(
+    mut tcp_stream: TcpStream,
+    kcp_stream: KcpStream,
+    role: Role,
+    options: SessionOptions,
+    budget: &Budget,
+    control: Control,
+    capture: Option<StreamCapture>,
+) -> SessionSummary {
+    let mut control = control;
+    let mut stop = control.stop.clone();
+    let farewell = control.farewell.take();
+    if options.low_latency {
+        let _ = tcp_stream.set_nodelay(true);
+    }
+    let (mut tcp_reader, mut tcp_writer) = tcp_stream.split();
+    let (mut kcp_reader, mut kcp_writer) = io::split(kcp_stream);
+
+    let mut sent = 0;
+    let mut received = 0;
+    let mut reason = None;
+    let mut error = None;
+    let activity = Activity::new(control, role, capture, &options);
+
+    {
+        let upstream = pump(
+            &mut tcp_reader,
+            &mut kcp_writer,
+            (Side::Tcp, Side::Kcp),
+            options.tcp_read_timeout,
+            None,
+            &activity,
+            &mut sent,
+        );
+        let downstream = pump(
+            &mut kcp_reader,
+            &mut tcp_writer,
+            (Side::Kcp, Side::Tcp),
+            options.kcp_read_timeout,
+            Some(budget),
+            &activity,
+            &mut received,
+        );
+        let idle = activity.idle(options.idle_timeout);
+        let expired = expire(options.max_duration);
+        let keepalive = activity.keepalive();
+        tokio::pin!(upstream, downstream, idle, expired, keepalive);
+        let mut upstream_done = false;
+        let mut downstream_done = false;
+
+        while !(upstream_done && downstream_done) {
+            let result = tokio::select! {
+                r = &mut upstream, if !upstream_done => {
+                    upstream_done = true;
+                    r
+                }
+                r = &mut downstream, if !downstream_done => {
+                    downstream_done = true;
+                    r
+                }
+                _ = &mut idle => {
+                    reason = Some(CloseReason::IdleTimeout);
+                    break;
+                }
+                _ = &mut expired => {
+                    reason = Some(CloseReason::MaxDuration);
+                    break;
+                }
+                never = &mut keepalive => match never {},
+                Ok(stop_reason) = stop.wait_for(Option::is_some) => {
+                    reason = *stop_reason;
+                    break;
+                }
+            };
+            match result {
+                Ok(side) => {
+                    reason.get_or_insert(side.eof_reason(role));
+                }
+                Err(PumpError::Io(side, e)) => {
+                    reason = Some(side.error_reason());
+                    error = Some(e);
+                    break;
+                }
+                Err(PumpError::Timeout(side)) => {
+                    reason = Some(CloseReason::ReadTimeout);
+                    error = Some(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        format!("no data from {} side in time", side.name()),
+                    ));
+                    break;
+                }
+            }
+        }
+    }
+
+    let reason = reason.unwrap_or(CloseReason::Shutdown);
+    // 告知原因的消息走另一条 KCP 连接，稍等一下再关闭，让对端先收到原因再看到连接断开
+    if farewell.is_some_and(|farewell| farewell.send(reason)) {
+        time::sleep(FAREWELL_HEAD_START).await;
+    }
+    let _ = tcp_writer.shutdown().await;
+    let _ = kcp_writer.shutdown().await;
+
+    SessionSummary {
+        reason,
+        sent,
+        received,
+        error,
+    }
+}
+
+/// 会话存活超过 `max_duration` 后返回
+async fn expire(max_duration: Option<Duration>) {
+    match max_duration {
+        Some(max_duration) => time::sleep(max_duration).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// 从 reader 搬运数据到 writer，正常结束时返回先到达 EOF 的一端
+///
+/// 传入 `batch` 时，每次读到数据后会继续取走 reader 中已经就绪的数据，
+/// 最多攒满 `MAX_WRITE_BATCH` 个缓冲区，再用一次 `write_vectored` 写出，减少写端的系统调用。
+/// KCP 每次读取只返回一个分片，大流量下载时能明显减少对 TCP 后端的写入次数。
+/// 攒批从不等待新数据，交互式会话通常只有一个分片就绪，照样立即写出，所以不区分会话类型。
+/// 第一个之后的缓冲区从 `batch` 预算中申请，预算不足时只写出已经攒下的数据。
+async fn pump<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    (read_side, write_side): (Side, Side),
+    read_timeout: Option<Duration>,
+    batch: Option<&Budget>,
+    activity: &Activity,
+    counter: &mut u64,
+) -> Result<Side, PumpError>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut bufs = vec![vec![0u8; RELAY_BUFFER_SIZE]];
+    let mut charges = Vec::new();
+    let mut filled = Vec::with_capacity(MAX_WRITE_BATCH);
+    loop {
+        let read = reader.read(&mut bufs[0]);
+        let n = match read_timeout {
+            Some(timeout) => time::timeout(timeout, read)
+                .await
+                .map_err(|_| PumpError::Timeout(read_side))?,
+            None => read.await,
+        }
+        .map_err(|e| PumpError::Io(read_side, e))?;
+        if n == 0 {
+            return finish(writer, read_side, write_side).await;
+        }
+        let read_at = Instant::now();
+
+        filled.clear();
+        filled.push(n);
+        // 攒批时遇到的 EOF 或错误，先把已读到的数据写出再处理
+        let mut deferred = None;
+        if let Some(budget) = batch {
+            deferred = gather(reader, &mut bufs, &mut filled, budget, &mut charges).await;
+        }
+
+        let mut slices: Vec<IoSlice> = bufs
+            .iter()
+            .zip(&filled)
+            .filter(|&(_, &len)| len > 0)
+            .map(|(buf, &len)| IoSlice::new(&buf[..len]))
+            .collect();
+        activity.observe((read_side, write_side), *counter, &slices);
+        activity
+            .pace(write_side, slices.iter().map(|slice| slice.len()).sum())
+            .await;
+        let total = write_all_vectored(writer, &mut slices)
+            .await
+            .map_err(|e| PumpError::Io(write_side, e))?;
+        *counter += total as u64;
+        activity.transferred(read_side, total as u64);
+        activity.forwarded(read_at.elapsed());
+        activity.classify(*counter);
+        activity.touch();
+
+        match deferred {
+            Some(Ok(())) => return finish(writer, read_side, write_side).await,
+            Some(Err(e)) => return Err(PumpError::Io(read_side, e)),
+            None => {}
+        }
+    }
+}
+
+/// 读端到达 EOF 后关闭写端
+async fn finish<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    read_side: Side,
+    write_side: Side,
+) -> Result<Side, PumpError> {
+    writer
+        .shutdown()
+        .await
+        .map_err(|e| PumpError::Io(write_side, e))?;
+    Ok(read_side)
+}
+
+/// 不等待地继续读取已经就绪的数据，依次填满 `bufs`，`filled` 记录每个缓冲区的有效长度。
+/// 新增的缓冲区从 `budget` 申请，额度记在 `charges` 里，跟缓冲区一起保留到会话结束。
+/// 读到 EOF 时返回 `Some(Ok(()))`，出错时返回 `Some(Err(_))`，没有更多就绪数据时返回 `None`。
+async fn gather<'a, R: AsyncRead + Unpin>(
+    reader: &mut R,
+    bufs: &mut Vec<Vec<u8>>,
+    filled: &mut Vec<usize>,
+    budget: &'a Budget,
+    charges: &mut Vec<Charge<'a>>,
+) -> Option<io::Result<()>> {
+    loop {
+        let i = filled.len() - 1;
+        if filled[i] == RELAY_BUFFER_SIZE {
+            if filled.len() == MAX_WRITE_BATCH {
+                return None;
+            }
+            if bufs.len() == filled.len() {
+                charges.push(budget.try_charge(RELAY_BUFFER_SIZE)?);
+                bufs.push(vec![0u8; RELAY_BUFFER_SIZE]);
+            }
+            filled.push(0);
+            continue;
+        }
+        let buf = &mut bufs[i][filled[i]..];
+        let ready = poll_fn(|cx| {
+            let mut read_buf = ReadBuf::new(buf);
+            match Pin::new(&mut *reader).poll_read(cx, &mut read_buf) {
+                Poll::Ready(result) => Poll::Ready(Some(result.map(|()| read_buf.filled().len()))),
+                Poll::Pending => Poll::Ready(None),
+            }
+        })
+        .await;
+        match ready {
+            Some(Ok(0)) => return Some(Ok(())),
+            Some(Ok(n)) => filled[i] += n,
+            Some(Err(e)) => return Some(Err(e)),
+            None => return None,
+        }
+    }
+}
+
+/// 用 `write_vectored` 写出全部数据，返回写出的字节数
+async fn write_all_vectored<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    mut slices: &mut [IoSlice<'_>],
+) -> io::Result<usize> {
+    let mut total = 0;
+    while !slices.is_empty() {
+        let n = writer.write_vectored(slices).await?;
+        if n == 0 {
+            return Err(io::ErrorKind::WriteZero.into());
+        }
+        total += n;
+        IoSlice::advance_slices(&mut slices, n);
+    }
+    Ok(total)
+}
+
+/// 按 `hexdump -C` 的格式输出数据，行首是在该方向上的偏移
+fn hexdump(offset: u64, data: &[u8]) -> String {
+    let mut out = String::new();
+    for (i, line) in data.chunks(16).enumerate() {
+        let mut hex = String::with_capacity(49);
+        for (j, byte) in line.iter().enumerate() {
+            if j == 8 {
+                hex.push(' ');
+            }
+            hex += &format!("{byte:02x} ");
+        }
+        let text: String = line
+            .iter()
+            .map(|&byte| {
+                if byte.is_ascii_graphic() || byte == b' ' {
+                    byte as char
+                } else {
+                    '.'
+                }
+            })
+            .collect();
+        out += &format!("  {:08x}  {hex:<49} |{text}|\n", offset + (i * 16) as u64);
+    }
+    out.pop();
+    out
+}
@@ -0,0 +1,245 @@
+//! 调试用的网络条件模拟：客户端的 UDP 收发两个方向都按设定丢包和延迟，
+//! 用于在本地复现糟糕的网络并验证 KCP 参数，不应在正式环境中开启。
+//!
+//! 只在客户端一侧注入：两个方向的包都经过客户端，效果等同于整条链路的网络变差，
+//! 服务端所有会话共用的收发循环（见 `demux`）里就不再加一层。
+
+use crate::disguise;
+use crate::dns;
+use crate::udp;
+use crate::wire::Wire;
+use anyhow::{Context, bail};
+use bytes::BytesMut;
+use kcp::{KcpConfig, KcpStream};
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+
+/// 模拟的网络条件，格式如 `loss=2%,delay=50ms,jitter=10ms`
+#[derive(Clone, Copy, Default)]
+pub struct Conditions {
+    /// 每个方向的丢包率，0 到 1
+    loss: f64,
+    /// 每个方向的固定延迟
+    delay: Duration,
+    /// 延迟在 `delay` 上下随机波动的幅度
+    jitter: Duration,
+}
+
+impl FromStr for Conditions {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let mut conditions = Conditions::default();
+        for item in s.split(',').map(str::trim).filter(|item| !item.is_empty()) {
+            let (key, value) = item
+                .split_once('=')
+                .with_context(|| format!("expected key=value, got {item:?}"))?;
+            match key {
+                "loss" => conditions.loss = parse_ratio(value)?,
+                "delay" => conditions.delay = parse_duration(value)?,
+                "jitter" => conditions.jitter = parse_duration(value)?,
+                _ => bail!("unknown condition {key:?}, expected loss, delay or jitter"),
+            }
+        }
+        Ok(conditions)
+    }
+}
+
+impl fmt::Display for Conditions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "loss {}%, delay {}ms ± {}ms",
+            self.loss * 100.0,
+            self.delay.as_millis(),
+            self.jitter.as_millis()
+        )
+    }
+}
+
+/// 解析 `2%` 或 `0.02`
+fn parse_ratio(value: &str) -> anyhow::Result<f64> {
+    let ratio = match value.strip_suffix('%') {
+        Some(percent) => percent.parse::<f64>()? / 100.0,
+        None => value.parse()?,
+    };
+    if !(0.0..=1.0).contains(&ratio) {
+        bail!("loss must be between 0% and 100%, got {value}");
+    }
+    Ok(ratio)
+}
+
+/// 解析 `50ms` 或 `1s`，不带单位时按毫秒处理
+fn parse_duration(value: &str) -> anyhow::Result<Duration> {
+    let (number, unit) = match value.find(|c: char| !c.is_ascii_digit() && c != '.') {
+        Some(i) => value.split_at(i),
+        None => (value, "ms"),
+    };
+    let number: f64 = number
+        .parse()
+        .with_context(|| format!("invalid duration {value:?}"))?;
+    let secs = match unit {
+        "ms" => number / 1000.0,
+        "s" => number,
+        _ => bail!("invalid duration unit in {value:?}, expected ms or s"),
+    };
+    Duration::try_from_secs_f64(secs).with_context(|| format!("duration {value:?} is too long"))
+}
+
+impl Conditions {
+    fn dropped(&self) -> bool {
+        self.loss > 0.0 && rand::random::<f64>() < self.loss
+    }
+
+    fn latency(&self) -> Duration {
+        if self.jitter.is_zero() {
+            return self.delay;
+        }
+        let offset = self.jitter.as_secs_f64() * (rand::random::<f64>() * 2.0 - 1.0);
+        Duration::from_secs_f64((self.delay.as_secs_f64() + offset).max(0.0))
+    }
+}
+
+/// 和 `KcpUdpStream::connect` 一样建立 KCP 连接，但收发的 UDP 包都经过模拟的网络条件；
+/// 收发的包记到 `wire` 上，模拟丢掉的发出的包并没有真正发出，不记
+pub async fn connect(
+    config: Arc<KcpConfig>,
+    addr: &str,
+    conditions: Conditions,
+    wire: Arc<Wire>,
+) -> io::Result<(KcpStream, SocketAddr)> {
+    let addr = dns::lookup(addr).await?;
+    let udp = udp::bind_for(addr, &config).await?;
+    udp.connect(addr).await?;
+
+    let window = config.snd_wnd.max(8) as usize;
+    let (outgoing_tx, outgoing_rx) = mpsc::channel(window);
+    let (incoming_tx, incoming_rx) = mpsc::channel(window);
+    tokio::spawn(relay(
+        Arc::new(udp),
+        outgoing_rx,
+        incoming_tx,
+        conditions,
+        wire,
+        addr,
+    ));
+
+    let transport = kcp::transport::tokio_mpsc_stream(outgoing_tx, incoming_rx);
+    let stream =
+        KcpStream::connect::<_, BytesMut, _>(config, transport, futures::sink::drain(), None)
+            .await?;
+    Ok((stream, addr))
+}
+
+/// 在 KCP 和 UDP 套接字之间转发数据包，按设定丢弃或延迟；KCP 连接关闭后结束
+async fn relay(
+    udp: Arc<UdpSocket>,
+    mut outgoing: mpsc::Receiver<BytesMut>,
+    incoming: mpsc::Sender<BytesMut>,
+    conditions: Conditions,
+    wire: Arc<Wire>,
+    addr: SocketAddr,
+) {
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        tokio::select! {
+            packet = outgoing.recv() => {
+                let Some(packet) = packet else { break };
+                wire.segments_in(&packet);
+                if conditions.dropped() {
+                    continue;
+                }
+                let udp = udp.clone();
+                let wire = wire.clone();
+                let latency = conditions.latency();
+                tokio::spawn(async move {
+                    tokio::time::sleep(latency).await;
+                    if udp.send(&disguise::wrap(&packet)).await.is_ok() {
+                        wire.send(packet.len(), addr);
+                    }
+                });
+            }
+            received = udp.recv(&mut buf) => {
+                let Ok(n) = received else { continue };
+                let Some(packet) = disguise::unwrap(&buf[..n]) else { continue };
+                wire.receive(packet.len(), addr);
+                if conditions.dropped() {
+                    continue;
+                }
+                let packet = BytesMut::from(packet);
+                let incoming = incoming.clone();
+                let wire = wire.clone();
+                let latency = conditions.latency();
+                tokio::spawn(async move {
+                    tokio::time::sleep(latency).await;
+                    // 往返时间按 KCP 收到确认的时刻算，包括模拟的延迟
+                    wire.acks_in(&packet);
+                    let _ = incoming.send(packet).await;
+                });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(s: &str) -> anyhow::Result<Conditions> {
+        s.parse()
+    }
+
+    #[test]
+    fn parses_conditions() {
+        let conditions = parse("loss=2%, delay=50ms,jitter=0.5s,").unwrap();
+        assert_eq!(conditions.loss, 0.02);
+        assert_eq!(conditions.delay, Duration::from_millis(50));
+        assert_eq!(conditions.jitter, Duration::from_millis(500));
+        assert_eq!(conditions.to_string(), "loss 2%, delay 50ms ± 500ms");
+
+        // 比例可以不带百分号，时间不带单位时按毫秒
+        let conditions = parse("loss=0.1,delay=20").unwrap();
+        assert_eq!(conditions.loss, 0.1);
+        assert_eq!(conditions.delay, Duration::from_millis(20));
+        assert_eq!(parse("").unwrap().to_string(), "loss 0%, delay 0ms ± 0ms");
+        assert_eq!(parse("loss=100%").unwrap().loss, 1.0);
+    }
+
+    #[test]
+    fn rejects_invalid_conditions() {
+        for s in [
+            "loss",
+            "loss=101%",
+            "loss=-1%",
+            "loss=NaN",
+            "loss=abc",
+            "delay=5m",
+            "delay=-5ms",
+            "delay=ms",
+            "jitter=99999999999999999999999s",
+            "bandwidth=1mbit",
+        ] {
+            assert!(parse(s).is_err(), "{s}");
+        }
+    }
+
+    #[test]
+    fn latency_stays_within_jitter() {
+        let conditions = parse("delay=10ms,jitter=20ms").unwrap();
+        for _ in 0..100 {
+            assert!(conditions.latency() <= Duration::from_millis(30));
+        }
+        assert_eq!(
+            parse("delay=10ms").unwrap().latency(),
+            Duration::from_millis(10)
+        );
+        assert!(!parse("loss=0").unwrap().dropped());
+        assert!(parse("loss=100%").unwrap().dropped());
+    }
+}
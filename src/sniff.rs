@@ -0,0 +1,304 @@
+//! 从连接的第一个数据包里识别访客要访问的域名，用于反向隧道按域名分流。
+//!
+//! 支持 TLS ClientHello 的 SNI、HTTP 请求的 Host 头，以及 Minecraft 握手包里的服务器地址
+//! （玩家在客户端里填写的地址，Forge 附加的 `\0FML\0` 等标记会被去掉）。
+
+use crate::codec::Cursor;
+use std::time::Duration;
+use tokio::net::TcpStream;
+
+/// 等待访客发来第一个数据包的时间
+const SNIFF_TIMEOUT: Duration = Duration::from_secs(5);
+/// 最多查看的字节数，足够放下一个完整的 TLS 记录
+const MAX_SNIFF: usize = 16 * 1024 + 5;
+
+#[derive(Debug, PartialEq)]
+enum Sniff {
+    Host(String),
+    /// 数据还不完整，需要等更多数据
+    Incomplete,
+    /// 不是能识别的协议
+    Unknown,
+}
+
+/// 查看（不取走）访客发来的数据，识别出域名时返回小写的域名
+pub async fn hostname(stream: &TcpStream) -> Option<String> {
+    let sniff = async {
+        let mut buf = vec![0u8; MAX_SNIFF];
+        loop {
+            let n = stream.peek(&mut buf).await.ok()?;
+            if n == 0 {
+                return None;
+            }
+            match parse(&buf[..n]) {
+                Sniff::Host(host) => return Some(host),
+                Sniff::Incomplete if n < buf.len() => {
+                    // peek 不会等待更多数据，稍后再看
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+                Sniff::Incomplete | Sniff::Unknown => return None,
+            }
+        }
+    };
+    tokio::time::timeout(SNIFF_TIMEOUT, sniff)
+        .await
+        .ok()
+        .flatten()
+}
+
+fn parse(data: &[u8]) -> Sniff {
+    let sniff = if data.first() == Some(&0x16) {
+        tls(data)
+    } else if data.first().is_some_and(u8::is_ascii_uppercase) {
+        http(data)
+    } else {
+        minecraft(data)
+    };
+    match sniff {
+        Sniff::Host(host) => normalize(&host).map_or(Sniff::Unknown, Sniff::Host),
+        other => other,
+    }
+}
+
+/// 去掉末尾的点并转成小写，不是合法域名时返回 `None`
+pub fn normalize(host: &str) -> Option<String> {
+    let host = host.trim_end_matches('.');
+    let valid = !host.is_empty()
+        && host.len() <= 253
+        && host
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'.');
+    valid.then(|| host.to_ascii_lowercase())
+}
+
+fn tls(data: &[u8]) -> Sniff {
+    let mut record = Cursor(data);
+    let (Some(_), Some(_)) = (record.u8(), record.u16()) else {
+        return Sniff::Incomplete;
+    };
+    // 超过 TLS 记录长度上限的不是 TLS，不用等到超时
+    if data
+        .get(3..5)
+        .is_some_and(|len| u16::from_be_bytes([len[0], len[1]]) as usize > MAX_SNIFF - 5)
+    {
+        return Sniff::Unknown;
+    }
+    let Some(body) = record.vec16() else {
+        return Sniff::Incomplete;
+    };
+    tls_client_hello(body).map_or(Sniff::Unknown, Sniff::Host)
+}
+
+fn tls_client_hello(body: &[u8]) -> Option<String> {
+    let mut hello = Cursor(body);
+    if hello.u8()? != 0x01 {
+        return None;
+    }
+    hello.take(3)?; // 长度
+    hello.take(2 + 32)?; // 版本和随机数
+    hello.vec8()?; // session id
+    hello.vec16()?; // 加密套件
+    hello.vec8()?; // 压缩方法
+    let mut extensions = Cursor(hello.vec16()?);
+    while let Some(kind) = extensions.u16() {
+        let data = extensions.vec16()?;
+        if kind != 0 {
+            continue;
+        }
+        let mut names = Cursor(Cursor(data).vec16()?);
+        while let Some(name_type) = names.u8() {
+            let name = names.vec16()?;
+            if name_type == 0 {
+                return std::str::from_utf8(name).ok().map(str::to_string);
+            }
+        }
+    }
+    None
+}
+
+fn http(data: &[u8]) -> Sniff {
+    let Some(end) = data.windows(4).position(|w| w == b"\r\n\r\n") else {
+        return Sniff::Incomplete;
+    };
+    let Ok(head) = std::str::from_utf8(&data[..end]) else {
+        return Sniff::Unknown;
+    };
+    let host = head.lines().skip(1).find_map(|line| {
+        let (name, value) = line.split_once(':')?;
+        name.trim()
+            .eq_ignore_ascii_case("host")
+            .then(|| value.trim())
+    });
+    let Some(host) = host else {
+        return Sniff::Unknown;
+    };
+    // 去掉端口，IPv6 地址不用于分流
+    let host = match host.rsplit_once(':') {
+        Some((name, port)) if port.bytes().all(|b| b.is_ascii_digit()) => name,
+        _ => host,
+    };
+    Sniff::Host(host.to_string())
+}
+
+fn minecraft(data: &[u8]) -> Sniff {
+    let mut cursor = Cursor(data);
+    let Some(len) = cursor.varint() else {
+        return if data.len() < 5 {
+            Sniff::Incomplete
+        } else {
+            Sniff::Unknown
+        };
+    };
+    let Some(packet) = cursor.take(len as usize) else {
+        return if len as usize <= MAX_SNIFF {
+            Sniff::Incomplete
+        } else {
+            Sniff::Unknown
+        };
+    };
+    let mut packet = Cursor(packet);
+    let address = (|| {
+        if packet.varint()? != 0 {
+            return None;
+        }
+        packet.varint()?; // 协议版本
+        let len = packet.varint()?;
+        let address = std::str::from_utf8(packet.take(len as usize)?).ok()?;
+        packet.u16()?; // 端口
+        Some(address.split('\0').next().unwrap_or_default().to_string())
+    })();
+    address.map_or(Sniff::Unknown, Sniff::Host)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::{put_vec8, put_vec16};
+
+    fn host(name: &str) -> Sniff {
+        Sniff::Host(name.to_string())
+    }
+
+    /// 带有 `names` 这些 server_name 的 ClientHello 记录
+    fn client_hello(names: &[(u8, &str)]) -> Vec<u8> {
+        let mut list = Vec::new();
+        for &(kind, name) in names {
+            list.push(kind);
+            put_vec16(&mut list, name.as_bytes());
+        }
+        let mut sni = Vec::new();
+        put_vec16(&mut sni, &list);
+        let mut extensions = Vec::new();
+        // 前面放一个无关的扩展
+        extensions.extend_from_slice(&[0x00, 0x17]);
+        put_vec16(&mut extensions, &[]);
+        if !names.is_empty() {
+            extensions.extend_from_slice(&[0x00, 0x00]);
+            put_vec16(&mut extensions, &sni);
+        }
+
+        let mut hello = vec![0x03, 0x03];
+        hello.extend_from_slice(&[0; 32]);
+        put_vec8(&mut hello, &[1; 32]);
+        put_vec16(&mut hello, &[0x13, 0x01]);
+        put_vec8(&mut hello, &[0]);
+        put_vec16(&mut hello, &extensions);
+        let mut handshake = vec![0x01];
+        handshake.extend_from_slice(&(hello.len() as u32).to_be_bytes()[1..]);
+        handshake.extend_from_slice(&hello);
+        let mut record = vec![0x16, 0x03, 0x01];
+        put_vec16(&mut record, &handshake);
+        record
+    }
+
+    /// Minecraft 握手包，`len` 不为空时用它代替实际的包长度
+    fn minecraft_handshake(address: &str, len: Option<&[u8]>) -> Vec<u8> {
+        let mut packet = vec![0x00, 0xff, 0x05]; // 包 id 0，协议版本 767
+        packet.push(address.len() as u8);
+        packet.extend_from_slice(address.as_bytes());
+        packet.extend_from_slice(&25565u16.to_be_bytes());
+        packet.push(2);
+        let mut data = len.map_or_else(|| vec![packet.len() as u8], <[u8]>::to_vec);
+        data.extend_from_slice(&packet);
+        data
+    }
+
+    #[test]
+    fn reads_tls_sni() {
+        let record = client_hello(&[(0, "Example.COM.")]);
+        assert_eq!(parse(&record), host("example.com"));
+        // 非 host_name 类型的名字跳过
+        assert_eq!(
+            parse(&client_hello(&[(1, "x"), (0, "a.example")])),
+            host("a.example")
+        );
+        // 没有 SNI 的 ClientHello
+        assert_eq!(parse(&client_hello(&[])), Sniff::Unknown);
+        // 不是合法域名
+        assert_eq!(parse(&client_hello(&[(0, "bad name")])), Sniff::Unknown);
+
+        // 截断的记录等待更多数据
+        for len in [1, 3, 5, record.len() - 1] {
+            assert_eq!(parse(&record[..len]), Sniff::Incomplete, "{len}");
+        }
+        // 记录完整但 ClientHello 里的长度超出记录
+        let mut broken = record.clone();
+        broken[5 + 4 + 34] = 200;
+        assert_eq!(parse(&broken), Sniff::Unknown);
+        // 不是 ClientHello
+        let mut server_hello = record.clone();
+        server_hello[5] = 0x02;
+        assert_eq!(parse(&server_hello), Sniff::Unknown);
+        // 记录长度超过 TLS 的上限
+        assert_eq!(parse(&[0x16, 0x03, 0x01, 0xff, 0xff, 0x01]), Sniff::Unknown);
+    }
+
+    #[test]
+    fn reads_http_host() {
+        let request = b"GET / HTTP/1.1\r\nUser-Agent: test\r\nhost: Example.com:8080\r\n\r\n";
+        assert_eq!(parse(request), host("example.com"));
+        assert_eq!(
+            parse(b"POST /x HTTP/1.1\r\nHost: a.example\r\nContent-Length: 3\r\n\r\nabc"),
+            host("a.example")
+        );
+        // 头部还没收完
+        assert_eq!(parse(&request[..request.len() - 2]), Sniff::Incomplete);
+        // 没有 Host 头，或者 Host 是 IPv6 地址
+        assert_eq!(
+            parse(b"GET / HTTP/1.0\r\nAccept: */*\r\n\r\n"),
+            Sniff::Unknown
+        );
+        assert_eq!(
+            parse(b"GET / HTTP/1.1\r\nHost: [::1]:80\r\n\r\n"),
+            Sniff::Unknown
+        );
+        // 请求行之前的内容不算 Host
+        assert_eq!(parse(b"Host: a.example\r\n\r\n"), Sniff::Unknown);
+    }
+
+    #[test]
+    fn reads_minecraft_address() {
+        assert_eq!(
+            parse(&minecraft_handshake("mc.example.com", None)),
+            host("mc.example.com")
+        );
+        // Forge 在地址后面附加的标记
+        assert_eq!(
+            parse(&minecraft_handshake("mc.example.com\0FML3\0", None)),
+            host("mc.example.com")
+        );
+
+        let handshake = minecraft_handshake("mc.example.com", None);
+        assert_eq!(parse(&handshake[..1]), Sniff::Incomplete);
+        assert_eq!(parse(&handshake[..handshake.len() - 1]), Sniff::Incomplete);
+        // 包长度超过最多查看的字节数
+        let oversized = minecraft_handshake("mc.example.com", Some(&[0xff, 0xff, 0x04]));
+        assert_eq!(parse(&oversized), Sniff::Unknown);
+        // 包长度的 varint 太长
+        assert_eq!(parse(&[0x80, 0x80, 0x80, 0x80, 0x80, 0x01]), Sniff::Unknown);
+        // 不是握手包
+        let mut status = handshake.clone();
+        status[1] = 0x01;
+        assert_eq!(parse(&status), Sniff::Unknown);
+    }
+}
@@ -0,0 +1,222 @@
+//! 发现重传风暴：会话的 KCP 一直在重发同一批数据，却没有新数据发得出去。
+//!
+//! 线路被黑洞（包发出去石沉大海）、严重丢包或者乱序时，KCP 按退避的间隔一直重传，要等到会话过期（默认 90 秒）
+//! 或者重传次数用完才报错，期间隧道里的 TCP 连接一动不动。kcp-rs 不公开 KCP 内部的统计，
+//! 这里用 `wire` 从发出的包里数出的数据段：序号不超过已经发过的就是重传。
+//!
+//! 每 `CHECK_INTERVAL` 看一次各个会话，发出的数据段里重传超过 `THRESHOLD` 的间隔算作卡住；重传退避得很长时
+//! 一个间隔内可能一个段都没发，这时对端也没有任何回应就接着算卡住。连续卡住 `STRIKES` 个间隔、
+//! 期间至少发出 `MIN_SEGMENTS` 个数据段算作重传风暴，打印诊断：最后一个间隔完全没有收到对端的包时多半是线路被黑洞，
+//! 收得到包但数据一直得不到确认时多半是严重丢包或者乱序。会话带上 `storm` 标签，恢复后去掉。
+//! `--storm-reset` 时直接关闭这个会话（原因记为 `retransmit_storm`），只影响这一条 KCP 连接，
+//! 本地程序重连后在新的 KCP 会话上重来，不用卡到过期。
+
+use crate::registry::Registry;
+use crate::session::CloseReason;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// 检查的间隔
+const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+/// 卡住期间发出的数据段少于这个数时不算，偶尔重传一两个段是正常的
+const MIN_SEGMENTS: u64 = 20;
+/// 重传占发出的数据段的比例超过这个值算作卡住
+const THRESHOLD: f64 = 0.9;
+/// 连续卡住这么多个间隔算作重传风暴
+const STRIKES: u32 = 3;
+/// 标记正在重传风暴中的会话的标签
+const TAG: &str = "storm";
+
+/// 一个会话上次检查时的计数和卡住的情况
+#[derive(Default)]
+struct Track {
+    segments: u64,
+    retransmits: u64,
+    wire_received: u64,
+    /// 连续卡住的间隔数，以及这期间发出的数据段和其中重传的
+    strikes: u32,
+    stalled_segments: u64,
+    stalled_retransmits: u64,
+    /// 已经报告过这次重传风暴
+    reported: bool,
+}
+
+/// 一次检查的结论
+#[derive(Debug, PartialEq)]
+enum Verdict {
+    Fine,
+    /// 报告过的重传风暴平息了
+    Recovered,
+    /// 卡住了，但还不算重传风暴，或者已经报告过
+    Stalled,
+    /// 新发现的重传风暴：卡住的秒数、其中重传的百分比，以及最后一个间隔有没有收到对端的包
+    Storm {
+        seconds: u64,
+        percent: u64,
+        silent: bool,
+    },
+}
+
+impl Track {
+    /// 用会话现在的计数更新，得出这个间隔的结论
+    fn update(&mut self, segments: u64, retransmits: u64, wire_received: u64) -> Verdict {
+        let new_segments = segments.saturating_sub(self.segments);
+        let new_retransmits = retransmits.saturating_sub(self.retransmits);
+        let silent = wire_received == self.wire_received;
+        self.segments = segments;
+        self.retransmits = retransmits;
+        self.wire_received = wire_received;
+
+        let stalled = if new_segments > 0 {
+            new_retransmits as f64 >= new_segments as f64 * THRESHOLD
+        } else {
+            self.strikes > 0 && silent
+        };
+        if !stalled {
+            let reported = self.reported;
+            *self = Track {
+                segments,
+                retransmits,
+                wire_received,
+                ..Track::default()
+            };
+            return if reported {
+                Verdict::Recovered
+            } else {
+                Verdict::Fine
+            };
+        }
+        self.strikes += 1;
+        self.stalled_segments += new_segments;
+        self.stalled_retransmits += new_retransmits;
+        if self.reported || self.strikes < STRIKES || self.stalled_segments < MIN_SEGMENTS {
+            return Verdict::Stalled;
+        }
+        self.reported = true;
+        Verdict::Storm {
+            seconds: (CHECK_INTERVAL * self.strikes).as_secs(),
+            percent: self.stalled_retransmits * 100 / self.stalled_segments,
+            silent,
+        }
+    }
+}
+
+/// 一直运行到进入排空状态；`reset` 为真时关闭发生重传风暴的会话
+pub async fn watch(registry: Arc<Registry>, reset: bool) {
+    let mut tracks: HashMap<String, Track> = HashMap::new();
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep(CHECK_INTERVAL) => {}
+            _ = registry.draining() => return,
+        }
+        let sessions = registry.list();
+        tracks.retain(|id, _| sessions.iter().any(|session| &session.id == id));
+        for session in sessions {
+            let track = tracks.entry(session.id.clone()).or_default();
+            let verdict =
+                track.update(session.segments, session.retransmits, session.wire_received);
+            let id = &session.id;
+            let (seconds, percent, silent) = match verdict {
+                Verdict::Fine | Verdict::Stalled => continue,
+                Verdict::Recovered => {
+                    notice!(
+                        "Session {id}: retransmission storm is over",
+                        "会话 {id}：重传风暴已经平息"
+                    );
+                    registry.untag(id, TAG);
+                    continue;
+                }
+                Verdict::Storm {
+                    seconds,
+                    percent,
+                    silent,
+                } => (seconds, percent, silent),
+            };
+            if silent {
+                warn!(
+                    "Session {id}: retransmission storm, {percent}% of segments in the last {seconds}s were retransmits and nothing came back from the other end, the path looks black-holed",
+                    "会话 {id}：重传风暴，最近 {seconds} 秒发出的数据段 {percent}% 是重传，对端一个包都没有回来，线路像是被黑洞了"
+                );
+            } else {
+                warn!(
+                    "Session {id}: retransmission storm, {percent}% of segments in the last {seconds}s were retransmits although the other end is still answering, likely heavy loss or reordering",
+                    "会话 {id}：重传风暴，最近 {seconds} 秒发出的数据段 {percent}% 是重传，对端仍有回应，多半是严重丢包或者乱序"
+                );
+            }
+            if reset {
+                notice!(
+                    "Session {id}: resetting the KCP connection",
+                    "会话 {id}：重置这条 KCP 连接"
+                );
+                registry.stop(id, CloseReason::RetransmitStorm);
+            } else {
+                registry.tag(id, TAG.to_string());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_storm_after_strikes() {
+        let mut track = Track::default();
+        // 正常发送：重传很少，对端有回应
+        assert_eq!(track.update(100, 2, 1000), Verdict::Fine);
+        // 连续三个间隔几乎全是重传，第三个间隔才报告
+        assert_eq!(track.update(120, 21, 1100), Verdict::Stalled);
+        assert_eq!(track.update(140, 40, 1200), Verdict::Stalled);
+        assert_eq!(
+            track.update(160, 60, 1300),
+            Verdict::Storm {
+                seconds: 15,
+                percent: 96,
+                silent: false,
+            }
+        );
+        // 只报告一次；退避得很长、一个段都没发而且对端没有回应，也接着算卡住
+        assert_eq!(track.update(160, 60, 1300), Verdict::Stalled);
+        assert_eq!(track.update(161, 61, 1300), Verdict::Stalled);
+        // 新数据发出去了
+        assert_eq!(track.update(200, 61, 1400), Verdict::Recovered);
+        assert_eq!(track.update(210, 61, 1500), Verdict::Fine);
+    }
+
+    #[test]
+    fn needs_enough_segments_and_ratio() {
+        // 每个间隔只重传几个段，凑不够 MIN_SEGMENTS
+        let mut track = Track::default();
+        for i in 1..=10 {
+            let verdict = track.update(i * 2, i * 2, 0);
+            assert!(matches!(verdict, Verdict::Stalled | Verdict::Storm { .. }));
+            if i * 2 < MIN_SEGMENTS {
+                assert_eq!(verdict, Verdict::Stalled);
+            }
+        }
+        assert!(track.reported);
+        // 对端一直没有回应时算作线路被黑洞
+        let mut track = Track::default();
+        let verdicts: Vec<_> = (1..=3).map(|i| track.update(i * 10, i * 10, 0)).collect();
+        assert_eq!(
+            verdicts[2],
+            Verdict::Storm {
+                seconds: 15,
+                percent: 100,
+                silent: true,
+            }
+        );
+
+        // 重传比例刚好低于阈值的间隔打断连续的计数
+        let mut track = Track::default();
+        assert_eq!(track.update(10, 10, 0), Verdict::Stalled);
+        assert_eq!(track.update(20, 20, 0), Verdict::Stalled);
+        assert_eq!(track.update(30, 28, 1), Verdict::Fine);
+        assert_eq!(track.update(40, 38, 1), Verdict::Stalled);
+        // 没有发出数据段而且从来没卡住过不算卡住
+        let mut track = Track::default();
+        assert_eq!(track.update(0, 0, 0), Verdict::Fine);
+    }
+}
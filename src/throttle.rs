@@ -0,0 +1,118 @@
+//! 新会话的速率限制：超过速率时推迟接受新连接而不是拒绝，
+//! 让大量客户端同时重连时（比如服务器重启后）分散开来建立会话，不会一下子压到后端上。
+//!
+//! 按 GCRA（等价于令牌桶）计算：空闲一段时间后可以立即接受 `burst` 个连接，之后按速率放行。
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+pub struct Throttle {
+    interval: Duration,
+    /// 允许提前放行的时间，即 `burst - 1` 个间隔
+    tolerance: Duration,
+    /// 下一个连接按速率应该被放行的时间
+    next: Mutex<Instant>,
+}
+
+impl Throttle {
+    /// 每秒最多 `rate` 个新会话，最多连续放行 `burst` 个
+    pub fn new(rate: u32, burst: u32) -> Self {
+        let interval = Duration::from_secs(1) / rate.max(1);
+        Self {
+            interval,
+            tolerance: interval * burst.max(1).saturating_sub(1),
+            next: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// 等到可以接受下一个新会话，多个接受循环共用时一起计算速率
+    pub async fn acquire(&self) {
+        let delay = self.reserve(Instant::now());
+        if !delay.is_zero() {
+            warn_repeated!(
+                tr!("new sessions throttled", "新会话被限速"),
+                "New sessions exceed the rate limit, delaying the next one by {}ms",
+                "新会话超过速率限制，下一个推迟 {}ms 接受",
+                delay.as_millis()
+            );
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    /// 在 `now` 时给下一个新会话排上位置，返回要推迟多久
+    fn reserve(&self, now: Instant) -> Duration {
+        let start = {
+            let mut next = self.next.lock().unwrap();
+            let start = (*next).max(now);
+            *next = start + self.interval;
+            start
+        };
+        start.saturating_duration_since(now + self.tolerance)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn allows_burst_then_paces() {
+        // 每秒 10 个，最多连续 3 个
+        let throttle = Throttle::new(10, 3);
+        let start = *throttle.next.lock().unwrap();
+        let delays: Vec<_> = (0..5).map(|_| throttle.reserve(start)).collect();
+        assert_eq!(delays, [ms(0), ms(0), ms(0), ms(100), ms(200)]);
+
+        // 按速率到来的不用等
+        let throttle = Throttle::new(10, 1);
+        let start = *throttle.next.lock().unwrap();
+        for i in 0..5 {
+            assert_eq!(throttle.reserve(start + ms(100) * i), Duration::ZERO);
+        }
+        // 早到的推迟到下一个间隔
+        assert_eq!(throttle.reserve(start + ms(450)), ms(50));
+    }
+
+    #[test]
+    fn refills_after_idle() {
+        let throttle = Throttle::new(10, 3);
+        let start = *throttle.next.lock().unwrap();
+        for _ in 0..3 {
+            throttle.reserve(start);
+        }
+        assert_eq!(throttle.reserve(start), ms(100));
+        // 空闲 1 秒后又能连续放行 3 个，但不会攒得更多
+        let later = start + Duration::from_secs(1);
+        let delays: Vec<_> = (0..4).map(|_| throttle.reserve(later)).collect();
+        assert_eq!(delays, [ms(0), ms(0), ms(0), ms(100)]);
+    }
+
+    #[test]
+    fn treats_zero_as_one() {
+        let throttle = Throttle::new(0, 0);
+        let start = *throttle.next.lock().unwrap();
+        assert_eq!(throttle.reserve(start), Duration::ZERO);
+        assert_eq!(throttle.reserve(start), Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn shared_between_tasks() {
+        let throttle = std::sync::Arc::new(Throttle::new(50, 1));
+        let started = Instant::now();
+        let tasks: Vec<_> = (0..4)
+            .map(|_| {
+                let throttle = throttle.clone();
+                tokio::spawn(async move { throttle.acquire().await })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+        // 4 个会话共用一个速率，最后一个至少等 3 个间隔
+        assert!(started.elapsed() >= ms(60));
+    }
+}
@@ -0,0 +1,298 @@
+//! KCP 使用的 UDP 套接字：按 `--udp-buffer` 设置内核的收发缓冲区，并处理平台差异。
+//!
+//! 内核默认的缓冲区（Linux 上通常约 200 KB）装不下一个满窗口的突发流量，多出来的包直接被丢弃，
+//! KCP 只能靠重传补回来。`auto` 按 KCP 窗口 × MTU 计算大小；服务端的套接字被所有会话共用，按几个会话的量计算。
+//!
+//! Windows 上对方不在线时回来的 ICMP 端口不可达会让同一个套接字的下一次接收报 `WSAECONNRESET`，
+//! 服务端所有会话共用一个套接字，一个客户端掉线就会打断其它会话的接收，所以关掉这个行为。
+//!
+//! Linux 上可以用 `--fwmark` 给套接字发出的包打上防火墙标记，配合 `ip rule add fwmark ...` 做策略路由，
+//! 比如在多 WAN 的路由器上让隧道固定走某一条线路。设置标记需要 `CAP_NET_ADMIN`。
+//!
+//! 客户端可以用 `--source-addr` 指定套接字绑定的本机地址，由它决定从哪块网卡、用哪个地址族出去，
+//! 不交给系统的源地址选择。
+//!
+//! 低延迟模式下 Linux 上可以用 `--busy-poll` 让套接字收包时在网卡队列上忙等一小段时间，
+//! 省掉中断和唤醒的延迟，代价是更多的 CPU；效果取决于网卡驱动，超过 `net.core.busy_read` 的值需要 `CAP_NET_ADMIN`。
+
+use crate::disguise::Disguised;
+use crate::wire::{Metered, Wire};
+use bytes::BytesMut;
+use kcp::transport::UdpStream;
+use kcp::{KcpConfig, KcpStream};
+use socket2::SockRef;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, OnceLock};
+use tokio::net::UdpSocket;
+
+/// 服务端套接字按这么多个会话的窗口计算缓冲区
+const SERVER_SESSIONS: usize = 4;
+
+static BUFFER: OnceLock<BufferSize> = OnceLock::new();
+static MARK: OnceLock<u32> = OnceLock::new();
+static SOURCE: OnceLock<IpAddr> = OnceLock::new();
+static BUSY_POLL: OnceLock<u32> = OnceLock::new();
+
+/// 缓冲区大小：`auto` 或者以 KB 为单位的数字
+#[derive(Clone, Copy)]
+pub enum BufferSize {
+    Auto,
+    Bytes(usize),
+}
+
+impl FromStr for BufferSize {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "auto" => Ok(BufferSize::Auto),
+            kb => kb
+                .parse::<usize>()
+                .ok()
+                .filter(|kb| *kb > 0)
+                .map(|kb| BufferSize::Bytes(kb * 1024))
+                .ok_or_else(|| format!("invalid buffer size {s:?}, expected auto or a size in KB")),
+        }
+    }
+}
+
+/// 解析防火墙标记，和 `ip rule` 一样可以写成十进制或者 0x 开头的十六进制
+pub fn parse_mark(s: &str) -> Result<u32, String> {
+    match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => s.parse(),
+    }
+    .map_err(|_| format!("invalid firewall mark {s:?}"))
+}
+
+/// 设置之后创建的套接字使用的缓冲区大小和防火墙标记，不调用时使用内核默认值；
+/// 设置了标记时先在一个临时套接字上试一次，没有权限或者平台不支持时返回错误
+pub fn init(size: Option<BufferSize>, mark: Option<u32>) -> io::Result<()> {
+    if let Some(size) = size {
+        let _ = BUFFER.set(size);
+    }
+    if let Some(mark) = mark {
+        let probe = std::net::UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+        set_mark(SockRef::from(&probe), mark)?;
+        let _ = MARK.set(mark);
+    }
+    Ok(())
+}
+
+/// 之后为连接服务端创建的套接字都绑定到本机地址 `ip`；先试着绑定一次，地址不在本机上时返回错误
+pub fn set_source(ip: IpAddr) -> io::Result<()> {
+    std::net::UdpSocket::bind((ip, 0))?;
+    let _ = SOURCE.set(ip);
+    Ok(())
+}
+
+/// 之后创建的套接字收包时忙等 `micros` 微秒；先在一个临时套接字上试一次，没有权限或者平台不支持时返回错误
+pub fn set_busy_poll(micros: u32) -> io::Result<()> {
+    let probe = std::net::UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    busy_poll(SockRef::from(&probe), micros)?;
+    let _ = BUSY_POLL.set(micros);
+    Ok(())
+}
+
+/// 调整新建的套接字，`sessions` 是共用这个套接字的会话数的估计
+pub fn tune(socket: &UdpSocket, config: &KcpConfig, sessions: usize) {
+    if let Err(e) = ignore_connreset(socket) {
+        warn!(
+            "Failed to turn off UDP connection reset reports: {e}",
+            "无法关闭 UDP 连接重置报告：{e}"
+        );
+    }
+    tune_buffers(socket, config, sessions);
+    if let Some(mark) = MARK.get()
+        && let Err(e) = set_mark(SockRef::from(socket), *mark)
+    {
+        warn_repeated!(
+            tr!("firewall mark failed", "防火墙标记设置失败"),
+            "Failed to set firewall mark {mark:#x} on UDP socket: {e}",
+            "无法给 UDP 套接字设置防火墙标记 {mark:#x}：{e}"
+        );
+    }
+    if let Some(micros) = BUSY_POLL.get()
+        && let Err(e) = busy_poll(SockRef::from(socket), *micros)
+    {
+        warn_repeated!(
+            tr!("busy polling failed", "忙等设置失败"),
+            "Failed to set busy polling on UDP socket: {e}",
+            "无法给 UDP 套接字设置忙等：{e}"
+        );
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn set_mark(socket: SockRef, mark: u32) -> io::Result<()> {
+    socket.set_mark(mark)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_mark(_socket: SockRef, _mark: u32) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "firewall marks are only supported on Linux",
+    ))
+}
+
+#[cfg(target_os = "linux")]
+fn busy_poll(socket: SockRef, micros: u32) -> io::Result<()> {
+    socket.set_busy_poll(micros)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn busy_poll(_socket: SockRef, _micros: u32) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "busy polling is only supported on Linux",
+    ))
+}
+
+/// 按设置调整套接字的收发缓冲区
+fn tune_buffers(socket: &UdpSocket, config: &KcpConfig, sessions: usize) {
+    let Some(size) = BUFFER.get() else { return };
+    let bytes = match size {
+        BufferSize::Auto => (config.snd_wnd.max(config.rcv_wnd) * config.mtu) as usize * sessions,
+        BufferSize::Bytes(bytes) => *bytes,
+    };
+    let socket = SockRef::from(socket);
+    let _ = socket.set_recv_buffer_size(bytes);
+    let _ = socket.set_send_buffer_size(bytes);
+    // 内核会把超过上限（Linux 上是 net.core.rmem_max/wmem_max）的值截断，只提示一次
+    static WARNED: AtomicBool = AtomicBool::new(false);
+    let actual = socket
+        .recv_buffer_size()
+        .unwrap_or(0)
+        .min(socket.send_buffer_size().unwrap_or(0));
+    // Linux 读回的是设置值的两倍，多出的一半留给内核自己记账
+    let actual = if cfg!(target_os = "linux") {
+        actual / 2
+    } else {
+        actual
+    };
+    if actual < bytes && !WARNED.swap(true, Ordering::Relaxed) {
+        warn!(
+            "UDP buffers limited to {} KB by the system instead of {} KB, raise net.core.rmem_max and net.core.wmem_max",
+            "UDP 缓冲区被系统限制为 {} KB，而不是 {} KB，可以调大 net.core.rmem_max 和 net.core.wmem_max",
+            actual / 1024,
+            bytes / 1024
+        );
+    }
+}
+
+/// 让 ICMP 端口不可达不再以 `WSAECONNRESET` 的形式报告给套接字
+#[cfg(windows)]
+fn ignore_connreset(socket: &UdpSocket) -> io::Result<()> {
+    use std::os::windows::io::AsRawSocket;
+
+    /// `_WSAIOW(IOC_VENDOR, 12)`
+    const SIO_UDP_CONNRESET: u32 = 0x9800_000c;
+
+    #[link(name = "ws2_32")]
+    unsafe extern "system" {
+        fn WSAIoctl(
+            socket: usize,
+            code: u32,
+            input: *const std::ffi::c_void,
+            input_len: u32,
+            output: *mut std::ffi::c_void,
+            output_len: u32,
+            returned: *mut u32,
+            overlapped: *mut std::ffi::c_void,
+            routine: *mut std::ffi::c_void,
+        ) -> i32;
+    }
+
+    let enable: u32 = 0;
+    let mut returned = 0;
+    let ret = unsafe {
+        WSAIoctl(
+            socket.as_raw_socket() as usize,
+            SIO_UDP_CONNRESET,
+            (&enable as *const u32).cast(),
+            size_of::<u32>() as u32,
+            std::ptr::null_mut(),
+            0,
+            &mut returned,
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(windows))]
+fn ignore_connreset(_socket: &UdpSocket) -> io::Result<()> {
+    Ok(())
+}
+
+/// 服务端：调整监听套接字
+pub fn tune_listener(socket: &UdpSocket, config: &KcpConfig) {
+    tune(socket, config, SERVER_SESSIONS);
+}
+
+/// 绑定一个用于连接 `addr` 的本地套接字，端口由系统分配
+pub async fn bind_for(addr: SocketAddr, config: &KcpConfig) -> io::Result<UdpSocket> {
+    let socket = UdpSocket::bind(local_for(addr)?).await?;
+    tune(&socket, config, 1);
+    Ok(socket)
+}
+
+/// 系统发往 `addr` 的包会从本机的哪个地址发出：在一个不发包的套接字上 `connect` 之后读出来，没有路由时返回错误
+pub fn source_for(addr: SocketAddr) -> io::Result<IpAddr> {
+    let socket = std::net::UdpSocket::bind(local_for(addr)?)?;
+    // 策略路由按标记选路由表，源地址也可能跟着不同
+    if let Some(mark) = MARK.get() {
+        let _ = set_mark(SockRef::from(&socket), *mark);
+    }
+    socket.connect(addr)?;
+    Ok(socket.local_addr()?.ip())
+}
+
+/// 连接 `addr` 的套接字应该绑定的本地地址，端口由系统分配
+fn local_for(addr: SocketAddr) -> io::Result<SocketAddr> {
+    Ok(match SOURCE.get() {
+        Some(source) if source.is_ipv4() != addr.is_ipv4() => {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrNotAvailable,
+                format!(
+                    "server address {addr} and --source-addr {source} are of different families"
+                ),
+            ));
+        }
+        Some(source) => (*source, 0).into(),
+        None if addr.is_ipv4() => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        None => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    })
+}
+
+/// 和 `KcpUdpStream::connect` 一样建立 KCP 连接，套接字按设置调整缓冲区，收发的包记到 `wire` 上
+pub async fn connect(
+    config: Arc<KcpConfig>,
+    addr: SocketAddr,
+    wire: Arc<Wire>,
+) -> io::Result<(KcpStream, SocketAddr)> {
+    let socket = bind_for(addr, &config).await?;
+    socket_connect(config, addr, socket, wire).await
+}
+
+/// 和 `KcpUdpStream::socket_connect` 一样在已经绑定的套接字上建立 KCP 连接，收发的包记到 `wire` 上
+pub async fn socket_connect(
+    config: Arc<KcpConfig>,
+    addr: SocketAddr,
+    socket: UdpSocket,
+    wire: Arc<Wire>,
+) -> io::Result<(KcpStream, SocketAddr)> {
+    let transport = Metered::new(Disguised::new(UdpStream::new(socket, addr)), wire, addr);
+    let stream =
+        KcpStream::connect::<_, BytesMut, _>(config, transport, futures::sink::drain(), None)
+            .await?;
+    Ok((stream, addr))
+}
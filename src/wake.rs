@@ -0,0 +1,61 @@
+//! 客户端：发现系统休眠后醒来，及早处理已经失效的会话。
+//!
+//! 休眠期间本机的 KCP 不会发包，服务端那边的会话超过 KCP 的过期时间（默认 90 秒）就被清掉了；
+//! 而本机的单调时钟在 Linux、macOS 上休眠时不走，醒来后 KCP 以为什么都没发生，继续往一个不存在的会话发数据，
+//! 要等重传次数用完或者再过一个过期时间才发现，每个隧道里的 TCP 连接都要卡上好几分钟。
+//!
+//! 这里定时比较墙上时钟和单调时钟各自走了多久，任何一个比预期多出一大截就认为刚从休眠中醒来。
+//! 睡的时间超过 KCP 的过期时间时，服务端一侧的会话肯定已经不在了，直接关闭所有会话（原因记为 `system_sleep`），
+//! 让本地程序马上看到断开、自己重连，控制通道和反向隧道也立即重新建立；睡得更短时会话多半还在，只记一条日志。
+//! 墙上时钟被手动或者 NTP 大幅调整时也可能被当成休眠，影响只是多关闭一次会话。
+
+use crate::registry::Registry;
+use crate::session::CloseReason;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+/// 检查的间隔
+const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+/// 比预期多出这么久才算休眠，避免把调度延迟、小幅校时当成休眠
+const MIN_SLEEP: Duration = Duration::from_secs(10);
+
+/// 一直运行到进入排空状态，`expire` 是 KCP 会话的过期时间
+pub async fn watch(registry: Arc<Registry>, expire: Duration) {
+    let mut wall = SystemTime::now();
+    let mut mono = Instant::now();
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep(CHECK_INTERVAL) => {}
+            _ = registry.draining() => return,
+        }
+        let (now_wall, now_mono) = (SystemTime::now(), Instant::now());
+        // 墙上时钟往回调时算作没有流逝
+        let elapsed = now_wall
+            .duration_since(wall)
+            .unwrap_or_default()
+            .max(now_mono - mono);
+        (wall, mono) = (now_wall, now_mono);
+        let Some(slept) = elapsed
+            .checked_sub(CHECK_INTERVAL)
+            .filter(|slept| *slept >= MIN_SLEEP)
+        else {
+            continue;
+        };
+        if slept < expire {
+            info!(
+                "System was asleep for about {}s, sessions should still be alive",
+                "系统休眠了大约 {} 秒，会话应该还在",
+                slept.as_secs()
+            );
+            continue;
+        }
+        notice!(
+            "System was asleep for about {}s, longer than KCP sessions last, closing {} sessions and reconnecting",
+            "系统休眠了大约 {} 秒，超过了 KCP 会话的过期时间，关闭 {} 个会话并重新连接",
+            slept.as_secs(),
+            registry.len()
+        );
+        registry.stop_all(CloseReason::SystemSleep);
+        registry.wake();
+    }
+}
@@ -0,0 +1,64 @@
+//! 向用户指定的地址发送事件通知：一次 HTTP POST，请求体是一行 JSON。
+//!
+//! 只支持 `http://`，需要 HTTPS 时可以在本机放一个转发程序。
+
+use crate::dns;
+use crate::json::Value;
+use anyhow::{Context, bail};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::time::timeout;
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// 检查地址格式，返回主机（含端口）和路径
+fn parse(url: &str) -> anyhow::Result<(String, &str)> {
+    let Some(rest) = url.strip_prefix("http://") else {
+        bail!("webhook {url:?} must start with http://");
+    };
+    let (authority, path) = match rest.find('/') {
+        Some(i) => rest.split_at(i),
+        None => (rest, "/"),
+    };
+    if authority.is_empty() {
+        bail!("webhook {url:?} has no host");
+    }
+    let authority = match authority.rsplit_once(':') {
+        Some((_, port)) if port.bytes().all(|b| b.is_ascii_digit()) => authority.to_string(),
+        _ => format!("{authority}:80"),
+    };
+    Ok((authority, path))
+}
+
+/// 启动时检查地址格式，避免等到第一次通知才发现写错
+pub fn validate(url: &str) -> anyhow::Result<()> {
+    parse(url).map(|_| ())
+}
+
+/// 发送一次通知，对方返回 2xx 以外的状态时返回错误
+pub async fn post(url: &str, body: &Value) -> anyhow::Result<()> {
+    let (authority, path) = parse(url)?;
+    let body = body.to_string();
+    let request = format!(
+        "POST {path} HTTP/1.1\r\nHost: {authority}\r\nContent-Type: application/json\r\n\
+         Content-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    let status = timeout(REQUEST_TIMEOUT, async {
+        let mut stream = dns::connect_tcp(&authority).await?;
+        stream.write_all(request.as_bytes()).await?;
+        let mut head = [0u8; 64];
+        let n = stream.read(&mut head).await?;
+        anyhow::Ok(String::from_utf8_lossy(&head[..n]).into_owned())
+    })
+    .await
+    .context("webhook timed out")??;
+    let code = status.split_whitespace().nth(1).unwrap_or_default();
+    if !code.starts_with('2') {
+        bail!(
+            "webhook answered {:?}",
+            status.lines().next().unwrap_or_default()
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,323 @@
+//! 线路上实际收发的 UDP 流量，和转发的有效数据比较得出协议开销：KCP 段头、确认、重传、握手、保活包，
+//! 以及 `--redundant-addr` 多发的副本都算在里面，用来衡量各种设置的代价。
+//!
+//! 每个 UDP 包按载荷加上 IP 和 UDP 头计算（IPv4 28 字节，IPv6 48 字节），不含链路层的帧头；
+//! 设置了 `--disguise` 时再加上伪装的包头（见 `disguise`）。
+//! 每个会话单独统计，同时计入全局；控制通道和反向隧道的注册连接不属于任何会话，只计入全局，
+//! 所以全局的开销比各个会话的都高一些。
+//!
+//! 发出的包还会按 KCP 的格式读出其中的数据段，序号不超过已经发过的就是重传，给 `storm` 判断重传风暴用。
+//!
+//! kcp-rs 不公开 KCP 内部测得的 RTT，这里从线路上的包估算：数据段的 ts 是发送方 KCP 时钟的读数，
+//! 对端的确认段原样带回被确认的数据段的 ts。发出数据段时记下 KCP 时钟和本地时钟的差，
+//! 收到确认时用它把 ts 换算成发出的时刻，和现在相比就是一次往返，再做指数平滑。
+//! 和 KCP 自己的算法一样，包含对端攒到下一个时钟周期才发确认的时间。
+
+use crate::disguise;
+use bytes::BytesMut;
+use futures::{Sink, Stream};
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, LazyLock};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+/// 所有 KCP 连接合计
+static TOTAL: Wire = Wire::new();
+
+/// KCP 段头的长度
+const SEGMENT_HEADER: usize = 24;
+/// 数据段的命令字
+const CMD_PUSH: u8 = 81;
+/// 确认段的命令字
+const CMD_ACK: u8 = 82;
+/// kcp-rs 握手和挥手时在命令字上加的标志位之外的部分
+const CMD_MASK: u8 = 0x57;
+/// 超过这个值的往返时间当作时钟对不上，不计入
+const MAX_RTT_MS: u32 = 60_000;
+
+/// 本地时钟的起点，换算成和 KCP 时钟一样的 32 位毫秒数
+static EPOCH: LazyLock<Instant> = LazyLock::new(Instant::now);
+
+/// 收发的 UDP 字节数，以及发出的 KCP 数据段数
+#[derive(Default)]
+pub struct Wire {
+    sent: AtomicU64,
+    received: AtomicU64,
+    /// 发出的数据段，包括重传的
+    segments: AtomicU64,
+    retransmits: AtomicU64,
+    /// 下一个新数据段的序号
+    next_sn: AtomicU32,
+    /// 本地时钟减去 KCP 时钟的毫秒数，最高的 32 位不为 0 表示已经有值
+    clock: AtomicU64,
+    /// 平滑后的往返时间加 1 毫秒，0 表示还没有采样
+    srtt: AtomicU32,
+}
+
+impl Wire {
+    const fn new() -> Self {
+        Self {
+            sent: AtomicU64::new(0),
+            received: AtomicU64::new(0),
+            segments: AtomicU64::new(0),
+            retransmits: AtomicU64::new(0),
+            next_sn: AtomicU32::new(0),
+            clock: AtomicU64::new(0),
+            srtt: AtomicU32::new(0),
+        }
+    }
+
+    /// 记录发往 `peer` 的一个 `len` 字节的包，同时计入全局
+    pub fn send(&self, len: usize, peer: SocketAddr) {
+        let bytes = len as u64 + header(peer);
+        self.sent.fetch_add(bytes, Ordering::Relaxed);
+        TOTAL.sent.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// 记录从 `peer` 收到的一个 `len` 字节的包，同时计入全局
+    pub fn receive(&self, len: usize, peer: SocketAddr) {
+        let bytes = len as u64 + header(peer);
+        self.received.fetch_add(bytes, Ordering::Relaxed);
+        TOTAL.received.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// 数出 KCP 要发出的一个包里的数据段和其中重传的；一个包发了多份（`--redundant-addr`）时只数一次
+    pub fn segments_in(&self, packet: &[u8]) {
+        for header in headers(packet) {
+            if header[4] & CMD_MASK == CMD_PUSH {
+                let ts = field(header, 8);
+                self.clock.store(
+                    1 << 32 | now_ms().wrapping_sub(ts) as u64,
+                    Ordering::Relaxed,
+                );
+                let sn = field(header, 12);
+                self.segments.fetch_add(1, Ordering::Relaxed);
+                if self
+                    .next_sn
+                    .fetch_max(sn.wrapping_add(1), Ordering::Relaxed)
+                    > sn
+                {
+                    self.retransmits.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
+    }
+
+    /// 从收到的包里的确认段估算往返时间
+    pub fn acks_in(&self, packet: &[u8]) {
+        let clock = self.clock.load(Ordering::Relaxed);
+        if clock == 0 {
+            return;
+        }
+        let now = now_ms();
+        for header in headers(packet) {
+            if header[4] & CMD_MASK != CMD_ACK {
+                continue;
+            }
+            let sent = (clock as u32).wrapping_add(field(header, 8));
+            let rtt = now.wrapping_sub(sent);
+            if rtt > MAX_RTT_MS {
+                continue;
+            }
+            let _ = self
+                .srtt
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |srtt| {
+                    Some(match srtt {
+                        0 => rtt + 1,
+                        srtt => (srtt * 7 + rtt + 1) / 8,
+                    })
+                });
+        }
+    }
+
+    pub fn sent(&self) -> u64 {
+        self.sent.load(Ordering::Relaxed)
+    }
+
+    pub fn received(&self) -> u64 {
+        self.received.load(Ordering::Relaxed)
+    }
+
+    pub fn segments(&self) -> u64 {
+        self.segments.load(Ordering::Relaxed)
+    }
+
+    pub fn retransmits(&self) -> u64 {
+        self.retransmits.load(Ordering::Relaxed)
+    }
+
+    /// 平滑后的往返时间，还没有收到过确认时为 `None`
+    pub fn rtt(&self) -> Option<Duration> {
+        match self.srtt.load(Ordering::Relaxed) {
+            0 => None,
+            srtt => Some(Duration::from_millis((srtt - 1).into())),
+        }
+    }
+}
+
+/// 包里依次的 KCP 段头，数据长度超出包尾时到此为止
+fn headers(packet: &[u8]) -> impl Iterator<Item = &[u8]> {
+    let mut rest = packet;
+    std::iter::from_fn(move || {
+        let header = rest.get(..SEGMENT_HEADER)?;
+        rest = rest
+            .get(SEGMENT_HEADER + field(header, 20) as usize..)
+            .unwrap_or_default();
+        Some(header)
+    })
+}
+
+fn field(header: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(header[at..at + 4].try_into().unwrap())
+}
+
+fn now_ms() -> u32 {
+    EPOCH.elapsed().as_millis() as u32
+}
+
+/// 线路上的 `wire` 字节中协议开销占的比例，`payload` 是两个方向转发的有效数据合计；还没有收发过包时为 `None`
+pub fn overhead(wire: u64, payload: u64) -> Option<f64> {
+    (wire > 0).then(|| wire.saturating_sub(payload) as f64 / wire as f64)
+}
+
+/// 所有 KCP 连接合计的 UDP 字节数
+pub fn total() -> &'static Wire {
+    &TOTAL
+}
+
+/// IP 头和 UDP 头的字节数
+fn header(peer: SocketAddr) -> u64 {
+    let ip = match peer {
+        SocketAddr::V4(_) => 20 + 8,
+        SocketAddr::V6(_) => 40 + 8,
+    };
+    ip + disguise::overhead() as u64
+}
+
+/// 包装 KCP 的传输层，经过的每个包都记到 `wire` 上
+pub struct Metered<T> {
+    inner: T,
+    wire: Arc<Wire>,
+    peer: SocketAddr,
+}
+
+impl<T> Metered<T> {
+    pub fn new(inner: T, wire: Arc<Wire>, peer: SocketAddr) -> Self {
+        Self { inner, wire, peer }
+    }
+}
+
+impl<T: Stream<Item = BytesMut> + Unpin> Stream for Metered<T> {
+    type Item = BytesMut;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<BytesMut>> {
+        let polled = Pin::new(&mut self.inner).poll_next(cx);
+        if let Poll::Ready(Some(packet)) = &polled {
+            self.wire.receive(packet.len(), self.peer);
+            self.wire.acks_in(packet);
+        }
+        polled
+    }
+}
+
+impl<T: Sink<Si> + Unpin, Si: AsRef<[u8]>> Sink<Si> for Metered<T> {
+    type Error = T::Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), T::Error>> {
+        Pin::new(&mut self.inner).poll_ready(cx)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, packet: Si) -> Result<(), T::Error> {
+        self.wire.segments_in(packet.as_ref());
+        self.wire.send(packet.as_ref().len(), self.peer);
+        Pin::new(&mut self.inner).start_send(packet)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), T::Error>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), T::Error>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CMD_ACK: u8 = 82;
+
+    /// 一个 KCP 段：段头加上 `len` 字节的数据
+    fn segment(cmd: u8, sn: u32, len: usize) -> Vec<u8> {
+        let mut segment = vec![0; SEGMENT_HEADER];
+        segment[4] = cmd;
+        segment[12..16].copy_from_slice(&sn.to_le_bytes());
+        segment[20..24].copy_from_slice(&(len as u32).to_le_bytes());
+        segment.resize(SEGMENT_HEADER + len, 0xab);
+        segment
+    }
+
+    #[test]
+    fn counts_segments_and_retransmits() {
+        let wire = Wire::default();
+        // 一个包里的确认段、两个数据段，以及握手时带标志位的数据段
+        let packet = [
+            segment(CMD_ACK, 7, 0),
+            segment(CMD_PUSH, 0, 100),
+            segment(CMD_PUSH, 1, 0),
+            segment(CMD_PUSH | 0x80, 2, 10),
+        ]
+        .concat();
+        wire.segments_in(&packet);
+        assert_eq!((wire.segments(), wire.retransmits()), (3, 0));
+
+        // 重发序号 1，同时发出新的序号 3
+        wire.segments_in(&[segment(CMD_PUSH, 1, 50), segment(CMD_PUSH, 3, 50)].concat());
+        assert_eq!((wire.segments(), wire.retransmits()), (5, 1));
+    }
+
+    #[test]
+    fn estimates_rtt_from_acks() {
+        let wire = Wire::default();
+        // 还没有发过数据段时确认段换算不了
+        wire.acks_in(&segment(CMD_ACK, 0, 0));
+        assert_eq!(wire.rtt(), None);
+
+        let mut push = segment(CMD_PUSH, 0, 10);
+        push[8..12].copy_from_slice(&5000u32.to_le_bytes());
+        wire.segments_in(&push);
+        std::thread::sleep(Duration::from_millis(30));
+        let mut ack = segment(CMD_ACK, 0, 0);
+        ack[8..12].copy_from_slice(&5000u32.to_le_bytes());
+        wire.acks_in(&ack);
+        let rtt = wire.rtt().unwrap();
+        assert!(
+            rtt >= Duration::from_millis(30) && rtt < Duration::from_secs(1),
+            "{rtt:?}"
+        );
+
+        // 带回的 ts 比发出的还晚，时钟对不上，丢弃
+        ack[8..12].copy_from_slice(&9000u32.to_le_bytes());
+        wire.acks_in(&ack);
+        assert_eq!(wire.rtt(), Some(rtt));
+    }
+
+    #[test]
+    fn stops_at_truncated_segments() {
+        let wire = Wire::default();
+        // 段头不完整
+        wire.segments_in(&segment(CMD_PUSH, 0, 0)[..SEGMENT_HEADER - 1]);
+        assert_eq!(wire.segments(), 0);
+        // 数据长度超出包尾：这个段照算，后面不再有段
+        let mut packet = segment(CMD_PUSH, 0, 10);
+        packet[20] = 200;
+        packet.extend_from_slice(&segment(CMD_PUSH, 1, 0));
+        wire.segments_in(&packet);
+        assert_eq!((wire.segments(), wire.retransmits()), (1, 0));
+        wire.segments_in(&[]);
+        assert_eq!(wire.segments(), 1);
+    }
+}